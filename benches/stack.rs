@@ -0,0 +1,180 @@
+//! Compares running programs on the slotted and packed operand stacks and with the optimiser, and
+//! measures how long an interpreter takes to start. Run with `cargo bench`.
+
+use std::time::{Duration, Instant};
+
+use stack::assembler::Assembler;
+use stack::interpreter::{Interpreter, InterpreterConfig};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+const RUNS: u32 = 10;
+
+/// Counts a double word down from a million while adding up bytes
+const BYTES_AND_DWORDS: &str = "
+.entry main
+
+main:
+    push.d 1000000
+    store.d 0
+    push.b 0
+    store.b 2
+loop:
+    load.b 2
+    push.b 1
+    add.b
+    store.b 2
+    load.d 0
+    push.d 1
+    sub.d
+    dup.d
+    store.d 0
+    push.d 0
+    cmp.d
+    jmp.gt loop
+    ret
+";
+
+const FIB: &str = "
+.entry main
+
+main:
+    push 24
+    call fib
+    ret.w
+
+fib:
+    load 0
+    push 2
+    cmp
+    jmp.lt base
+    load 0
+    push 1
+    sub
+    call fib
+    store 1
+    load 0
+    push 2
+    sub
+    call fib
+    load 1
+    add
+    ret.w
+base:
+    load 0
+    ret.w
+";
+
+/// Adds up the numbers from a hundred thousand down, calling a small function for each, which
+/// the optimiser inlines
+const CALLS: &str = "
+.entry main
+
+main:
+    push 0
+    store 0
+    push 100000
+    store 1
+loop:
+    load 0
+    load 1
+    call add
+    store 0
+    load 1
+    push 1
+    sub
+    dup
+    store 1
+    push 0
+    cmp
+    jmp.gt loop
+    load 0
+    ret.w
+
+add:
+    load 0
+    load 1
+    add
+    ret.w
+";
+
+fn bench(src: &str, packed_stack: bool, optimise: bool) -> Result<Duration> {
+    let output = Assembler::new().with_optimise(optimise).assemble(src)?;
+    let config = InterpreterConfig {
+        packed_stack,
+        ..Default::default()
+    };
+    let mut interpreter = Interpreter::new(&output, None, None)?.with_config(config);
+
+    let start = Instant::now();
+    for _ in 0..RUNS {
+        interpreter.reset();
+        interpreter.run().into_result()?;
+    }
+
+    Ok(start.elapsed() / RUNS)
+}
+
+const STARTS: u32 = 1000;
+
+/// A program with a 16 KiB data section and a few hundred functions, to show the cost of
+/// creating an interpreter separately from running one
+fn startup_src() -> String {
+    let mut src = String::from(".entry main\n\n.data table .byte ");
+    src.push_str(&vec!["7"; 16 * 1024].join(", "));
+    src.push_str("\n\nmain:\n    push 1\n    ret.w\n");
+    for i in 0..500 {
+        src.push_str(&format!("\nf{i}:\n    load 0\n    ret.w\n"));
+    }
+    src
+}
+
+/// The average time to create an interpreter, and to create and run one, either from a borrowed
+/// program or one it takes ownership of
+fn startup(owned: bool) -> Result<(Duration, Duration)> {
+    let output = Assembler::new().assemble(&startup_src())?;
+    let mut outputs = vec![output.clone(); STARTS as usize];
+
+    let start = Instant::now();
+    for _ in 0..STARTS {
+        let _ = match owned {
+            true => Interpreter::from_output(outputs.pop().unwrap(), None, None)?,
+            false => Interpreter::new(&output, None, None)?,
+        };
+    }
+    let new = start.elapsed() / STARTS;
+
+    let start = Instant::now();
+    for _ in 0..STARTS {
+        let mut interpreter = Interpreter::new(&output, None, None)?;
+        interpreter.run().into_result()?;
+    }
+    let run = start.elapsed() / STARTS;
+
+    Ok((new, run))
+}
+
+fn main() -> Result<()> {
+    let programs = [
+        ("bytes-and-dwords", BYTES_AND_DWORDS),
+        ("fib", FIB),
+        ("calls", CALLS),
+    ];
+    for (name, src) in programs {
+        let slotted = bench(src, false, false)?;
+        let packed = bench(src, true, false)?;
+        let optimised = bench(src, false, true)?;
+        println!(
+            "{name:<20} slotted {slotted:>12.2?} packed {packed:>12.2?} optimised {optimised:>12.2?}"
+        );
+    }
+
+    let (borrowed, run) = startup(false)?;
+    let (owned, _) = startup(true)?;
+    println!(
+        "{:<20} new {borrowed:>12.2?} from_output {owned:>12.2?} new and run {run:>12.2?}",
+        "startup"
+    );
+
+    Ok(())
+}
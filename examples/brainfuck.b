@@ -0,0 +1,209 @@
+.entry main
+
+#define GETC 1006
+#define PUTC 1007
+#define TAPE 30000
+
+; A brainfuck program which prints "Hello World!"
+.data program .string "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++."
+
+main:
+    push.d @TAPE
+    newarr.b
+    store.d 0 ; tape
+    push.d 0
+    store.d 2 ; pc
+    push.d 0
+    store.d 4 ; head
+
+step:
+    load.d 2
+    push.d program.len
+    cmp.d
+    jmp.ge done
+
+    push.d program
+    load.d 2
+    get.b
+    store.b 6 ; the instruction
+
+    load.b 6
+    push.b '+'
+    cmp.b
+    jmp.eq plus
+    load.b 6
+    push.b '-'
+    cmp.b
+    jmp.eq minus
+    load.b 6
+    push.b '>'
+    cmp.b
+    jmp.eq right
+    load.b 6
+    push.b '<'
+    cmp.b
+    jmp.eq left
+    load.b 6
+    push.b '.'
+    cmp.b
+    jmp.eq output
+    load.b 6
+    push.b ','
+    cmp.b
+    jmp.eq input
+    load.b 6
+    push.b '['
+    cmp.b
+    jmp.eq open
+    load.b 6
+    push.b ']'
+    cmp.b
+    jmp.eq close
+    ; anything else is a comment
+    jmp next
+
+plus:
+    load.d 0
+    load.d 4
+    load.d 0
+    load.d 4
+    aget.b
+    push.b 1
+    add.b
+    aput.b
+    jmp next
+
+minus:
+    load.d 0
+    load.d 4
+    load.d 0
+    load.d 4
+    aget.b
+    push.b 1
+    sub.b
+    aput.b
+    jmp next
+
+right:
+    load.d 4
+    push.d 1
+    add.d
+    store.d 4
+    jmp next
+
+left:
+    load.d 4
+    push.d 1
+    sub.d
+    store.d 4
+    jmp next
+
+output:
+    load.d 0
+    load.d 4
+    aget.b
+    b2w
+    push @PUTC
+    system
+    jmp next
+
+input:
+    ; the end of the input leaves -1 in the cell
+    load.d 0
+    load.d 4
+    push @GETC
+    system
+    w2b
+    aput.b
+    jmp next
+
+open:
+    ; skip past the matching ] if the cell is zero
+    load.d 0
+    load.d 4
+    aget.b
+    push.b 0
+    cmp.b
+    jmp.ne next
+
+    push 1
+    store 7 ; depth
+forward:
+    load.d 2
+    push.d 1
+    add.d
+    store.d 2
+
+    push.d program
+    load.d 2
+    get.b
+    store.b 6
+
+    load.b 6
+    push.b '['
+    cmp.b
+    jmp.ne forward_close
+    inc 7
+    jmp forward
+forward_close:
+    load.b 6
+    push.b ']'
+    cmp.b
+    jmp.ne forward
+    dec 7
+    load 7
+    push 0
+    cmp
+    jmp.ne forward
+    jmp next
+
+close:
+    ; go back to the matching [ if the cell isn't zero
+    load.d 0
+    load.d 4
+    aget.b
+    push.b 0
+    cmp.b
+    jmp.eq next
+
+    push 1
+    store 7 ; depth
+backward:
+    load.d 2
+    push.d 1
+    sub.d
+    store.d 2
+
+    push.d program
+    load.d 2
+    get.b
+    store.b 6
+
+    load.b 6
+    push.b ']'
+    cmp.b
+    jmp.ne backward_open
+    inc 7
+    jmp backward
+backward_open:
+    load.b 6
+    push.b '['
+    cmp.b
+    jmp.ne backward
+    dec 7
+    load 7
+    push 0
+    cmp
+    jmp.ne backward
+
+next:
+    load.d 2
+    push.d 1
+    add.d
+    store.d 2
+    jmp step
+
+done:
+    load.d 0
+    free
+    ret
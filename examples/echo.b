@@ -1,33 +1,21 @@
 .entry main
 
-#include "examples/io_include.b"
-
-.data readerror .string "error: could not read\n"
-
-#define BUFSZ 512
+#define GETC 1006
+#define PUTC 1007
 
+; Copies stdin to stdout a byte at a time
 main:
-    push.d @BUFSZ
-    alloc
-    store.d 0
-
-    push @STDIN
-    load.d 0
-    push.d @BUFSZ
-    push @READ
+    push @GETC
     system
+    dup
     push -1
     cmp
-    jmp.eq read_error
+    jmp.eq done
 
-    load.d 0
-    push.d @BUFSZ
-    call print
-
-    ret
+    push @PUTC
+    system
+    jmp main
 
-read_error:
-    dataptr readerror
-    push.d sizeof readerror
-    call print
+done:
+    pop
     ret
@@ -0,0 +1,239 @@
+.entry main
+
+#define PUTC 1007
+#define COUNT 10
+
+.data numbers .word 31, 4, 15, 9, 26, 5, 35, 8, 97, 93
+
+main:
+    push.d @COUNT
+    newarr
+    store.d 0 ; arr
+
+    ; copy the numbers from the data into the array
+    push.d 0
+    store.d 2 ; i
+fill:
+    load.d 2
+    push.d @COUNT
+    cmp.d
+    jmp.ge fill_done
+
+    load.d 0
+    load.d 2
+    push.d numbers
+    load.d 2
+    push.d 4
+    mul.d
+    get
+    aput
+
+    load.d 2
+    push.d 1
+    add.d
+    store.d 2
+    jmp fill
+
+fill_done:
+    load.d 0
+    push.d @COUNT
+    call heapsort
+
+    load.d 0
+    push.d @COUNT
+    call print_array
+
+    load.d 0
+    free
+    ret
+
+; heapsort(arr, n)
+heapsort:
+    ; build a max heap by sifting down from n / 2 - 1 to 0
+    load.d 2
+    push.d 2
+    div.d
+    store.d 4 ; start
+heapify:
+    load.d 4
+    push.d 0
+    cmp.d
+    jmp.le sort
+
+    load.d 4
+    push.d 1
+    sub.d
+    store.d 4
+
+    load.d 0
+    load.d 4
+    load.d 2
+    call sift
+    jmp heapify
+
+sort:
+    ; move the largest to the end, then restore the heap in front of it
+    load.d 2
+    store.d 4 ; end
+sort_loop:
+    load.d 4
+    push.d 1
+    cmp.d
+    jmp.le sorted
+
+    load.d 4
+    push.d 1
+    sub.d
+    store.d 4
+
+    load.d 0
+    push.d 0
+    load.d 4
+    call swap
+
+    load.d 0
+    push.d 0
+    load.d 4
+    call sift
+    jmp sort_loop
+
+sorted:
+    ret
+
+; sift(arr, root, end) moves arr[root] down until it's no smaller than its children before end
+sift:
+    ; child = 2 * root + 1
+    load.d 2
+    push.d 2
+    mul.d
+    push.d 1
+    add.d
+    dup.d
+    store.d 6 ; child
+    load.d 4
+    cmp.d
+    jmp.ge sift_done
+
+    ; use the right child if it's larger
+    load.d 6
+    push.d 1
+    add.d
+    load.d 4
+    cmp.d
+    jmp.ge sift_compare
+
+    load.d 0
+    load.d 6
+    aget
+    load.d 0
+    load.d 6
+    push.d 1
+    add.d
+    aget
+    cmp
+    jmp.ge sift_compare
+
+    load.d 6
+    push.d 1
+    add.d
+    store.d 6
+
+sift_compare:
+    load.d 0
+    load.d 2
+    aget
+    load.d 0
+    load.d 6
+    aget
+    cmp
+    jmp.ge sift_done
+
+    load.d 0
+    load.d 2
+    load.d 6
+    call swap
+
+    load.d 6
+    store.d 2
+    jmp sift
+
+sift_done:
+    ret
+
+; swap(arr, i, j)
+swap:
+    load.d 0
+    load.d 2
+    aget
+    store 6 ; arr[i]
+
+    load.d 0
+    load.d 2
+    load.d 0
+    load.d 4
+    aget
+    aput
+
+    load.d 0
+    load.d 4
+    load 6
+    aput
+    ret
+
+; print_array(arr, n) writes the numbers separated by spaces
+print_array:
+    push.d 0
+    store.d 4 ; i
+print_loop:
+    load.d 4
+    load.d 2
+    cmp.d
+    jmp.ge print_done
+
+    load.d 4
+    push.d 0
+    cmp.d
+    jmp.eq print_number
+    push ' '
+    push @PUTC
+    system
+
+print_number:
+    load.d 0
+    load.d 4
+    aget
+    call print_int
+
+    load.d 4
+    push.d 1
+    add.d
+    store.d 4
+    jmp print_loop
+
+print_done:
+    push '\n'
+    push @PUTC
+    system
+    ret
+
+; print_int(n) writes a non-negative word in decimal
+print_int:
+    load 0
+    push 10
+    cmp
+    jmp.lt print_digit
+
+    load 0
+    push 10
+    div
+    call print_int
+
+print_digit:
+    load 0
+    push 10
+    rem
+    push '0'
+    add
+    push @PUTC
+    system
+    ret
@@ -2,12 +2,30 @@ use std::collections::HashMap;
 use std::fs::File;
 use std::io::Read;
 use std::mem;
-use std::path::PathBuf;
-
-use crate::output::Output;
-use crate::program::Bytecode;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use crate::diagnostic::{did_you_mean, Diagnostic, Source, Span};
+use crate::limits::Limits;
+use crate::optimiser::Optimiser;
+use crate::output::{Hooks, Origin, Output};
+use crate::program::{Bytecode, Fields};
 use crate::tokeniser::{Keyword, Token, TokenState, Tokeniser, Value};
-use crate::{Number, Result};
+use crate::{Float, Number, Result};
+
+/// Standard macro definitions which can be injected before the program source
+const PRELUDE: &str = include_str!("prelude.b");
+
+/// Mnemonics which don't name an opcode. Other than `dec`, which is `inc` with the word negated,
+/// they expand to a sequence of instructions.
+const PSEUDO_INSTRUCTIONS: &[&str] = &["dec", "jmp.nz", "jmp.z", "zero"];
+
+/// Whether `word` is an instruction rather than a label, including the `.w` spellings of word
+/// sized instructions
+fn is_mnemonic(word: &str) -> bool {
+    let word = word.strip_suffix(".w").unwrap_or(word);
+    Bytecode::all().any(|op| op.to_string() == word) || PSEUDO_INSTRUCTIONS.contains(&word)
+}
 
 #[derive(PartialEq, Eq)]
 enum Section {
@@ -32,7 +50,7 @@ impl Label {
         Self { section, offset }
     }
 
-    fn resolve_offset(&self, data: &Vec<u8>) -> u64 {
+    fn resolve_offset(&self, data: &[u8]) -> u64 {
         // Since the program is loaded as [entry][data][text], the data section offsets stay as is
         // while the text offsets are offset further by the data length
         (match self.section {
@@ -42,13 +60,47 @@ impl Label {
     }
 }
 
+#[derive(Default)]
 pub struct Assembler {
     data: Vec<u8>,
     text: Vec<u8>,
     labels: HashMap<String, Label>,
     unresolved: HashMap<u64, String>,
+    /// Labels whose position is stored in the data, by data offset
+    data_references: HashMap<usize, String>,
+    /// Where each label is first referenced in the source, for reporting unresolved labels
+    references: HashMap<String, Span>,
     macros: HashMap<String, Vec<Token>>,
+    /// The macro expansions and includes currently being assembled, outermost first
+    expansions: Vec<String>,
+    /// Where instructions assembled within an expansion came from, by text offset
+    origins: HashMap<usize, Origin>,
+    /// Values known at assembly time, such as the `<label>.len` of a string
+    constants: HashMap<String, i64>,
+    /// Keys and values from `.meta` directives, in the order they were written
+    metadata: Vec<(String, String)>,
+    /// The labels from `.onenter` and `.onexit`
+    on_enter: Option<String>,
+    on_exit: Option<String>,
+    /// The namespace from the last `.namespace` in the file being assembled
+    namespace: Option<String>,
+    /// Labels referenced within a namespace mapped to the global label used if the namespace
+    /// doesn't define them
+    fallbacks: HashMap<String, String>,
+    /// The last label defined in the text, which `.name:` labels are local to
+    scope: Option<String>,
     include_paths: Vec<PathBuf>,
+    /// Every file read by `#include`, `.incbin` or as one of the sources, in the order they were
+    /// first read
+    dependencies: Vec<PathBuf>,
+    /// Files assembled before the program source, as if they were included
+    sources: Vec<PathBuf>,
+    source_name: String,
+    prelude: bool,
+    optimise: bool,
+    allow_truncation: bool,
+    allow_include: bool,
+    limits: Limits,
 }
 
 impl Assembler {
@@ -57,16 +109,54 @@ impl Assembler {
         let text = Vec::new();
         let labels = HashMap::new();
         let unresolved = HashMap::new();
+        let data_references = HashMap::new();
+        let references = HashMap::new();
         let macros = HashMap::new();
+        let expansions = Vec::new();
+        let origins = HashMap::new();
+        let constants = HashMap::new();
+        let metadata = Vec::new();
+        let on_enter = None;
+        let on_exit = None;
+        let namespace = None;
+        let fallbacks = HashMap::new();
+        let scope = None;
         let include_paths = Vec::new();
+        let dependencies = Vec::new();
+        let sources = Vec::new();
+        let source_name = String::from("<source>");
+        let prelude = false;
+        let optimise = false;
+        let allow_truncation = false;
+        let allow_include = true;
+        let limits = Limits::default();
 
         Self {
             data,
             text,
             labels,
             unresolved,
+            data_references,
+            references,
             macros,
+            expansions,
+            origins,
+            constants,
+            metadata,
+            on_enter,
+            on_exit,
+            namespace,
+            fallbacks,
+            scope,
             include_paths,
+            dependencies,
+            sources,
+            source_name,
+            prelude,
+            optimise,
+            allow_truncation,
+            allow_include,
+            limits,
         }
     }
 
@@ -75,12 +165,111 @@ impl Assembler {
         self
     }
 
-    pub fn assemble(mut self, src: &str) -> Result<Output> {
-        let mut tokens = TokenState::new(Tokeniser::new(src).into_iter().collect());
+    /// Assemble these files after the prelude and before the program source, so the program can
+    /// use their macros and labels
+    pub fn with_sources(mut self, sources: Vec<PathBuf>) -> Self {
+        self.sources = sources;
+        self
+    }
+
+    /// Define macros before assembling, each of which expands to the tokens in its value
+    pub fn with_defines(mut self, defines: impl IntoIterator<Item = (String, String)>) -> Self {
+        for (name, value) in defines {
+            let tokens = Tokeniser::new(&value)
+                .into_iter()
+                .filter(|token| token != &Token::Eof)
+                .collect();
+            self.macros.insert(name, tokens);
+        }
+        self
+    }
+
+    /// The name to use for the program source in locations, such as its path
+    pub fn with_source_name(mut self, source_name: impl Into<String>) -> Self {
+        self.source_name = source_name.into();
+        self
+    }
+
+    /// Assemble the standard definitions from [`PRELUDE`] before the program source
+    pub fn with_prelude(mut self, prelude: bool) -> Self {
+        self.prelude = prelude;
+        self
+    }
+
+    /// Run the [`Optimiser`] passes over the text before labels are resolved
+    pub fn with_optimise(mut self, optimise: bool) -> Self {
+        self.optimise = optimise;
+        self
+    }
+
+    /// Keep the low bytes of values which don't fit their operand or data type instead of
+    /// rejecting them
+    pub fn with_allow_truncation(mut self, allow_truncation: bool) -> Self {
+        self.allow_truncation = allow_truncation;
+        self
+    }
+
+    /// Reject `#include` and `.incbin` directives instead of reading the file they name
+    pub fn with_allow_include(mut self, allow_include: bool) -> Self {
+        self.allow_include = allow_include;
+        self
+    }
+
+    /// Sets the limits on the size of the assembled program's data and text
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn assemble(self, src: &str) -> Result<Output> {
+        self.assemble_with_dependencies(src)
+            .map(|(output, _)| output)
+    }
+
+    /// Assembles the program, also returning the path of every file it read besides the source,
+    /// so a build tool knows when to assemble it again
+    pub fn assemble_with_dependencies(mut self, src: &str) -> Result<(Output, Vec<PathBuf>)> {
+        self.limits.validate()?;
+
+        let source = Source {
+            name: self.source_name.clone(),
+            text: src.to_string(),
+        };
+        let mut tokens = TokenState::with_spans(Tokeniser::new(src).spanned(), Rc::new(source));
+
+        let entry = self
+            .parse_entry(&mut tokens)
+            .map_err(|err| tokens.diagnose(err))?;
 
-        let entry = self.parse_entry(&mut tokens)?;
+        if self.prelude {
+            let source = Source {
+                name: String::from("<prelude>"),
+                text: PRELUDE.to_string(),
+            };
+            let mut ptokens =
+                TokenState::with_spans(Tokeniser::new(PRELUDE).spanned(), Rc::new(source)).nested();
+            self.assemble_bytecode(&mut ptokens)?;
+        }
 
-        self.assemble_bytecode(&mut tokens)?;
+        for path in mem::take(&mut self.sources) {
+            let expansion = format!("included before {}", self.source_name);
+            if let Err(err) = self.include(&path, expansion) {
+                Err(self.with_expansions(err))?
+            }
+        }
+
+        if let Err(err) = self.assemble_bytecode(&mut tokens) {
+            let err = tokens.diagnose(err);
+            Err(self.with_expansions(err))?
+        }
+
+        self.link();
+
+        if self.optimise {
+            // Instructions move around, so where they came from no longer lines up
+            self.origins.clear();
+            self.optimise(&entry)?;
+        }
 
         // Add entry offset to labels
         let mut labels = HashMap::new();
@@ -98,10 +287,47 @@ impl Assembler {
             let offset = self.resolve_label(&r#ref)?;
             self.text[i..i + mem::size_of::<u64>()].copy_from_slice(&offset.to_le_bytes());
         }
+        let data_references = std::mem::take(&mut self.data_references);
+        for (i, r#ref) in data_references {
+            let offset = self.resolve_label(&r#ref)?;
+            self.data[i..i + mem::size_of::<u64>()].copy_from_slice(&offset.to_le_bytes());
+        }
+
+        let hooks = Hooks {
+            enter: self
+                .on_enter
+                .as_deref()
+                .map(|label| self.resolve_label(label))
+                .transpose()?,
+            exit: self
+                .on_exit
+                .as_deref()
+                .map(|label| self.resolve_label(label))
+                .transpose()?,
+        };
+
+        self.limits
+            .check_program(self.data.len(), self.text.len())?;
+
+        let text_offset = mem::size_of::<u64>() + self.data.len();
+        let origins = self
+            .origins
+            .into_iter()
+            .map(|(offset, origin)| ((text_offset + offset) as u64, origin))
+            .collect();
 
-        let out = Output::new(entry_offset, self.data, self.text, labels);
+        let out = Output::new(entry_offset, self.data, self.text, labels)
+            .with_origins(origins)
+            .with_metadata(self.metadata)
+            .with_hooks(hooks);
+        let local_slots = out.analyse_locals()?;
+        let extensions = out.analyse_extensions()?;
 
-        Ok(out)
+        let out = out
+            .with_local_slots(local_slots)
+            .with_extensions(extensions);
+
+        Ok((out, self.dependencies))
     }
 
     fn assemble_bytecode(&mut self, tokens: &mut TokenState) -> Result<()> {
@@ -109,18 +335,40 @@ impl Assembler {
             match tokens.next() {
                 Token::Word(word) => {
                     if tokens.check(&[Token::Colon]) {
+                        let label = self.qualify(&word);
                         if self
                             .labels
-                            .insert(word.to_string(), Label::text(self.text.len()))
+                            .insert(label.clone(), Label::text(self.text.len()))
                             .is_some()
                         {
                             Err(format!("duplicate label: {word}"))?;
                         }
+                        self.scope = Some(label);
                         continue;
                     }
 
+                    if !self.expansions.is_empty() {
+                        let origin = Origin {
+                            location: tokens.location(),
+                            expansions: self.expansions.iter().rev().cloned().collect(),
+                        };
+                        self.origins.insert(self.text.len(), origin);
+                    }
+
                     self.assemble_instruction(tokens, word.as_str())?;
                 }
+                Token::Dot if tokens.peek_n(1) == Some(Token::Colon) => {
+                    let name = tokens.next_word()?;
+                    tokens.next();
+                    let label = self.local(&name)?;
+                    if self
+                        .labels
+                        .insert(label, Label::text(self.text.len()))
+                        .is_some()
+                    {
+                        Err(format!("duplicate label: .{name}"))?;
+                    }
+                }
                 Token::Dot => {
                     self.assemble_directive(tokens)?;
                 }
@@ -138,9 +386,101 @@ impl Assembler {
         Ok(())
     }
 
+    /// Adds the expansions the error was raised within to it as notes, innermost first
+    fn with_expansions(&mut self, err: Box<dyn std::error::Error>) -> Box<dyn std::error::Error> {
+        let notes: Vec<_> = mem::take(&mut self.expansions).into_iter().rev().collect();
+        match err.downcast::<Diagnostic>() {
+            Ok(mut diagnostic) => {
+                diagnostic.notes.extend(notes);
+                diagnostic
+            }
+            Err(err) if notes.is_empty() => err,
+            Err(err) => format!("{err} ({})", notes.join(", ")).into(),
+        }
+    }
+
+    /// The name of a label defined or referenced in the current namespace
+    fn qualify(&self, name: &str) -> String {
+        match &self.namespace {
+            Some(namespace) if !name.contains("::") => format!("{namespace}::{name}"),
+            _ => name.to_string(),
+        }
+    }
+
+    /// Qualifies a label reference, remembering the global label to fall back to
+    fn reference(&mut self, name: String) -> String {
+        let label = self.qualify(&name);
+        if label != name {
+            self.fallbacks.insert(label.clone(), name);
+        }
+
+        label
+    }
+
+    /// The name of a `.name` label, which is local to the last label defined before it
+    fn local(&self, name: &str) -> Result<String> {
+        let Some(scope) = &self.scope else {
+            Err(format!("local label must follow a label: .{name}"))?
+        };
+
+        Ok(format!("{scope}.{name}"))
+    }
+
+    /// Points references made within a namespace at the global label when the namespace doesn't
+    /// define one, once every source has been assembled
+    fn link(&mut self) {
+        for (label, global) in mem::take(&mut self.fallbacks) {
+            if self.labels.contains_key(&label) {
+                continue;
+            }
+
+            let references = self
+                .unresolved
+                .values_mut()
+                .chain(self.data_references.values_mut())
+                .chain(self.on_enter.as_mut())
+                .chain(self.on_exit.as_mut());
+            for reference in references.filter(|reference| **reference == label) {
+                *reference = global.clone();
+            }
+
+            if let Some(span) = self.references.get(&label).copied() {
+                self.references.entry(global).or_insert(span);
+            }
+        }
+    }
+
+    fn optimise(&mut self, entry: &str) -> Result<()> {
+        let labels = self
+            .labels
+            .iter()
+            .filter(|(_, label)| label.section == Section::Text)
+            .map(|(name, label)| (name.clone(), label.offset))
+            .collect();
+        let unresolved = mem::take(&mut self.unresolved);
+
+        let mut optimiser = Optimiser::new(&self.text, labels, unresolved)?;
+        // Hooks run around every call, so calls must stay calls while there are any
+        if self.on_enter.is_none() && self.on_exit.is_none() {
+            optimiser.inline(entry);
+        }
+        while optimiser.dead_stores(entry) + optimiser.peephole() + optimiser.fold() > 0 {}
+
+        let (text, labels, unresolved) = optimiser.finish();
+        self.text = text;
+        self.unresolved = unresolved;
+        for (name, offset) in labels {
+            self.labels.insert(name, Label::text(offset));
+        }
+
+        Ok(())
+    }
+
     fn resolve_label(&self, r#ref: &str) -> Result<u64> {
         let Some(label) = self.labels.get(r#ref) else {
-            Err(format!("could not resolve label: {}", r#ref))?
+            let message = format!("could not resolve label: {}", r#ref);
+            let help = did_you_mean(r#ref, self.labels.keys().map(String::as_str));
+            Err(error(message, self.references.get(r#ref).copied(), help))?
         };
 
         let offset = label.resolve_offset(&self.data);
@@ -151,27 +491,104 @@ impl Assembler {
     fn assemble_directive(&mut self, tokens: &mut TokenState) -> Result<()> {
         match tokens.next_keyword()? {
             Keyword::Data => self.assemble_data(tokens)?,
+            Keyword::Meta => self.assemble_meta(tokens)?,
+            Keyword::Equ => self.assemble_equ(tokens)?,
+            Keyword::OnEnter => {
+                let label = self.parse_hook(tokens)?;
+                if self.on_enter.replace(label).is_some() {
+                    Err("duplicate .onenter hook")?
+                }
+            }
+            Keyword::OnExit => {
+                let label = self.parse_hook(tokens)?;
+                if self.on_exit.replace(label).is_some() {
+                    Err("duplicate .onexit hook")?
+                }
+            }
+            Keyword::Namespace => {
+                let namespace = tokens.next_word()?;
+                if namespace.contains("::") {
+                    Err(format!("namespaces cannot be nested: {namespace}"))?
+                }
+                self.namespace = Some(namespace);
+            }
             keyword => Err(format!("unexpected keyword: {keyword:?}"))?,
         }
 
         Ok(())
     }
 
+    fn assemble_meta(&mut self, tokens: &mut TokenState) -> Result<()> {
+        let key = tokens.next_word()?;
+        let value = match tokens.next_value()? {
+            Value::String(value) => value,
+            Value::Number(value) => value,
+            value => Err(format!(
+                "expected a string or number for .meta {key}: {value:?}"
+            ))?,
+        };
+
+        if self.metadata.iter().any(|(k, _)| *k == key) {
+            Err(format!("duplicate metadata: {key}"))?
+        }
+        self.metadata.push((key, value));
+
+        Ok(())
+    }
+
+    /// Declares a constant, which can be used wherever an immediate is accepted without taking up
+    /// any data
+    fn assemble_equ(&mut self, tokens: &mut TokenState) -> Result<()> {
+        let name = tokens.next_word()?;
+        let operand = format!(".equ {name}");
+        let value = match tokens.next() {
+            Token::Value(Value::Number(number)) => {
+                self.number::<i64>(&number, &operand, Operand::Immediate)?
+            }
+            Token::Value(Value::Char(char)) => char as i64,
+            Token::Word(word) if self.constant(&word).is_some() => {
+                self.constant(&word).unwrap_or_default()
+            }
+            token => Err(format!("unexpected token for {operand}: {token:?}"))?,
+        };
+
+        let name = self.qualify(&name);
+        if self.constants.contains_key(&name) || self.labels.contains_key(&name) {
+            Err(format!("label is declared twice: {name}"))?
+        }
+        self.constants.insert(name, value);
+
+        Ok(())
+    }
+
+    fn parse_hook(&mut self, tokens: &mut TokenState) -> Result<String> {
+        let label = tokens.next_word()?;
+        let label = self.reference(label);
+        if let Some(span) = tokens.span() {
+            self.references.entry(label.clone()).or_insert(span);
+        }
+
+        Ok(label)
+    }
+
     fn assemble_data(&mut self, tokens: &mut TokenState) -> Result<()> {
         let name = tokens.next_word()?;
+        let name = self.qualify(&name);
 
         let offset = self.data.len();
 
         let mut size = 0;
+        let mut len = None;
         while {
             tokens.expect(&[Token::Dot])?;
 
             // If it's a string, we'll set the size once we see it
-            let mut value_size = match tokens.next_keyword()? {
+            let keyword = tokens.next_keyword()?;
+            let mut value_size = match keyword {
                 Keyword::Byte => i8::SIZE,
                 Keyword::Word => i32::SIZE,
                 Keyword::Dword => i64::SIZE,
-                Keyword::String => 0,
+                Keyword::String | Keyword::CString | Keyword::PString | Keyword::IncBin => 0,
                 keyword => Err(format!("unexpected keyword: {keyword:?}"))?,
             };
 
@@ -181,15 +598,18 @@ impl Assembler {
                         tokens.next();
                         match value {
                             Value::Number(number) if value_size == i8::SIZE => {
-                                let value = number.parse::<i8>()?;
+                                let value =
+                                    self.number::<i8>(&number, ".byte", Operand::Immediate)?;
                                 self.data.extend(value.to_le_bytes());
                             }
                             Value::Number(number) if value_size == i32::SIZE => {
-                                let value = number.parse::<i32>()?;
+                                let value =
+                                    self.number::<i32>(&number, ".word", Operand::Immediate)?;
                                 self.data.extend(value.to_le_bytes());
                             }
                             Value::Number(number) if value_size == i64::SIZE => {
-                                let value = number.parse::<i64>()?;
+                                let value =
+                                    self.number::<i64>(&number, ".dword", Operand::Immediate)?;
                                 self.data.extend(value.to_le_bytes());
                             }
                             Value::Char(char) if value_size == i8::SIZE && char.is_ascii() => {
@@ -200,15 +620,73 @@ impl Assembler {
                                 let value = char as u32;
                                 self.data.extend(value.to_le_bytes());
                             }
+                            Value::String(path) if keyword == Keyword::IncBin => {
+                                if !self.allow_include {
+                                    Err(".incbin is not allowed")?
+                                }
+
+                                let mut bytes = Vec::new();
+                                self.open(Path::new(&path))?.read_to_end(&mut bytes)?;
+                                *len.get_or_insert(0) += bytes.len() as i64;
+                                value_size = bytes.len();
+                                self.data.extend(bytes);
+                            }
                             Value::String(string) if value_size == 0 => {
-                                value_size = string.len();
-                                self.data.extend(string.into_bytes());
+                                *len.get_or_insert(0) += string.len() as i64;
+
+                                match keyword {
+                                    Keyword::CString => {
+                                        value_size = string.len() + 1;
+                                        self.data.extend(string.into_bytes());
+                                        self.data.push(0);
+                                    }
+                                    Keyword::PString => {
+                                        let prefix = u32::try_from(string.len())?;
+                                        value_size = mem::size_of::<u32>() + string.len();
+                                        self.data.extend(prefix.to_le_bytes());
+                                        self.data.extend(string.into_bytes());
+                                    }
+                                    _ => {
+                                        value_size = string.len();
+                                        self.data.extend(string.into_bytes());
+                                    }
+                                }
                             }
                             value => {
                                 Err(format!("value {value:?} does not match size {value_size}"))?
                             }
                         }
                     }
+                    // A constant, such as one declared with .equ
+                    Token::Word(word) if value_size != 0 && self.constant(&word).is_some() => {
+                        tokens.next();
+                        let constant = self.constant(&word).unwrap_or_default().to_string();
+                        match keyword {
+                            Keyword::Byte => {
+                                let value =
+                                    self.number::<i8>(&constant, ".byte", Operand::Immediate)?;
+                                self.data.extend(value.to_le_bytes());
+                            }
+                            Keyword::Word => {
+                                let value =
+                                    self.number::<i32>(&constant, ".word", Operand::Immediate)?;
+                                self.data.extend(value.to_le_bytes());
+                            }
+                            _ => self.data.extend(constant.parse::<i64>()?.to_le_bytes()),
+                        }
+                    }
+                    // A label, unless it's the definition of one following a zeroed value
+                    Token::Word(label)
+                        if keyword == Keyword::Dword && tokens.peek_n(1) != Some(Token::Colon) =>
+                    {
+                        tokens.next();
+                        let label = self.reference(label);
+                        if let Some(span) = tokens.span() {
+                            self.references.entry(label.clone()).or_insert(span);
+                        }
+                        self.data_references.insert(self.data.len(), label);
+                        self.data.extend(0u64.to_le_bytes());
+                    }
                     _ => self.data.extend(std::iter::repeat_n(0u8, value_size)),
                 };
 
@@ -235,6 +713,12 @@ impl Assembler {
             Err(format!("label is declared twice: {name}"))?;
         }
 
+        // Strings and included files also define `<label>.len`, the number of bytes excluding any
+        // other values, terminators or length prefixes
+        if let Some(len) = len {
+            self.constants.insert(format!("{name}.len"), len);
+        }
+
         Ok(())
     }
 
@@ -257,56 +741,122 @@ impl Assembler {
                 }
             }
             Keyword::Include => {
+                if !self.allow_include {
+                    Err("#include is not allowed")?
+                }
+
                 let path = match tokens.next_value()? {
                     Value::String(path) => path,
                     value => format!("unexpected value: {value:?}"),
                 };
+                let expansion = match tokens.location() {
+                    Some(location) => format!("included from {location}"),
+                    None => format!("included {path}"),
+                };
 
-                let mut file = File::options().read(true).open(&path);
-                if file.is_err() {
-                    for include_path in &self.include_paths {
-                        file = File::options().read(true).open(include_path.join(&path));
-                        if file.is_ok() {
-                            break;
-                        }
-                    }
+                self.include(Path::new(&path), expansion)?;
+            }
+            _ => Err(format!("unexpected keyword: {keyword:?}"))?,
+        }
+
+        Ok(())
+    }
+
+    /// Opens the file at `path`, looking through the include paths if it isn't found relative to
+    /// the working directory
+    fn open(&mut self, path: &Path) -> Result<File> {
+        let mut found = path.to_path_buf();
+        let mut file = File::options().read(true).open(path);
+        if file.is_err() {
+            for include_path in &self.include_paths {
+                found = include_path.join(path);
+                file = File::options().read(true).open(&found);
+                if file.is_ok() {
+                    break;
                 }
+            }
+        }
 
-                let mut file = match file {
-                    Ok(file) => file,
-                    Err(_) => Err(format!("could not find file in include paths: {path}"))?,
-                };
+        match file {
+            Ok(file) => {
+                if !self.dependencies.contains(&found) {
+                    self.dependencies.push(found);
+                }
+                Ok(file)
+            }
+            Err(_) => Err(format!(
+                "could not find file in include paths: {}",
+                path.display()
+            ))?,
+        }
+    }
 
-                let mut contents = String::new();
-                file.read_to_string(&mut contents)?;
+    /// Assembles the file at `path`, looking through the include paths if it isn't found
+    /// relative to the working directory
+    fn include(&mut self, path: &Path, expansion: String) -> Result<()> {
+        let mut file = self.open(path)?;
 
-                let mut mtokens =
-                    TokenState::new(Tokeniser::new(contents.as_str()).into_iter().collect());
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
 
-                self.assemble_bytecode(&mut mtokens)?;
+        let spanned = Tokeniser::new(contents.as_str()).spanned();
+        let source = Source {
+            name: path.display().to_string(),
+            text: contents,
+        };
+        let mut tokens = TokenState::with_spans(spanned, Rc::new(source)).nested();
+
+        let depth = self.expansions.len();
+        self.expansions.push(expansion);
+        // Each file starts outside of any namespace, with no label for local labels to belong to
+        let namespace = self.namespace.take();
+        let scope = self.scope.take();
+
+        if let Err(err) = self.assemble_bytecode(&mut tokens) {
+            // Point into the included file, unless the error came from a further expansion
+            if self.expansions.len() == depth + 1 {
+                if let Some(location) = tokens.location() {
+                    self.expansions.push(format!("at {location}"));
+                }
             }
-            _ => Err(format!("unexpected keyword: {keyword:?}"))?,
+            Err(err)?
         }
 
+        self.expansions.pop();
+        self.namespace = namespace;
+        self.scope = scope;
+
         Ok(())
     }
 
     fn assemble_expansion(&mut self, tokens: &mut TokenState) -> Result<()> {
         let word = tokens.next_word()?;
 
-        let Some(mut tokens) = self.macros.get(&word).cloned().map(TokenState::new) else {
+        let Some(mut mtokens) = self.macros.get(&word).cloned().map(TokenState::new) else {
             Err(format!(
                 "macro must be declared before it is expanded: {word}"
             ))?
         };
 
-        self.assemble_bytecode(&mut tokens)?;
+        self.expansions.push(match tokens.location() {
+            Some(location) => format!("expanded from @{word} at {location}"),
+            None => format!("expanded from @{word}"),
+        });
+        self.assemble_bytecode(&mut mtokens)?;
+        self.expansions.pop();
 
         Ok(())
     }
 
     fn assemble_instruction(&mut self, tokens: &mut TokenState, word: &str) -> Result<()> {
         match word {
+            "aget" | "aget.w" => self.assemble_operator(Bytecode::AGet),
+            "aget.b" => self.assemble_operator(Bytecode::AGetB),
+            "aget.d" => self.assemble_operator(Bytecode::AGetD),
+            "alen" => self.assemble_operator(Bytecode::ALen),
+            "aput" | "aput.w" => self.assemble_operator(Bytecode::APut),
+            "aput.b" => self.assemble_operator(Bytecode::APutB),
+            "aput.d" => self.assemble_operator(Bytecode::APutD),
             "add" | "add.w" => self.assemble_operator(Bytecode::Add),
             "add.b" => self.assemble_operator(Bytecode::AddB),
             "add.d" => self.assemble_operator(Bytecode::AddD),
@@ -318,14 +868,36 @@ impl Assembler {
             "astore.b" => self.assemble_operator(Bytecode::AStoreB),
             "astore.d" => self.assemble_operator(Bytecode::AStoreD),
             "call" => self.assemble_operator_with_label(tokens, Bytecode::Call)?,
+            "call.ptr" => self.assemble_operator(Bytecode::CallPtr),
+            "jmp.ptr" => self.assemble_operator(Bytecode::JmpPtr),
             "cmp" | "cmp.w" => self.assemble_operator(Bytecode::Cmp),
+            "cmp.b" => self.assemble_operator(Bytecode::CmpB),
             "cmp.d" => self.assemble_operator(Bytecode::CmpD),
+            "add.f" => self.assemble_operator(Bytecode::AddF),
+            "add.d.f" => self.assemble_operator(Bytecode::AddDF),
+            "sub.f" => self.assemble_operator(Bytecode::SubF),
+            "sub.d.f" => self.assemble_operator(Bytecode::SubDF),
+            "mul.f" => self.assemble_operator(Bytecode::MulF),
+            "mul.d.f" => self.assemble_operator(Bytecode::MulDF),
+            "div.f" => self.assemble_operator(Bytecode::DivF),
+            "div.d.f" => self.assemble_operator(Bytecode::DivDF),
+            "cmp.f" => self.assemble_operator(Bytecode::CmpF),
+            "cmp.d.f" => self.assemble_operator(Bytecode::CmpDF),
             "dataptr" => self.assemble_operator_with_operand::<u64>(tokens, Bytecode::DataPtr)?,
             "div" | "div.w " => self.assemble_operator(Bytecode::Div),
+            "div.b" => self.assemble_operator(Bytecode::DivB),
             "div.d" => self.assemble_operator(Bytecode::DivD),
             "dup" | "dup.w" => self.assemble_operator(Bytecode::Dup),
+            "dup.b" => self.assemble_operator(Bytecode::DupB),
             "dup.d" => self.assemble_operator(Bytecode::DupD),
             "free" => self.assemble_operator(Bytecode::Free),
+            "freemap" => self.assemble_operator(Bytecode::FreeMap),
+            "get.be" => self.assemble_operator(Bytecode::GetBe),
+            "get.d.be" => self.assemble_operator(Bytecode::GetDBe),
+            "aload.be" => self.assemble_operator(Bytecode::ALoadBe),
+            "aload.d.be" => self.assemble_operator(Bytecode::ALoadDBe),
+            "astore.be" => self.assemble_operator(Bytecode::AStoreBe),
+            "astore.d.be" => self.assemble_operator(Bytecode::AStoreDBe),
             "get" | "get.w" => self.assemble_operator(Bytecode::Get),
             "get.b" => self.assemble_operator(Bytecode::GetB),
             "get.d" => self.assemble_operator(Bytecode::GetD),
@@ -336,14 +908,55 @@ impl Assembler {
             "jmp.le" => self.assemble_operator_with_label(tokens, Bytecode::JmpLe)?,
             "jmp.lt" => self.assemble_operator_with_label(tokens, Bytecode::JmpLt)?,
             "jmp.ne" => self.assemble_operator_with_label(tokens, Bytecode::JmpNe)?,
+            "jmp.nz" => {
+                // push 0, cmp, jmp.ne <label>
+                self.assemble_operator(Bytecode::Push);
+                self.text.extend(0i32.to_le_bytes());
+                self.assemble_operator(Bytecode::Cmp);
+                self.assemble_operator_with_label(tokens, Bytecode::JmpNe)?
+            }
+            "jmp.z" => {
+                // push 0, cmp, jmp.eq <label>
+                self.assemble_operator(Bytecode::Push);
+                self.text.extend(0i32.to_le_bytes());
+                self.assemble_operator(Bytecode::Cmp);
+                self.assemble_operator_with_label(tokens, Bytecode::JmpEq)?
+            }
+            "mapdel" => self.assemble_operator(Bytecode::MapDel),
+            "mapdel.s" => self.assemble_operator(Bytecode::MapDelS),
+            "mapget" => self.assemble_operator(Bytecode::MapGet),
+            "mapget.s" => self.assemble_operator(Bytecode::MapGetS),
+            "maplen" => self.assemble_operator(Bytecode::MapLen),
+            "mapput" => self.assemble_operator(Bytecode::MapPut),
+            "mapput.s" => self.assemble_operator(Bytecode::MapPutS),
             "load" | "load.w" => {
                 self.assemble_operator_with_operand::<u64>(tokens, Bytecode::Load)?
             }
             "load.b" => self.assemble_operator_with_operand::<u64>(tokens, Bytecode::LoadB)?,
+            "inc" => self.assemble_inc(tokens, false)?,
+            "dec" => self.assemble_inc(tokens, true)?,
             "load.d" => self.assemble_operator_with_operand::<u64>(tokens, Bytecode::LoadD)?,
             "mul" | "mul.w" => self.assemble_operator(Bytecode::Mul),
+            "mul.b" => self.assemble_operator(Bytecode::MulB),
             "mul.d" => self.assemble_operator(Bytecode::MulD),
-            "panic" => self.assemble_operator(Bytecode::Panic),
+            "newarr" | "newarr.w" => self.assemble_operator(Bytecode::NewArr),
+            "newmap" => self.assemble_operator(Bytecode::NewMap),
+            "newarr.b" => self.assemble_operator(Bytecode::NewArrB),
+            "newarr.d" => self.assemble_operator(Bytecode::NewArrD),
+            "panic" => match tokens.peek() {
+                // The message is optional, so the next word is only taken if it isn't the next
+                // instruction or label
+                Token::Word(word)
+                    if tokens.peek_n(1) != Some(Token::Colon) && !is_mnemonic(&word) =>
+                {
+                    self.assemble_operator_with_label(tokens, Bytecode::PanicMsg)?
+                }
+                _ => self.assemble_operator(Bytecode::Panic),
+            },
+            "halt" => self.assemble_operator(Bytecode::Halt),
+            "nop" => self.assemble_operator(Bytecode::Nop),
+            "memcpy" => self.assemble_operator(Bytecode::MemCpy),
+            "memset" => self.assemble_operator(Bytecode::MemSet),
             "pop" | "pop.w" => self.assemble_operator(Bytecode::Pop),
             "pop.b" => self.assemble_operator(Bytecode::PopB),
             "pop.d" => self.assemble_operator(Bytecode::PopD),
@@ -352,9 +965,63 @@ impl Assembler {
             }
             "push.b" => self.assemble_operator_with_operand::<i8>(tokens, Bytecode::PushB)?,
             "push.d" => self.assemble_operator_with_operand::<i64>(tokens, Bytecode::PushD)?,
+            "push.f" => self.assemble_operator_with_float::<f32>(tokens, Bytecode::PushF)?,
+            "push.d.f" => self.assemble_operator_with_float::<f64>(tokens, Bytecode::PushDF)?,
             "ret" => self.assemble_operator(Bytecode::Ret),
+            "ret.b" => self.assemble_operator(Bytecode::RetB),
             "ret.d" => self.assemble_operator(Bytecode::RetD),
+            "ret.n" => self.assemble_operator_with_operand::<u64>(tokens, Bytecode::RetN)?,
+            "pack" => self.assemble_operator_with_fields(tokens, Bytecode::Pack)?,
+            "unpack" => self.assemble_operator_with_fields(tokens, Bytecode::Unpack)?,
+            "tor" => self.assemble_operator(Bytecode::ToR),
+            "tor.d" => self.assemble_operator(Bytecode::ToRD),
+            "fromr" => self.assemble_operator(Bytecode::FromR),
+            "fromr.d" => self.assemble_operator(Bytecode::FromRD),
+            "rpeek" => self.assemble_operator(Bytecode::RPeek),
+            "rpeek.d" => self.assemble_operator(Bytecode::RPeekD),
+            "swap" => self.assemble_operator(Bytecode::Swap),
+            "swap.b" => self.assemble_operator(Bytecode::SwapB),
+            "swap.d" => self.assemble_operator(Bytecode::SwapD),
+            "over" => self.assemble_operator(Bytecode::Over),
+            "over.b" => self.assemble_operator(Bytecode::OverB),
+            "over.d" => self.assemble_operator(Bytecode::OverD),
+            "rem" | "rem.w" => self.assemble_operator(Bytecode::Rem),
+            "rem.b" => self.assemble_operator(Bytecode::RemB),
+            "rem.d" => self.assemble_operator(Bytecode::RemD),
+            "and" | "and.w" => self.assemble_operator(Bytecode::And),
+            "and.b" => self.assemble_operator(Bytecode::AndB),
+            "and.d" => self.assemble_operator(Bytecode::AndD),
+            "or" | "or.w" => self.assemble_operator(Bytecode::Or),
+            "or.b" => self.assemble_operator(Bytecode::OrB),
+            "or.d" => self.assemble_operator(Bytecode::OrD),
+            "xor" | "xor.w" => self.assemble_operator(Bytecode::Xor),
+            "xor.b" => self.assemble_operator(Bytecode::XorB),
+            "xor.d" => self.assemble_operator(Bytecode::XorD),
+            "not" | "not.w" => self.assemble_operator(Bytecode::Not),
+            "not.b" => self.assemble_operator(Bytecode::NotB),
+            "not.d" => self.assemble_operator(Bytecode::NotD),
+            "neg" | "neg.w" => self.assemble_operator(Bytecode::Neg),
+            "neg.b" => self.assemble_operator(Bytecode::NegB),
+            "neg.d" => self.assemble_operator(Bytecode::NegD),
+            "min" | "min.w" => self.assemble_operator(Bytecode::Min),
+            "min.d" => self.assemble_operator(Bytecode::MinD),
+            "max" | "max.w" => self.assemble_operator(Bytecode::Max),
+            "max.d" => self.assemble_operator(Bytecode::MaxD),
+            "abs" | "abs.w" => self.assemble_operator(Bytecode::Abs),
+            "abs.d" => self.assemble_operator(Bytecode::AbsD),
+            "b2w" => self.assemble_operator(Bytecode::B2W),
+            "w2d" => self.assemble_operator(Bytecode::W2D),
+            "d2w" => self.assemble_operator(Bytecode::D2W),
+            "w2b" => self.assemble_operator(Bytecode::W2B),
+            "shl" | "shl.w" => self.assemble_operator(Bytecode::Shl),
+            "shl.d" => self.assemble_operator(Bytecode::ShlD),
+            "shr" | "shr.w" => self.assemble_operator(Bytecode::Shr),
+            "shr.d" => self.assemble_operator(Bytecode::ShrD),
+            "shr.u" | "shr.w.u" => self.assemble_operator(Bytecode::ShrU),
+            "shr.d.u" => self.assemble_operator(Bytecode::ShrDU),
             "ret.w" => self.assemble_operator(Bytecode::RetW),
+            "sload" => self.assemble_operator_with_operand::<u64>(tokens, Bytecode::SLoad)?,
+            "sstore" => self.assemble_operator_with_operand::<u64>(tokens, Bytecode::SStore)?,
             "store" | "store.w" => {
                 self.assemble_operator_with_operand::<u64>(tokens, Bytecode::Store)?
             }
@@ -364,7 +1031,22 @@ impl Assembler {
             "sub.b" => self.assemble_operator(Bytecode::SubB),
             "sub.d" => self.assemble_operator(Bytecode::SubD),
             "system" => self.assemble_operator(Bytecode::System),
-            word => Err(format!("unknown instruction: {word}"))?,
+            "zero" => {
+                // push 0, store <local>
+                self.assemble_operator(Bytecode::Push);
+                self.text.extend(0i32.to_le_bytes());
+                self.assemble_operator_with_operand::<u64>(tokens, Bytecode::Store)?
+            }
+            word => {
+                let mnemonics: Vec<String> = Bytecode::all().map(|op| op.to_string()).collect();
+                let mnemonics = mnemonics.iter().map(String::as_str);
+                let help = did_you_mean(word, mnemonics.chain(PSEUDO_INSTRUCTIONS.iter().copied()));
+                Err(error(
+                    format!("unknown instruction: {word}"),
+                    tokens.span(),
+                    help,
+                ))?
+            }
         }
 
         Ok(())
@@ -389,14 +1071,12 @@ impl Assembler {
         match tokens.peek() {
             Token::Value(Value::Number(number)) => {
                 tokens.next();
-                let value = number
-                    .parse::<T>()
-                    .map_err(|_| format!("value cannot be parsed: {number}"))?;
+                let value = self.number::<T>(&number, code, Operand::of(code))?;
                 self.text.extend(value.to_le_bytes());
             }
             Token::Value(Value::Char(char)) if T::SIZE == 1 => {
                 if !char.is_ascii() {
-                    Err(format!("non-ascii char cannot be entered with push.b"))?
+                    Err("non-ascii char cannot be entered with push.b")?
                 }
 
                 tokens.next();
@@ -410,13 +1090,20 @@ impl Assembler {
                 tokens.next();
                 self.text.extend((char as u64).to_le_bytes());
             }
-            Token::Word(_) if T::SIZE == 8 => {
+            Token::Word(word) if self.constant(&word).is_some() => {
+                tokens.next();
+                let constant = self.constant(&word).unwrap_or_default();
+                let value = self.number::<T>(&constant.to_string(), code, Operand::of(code))?;
+                self.text.extend(value.to_le_bytes());
+            }
+            Token::Word(_) | Token::Dot if T::SIZE == 8 => {
                 self.assemble_label(tokens)?;
             }
             Token::Keyword(Keyword::SizeOf) if T::SIZE == 8 => {
                 tokens.next();
                 let word = tokens.next_word()?;
-                let Some(label) = self.labels.get(&word) else {
+                let label = self.labels.get(&self.qualify(&word));
+                let Some(label) = label.or_else(|| self.labels.get(&word)) else {
                     Err(format!("label must be defined before sizeof: {word}"))?
                 };
                 let Section::Data { size } = label.section else {
@@ -437,9 +1124,7 @@ impl Assembler {
                 match mtokens.next() {
                     Token::Value(Value::Number(number)) => {
                         mtokens.next();
-                        let value = number
-                            .parse::<T>()
-                            .map_err(|_| format!("value cannot be parsed: {number}"))?;
+                        let value = self.number::<T>(&number, code, Operand::of(code))?;
                         self.text.extend(value.to_le_bytes());
                     }
                     Token::Word(_) if T::SIZE == 8 => {
@@ -458,7 +1143,112 @@ impl Assembler {
         Ok(())
     }
 
+    /// Append an operator followed by a floating point literal, such as `1.5` or `-2`
+    fn assemble_operator_with_float<F: Float>(
+        &mut self,
+        tokens: &mut TokenState,
+        code: Bytecode,
+    ) -> Result<()> {
+        self.assemble_operator(code);
+
+        let number = match tokens.next_value()? {
+            Value::Number(number) => number,
+            value => Err(format!("unexpected value: {value:?}"))?,
+        };
+        let Ok(value) = number.parse::<F>() else {
+            Err(format!("value cannot be parsed: {number}"))?
+        };
+        self.text.extend(value.to_bits().to_le_bytes());
+
+        Ok(())
+    }
+
+    /// Parses a value for `operand`, checking it fits within a `T`
+    fn number<T: Number>(
+        &self,
+        number: &str,
+        operand: impl std::fmt::Display,
+        kind: Operand,
+    ) -> Result<T> {
+        let Some((value, pattern)) = parse_literal(number) else {
+            Err(format!("value cannot be parsed: {number}"))?
+        };
+
+        let bits = T::SIZE as u32 * 8;
+        let signed = (-(1i128 << (bits - 1)), (1i128 << (bits - 1)) - 1);
+        let unsigned = (1i128 << bits) - 1;
+        let (min, max) = match kind {
+            Operand::Index => (0, unsigned),
+            Operand::Offset => (-1, unsigned),
+            Operand::Immediate if pattern => (signed.0, unsigned),
+            Operand::Immediate => signed,
+        };
+
+        if !(min..=max).contains(&value) && !self.allow_truncation {
+            Err(format!(
+                "value {number} is out of range for {operand}: expected {min}..={max}"
+            ))?
+        }
+
+        // Values in range are unchanged by keeping their low bytes, since negative values are
+        // stored as two's complement
+        Ok(T::from_le_bytes(&value.to_le_bytes()[..T::SIZE]))
+    }
+
     /// Append an operator which expects a label offset inline.
+    /// Append an operator which expects the fields of a record inline, such as `bwd`
+    fn assemble_operator_with_fields(
+        &mut self,
+        tokens: &mut TokenState,
+        code: Bytecode,
+    ) -> Result<()> {
+        self.assemble_operator(code);
+        let fields = tokens.next_word()?.parse::<Fields>()?;
+        self.text.extend(fields.0.to_le_bytes());
+
+        Ok(())
+    }
+
+    /// Append `inc` with a local slot and the word added to it, such as `inc 0, 2`, which is 1
+    /// if it's left out. `dec` is written the same way but subtracts the word.
+    fn assemble_inc(&mut self, tokens: &mut TokenState, dec: bool) -> Result<()> {
+        let name = match dec {
+            true => "dec",
+            false => "inc",
+        };
+        self.assemble_operator(Bytecode::IncLocal);
+
+        let slot = self.inc_operand(tokens)?;
+        let slot = self.number::<u64>(&slot, name, Operand::Index)?;
+        let n = match tokens.check(&[Token::Comma]) {
+            true => {
+                let n = self.inc_operand(tokens)?;
+                self.number::<i32>(&n, name, Operand::Immediate)?
+            }
+            false => 1,
+        };
+        let n = match dec {
+            true => n.wrapping_neg(),
+            false => n,
+        };
+
+        self.text.extend(slot.to_le_bytes());
+        self.text.extend(n.to_le_bytes());
+
+        Ok(())
+    }
+
+    /// A number, or the value of a constant, written as an operand of `inc` or `dec`
+    fn inc_operand(&self, tokens: &mut TokenState) -> Result<String> {
+        match tokens.next() {
+            Token::Value(Value::Number(number)) => Ok(number),
+            Token::Word(word) if self.constant(&word).is_some() => {
+                Ok(self.constant(&word).unwrap_or_default().to_string())
+            }
+            token => Err(format!("unexpected token: {token:?}"))?,
+        }
+    }
+
     fn assemble_operator_with_label(
         &mut self,
         tokens: &mut TokenState,
@@ -468,8 +1258,23 @@ impl Assembler {
         self.assemble_label(tokens)
     }
 
+    /// A value known at assembly time, looking in the current namespace before the global one
+    fn constant(&self, name: &str) -> Option<i64> {
+        let constant = self.constants.get(&self.qualify(name));
+        constant.or_else(|| self.constants.get(name)).copied()
+    }
+
     fn assemble_label(&mut self, tokens: &mut TokenState) -> Result<()> {
-        let label = tokens.next_word()?;
+        let label = if tokens.check(&[Token::Dot]) {
+            let name = tokens.next_word()?;
+            self.local(&name)?
+        } else {
+            let label = tokens.next_word()?;
+            self.reference(label)
+        };
+        if let Some(span) = tokens.span() {
+            self.references.entry(label.clone()).or_insert(span);
+        }
         self.unresolved.insert(self.text.len() as u64, label);
         self.text.extend(0u64.to_le_bytes());
 
@@ -479,13 +1284,82 @@ impl Assembler {
     fn parse_entry(&mut self, tokens: &mut TokenState) -> Result<String> {
         tokens.expect(&[Token::Dot, Token::Keyword(Keyword::Entry)])?;
         let entry = tokens.next_word()?;
+        if let Some(span) = tokens.span() {
+            self.references.insert(entry.clone(), span);
+        }
 
         Ok(entry)
     }
 }
 
+/// The kind of value an operand holds, which decides the literals it accepts
+#[derive(Clone, Copy)]
+enum Operand {
+    /// A slot in the locals array or a number of slots, which can't be negative
+    Index,
+    /// An offset into the program, which also accepts `-1` as a sentinel for the largest offset
+    Offset,
+    /// A signed value, which can also be written as its unsigned bit pattern in hex, binary or
+    /// octal
+    Immediate,
+}
+
+impl Operand {
+    fn of(code: Bytecode) -> Self {
+        match code {
+            Bytecode::Load
+            | Bytecode::LoadB
+            | Bytecode::LoadD
+            | Bytecode::Store
+            | Bytecode::StoreB
+            | Bytecode::StoreD
+            | Bytecode::RetN
+            | Bytecode::SLoad
+            | Bytecode::SStore => Operand::Index,
+            Bytecode::DataPtr => Operand::Offset,
+            _ => Operand::Immediate,
+        }
+    }
+}
+
+/// Parses a decimal literal, or a hex, binary or octal one prefixed with `0x`, `0b` or `0o`, any
+/// of which may be negative. Returns the value and whether it was written in one of the prefixed
+/// forms, which can give a bit pattern.
+fn parse_literal(number: &str) -> Option<(i128, bool)> {
+    let (negative, digits) = match number.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, number),
+    };
+    // Parsing would otherwise accept a second sign, such as in `--5` or `0x+5`
+    if !digits.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return None;
+    }
+
+    let radix = [("0x", 16), ("0b", 2), ("0o", 8)]
+        .into_iter()
+        .find_map(|(prefix, radix)| Some((digits.strip_prefix(prefix)?, radix)));
+    let (value, pattern) = match radix {
+        Some((digits, radix)) => (i128::from_str_radix(digits, radix).ok()?, true),
+        None => (digits.parse::<i128>().ok()?, false),
+    };
+
+    Some((if negative { -value } else { value }, pattern))
+}
+
+/// Builds a [`Diagnostic`] if the source location is known, otherwise a plain message
+fn error(message: String, span: Option<Span>, help: Option<String>) -> Box<dyn std::error::Error> {
+    match (span, help) {
+        (Some(span), Some(help)) => Box::new(Diagnostic::new(message, span).with_help(help)),
+        (Some(span), None) => Box::new(Diagnostic::new(message, span)),
+        (None, Some(help)) => format!("{message} ({help})").into(),
+        (None, None) => message.into(),
+    }
+}
+
 #[cfg(test)]
 mod test {
+    use crate::diagnostic::Diagnostic;
+    use crate::limits::Limits;
     use crate::program::Bytecode;
     use crate::Result;
 
@@ -601,4 +1475,462 @@ main:
         assert_eq!(want, have);
         Ok(())
     }
+
+    #[test]
+    fn test_assemble_prelude() -> Result<()> {
+        let src = "
+.entry main
+
+.data message .string \"abc\"
+
+main:
+    dataptr message
+    push.d sizeof message
+    @PRINT
+    push 0
+    @EXIT
+";
+        assert!(Assembler::new().assemble(src).is_err());
+
+        let output = Assembler::new().with_prelude(true).assemble(src)?;
+        assert!(output
+            .labels()
+            .values()
+            .any(|label| label == "prelude_print"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_assemble_diagnostics() {
+        for (src, message, token) in [
+            (
+                ".entry main\nmain:\n    jmp.lw main\n",
+                "unknown instruction: jmp.lw",
+                "jmp.lw",
+            ),
+            (
+                ".entry main\nmain:\n    push.b 300\n",
+                "value 300 is out of range for push.b: expected -128..=127",
+                "300",
+            ),
+            (
+                ".entry main\nmain:\n    call f\n    ret\n",
+                "could not resolve label: f",
+                "f",
+            ),
+            (
+                ".entry start\nmain:\n    ret\n",
+                "could not resolve label: start",
+                "start",
+            ),
+        ] {
+            let err = Assembler::new().assemble(src).err().unwrap();
+            let diagnostic = err.downcast_ref::<Diagnostic>().unwrap();
+
+            assert_eq!(diagnostic.message, message);
+            assert_eq!(&src[diagnostic.span.start..diagnostic.span.end], token);
+        }
+    }
+
+    #[test]
+    fn test_assemble_ranges() -> Result<()> {
+        for (src, want) in [
+            (
+                "push.b 128",
+                "value 128 is out of range for push.b: expected -128..=127",
+            ),
+            (
+                "push 4294967296",
+                "value 4294967296 is out of range for push: expected -2147483648..=2147483647",
+            ),
+            (
+                "load -1",
+                "value -1 is out of range for load: expected 0..=18446744073709551615",
+            ),
+            (
+                "push.d 999999999999999999999999999999999999999999",
+                "value cannot be parsed: 999999999999999999999999999999999999999999",
+            ),
+        ] {
+            let src = format!(".entry main\nmain:\n    {src}\n");
+            let err = Assembler::new().assemble(&src).err().unwrap();
+            assert_eq!(err.to_string(), want);
+        }
+
+        let src = ".entry main\n.data b .byte 300\nmain:\n    push.b 255\n    push 4294967297\n";
+        let err = Assembler::new().assemble(src).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "value 300 is out of range for .byte: expected -128..=127"
+        );
+
+        let have: Vec<u8> = Assembler::new()
+            .with_allow_truncation(true)
+            .assemble(src)?
+            .into();
+        #[rustfmt::skip]
+        let want: Vec<u8> = vec![
+            9, 0, 0, 0, 0, 0, 0, 0,
+            44, // data
+            Bytecode::PushB as u8, 255,
+            Bytecode::Push as u8, 1, 0, 0, 0,
+        ];
+        assert_eq!(want, have);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_assemble_literals() -> Result<()> {
+        let src = "
+.entry main
+main:
+    push.b 0xFF
+    push -0x10
+    load 0x2
+    dataptr -1
+    push.d 0xFFFFFFFFFFFFFFFF
+    push.b 0b11111111
+    push -0b1010
+    push 0o755
+";
+        let have: Vec<u8> = Assembler::new().assemble(src)?.into();
+        #[rustfmt::skip]
+        let want: Vec<u8> = vec![
+            8, 0, 0, 0, 0, 0, 0, 0,
+            Bytecode::PushB as u8, 255,
+            Bytecode::Push as u8, 240, 255, 255, 255,
+            Bytecode::Load as u8, 2, 0, 0, 0, 0, 0, 0, 0,
+            Bytecode::DataPtr as u8, 255, 255, 255, 255, 255, 255, 255, 255,
+            Bytecode::PushD as u8, 255, 255, 255, 255, 255, 255, 255, 255,
+            Bytecode::PushB as u8, 255,
+            Bytecode::Push as u8, 246, 255, 255, 255,
+            Bytecode::Push as u8, 237, 1, 0, 0,
+        ];
+        assert_eq!(want, have);
+
+        for (src, want) in [
+            (
+                "push.b 0x100",
+                "value 0x100 is out of range for push.b: expected -128..=255",
+            ),
+            (
+                "push.b 255",
+                "value 255 is out of range for push.b: expected -128..=127",
+            ),
+            (
+                "dataptr -2",
+                "value -2 is out of range for dataptr: expected -1..=18446744073709551615",
+            ),
+            ("push 0xZ", "value cannot be parsed: 0xZ"),
+            ("push 12ab", "value cannot be parsed: 12ab"),
+            ("push 0b102", "value cannot be parsed: 0b102"),
+            ("push 0o8", "value cannot be parsed: 0o8"),
+            ("push 0b", "value cannot be parsed: 0b"),
+            ("push --5", "value cannot be parsed: --5"),
+            ("push -", "value cannot be parsed: -"),
+            ("push 0x+5", "value cannot be parsed: 0x+5"),
+            ("push 5-", "value cannot be parsed: 5-"),
+            (
+                "push.b 0b100000000",
+                "value 0b100000000 is out of range for push.b: expected -128..=255",
+            ),
+        ] {
+            let src = format!(".entry main\nmain:\n    {src}\n");
+            let err = Assembler::new().assemble(&src).err().unwrap();
+            assert_eq!(err.to_string(), want);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_assemble_suggestions() {
+        for (src, help) in [
+            (
+                ".entry main\nmain:\n    jmp.lw main\n",
+                "did you mean `jmp.le`?",
+            ),
+            (".entry main\nmain:\n    sytsem\n", "did you mean `system`?"),
+            (
+                ".entry main\nmain:\n    jmp lop\nloop:\n    ret\n",
+                "did you mean `loop`?",
+            ),
+            (".entry mian\nmain:\n    ret\n", "did you mean `main`?"),
+        ] {
+            let err = Assembler::new().assemble(src).err().unwrap();
+            let diagnostic = err.downcast_ref::<Diagnostic>().unwrap();
+
+            assert_eq!(diagnostic.help.as_deref(), Some(help));
+        }
+
+        // Suggestions are still given without a source location, such as within a macro
+        let src = "
+.entry main
+#define JUMP { jmp lop }
+main:
+    @JUMP
+loop:
+    ret
+";
+        let err = Assembler::new().assemble(src).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "could not resolve label: lop (did you mean `loop`?)"
+        );
+    }
+
+    #[test]
+    fn test_assemble_equ() -> Result<()> {
+        // Constants don't take up any data
+        let src = ".entry main\n.equ SIZE 256\nmain:\n    push.d SIZE\n";
+        let output = Assembler::new().assemble(src)?;
+        assert!(output.data().is_empty());
+
+        for (src, want) in [
+            (".equ A 1\n.equ A 2", "label is declared twice: A"),
+            (".data A .byte 0\n.equ A 1", "label is declared twice: A"),
+            (".equ A B", "unexpected token for .equ A: Word(\"B\")"),
+            (
+                ".equ A 0x1FFFFFFFFFFFFFFFF",
+                "value 0x1FFFFFFFFFFFFFFFF is out of range for .equ A: expected -9223372036854775808..=18446744073709551615",
+            ),
+        ] {
+            let src = format!(".entry main\n{src}\nmain:\n    ret\n");
+            let err = Assembler::new().assemble(&src).err().unwrap();
+            assert_eq!(err.to_string(), want);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_assemble_duplicate_meta() {
+        let src = ".entry main\n.meta version \"1\"\n.meta version \"2\"\nmain:\n    ret\n";
+        let err = Assembler::new().assemble(src).err().unwrap();
+        let diagnostic = err.downcast_ref::<Diagnostic>().unwrap();
+
+        assert_eq!(diagnostic.message, "duplicate metadata: version");
+    }
+
+    #[test]
+    fn test_assemble_namespaces() -> Result<()> {
+        let src = "
+.entry main
+
+main:
+    call shapes::area
+    ret
+
+.namespace shapes
+.data side .word 3
+
+area:
+    dataptr side
+    get
+    call util::square
+    ret.w
+
+.namespace util
+
+square:
+    load 0
+    load 0
+    mul
+    ret.w
+";
+        let output = Assembler::new().assemble(src)?;
+        let labels: Vec<_> = output.labels().values().cloned().collect();
+        assert!(labels.contains(&String::from("shapes::area")));
+        assert!(labels.contains(&String::from("shapes::side")));
+        assert!(labels.contains(&String::from("util::square")));
+
+        // Unlike an unqualified reference, a qualified one doesn't fall back to the global label
+        let src = ".entry main\nmain:\n    call a::g\n    ret\nf:\n    ret\n.namespace a\ng:\n    call a::f\n    ret\n";
+        let err = Assembler::new().assemble(src).err().unwrap();
+        let diagnostic = err.downcast_ref::<Diagnostic>().unwrap();
+        assert_eq!(diagnostic.message, "could not resolve label: a::f");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_assemble_local_labels() -> Result<()> {
+        let src = "
+.entry main
+
+main:
+    call f
+    call g
+    ret
+
+f:
+    push 1
+.loop:
+    jmp .loop
+
+g:
+    push 2
+.loop:
+    jmp .loop
+    jmp f.loop
+";
+        let output = Assembler::new().assemble(src)?;
+        let labels: Vec<_> = output.labels().values().cloned().collect();
+        assert!(labels.contains(&String::from("f.loop")));
+        assert!(labels.contains(&String::from("g.loop")));
+
+        let src = ".entry main\n.loop:\nmain:\n    ret\n";
+        let err = Assembler::new().assemble(src).err().unwrap();
+        assert_eq!(err.to_string(), "local label must follow a label: .loop");
+
+        let src = ".entry main\nmain:\n.loop:\n.loop:\n    ret\n";
+        let err = Assembler::new().assemble(src).err().unwrap();
+        assert_eq!(err.to_string(), "duplicate label: .loop");
+
+        let src = ".entry main\nmain:\n    jmp .done\nf:\n.done:\n    ret\n";
+        let err = Assembler::new().assemble(src).err().unwrap();
+        let diagnostic = err.downcast_ref::<Diagnostic>().unwrap();
+        assert_eq!(diagnostic.message, "could not resolve label: main.done");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_assemble_disallowed_include() {
+        let src = ".entry main\n#include \"include.b\"\nmain:\n    ret\n";
+        let err = Assembler::new()
+            .with_allow_include(false)
+            .assemble(src)
+            .err()
+            .unwrap();
+        let diagnostic = err.downcast_ref::<Diagnostic>().unwrap();
+
+        assert_eq!(diagnostic.message, "#include is not allowed");
+
+        let src = ".entry main\n.data table .incbin \"table.bin\"\nmain:\n    ret\n";
+        let err = Assembler::new()
+            .with_allow_include(false)
+            .assemble(src)
+            .err()
+            .unwrap();
+        let diagnostic = err.downcast_ref::<Diagnostic>().unwrap();
+
+        assert_eq!(diagnostic.message, ".incbin is not allowed");
+    }
+
+    #[test]
+    fn test_assemble_limits() {
+        let src = ".entry main\n.data a .string \"abcdef\"\nmain:\n    push 1\n    push 2\n    add\n    ret\n";
+        let limits = Limits {
+            text_size: 8,
+            ..Default::default()
+        };
+        let err = Assembler::new()
+            .with_limits(limits)
+            .assemble(src)
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "program exceeds configured text size (8)");
+
+        let limits = Limits {
+            data_size: 4,
+            ..Default::default()
+        };
+        let err = Assembler::new()
+            .with_limits(limits)
+            .assemble(src)
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "program exceeds configured data size (4)");
+    }
+
+    #[test]
+    fn test_assemble_origins() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("stack-origins-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        std::fs::write(
+            dir.join("lib.s"),
+            "#define TWO { push 2 }\n#define PUSH { @TWO }\n",
+        )?;
+        std::fs::write(dir.join("bad.s"), "#define BAD { push.b 300 }\n")?;
+        std::fs::write(dir.join("broken.s"), "pop\nsytsem\n")?;
+
+        let src = "\
+.entry main
+#include \"lib.s\"
+main:
+    push 1
+    @PUSH
+    ret
+";
+        let output = Assembler::new()
+            .with_source_name("main.s")
+            .with_include_paths(vec![dir.clone()])
+            .assemble(src)?;
+
+        // push 1 is at 8, so push 2 is at 8 + 5
+        assert_eq!(output.origin(8), None);
+        let origin = output.origin(13).unwrap();
+        assert_eq!(origin.location, None);
+        assert_eq!(
+            origin.expansions,
+            ["expanded from @TWO", "expanded from @PUSH at main.s:5:6"]
+        );
+
+        // Errors within expansions note where they came from, innermost first
+        for (src, notes) in [
+            (
+                ".entry main\n#include \"bad.s\"\nmain:\n    @BAD\n",
+                ["expanded from @BAD at main.s:4:6"].as_slice(),
+            ),
+            (
+                ".entry main\nmain:\n#include \"broken.s\"\n",
+                ["at broken.s:2:1", "included from main.s:3:10"].as_slice(),
+            ),
+        ] {
+            let err = Assembler::new()
+                .with_source_name("main.s")
+                .with_include_paths(vec![dir.clone()])
+                .assemble(src)
+                .err()
+                .unwrap();
+            let diagnostic = err.downcast_ref::<Diagnostic>().unwrap();
+            assert_eq!(diagnostic.notes, notes);
+        }
+
+        std::fs::remove_dir_all(&dir)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_assemble_dependencies() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("stack-dependencies-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        std::fs::write(dir.join("lib.s"), "#define ONE 1\n")?;
+        std::fs::write(dir.join("table.bin"), [1, 2, 3])?;
+
+        // lib.s is only recorded once, and the prelude isn't a file
+        let src = "\
+.entry main
+#include \"lib.s\"
+#include \"lib.s\"
+.data table .incbin \"table.bin\"
+main:
+    push @ONE
+    ret
+";
+        let (output, dependencies) = Assembler::new()
+            .with_prelude(true)
+            .with_include_paths(vec![dir.clone()])
+            .assemble_with_dependencies(src)?;
+        assert_eq!(output.data(), [1, 2, 3]);
+        assert_eq!(dependencies, [dir.join("lib.s"), dir.join("table.bin")]);
+
+        std::fs::remove_dir_all(&dir)?;
+
+        Ok(())
+    }
 }
@@ -0,0 +1,311 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Cursor};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::interpreter::{Interpreter, RunOutcome};
+use crate::manifest::{Manifest, Value};
+use crate::output::Output;
+use crate::Result;
+
+/// A program to run and the file to use as its standard input
+#[derive(Debug, PartialEq)]
+pub struct Job {
+    pub name: String,
+    pub program: PathBuf,
+    pub stdin: Option<PathBuf>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Status {
+    /// The program returned from main, or made the exit system call with this status
+    Exit(i32),
+    Error(String),
+}
+
+impl std::fmt::Display for Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Status::Exit(code) => write!(f, "exit {code}"),
+            Status::Error(err) => write!(f, "error: {err}"),
+        }
+    }
+}
+
+pub struct JobResult {
+    pub name: String,
+    pub status: Status,
+    pub stdout: Vec<u8>,
+    pub duration: Duration,
+}
+
+/// A list of jobs read from a manifest such as:
+///
+/// ```toml
+/// workers = 4
+///
+/// [[job]]
+/// name = "hello"
+/// program = "hello.out"
+/// stdin = "hello.in"
+/// ```
+///
+/// Paths are relative to the manifest. `name` defaults to the program path and `workers` to the
+/// available parallelism.
+pub struct Batch {
+    pub jobs: Vec<Job>,
+    pub workers: usize,
+}
+
+impl Batch {
+    pub fn load(path: &Path) -> Result<Self> {
+        let src = fs::read_to_string(path)?;
+        let base = path.parent().unwrap_or(Path::new(""));
+
+        Self::parse(&src, base).map_err(|err| format!("{}: {err}", path.display()).into())
+    }
+
+    pub fn parse(src: &str, base: &Path) -> Result<Self> {
+        let manifest = Manifest::parse(src)?;
+
+        let workers = match manifest.root.get("workers") {
+            Some(Value::Integer(n)) if *n > 0 => *n as usize,
+            Some(value) => Err(format!("invalid workers: {value:?}"))?,
+            None => thread::available_parallelism().map_or(1, |n| n.get()),
+        };
+
+        let mut jobs = Vec::new();
+        for (i, table) in manifest.arrays.get("job").into_iter().flatten().enumerate() {
+            let path = |key: &str| match table.get(key) {
+                Some(Value::String(path)) => Ok(Some(base.join(path))),
+                Some(value) => Err(format!("job {i}: invalid {key}: {value:?}")),
+                None => Ok(None),
+            };
+
+            let Some(program) = path("program")? else {
+                Err(format!("job {i}: missing program"))?
+            };
+            let stdin = path("stdin")?;
+            let name = match table.get("name") {
+                Some(Value::String(name)) => name.clone(),
+                Some(value) => Err(format!("job {i}: invalid name: {value:?}"))?,
+                None => program.display().to_string(),
+            };
+
+            jobs.push(Job {
+                name,
+                program,
+                stdin,
+            });
+        }
+
+        Ok(Self { jobs, workers })
+    }
+
+    /// Runs every job across the workers, returning the results in the same order as the jobs
+    pub fn run(&self) -> Vec<JobResult> {
+        let next = Mutex::new(self.jobs.iter().enumerate());
+        let results = Mutex::new(HashMap::new());
+
+        thread::scope(|scope| {
+            for _ in 0..self.workers.min(self.jobs.len()) {
+                scope.spawn(|| {
+                    let mut worker = Worker::default();
+                    loop {
+                        let Some((i, job)) = next.lock().unwrap().next() else {
+                            break;
+                        };
+
+                        let result = worker.run(job);
+                        results.lock().unwrap().insert(i, result);
+                    }
+                });
+            }
+        });
+
+        let mut results = results.into_inner().unwrap();
+        (0..self.jobs.len())
+            .map(|i| results.remove(&i).unwrap())
+            .collect()
+    }
+}
+
+/// An interpreter kept around by a worker so later jobs for the same program can reuse it
+struct Pooled {
+    interpreter: Interpreter,
+    stdin: Arc<Mutex<Cursor<Vec<u8>>>>,
+}
+
+#[derive(Default)]
+struct Worker {
+    pool: HashMap<PathBuf, Pooled>,
+}
+
+impl Worker {
+    fn run(&mut self, job: &Job) -> JobResult {
+        let start = Instant::now();
+
+        let (status, stdout) = match self.execute(job) {
+            Ok(result) => result,
+            Err(err) => (Status::Error(err.to_string()), Vec::new()),
+        };
+
+        JobResult {
+            name: job.name.clone(),
+            status,
+            stdout,
+            duration: start.elapsed(),
+        }
+    }
+
+    fn execute(&mut self, job: &Job) -> Result<(Status, Vec<u8>)> {
+        let input = match &job.stdin {
+            Some(path) => fs::read(path)?,
+            None => Vec::new(),
+        };
+
+        if !self.pool.contains_key(&job.program) {
+            let output = Output::deserialise(File::open(&job.program)?)?;
+            let stdin = Arc::new(Mutex::new(Cursor::new(Vec::new())));
+            // The program's stderr still goes to the process's, since only stdout is collected
+            let stderr = Arc::new(Mutex::new(io::stderr()));
+            let interpreter = Interpreter::new(&output, None, Some(stderr))?
+                .with_stdin(stdin.clone())
+                .with_captured_output();
+
+            let pooled = Pooled { interpreter, stdin };
+            self.pool.insert(job.program.clone(), pooled);
+        }
+
+        let pooled = self.pool.get_mut(&job.program).unwrap();
+
+        *pooled.stdin.lock().unwrap() = Cursor::new(input);
+        pooled.interpreter.reset();
+
+        let status = match pooled.interpreter.run() {
+            RunOutcome::Completed { .. } => {
+                Status::Exit(pooled.interpreter.exit_code().unwrap_or(0))
+            }
+            RunOutcome::Exited { code } => Status::Exit(code),
+            RunOutcome::Trapped { error, .. } => Status::Error(error),
+            RunOutcome::FuelExhausted => Status::Error(String::from("out of gas")),
+        };
+        let stdout = pooled.interpreter.captured_stdout().unwrap_or_default();
+
+        Ok((status, stdout))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use super::{Batch, Job, Status};
+    use crate::assembler::Assembler;
+    use crate::Result;
+
+    #[test]
+    fn test_batch() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("stack-batch-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+
+        // Echoes up to 16 bytes of stdin, then exits with the number of bytes read
+        let echo = "
+.entry main
+
+main:
+    push.d 16
+    alloc
+    store.d 0
+    push 0
+    load.d 0
+    push.d 16
+    push 3
+    system
+    store 2
+    push 1
+    load.d 0
+    load 2
+    push 0
+    push 4
+    system
+    pop
+    load 2
+    push 1
+    system
+";
+        let output = Assembler::new().assemble(echo)?;
+        std::fs::write(dir.join("echo.out"), output.serialise())?;
+        std::fs::write(dir.join("a.in"), "hello")?;
+        std::fs::write(dir.join("b.in"), "hi")?;
+
+        let manifest = r#"
+workers = 2
+
+[[job]]
+name = "a"
+program = "echo.out"
+stdin = "a.in"
+
+[[job]]
+name = "b"
+program = "echo.out"
+stdin = "b.in"
+
+[[job]]
+program = "missing.out"
+
+[[job]]
+name = "c"
+program = "echo.out"
+"#;
+        let batch = Batch::parse(manifest, &dir)?;
+        assert_eq!(batch.workers, 2);
+        assert_eq!(
+            batch.jobs[0],
+            Job {
+                name: "a".into(),
+                program: dir.join("echo.out"),
+                stdin: Some(dir.join("a.in")),
+            }
+        );
+
+        let results = batch.run();
+        let results: Vec<_> = results
+            .iter()
+            .map(|result| {
+                (
+                    result.name.as_str(),
+                    &result.status,
+                    result.stdout.as_slice(),
+                )
+            })
+            .collect();
+
+        let missing = dir.join("missing.out").display().to_string();
+        assert_eq!(results[0], ("a", &Status::Exit(5), b"hello".as_slice()));
+        assert_eq!(results[1], ("b", &Status::Exit(2), b"hi".as_slice()));
+        assert_eq!(results[2].0, missing);
+        assert!(matches!(results[2].1, Status::Error(_)));
+        assert_eq!(results[3], ("c", &Status::Exit(0), b"".as_slice()));
+
+        std::fs::remove_dir_all(&dir)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        for (src, want) in [
+            ("[[job]]\nname = \"a\"", "job 0: missing program"),
+            ("workers = 0", "invalid workers: Integer(0)"),
+            ("[[job]]\nprogram = 1", "job 0: invalid program: Integer(1)"),
+        ] {
+            let err = Batch::parse(src, Path::new("")).err().unwrap();
+            assert_eq!(err.to_string(), want);
+        }
+    }
+}
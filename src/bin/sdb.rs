@@ -5,24 +5,33 @@ use std::process;
 
 use stack::debugger::Debugger;
 use stack::output::Output;
+use stack::{Fields, StackValue};
 
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 
 enum Command {
     Backtrace,
-    BreakLabel(String),
-    BreakPosition(u64),
+    Balance,
+    Break(String),
     Continue,
-    Delete(u64),
+    Delete(String),
+    Diff,
     Disassembly,
+    Find(Vec<u8>),
     List,
+    Listing(String),
     Peek,
     PeekLong,
     Run,
     Stack,
+    StackJson(Fields),
     Step,
+    Stress(usize),
+    Unwatch,
     Variable(u64),
     VariableLong(u64),
+    Viz(Fields),
+    WatchStdout(Vec<u8>),
 }
 
 fn main() -> Result<()> {
@@ -40,11 +49,11 @@ fn main() -> Result<()> {
     let mut debugger = Debugger::new(output)?;
 
     let mut stdout = stdout();
-    let mut stdin = stdin().lines();
+    let stdin = stdin().lines();
 
     stdout.write_fmt(format_args!("{PROMPT}"))?;
     stdout.flush()?;
-    while let Some(line) = stdin.next() {
+    for line in stdin {
         let line = line?;
 
         if let Err(e) = parse_evaluate(&mut stdout, &mut debugger, line) {
@@ -74,21 +83,35 @@ fn parse_evaluate(stdout: &mut Stdout, debugger: &mut Debugger, line: String) ->
             let position = debugger.r#continue()?;
             debugger.fmt_line(stdout, position)?;
         }
-        Command::Stack => writeln!(stdout, "{}", debugger.stack())?,
+        Command::Stress(runs) => match debugger.stress(runs)? {
+            Some((run, failure)) => {
+                writeln!(stdout, "run {run} failed: {failure}")?;
+                debugger.fmt_line(stdout, debugger.position())?;
+            }
+            None => writeln!(stdout, "all {runs} runs passed")?,
+        },
+        Command::Stack => debugger.fmt_stack(stdout)?,
+        Command::StackJson(widths) => writeln!(
+            stdout,
+            "{}",
+            StackValue::json(&debugger.frame().values(widths))
+        )?,
         Command::Peek => writeln!(stdout, "{:?}", debugger.peek::<i32>())?,
         Command::PeekLong => writeln!(stdout, "{:?}", debugger.peek::<i64>())?,
-        Command::BreakPosition(position) => debugger.set_breakpoint(position)?,
-        Command::BreakLabel(label) => debugger.set_label_breakpoint(&label)?,
-        Command::Delete(position) => debugger.delete_breakpoint(position),
+        Command::Break(position) => debugger.set_breakpoint(debugger.resolve(&position)?)?,
+        Command::Delete(position) => debugger.delete_breakpoint(debugger.resolve(&position)?),
         Command::List => debugger.fmt_breakpoints(stdout)?,
-        Command::Variable(variable) => {
-            writeln!(stdout, "{}", debugger.variable::<i32>(variable))?;
-        }
-        Command::VariableLong(variable) => {
-            writeln!(stdout, "{}", debugger.variable::<i64>(variable))?;
-        }
+        Command::Listing(position) => debugger.fmt_listing(stdout, debugger.resolve(&position)?)?,
+        Command::Variable(variable) => debugger.fmt_variable::<i32>(stdout, variable)?,
+        Command::VariableLong(variable) => debugger.fmt_variable::<i64>(stdout, variable)?,
+        Command::Viz(widths) => debugger.fmt_viz(stdout, widths)?,
         Command::Backtrace => debugger.fmt_backtrace(stdout)?,
+        Command::Balance => debugger.fmt_balance(stdout)?,
         Command::Disassembly => write!(stdout, "{}", debugger.output())?,
+        Command::Find(pattern) => debugger.fmt_find(stdout, &pattern)?,
+        Command::Diff => debugger.fmt_diff(stdout)?,
+        Command::WatchStdout(pattern) => debugger.watch_stdout(pattern)?,
+        Command::Unwatch => debugger.unwatch_stdout(),
     }
 
     Ok(())
@@ -101,25 +124,34 @@ fn parse_command(line: &str) -> Result<Command> {
         "r" | "run" => Command::Run,
         "s" | "step" | "" => Command::Step,
         "st" | "stack" => Command::Stack,
+        "sj" | "stack-json" => {
+            // The widths of the values from the bottom of the stack, with words after them
+            let widths = match parts.next() {
+                Some(widths) => widths.parse::<Fields>()?,
+                None => Fields(0),
+            };
+            Command::StackJson(widths)
+        }
         "c" | "continue" => Command::Continue,
         "b" | "break" => {
-            let Some(arg) = parts.next() else {
+            let Some(position) = parts.next() else {
                 Err("could not parse argument")?
             };
-
-            match arg.parse::<u64>() {
-                Ok(position) => Command::BreakPosition(position),
-                Err(_) => Command::BreakLabel(arg.into()),
-            }
+            Command::Break(position.into())
         }
         "d" => {
             let Some(position) = parts.next() else {
                 Err("could not parse argument")?
             };
-            let position = position.parse::<u64>()?;
-            Command::Delete(position)
+            Command::Delete(position.into())
         }
         "ls" => Command::List,
+        "l" | "list" => {
+            let Some(position) = parts.next() else {
+                Err("could not parse argument")?
+            };
+            Command::Listing(position.into())
+        }
         "v" | "var" => {
             let Some(variable) = parts.next() else {
                 Err("could not parse argument")?
@@ -134,12 +166,89 @@ fn parse_command(line: &str) -> Result<Command> {
             let variable = variable.parse::<u64>()?;
             Command::VariableLong(variable)
         }
+        "viz" => {
+            // Like stack-json, the widths of the values from the bottom of the stack
+            let widths = match parts.next() {
+                Some(widths) => widths.parse::<Fields>()?,
+                None => Fields(0),
+            };
+            Command::Viz(widths)
+        }
+        "stress" => {
+            let Some(runs) = parts.next() else {
+                Err("could not parse argument")?
+            };
+            let runs = runs.parse::<usize>()?;
+            Command::Stress(runs)
+        }
         "p" | "peek" => Command::Peek,
         "pl" | "peekl" => Command::PeekLong,
         "bt" | "backtrace" => Command::Backtrace,
+        "balance" => Command::Balance,
+        "diff-heap" => Command::Diff,
         "dis" | "disassembly" => Command::Disassembly,
+        "f" | "find" => {
+            // The pattern is the rest of the line, since a string may contain spaces
+            let pattern = line
+                .trim()
+                .split_once(char::is_whitespace)
+                .map_or("", |(_, pattern)| pattern.trim());
+            Command::Find(parse_pattern(pattern)?)
+        }
+        "w" | "watch" => {
+            if parts.next() != Some("stdout") {
+                Err("only stdout can be watched")?
+            }
+            // The pattern is the rest of the line after `stdout`
+            let pattern = line
+                .trim()
+                .split_once("stdout")
+                .map_or("", |(_, pattern)| pattern.trim());
+            Command::WatchStdout(parse_pattern(pattern)?)
+        }
+        "unwatch" => Command::Unwatch,
         cmd => Err(format!("invalid command: {cmd}"))?,
     };
 
     Ok(command)
 }
+
+/// Parses a quoted string such as `"abc\n"`, or hex bytes such as `de ad be ef` or `0xdeadbeef`
+fn parse_pattern(pattern: &str) -> Result<Vec<u8>> {
+    if let Some(s) = pattern.strip_prefix('"') {
+        let Some(s) = s.strip_suffix('"') else {
+            Err("expected \" at the end of the string")?
+        };
+
+        let mut bytes = Vec::new();
+        let mut chars = s.chars();
+        while let Some(c) = chars.next() {
+            let c = match c {
+                '\\' => match chars.next() {
+                    Some('n') => '\n',
+                    Some('t') => '\t',
+                    Some('0') => '\0',
+                    Some('\\') => '\\',
+                    Some('"') => '"',
+                    Some(c) => Err(format!("unknown escape: \\{c}"))?,
+                    None => Err("unterminated escape")?,
+                },
+                c => c,
+            };
+            bytes.extend(c.to_string().as_bytes());
+        }
+
+        return Ok(bytes);
+    }
+
+    let digits: String = pattern.split_whitespace().collect();
+    let digits = digits.strip_prefix("0x").unwrap_or(&digits);
+    if digits.is_empty() || !digits.len().is_multiple_of(2) {
+        Err("expected a string or an even number of hex digits")?
+    }
+
+    (0..digits.len())
+        .step_by(2)
+        .map(|i| Ok(u8::from_str_radix(&digits[i..i + 2], 16)?))
+        .collect()
+}
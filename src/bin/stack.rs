@@ -1,31 +1,244 @@
 use std::env;
-use std::fs::File;
+use std::fs::{self, File};
+use std::io::{self, BufWriter};
+use std::path::Path;
 use std::process;
 
-use stack::interpreter::Interpreter;
+use stack::batch::{Batch, Status};
+use stack::examples::{self, Example, EXAMPLES};
+use stack::gas::GasTable;
+use stack::interpreter::{Interpreter, InterpreterConfig, RunOutcome};
 use stack::output::Output;
+use stack::signing::KeyedHash;
+use stack::verify::verify;
 
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 
+/// The exit statuses when the program traps or runs out of gas, set apart from the statuses
+/// programs usually exit with
+const TRAPPED: i32 = 101;
+const OUT_OF_GAS: i32 = 102;
+
 fn main() -> Result<()> {
     let mut args = env::args();
     let program = args.next().unwrap();
     let Some(path) = args.next() else {
-        eprintln!("usage: {} path/to/file", program);
+        eprintln!(
+            "usage: {} path/to/file|- [--unprotected] [--deterministic] [--packed-stack] [--checked-arithmetic] [--strict-fds] [--check-bytecode] [--gas limit] [--require-signed --pubkey path/to/key] [--trace-fn label [--trace-nested]] [--chrome-trace path/to/trace.json] [--explain]",
+            program
+        );
+        eprintln!("       {} batch path/to/jobs.toml", program);
+        eprintln!("       {} examples list|run name [options]", program);
         process::exit(1);
     };
 
-    let file = File::open(path)?;
-    let output = Output::deserialise(file)?;
+    if path == "batch" {
+        let Some(manifest) = args.next() else {
+            eprintln!("expected path to jobs manifest");
+            process::exit(1);
+        };
+
+        return batch(Path::new(&manifest));
+    }
+
+    // A bundled example is run like a program file, taking the same options after its name
+    let mut example = None;
+    if path == "examples" {
+        match args.next().as_deref() {
+            Some("list") => {
+                list_examples();
+                return Ok(());
+            }
+            Some("run") => {
+                let Some(name) = args.next() else {
+                    eprintln!("expected name of example to run");
+                    process::exit(1);
+                };
+                let Some(found) = examples::find(&name) else {
+                    eprintln!("unknown example: {name}, see {program} examples list");
+                    process::exit(1);
+                };
+
+                example = Some(found);
+            }
+            _ => {
+                eprintln!("expected list or run after examples");
+                process::exit(1);
+            }
+        }
+    }
+
+    let mut config = InterpreterConfig::default();
+    let mut check_bytecode = false;
+    let mut require_signed = false;
+    let mut key_path = None;
+    let mut trace_fn = None;
+    let mut trace_nested = false;
+    let mut chrome_trace = None;
+    let mut explain = false;
+
+    while let Some(option) = args.next() {
+        match option.as_str() {
+            "--unprotected" => config.protect_program = false,
+            "--deterministic" => config.deterministic = true,
+            "--packed-stack" => config.packed_stack = true,
+            "--checked-arithmetic" => config.checked_arithmetic = true,
+            "--strict-fds" => config.strict_fds = true,
+            "--check-bytecode" => check_bytecode = true,
+            "--require-signed" => require_signed = true,
+            "--gas" => {
+                let Some(limit) = args.next().and_then(|limit| limit.parse().ok()) else {
+                    eprintln!("expected number with --gas");
+                    process::exit(1);
+                };
+
+                config.gas = Some(GasTable {
+                    limit: Some(limit),
+                    ..Default::default()
+                });
+            }
+            "--pubkey" => {
+                let Some(path) = args.next() else {
+                    eprintln!("expected path to key with --pubkey");
+                    process::exit(1);
+                };
+
+                key_path = Some(path);
+            }
+            "--trace-fn" => {
+                let Some(label) = args.next() else {
+                    eprintln!("expected label with --trace-fn");
+                    process::exit(1);
+                };
+
+                trace_fn = Some(label);
+            }
+            "--trace-nested" => trace_nested = true,
+            "--explain" => explain = true,
+            "--chrome-trace" => {
+                let Some(path) = args.next() else {
+                    eprintln!("expected path with --chrome-trace");
+                    process::exit(1);
+                };
+
+                chrome_trace = Some(path);
+            }
+            _ => {
+                eprintln!("unknown option: {option}");
+                process::exit(1);
+            }
+        }
+    }
+
+    // Read the program from stdin with -, such as when piped from stackc
+    let output = match (example, path.as_str()) {
+        (Some(example), _) => example.assemble()?,
+        (None, "-") => Output::deserialise(io::stdin().lock())?,
+        (None, path) => Output::deserialise(File::open(path)?)?,
+    };
+
+    if check_bytecode {
+        let errors = verify(&output);
+        for err in &errors {
+            eprintln!("{err}");
+        }
+        if !errors.is_empty() {
+            process::exit(1);
+        }
+    }
 
     // Use the system stdout and stderr
     let (stdout, stderr) = (None, None);
-    let mut interpreter = Interpreter::new(&output, stdout, stderr)?;
-    if let Err(err) = interpreter.run() {
-        eprintln!("{err}");
+    let interpreter = match (require_signed, key_path) {
+        (true, Some(path)) => {
+            let key = KeyedHash::new(fs::read(path)?);
+            Interpreter::new_verified(&output, stdout, stderr, &key)?
+        }
+        (true, None) => {
+            eprintln!("expected --pubkey with --require-signed");
+            process::exit(1);
+        }
+        (false, _) => Interpreter::from_output(output, stdout, stderr)?,
     };
+    let mut interpreter = interpreter.with_config(config);
+    // The trace goes to stderr, so it doesn't mix with what the program writes to stdout
+    let outcome = match (&trace_fn, &chrome_trace, explain) {
+        (Some(label), None, false) => {
+            interpreter.run_traced(label, trace_nested, &mut io::stderr().lock())?
+        }
+        (None, Some(path), false) => {
+            interpreter.run_chrome_trace(&mut BufWriter::new(File::create(path)?))?
+        }
+        (None, None, true) => interpreter.run_explained(&mut io::stderr().lock())?,
+        (None, None, false) => interpreter.run(),
+        _ => {
+            eprintln!("only one of --trace-fn, --chrome-trace and --explain can be used");
+            process::exit(1);
+        }
+    };
+    let failed = match outcome {
+        RunOutcome::Trapped { error, backtrace } => {
+            eprintln!("{error}");
+            for frame in backtrace.iter().rev() {
+                eprintln!("    in {frame}");
+            }
+            Some(TRAPPED)
+        }
+        RunOutcome::FuelExhausted => {
+            eprintln!("out of gas: used {}", interpreter.gas_used());
+            Some(OUT_OF_GAS)
+        }
+        RunOutcome::Completed { .. } | RunOutcome::Exited { .. } => {
+            // Exit with the status from the exit system call or the word returned from main
+            if let Some(code) = interpreter.exit_code() {
+                process::exit(code);
+            }
+            None
+        }
+    };
+
+    // A program which stopped early still shows the frame it stopped in
+    println!("{}", interpreter.current_frame());
+    if let Some(code) = failed {
+        process::exit(code);
+    }
+
+    Ok(())
+}
 
-    println!("{}", interpreter.frames().last().unwrap().opstack);
+fn list_examples() {
+    let width = EXAMPLES
+        .iter()
+        .map(|example| example.name.len())
+        .max()
+        .unwrap_or_default();
+    for Example {
+        name, description, ..
+    } in EXAMPLES
+    {
+        println!("{name:width$}  {description}");
+    }
+}
+
+fn batch(manifest: &Path) -> Result<()> {
+    let batch = Batch::load(manifest)?;
+
+    let mut failed = false;
+    for result in batch.run() {
+        println!(
+            "{}: {} in {:?}",
+            result.name, result.status, result.duration
+        );
+        if !result.stdout.is_empty() {
+            println!("{}", String::from_utf8_lossy(&result.stdout).trim_end());
+        }
+
+        failed |= result.status != Status::Exit(0);
+    }
+
+    if failed {
+        process::exit(1);
+    }
 
     Ok(())
 }
@@ -1,10 +1,15 @@
 use std::env;
-use std::fs::File;
 use std::fs::OpenOptions;
-use std::io::{Read, Write};
+use std::fs::{self, File};
+use std::io::{self, IsTerminal, Read, Write};
+use std::iter;
+use std::path::{Path, PathBuf};
 use std::process;
 
 use stack::assembler::Assembler;
+use stack::build::Build;
+use stack::diagnostic::Diagnostic;
+use stack::signing::KeyedHash;
 
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 
@@ -13,11 +18,25 @@ fn main() -> Result<()> {
     let program = args.next().unwrap();
 
     let Some(path) = args.next() else {
-        eprintln!("usage: {} path/to/file [-I path/to/directory ...]", program);
+        eprintln!("usage: {} path/to/file [-I path/to/directory ...] [-o path/to/output|-] [--prelude] [-O] [--allow-truncation] [--check-loops] [--compress] [--sign path/to/key] [--emit=deps]", program);
+        eprintln!("       {} build [path/to/stack.toml]", program);
         process::exit(1);
     };
 
+    if path == "build" {
+        let manifest = args.next().unwrap_or(String::from("stack.toml"));
+        return build(Path::new(&manifest));
+    }
+
     let mut include_paths = Vec::new();
+    let mut output_path = String::from("a.out");
+    let mut prelude = false;
+    let mut optimise = false;
+    let mut allow_truncation = false;
+    let mut check_loops = false;
+    let mut compress = false;
+    let mut key_path = None;
+    let mut emit_deps = false;
 
     while let Some(option) = args.next() {
         match option.as_str() {
@@ -29,6 +48,28 @@ fn main() -> Result<()> {
 
                 include_paths.push(path.into());
             }
+            "-o" => {
+                let Some(path) = args.next() else {
+                    eprintln!("expected path with -o");
+                    process::exit(1);
+                };
+
+                output_path = path;
+            }
+            "--sign" => {
+                let Some(path) = args.next() else {
+                    eprintln!("expected path to key with --sign");
+                    process::exit(1);
+                };
+
+                key_path = Some(path);
+            }
+            "--prelude" => prelude = true,
+            "-O" => optimise = true,
+            "--allow-truncation" => allow_truncation = true,
+            "--check-loops" => check_loops = true,
+            "--compress" => compress = true,
+            "--emit=deps" => emit_deps = true,
             _ => {
                 eprintln!("unknown option: {option}");
                 process::exit(1);
@@ -36,21 +77,112 @@ fn main() -> Result<()> {
         }
     }
 
+    if emit_deps && output_path == "-" {
+        eprintln!("expected path with -o to write dependencies beside");
+        process::exit(1);
+    }
+
     let mut src = String::new();
-    let mut file = File::open(path)?;
+    let mut file = File::open(&path)?;
     file.read_to_string(&mut src)?;
 
-    const OUTPUT_FILE: &str = "a.out";
-    let output = Assembler::new()
-        .with_include_paths(include_paths)
-        .assemble(&src)?;
+    let assembler = || {
+        Assembler::new()
+            .with_include_paths(include_paths.clone())
+            .with_prelude(prelude)
+            .with_allow_truncation(allow_truncation)
+    };
+    let (mut output, dependencies) = match assembler()
+        .with_source_name(path.as_str())
+        .with_optimise(optimise)
+        .assemble_with_dependencies(&src)
+    {
+        Ok(assembled) => assembled,
+        Err(err) => report(err, &src)?,
+    };
+
+    if optimise {
+        let before = assembler().assemble(&src)?.instruction_count()?;
+        let after = output.instruction_count()?;
+        eprintln!("optimised: {before} -> {after} instructions");
+    }
+
+    if check_loops {
+        for (start, end) in output.infinite_loops()? {
+            eprintln!(
+                "warning: infinite loop from {} to {}",
+                output.symbolise(start),
+                output.symbolise(end)
+            );
+        }
+    }
+
+    if let Some(path) = key_path {
+        output.sign(&KeyedHash::new(fs::read(path)?));
+    }
+
+    let serialised = match compress {
+        true => output.serialise_compressed(),
+        false => output.serialise(),
+    };
+
+    // Write the program to stdout with -o -, such as to pipe it into stack -
+    match output_path.as_str() {
+        "-" => io::stdout().lock().write_all(&serialised)?,
+        path => OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(path)?
+            .write_all(&serialised)?,
+    }
+
+    // A make rule for the program, which ninja also reads as a depfile, so it's assembled again
+    // when the source or anything it reads changes
+    if emit_deps {
+        let mut rule = format!("{}:", escape(&output_path));
+        for dependency in iter::once(PathBuf::from(&path)).chain(dependencies) {
+            rule.push(' ');
+            rule.push_str(&escape(&dependency.to_string_lossy()));
+        }
+        fs::write(format!("{output_path}.d"), rule + "\n")?;
+    }
+
+    Ok(())
+}
+
+/// Escapes the characters make treats specially in a rule
+fn escape(path: &str) -> String {
+    path.replace('$', "$$")
+        .replace(' ', "\\ ")
+        .replace('#', "\\#")
+}
+
+/// Assembles every target in the build manifest, stopping at the first which fails
+fn build(path: &Path) -> Result<()> {
+    let build = Build::load(path)?;
 
-    OpenOptions::new()
-        .create(true)
-        .write(true)
-        .truncate(true)
-        .open(OUTPUT_FILE)?
-        .write_all(&output.serialise())?;
+    for target in &build.targets {
+        if let Err(err) = target.build() {
+            eprintln!("failed to build {}", target.name);
+            let src = fs::read_to_string(&target.sources[0]).unwrap_or_default();
+            report::<()>(err, &src)?;
+        }
+
+        eprintln!("built {} -> {}", target.name, target.output.display());
+    }
 
     Ok(())
 }
+
+/// Renders the error against the source if it points into it, then exits
+fn report<T>(err: Box<dyn std::error::Error>, src: &str) -> Result<T> {
+    match err.downcast_ref::<Diagnostic>() {
+        Some(diagnostic) => {
+            let colour = io::stderr().is_terminal() && env::var_os("NO_COLOR").is_none();
+            eprint!("{}", diagnostic.render(src, colour));
+            process::exit(1);
+        }
+        None => Err(err)?,
+    }
+}
@@ -0,0 +1,60 @@
+use std::env;
+use std::fs::File;
+use std::io;
+use std::process;
+
+use stack::output::Output;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+fn main() -> Result<()> {
+    let mut args = env::args();
+    let program = args.next().unwrap();
+    let Some(path) = args.next() else {
+        eprintln!("usage: {} path/to/file|- [--bytes|--info]", program);
+        process::exit(1);
+    };
+
+    let mut bytes = false;
+    let mut info = false;
+
+    for option in args {
+        match option.as_str() {
+            "--bytes" => bytes = true,
+            "--info" => info = true,
+            _ => {
+                eprintln!("unknown option: {option}");
+                process::exit(1);
+            }
+        }
+    }
+
+    let output = match path.as_str() {
+        "-" => Output::deserialise(io::stdin().lock())?,
+        path => Output::deserialise(File::open(path)?)?,
+    };
+
+    if info {
+        let mut summary = String::new();
+        output.fmt_info(&mut summary)?;
+        print!("{summary}");
+        return Ok(());
+    }
+
+    if !bytes {
+        print!("{output}");
+        return Ok(());
+    }
+
+    // Show the encoding of each instruction rather than pseudo-instructions
+    let mut listing = String::new();
+    output.fmt_entry(&mut listing)?;
+    output.fmt_metadata(&mut listing)?;
+    listing.push('\n');
+    output.fmt_data(&mut listing)?;
+    listing.push('\n');
+    output.fmt_text_bytes(&mut listing)?;
+    print!("{listing}");
+
+    Ok(())
+}
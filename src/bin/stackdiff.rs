@@ -0,0 +1,32 @@
+use std::env;
+use std::fs::File;
+use std::process;
+
+use stack::diff::diff;
+use stack::output::Output;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+fn main() -> Result<()> {
+    let mut args = env::args();
+    let program = args.next().unwrap();
+    let (Some(a), Some(b)) = (args.next(), args.next()) else {
+        eprintln!("usage: {} path/to/a path/to/b", program);
+        process::exit(1);
+    };
+
+    let a = Output::deserialise(File::open(a)?)?;
+    let b = Output::deserialise(File::open(b)?)?;
+
+    let labels = diff(&a, &b)?;
+    for label in &labels {
+        print!("{label}");
+    }
+
+    // Exit with 1 when the programs differ, like diff
+    if !labels.is_empty() {
+        process::exit(1);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,71 @@
+use std::env;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::process;
+
+use stack::output::Output;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+fn main() -> Result<()> {
+    let mut args = env::args();
+    let program = args.next().unwrap();
+    let Some(path) = args.next() else {
+        eprintln!(
+            "usage: {} path/to/file|- [-o path/to/output|-] [--obfuscate] [--compress]",
+            program
+        );
+        process::exit(1);
+    };
+
+    // The program is stripped in place unless it's given somewhere else to go
+    let mut output_path = path.clone();
+    let mut obfuscate = false;
+    let mut compress = false;
+
+    while let Some(option) = args.next() {
+        match option.as_str() {
+            "-o" => {
+                let Some(path) = args.next() else {
+                    eprintln!("expected path with -o");
+                    process::exit(1);
+                };
+
+                output_path = path;
+            }
+            "--obfuscate" => obfuscate = true,
+            "--compress" => compress = true,
+            _ => {
+                eprintln!("unknown option: {option}");
+                process::exit(1);
+            }
+        }
+    }
+
+    let mut output = match path.as_str() {
+        "-" => Output::deserialise(io::stdin().lock())?,
+        path => Output::deserialise(File::open(path)?)?,
+    };
+
+    match obfuscate {
+        true => output.obfuscate_labels(),
+        false => output.strip(),
+    }
+
+    let serialised = match compress {
+        true => output.serialise_compressed(),
+        false => output.serialise(),
+    };
+
+    match output_path.as_str() {
+        "-" => io::stdout().lock().write_all(&serialised)?,
+        path => OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(path)?
+            .write_all(&serialised)?,
+    }
+
+    Ok(())
+}
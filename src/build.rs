@@ -0,0 +1,275 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::assembler::Assembler;
+use crate::manifest::{Manifest, Table, Value};
+use crate::output::Output;
+use crate::Result;
+
+/// A program to assemble from one or more sources
+#[derive(Debug, PartialEq)]
+pub struct Target {
+    pub name: String,
+    /// The first source is the program, which declares the entry. The rest are assembled before
+    /// it, so it can use their macros and labels.
+    pub sources: Vec<PathBuf>,
+    pub include_paths: Vec<PathBuf>,
+    pub defines: Vec<(String, String)>,
+    pub output: PathBuf,
+    pub prelude: bool,
+    pub optimise: bool,
+}
+
+impl Target {
+    pub fn assemble(&self) -> Result<Output> {
+        let (program, sources) = self.sources.split_first().unwrap();
+        let src = fs::read_to_string(program)?;
+
+        Assembler::new()
+            .with_source_name(program.display().to_string())
+            .with_include_paths(self.include_paths.clone())
+            .with_sources(sources.to_vec())
+            .with_defines(self.defines.clone())
+            .with_prelude(self.prelude)
+            .with_optimise(self.optimise)
+            .assemble(&src)
+    }
+
+    /// Assembles the target and writes it to its output, creating the directory if needed
+    pub fn build(&self) -> Result<()> {
+        let output = self.assemble()?;
+
+        if let Some(dir) = self.output.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(&self.output, output.serialise())?;
+
+        Ok(())
+    }
+}
+
+/// The targets read from a build manifest such as:
+///
+/// ```toml
+/// include = ["lib"]
+/// prelude = true
+///
+/// [[target]]
+/// name = "hello"
+/// sources = ["src/hello.s", "lib/print.s"]
+/// defines = ["GREETING=\"hi\"", "DEBUG"]
+/// output = "build/hello.out"
+/// ```
+///
+/// Paths are relative to the manifest. `include` and `defines` at the top level apply to every
+/// target as well as the target's own, and `prelude` and `optimise` at the top level are the
+/// defaults for each target. A define without a value expands to `1`. `name` defaults to the
+/// file stem of the first source and `output` to `<name>.out`.
+pub struct Build {
+    pub targets: Vec<Target>,
+}
+
+impl Build {
+    pub fn load(path: &Path) -> Result<Self> {
+        let src = fs::read_to_string(path)?;
+        let base = path.parent().unwrap_or(Path::new(""));
+
+        Self::parse(&src, base).map_err(|err| format!("{}: {err}", path.display()).into())
+    }
+
+    pub fn parse(src: &str, base: &Path) -> Result<Self> {
+        let manifest = Manifest::parse(src)?;
+        let root = &manifest.root;
+
+        let include_paths = paths(root, "include", base)?;
+        let defines = defines(root)?;
+        let prelude = boolean(root, "prelude")?.unwrap_or(false);
+        let optimise = boolean(root, "optimise")?.unwrap_or(false);
+
+        let mut targets = Vec::new();
+        for (i, table) in manifest
+            .arrays
+            .get("target")
+            .into_iter()
+            .flatten()
+            .enumerate()
+        {
+            let err = |err: Box<dyn std::error::Error>| format!("target {i}: {err}");
+
+            let sources = paths(table, "sources", base).map_err(err)?;
+            let Some(program) = sources.first() else {
+                Err(format!("target {i}: missing sources"))?
+            };
+
+            let name = match string(table, "name").map_err(err)? {
+                Some(name) => name,
+                None => program
+                    .file_stem()
+                    .map_or(String::from("a"), |stem| stem.to_string_lossy().into()),
+            };
+            let output = match string(table, "output").map_err(err)? {
+                Some(output) => base.join(output),
+                None => base.join(format!("{name}.out")),
+            };
+
+            let mut target_include_paths = include_paths.clone();
+            target_include_paths.extend(paths(table, "include", base).map_err(err)?);
+            let mut target_defines = defines.clone();
+            target_defines.extend(self::defines(table).map_err(err)?);
+
+            targets.push(Target {
+                name,
+                sources,
+                include_paths: target_include_paths,
+                defines: target_defines,
+                output,
+                prelude: boolean(table, "prelude").map_err(err)?.unwrap_or(prelude),
+                optimise: boolean(table, "optimise").map_err(err)?.unwrap_or(optimise),
+            });
+        }
+
+        Ok(Self { targets })
+    }
+}
+
+fn string(table: &Table, key: &str) -> Result<Option<String>> {
+    match table.get(key) {
+        Some(Value::String(s)) => Ok(Some(s.clone())),
+        Some(value) => Err(format!("invalid {key}: {value:?}"))?,
+        None => Ok(None),
+    }
+}
+
+fn boolean(table: &Table, key: &str) -> Result<Option<bool>> {
+    match table.get(key) {
+        Some(Value::Boolean(b)) => Ok(Some(*b)),
+        Some(value) => Err(format!("invalid {key}: {value:?}"))?,
+        None => Ok(None),
+    }
+}
+
+fn strings(table: &Table, key: &str) -> Result<Vec<String>> {
+    match table.get(key) {
+        Some(Value::Array(values)) => values
+            .iter()
+            .map(|value| match value {
+                Value::String(s) => Ok(s.clone()),
+                value => Err(format!("invalid {key}: {value:?}").into()),
+            })
+            .collect(),
+        Some(value) => Err(format!("invalid {key}: {value:?}"))?,
+        None => Ok(Vec::new()),
+    }
+}
+
+fn paths(table: &Table, key: &str, base: &Path) -> Result<Vec<PathBuf>> {
+    Ok(strings(table, key)?
+        .into_iter()
+        .map(|path| base.join(path))
+        .collect())
+}
+
+fn defines(table: &Table) -> Result<Vec<(String, String)>> {
+    Ok(strings(table, "defines")?
+        .into_iter()
+        .map(|define| match define.split_once('=') {
+            Some((name, value)) => (name.trim().to_string(), value.trim().to_string()),
+            None => (define.trim().to_string(), String::from("1")),
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::File;
+    use std::path::Path;
+
+    use super::Build;
+    use crate::interpreter::Interpreter;
+    use crate::output::Output;
+    use crate::Result;
+
+    #[test]
+    fn test_build() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("stack-build-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("lib"))?;
+
+        // A shared library which prints the byte in local 0, and two programs which use it
+        std::fs::write(
+            dir.join("lib/print.s"),
+            "
+#define STDOUT 1
+
+print:
+    push.d 1
+    alloc
+    store.d 2
+    load.d 2
+    push.d 0
+    load.b 0
+    astore.b
+    push @STDOUT
+    load.d 2
+    push.d 1
+    push 4
+    system
+    pop
+    ret
+",
+        )?;
+        std::fs::write(
+            dir.join("a.s"),
+            ".entry main\nmain:\n    push @CHAR\n    call print\n    ret\n",
+        )?;
+        std::fs::write(
+            dir.join("b.s"),
+            ".entry main\nmain:\n    push 98\n    call print\n    ret\n",
+        )?;
+
+        let manifest = r#"
+include = ["lib"]
+
+[[target]]
+sources = ["a.s", "lib/print.s"]
+defines = ["CHAR = 97"]
+output = "build/a.out"
+
+[[target]]
+name = "b"
+sources = ["b.s", "lib/print.s"]
+optimise = true
+"#;
+        let build = Build::parse(manifest, &dir)?;
+        assert_eq!(build.targets[0].name, "a");
+        assert_eq!(build.targets[0].include_paths, [dir.join("lib")]);
+        assert_eq!(build.targets[1].output, dir.join("b.out"));
+
+        for (target, want) in build.targets.iter().zip([b"a", b"b"]) {
+            target.build()?;
+
+            let output = Output::deserialise(File::open(&target.output)?)?;
+            let mut interpreter = Interpreter::new(&output, None, None)?.with_captured_output();
+            interpreter.run().into_result()?;
+            assert_eq!(interpreter.captured_stdout().unwrap(), want);
+        }
+
+        std::fs::remove_dir_all(&dir)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        for (src, want) in [
+            ("[[target]]\nname = \"a\"", "target 0: missing sources"),
+            ("prelude = 1", "invalid prelude: Integer(1)"),
+            (
+                "[[target]]\nsources = [1]",
+                "target 0: invalid sources: Integer(1)",
+            ),
+        ] {
+            let err = Build::parse(src, Path::new("")).err().unwrap();
+            assert_eq!(err.to_string(), want);
+        }
+    }
+}
@@ -0,0 +1,91 @@
+use std::io::{self, Write};
+
+use crate::SharedWriter;
+
+/// A writer which keeps a copy of everything written to it, passing it on to another writer if
+/// it was given one
+#[derive(Default)]
+pub struct CaptureWriter {
+    inner: Option<SharedWriter>,
+    captured: Vec<u8>,
+}
+
+impl CaptureWriter {
+    /// Keeps what is written without passing it on
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Passes what is written on to `inner` as well as keeping a copy
+    pub fn forward(inner: SharedWriter) -> Self {
+        let inner = Some(inner);
+        let captured = Vec::new();
+        Self { inner, captured }
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.captured
+    }
+
+    /// The captured output, with any invalid UTF-8 replaced
+    pub fn string(&self) -> String {
+        String::from_utf8_lossy(&self.captured).into_owned()
+    }
+
+    /// Returns the captured output, leaving the capture empty
+    pub fn take(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.captured)
+    }
+
+    pub fn clear(&mut self) {
+        self.captured.clear();
+    }
+}
+
+impl Write for CaptureWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = match &self.inner {
+            Some(inner) => inner.lock().unwrap().write(buf)?,
+            None => buf.len(),
+        };
+        self.captured.extend(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &self.inner {
+            Some(inner) => inner.lock().unwrap().flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+
+    use super::CaptureWriter;
+
+    #[test]
+    fn test_capture_writer() -> std::io::Result<()> {
+        let inner = Arc::new(Mutex::new(Vec::new()));
+        let mut capture = CaptureWriter::forward(inner.clone());
+        capture.write_all(b"abc")?;
+        capture.write_all(&[0xff])?;
+        assert_eq!(capture.bytes(), b"abc\xff");
+        assert_eq!(capture.string(), "abc\u{fffd}");
+        assert_eq!(*inner.lock().unwrap(), b"abc\xff");
+
+        assert_eq!(capture.take(), b"abc\xff");
+        assert!(capture.bytes().is_empty());
+
+        let mut capture = CaptureWriter::new();
+        capture.write_all(b"def")?;
+        capture.clear();
+        capture.write_all(b"g")?;
+        assert_eq!(capture.string(), "g");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,156 @@
+//! LZSS compression for the sections of a serialised program. Each group of up to eight items is
+//! preceded by a byte whose bits, from the lowest, mark which of them are matches. A literal is
+//! a single byte, and a match is a little endian pair of bytes holding the distance back to copy
+//! from, less one, in the low 12 bits and the length, less [`MIN_MATCH`], in the high 4.
+
+use std::collections::HashMap;
+
+use crate::Result;
+
+const WINDOW: usize = 1 << 12;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = MIN_MATCH + 15;
+/// How many earlier occurrences of a sequence are tried when looking for the longest match
+const MAX_PROBES: usize = 64;
+
+pub fn compress(input: &[u8]) -> Vec<u8> {
+    let mut output = Vec::new();
+    // The latest position each sequence of three bytes was seen at, and for each position the
+    // one before it with the same sequence
+    let mut head: HashMap<&[u8], usize> = HashMap::new();
+    let mut prev = vec![usize::MAX; input.len()];
+
+    let mut flags = 0;
+    let mut bit = 8;
+    let mut i = 0;
+    while i < input.len() {
+        if bit == 8 {
+            flags = output.len();
+            output.push(0);
+            bit = 0;
+        }
+
+        let (distance, len) = longest_match(input, i, &head, &prev);
+        let len = if len >= MIN_MATCH {
+            output[flags] |= 1 << bit;
+            let token = (distance - 1) as u16 | ((len - MIN_MATCH) as u16) << 12;
+            output.extend(token.to_le_bytes());
+            len
+        } else {
+            output.push(input[i]);
+            1
+        };
+
+        for (j, key) in input.windows(MIN_MATCH).enumerate().skip(i).take(len) {
+            prev[j] = head.insert(key, j).unwrap_or(usize::MAX);
+        }
+
+        i += len;
+        bit += 1;
+    }
+
+    output
+}
+
+/// Finds the distance back to and the length of the longest match for the bytes at `i`
+fn longest_match(
+    input: &[u8],
+    i: usize,
+    head: &HashMap<&[u8], usize>,
+    prev: &[usize],
+) -> (usize, usize) {
+    let Some(key) = input.get(i..i + MIN_MATCH) else {
+        return (0, 0);
+    };
+    let max = MAX_MATCH.min(input.len() - i);
+
+    let mut best = (0, 0);
+    let mut candidate = head.get(key).copied().unwrap_or(usize::MAX);
+    for _ in 0..MAX_PROBES {
+        if candidate == usize::MAX || i - candidate > WINDOW {
+            break;
+        }
+
+        let len = (0..max)
+            .take_while(|&n| input[candidate + n] == input[i + n])
+            .count();
+        if len > best.1 {
+            best = (i - candidate, len);
+        }
+        if len == max {
+            break;
+        }
+
+        candidate = prev[candidate];
+    }
+
+    best
+}
+
+pub fn decompress(input: &[u8]) -> Result<Vec<u8>> {
+    let mut output = Vec::new();
+    let mut bytes = input.iter().copied();
+
+    while let Some(flags) = bytes.next() {
+        for bit in 0..8 {
+            if flags & (1 << bit) == 0 {
+                match bytes.next() {
+                    Some(byte) => output.push(byte),
+                    // The flags for the last group may be followed by fewer than eight items
+                    None => break,
+                }
+                continue;
+            }
+
+            let (Some(low), Some(high)) = (bytes.next(), bytes.next()) else {
+                Err("compressed section ends within a match")?
+            };
+            let token = u16::from_le_bytes([low, high]);
+            let distance = (token & 0xFFF) as usize + 1;
+            let len = (token >> 12) as usize + MIN_MATCH;
+            if distance > output.len() {
+                Err("compressed section refers back past its start")?
+            }
+
+            // The match may overlap the bytes it produces, so they are copied one at a time
+            for _ in 0..len {
+                output.push(output[output.len() - distance]);
+            }
+        }
+    }
+
+    Ok(output)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{compress, decompress};
+
+    #[test]
+    fn test_round_trip() {
+        let repeated = b"abcabcabcabcabcabcabcabc".repeat(100);
+        let mixed: Vec<u8> = (0..5000u32).map(|n| (n * 7919 % 251) as u8).collect();
+
+        for input in [
+            &b""[..],
+            b"a",
+            b"ab",
+            b"aaaaaaaaaaaaaaaaaaaaaaaa",
+            &repeated,
+            &mixed,
+        ] {
+            let compressed = compress(input);
+            assert_eq!(decompress(&compressed).unwrap(), input);
+        }
+
+        assert!(compress(&repeated).len() < repeated.len() / 5);
+    }
+
+    #[test]
+    fn test_invalid() {
+        // A match before any bytes have been written
+        assert!(decompress(&[0b1, 0, 0]).is_err());
+        // A match missing its second byte
+        assert!(decompress(&[0b10, b'a', 0]).is_err());
+    }
+}
@@ -1,11 +1,92 @@
 use std::collections::{HashMap, HashSet};
-use std::io::Write;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
 
-use crate::frame::Frame;
-use crate::interpreter::Interpreter;
+use crate::interpreter::{FrameView, Interpreter};
+use crate::limits::MAX_LOCAL_SLOTS;
 use crate::output::Output;
-use crate::stack::OperandStack;
-use crate::{Number, Result};
+use crate::program::{Bytecode, Program};
+use crate::{Fields, Number, Result, StackValue};
+
+/// A match from [`Debugger::find`]
+#[derive(Debug, PartialEq)]
+pub struct Found {
+    /// The offset into the program for data, or the heap address the program would use
+    pub address: u64,
+    /// The label and offset for data, or the allocation and offset for the heap
+    pub location: String,
+    /// The bytes around the match
+    pub context: Vec<u8>,
+    /// Where the match starts within the context
+    pub start: usize,
+}
+
+/// A return from [`Debugger::balance`] which left a different number of slots on the stack than
+/// the return instruction takes
+#[derive(Debug, PartialEq)]
+pub struct Imbalance {
+    /// The label of the function which returned
+    pub function: String,
+    /// The position of the return instruction
+    pub position: u64,
+    /// The slots the return instruction takes: none for `ret`, one for `ret.w`, two for `ret.d`
+    /// and the operand of `ret.n`
+    pub expected: usize,
+    /// The slots on the stack when it returned
+    pub depth: usize,
+    /// How many times it returned with this depth
+    pub count: usize,
+}
+
+/// A difference from [`Debugger::diff`] between the previous stop and now
+#[derive(Debug, PartialEq)]
+pub enum Change {
+    /// An allocation made since the previous stop
+    Allocated { address: u64, len: usize },
+    /// An allocation freed since the previous stop
+    Freed { address: u64, len: usize },
+    /// A run of bytes which changed within an allocation
+    Heap {
+        address: u64,
+        offset: usize,
+        before: Vec<u8>,
+        after: Vec<u8>,
+    },
+    /// A local slot of the current frame which changed
+    Local { slot: u64, before: i32, after: i32 },
+    /// The current frame isn't the one from the previous stop, so the locals weren't compared.
+    /// Holds the entry of each frame.
+    Frame { before: u64, after: u64 },
+}
+
+/// The heap and the current frame's locals at a stop, for [`Debugger::diff`]
+struct Snapshot {
+    heap: Vec<(u64, Vec<u8>)>,
+    /// The number of frames and the entry of the current one
+    frame: (usize, u64),
+    locals: Vec<u8>,
+}
+
+/// Passes the program's stdout through to the process's, keeping what has been written since it
+/// was last searched while there's a pattern given to [`Debugger::watch_stdout`]
+struct Tee {
+    inner: Box<dyn Write + Send>,
+    written: Option<Vec<u8>>,
+}
+
+impl Write for Tee {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        if let Some(written) = &mut self.written {
+            written.extend(&buf[..n]);
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
 
 #[derive(Debug, Default)]
 enum State {
@@ -23,19 +104,33 @@ pub struct Debugger {
     text: Vec<String>,
     /// Maps a position from the program to a line in [`Debugger::text`]
     lines: HashMap<u64, usize>,
+    /// The start of each instruction, in order
+    positions: Vec<u64>,
+    /// The state at the previous stop
+    previous: Option<Snapshot>,
+    stdout: Arc<Mutex<Tee>>,
+    /// Stop continuing once this is written to stdout
+    watch: Option<Vec<u8>>,
 }
 
 impl Debugger {
     pub fn new(output: Output) -> Result<Self> {
-        // Use the system stdout and stderr
-        let (stdout, stderr) = (None, None);
-        let interpreter = Interpreter::new(&output, stdout, stderr)?;
+        // Use the system stdout and stderr, keeping what's written to stdout to watch it
+        let stdout = Arc::new(Mutex::new(Tee {
+            inner: Box::new(io::stdout()),
+            written: None,
+        }));
+        let stderr = None;
+        let interpreter = Interpreter::new(&output, Some(stdout.clone()), stderr)?;
         let state = State::default();
         let breakpoints = HashSet::new();
 
         let mut text = String::new();
         let lines = output.fmt_text(&mut text)?;
         let text = text.lines().map(String::from).collect();
+        let positions = output.instruction_positions()?;
+        let previous = None;
+        let watch = None;
 
         Ok(Self {
             state,
@@ -44,31 +139,51 @@ impl Debugger {
             breakpoints,
             text,
             lines,
+            positions,
+            previous,
+            stdout,
+            watch,
         })
     }
 
     pub fn fmt_line(&self, w: &mut impl Write, position: u64) -> Result<()> {
+        let frame = self.interpreter.current_frame();
+
+        writeln!(
+            w,
+            "\x1b[94mFrame #{} `{}`\x1b[0m at {}",
+            self.interpreter.frames().len() - 1,
+            frame.name(),
+            self.symbolise(position)
+        )?;
+
+        if let Some(origin) = self.output.origin(position) {
+            if let Some(location) = &origin.location {
+                writeln!(w, "\x1b[90mat {location}\x1b[0m")?;
+            }
+            for expansion in &origin.expansions {
+                writeln!(w, "\x1b[90m{expansion}\x1b[0m")?;
+            }
+        }
+
+        self.fmt_listing(w, position)
+    }
+
+    /// Writes the disassembly from `position`, pointing at the instruction there
+    pub fn fmt_listing(&self, w: &mut impl Write, position: u64) -> Result<()> {
         const LOOK_FORWARD: usize = 8;
         const POINTER: &str = "->";
         const WIDTH: usize = 2;
 
-        let start = self.lines[&position];
+        let Some(&start) = self.lines.get(&position) else {
+            Err("invalid position, must be at the start of an instruction")?
+        };
 
         let mut end = start + LOOK_FORWARD;
         if end >= self.text.len() {
             end = self.text.len()
         }
 
-        let frames = self.interpreter.frames();
-        let entry = frames.last().unwrap().entry;
-
-        writeln!(
-            w,
-            "\x1b[94mFrame #{} `{}`\x1b[0m",
-            frames.len() - 1,
-            self.output.labels()[&entry]
-        )?;
-
         for i in start..end {
             if i == start {
                 writeln!(w, "\x1b[93m{POINTER:>WIDTH$}{}\x1b[0m", self.text[i])?;
@@ -85,15 +200,15 @@ impl Debugger {
         const TAB_SPACES: usize = 2;
 
         let mut tab = 0;
-        for (i, frame) in self.interpreter.frames().iter().enumerate() {
+        for (i, frame) in self.interpreter.frames().enumerate() {
             writeln!(
                 w,
                 "{:tab$}\x1b[94mFrame #{} `{}`\x1b[0m: Entry: {} Return: {}",
                 "",
                 i,
-                self.output.labels()[&frame.entry],
-                frame.entry,
-                frame.ret
+                frame.name(),
+                frame.entry(),
+                self.symbolise(frame.ret())
             )?;
             tab += TAB_SPACES;
         }
@@ -101,6 +216,233 @@ impl Debugger {
         Ok(())
     }
 
+    /// Searches the data section and the live heap allocations for `pattern`
+    pub fn find(&self, pattern: &[u8]) -> Vec<Found> {
+        const CONTEXT: usize = 8;
+
+        // The start of each match, and of the context before it
+        fn matches(memory: &[u8], pattern: &[u8]) -> Vec<(usize, usize)> {
+            if pattern.is_empty() {
+                return Vec::new();
+            }
+
+            memory
+                .windows(pattern.len())
+                .enumerate()
+                .filter(|(_, window)| *window == pattern)
+                .map(|(i, _)| (i.saturating_sub(CONTEXT), i))
+                .collect()
+        }
+
+        let mut found = Vec::new();
+
+        let data = self.output.data();
+        for (from, i) in matches(data, pattern) {
+            let address = (size_of::<u64>() + i) as u64;
+            let to = (i + pattern.len() + CONTEXT).min(data.len());
+            found.push(Found {
+                address,
+                location: self.symbolise(address),
+                context: data[from..to].to_vec(),
+                start: i - from,
+            });
+        }
+
+        let mut allocations = self.interpreter.heap().live();
+        allocations.sort_by_key(|(address, _)| *address);
+        for (base, memory) in allocations {
+            for (from, i) in matches(&memory, pattern) {
+                let to = (i + pattern.len() + CONTEXT).min(memory.len());
+                found.push(Found {
+                    address: base + i as u64,
+                    location: format!("{base:#x}+{i}"),
+                    context: memory[from..to].to_vec(),
+                    start: i - from,
+                });
+            }
+        }
+
+        found
+    }
+
+    pub fn fmt_find(&self, w: &mut impl Write, pattern: &[u8]) -> Result<()> {
+        let found = self.find(pattern);
+        if found.is_empty() {
+            writeln!(w, "not found")?;
+        }
+
+        for Found {
+            location,
+            context,
+            start,
+            ..
+        } in found
+        {
+            write!(w, "\x1b[94m{location}\x1b[0m:")?;
+            for (i, byte) in context.iter().enumerate() {
+                match i >= start && i < start + pattern.len() {
+                    true => write!(w, " \x1b[93m{byte:02x}\x1b[0m")?,
+                    false => write!(w, " {byte:02x}")?,
+                }
+            }
+
+            let text: String = context
+                .iter()
+                .map(|&byte| match byte.is_ascii_graphic() || byte == b' ' {
+                    true => byte as char,
+                    false => '.',
+                })
+                .collect();
+            writeln!(w, "  |{text}|")?;
+        }
+
+        Ok(())
+    }
+
+    /// Describes `value` if it points into a live heap allocation or the data section, such as
+    /// `heap#3, 64B` or `record+2`. Allocations are numbered in address order.
+    pub fn describe(&self, value: u64) -> Option<String> {
+        if value == 0 {
+            return None;
+        }
+
+        let mut allocations = self.interpreter.heap().live();
+        allocations.sort_by_key(|(address, _)| *address);
+        for (i, (base, memory)) in allocations.iter().enumerate() {
+            let len = memory.len() as u64;
+            match value.checked_sub(*base) {
+                Some(0) => return Some(format!("heap#{i}, {len}B")),
+                Some(offset) if offset < len => {
+                    return Some(format!("heap#{i}+{offset}, {len}B"));
+                }
+                _ => {}
+            }
+        }
+
+        // Data is referred to by its position, such as with `get`, or by the address from
+        // `dataptr`
+        let data = size_of::<u64>() as u64..(size_of::<u64>() + self.output.data().len()) as u64;
+        [value, self.interpreter.program_position(value)]
+            .into_iter()
+            .find(|position| data.contains(position))
+            .map(|position| self.symbolise(position))
+            .filter(|location| location.parse::<u64>().is_err())
+    }
+
+    /// Writes the operand stack, then each value in it which points into the heap or data. A
+    /// pointer takes two slots, so each slot is read as the start of a double word first.
+    pub fn fmt_stack(&self, w: &mut impl Write) -> Result<()> {
+        let frame = self.frame();
+        writeln!(w, "{frame}")?;
+
+        let stack = frame.stack();
+        for (slot, offset) in (0..stack.len()).step_by(4).enumerate() {
+            let dword = stack
+                .get(offset..offset + 8)
+                .map(<u64 as Number>::from_le_bytes);
+            let word = <i32 as Number>::from_le_bytes(&stack[offset..offset + 4]) as u32;
+            let described = dword
+                .and_then(|dword| Some((dword, self.describe(dword)?)))
+                .or_else(|| Some((word as u64, self.describe(word as u64)?)));
+            if let Some((value, description)) = described {
+                writeln!(w, "{slot:>4}: {value} ({description})")?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes a local, followed by what it points to if it's a pointer
+    pub fn fmt_variable<N: Number + Into<i64>>(&self, w: &mut impl Write, i: u64) -> Result<()> {
+        let value = self.variable::<N>(i)?;
+        let text = value.to_string();
+        match self.describe(value.into() as u64) {
+            Some(description) => writeln!(w, "{text} ({description})")?,
+            None => writeln!(w, "{text}")?,
+        }
+
+        Ok(())
+    }
+
+    /// Draws the operand stack of the current frame, top first, and its locals as tables. Each
+    /// value is shown with the slots and byte offset it starts at, its width, and its value in
+    /// hex and decimal. The stack is read with the widths in `widths` from the bottom and as words
+    /// after them, like `stack-json`, so a value read with the wrong width stands out.
+    pub fn fmt_viz(&self, w: &mut impl Write, widths: Fields) -> Result<()> {
+        let frame = self.frame();
+        let name = frame.name();
+
+        let rows: Vec<_> = frame
+            .values_at(widths)
+            .into_iter()
+            .rev()
+            .map(|(offset, value)| match value {
+                StackValue::Byte(value) => {
+                    self.viz_row(offset, 1, value as u8 as u64, value.into())
+                }
+                StackValue::Word(value) => {
+                    self.viz_row(offset, 4, value as u32 as u64, value.into())
+                }
+                StackValue::DoubleWord(value) => self.viz_row(offset, 8, value as u64, value),
+            })
+            .collect();
+        writeln!(
+            w,
+            "operand stack of {name}: {} slots, {} bytes, top first",
+            frame.depth(),
+            frame.stack().len()
+        )?;
+        fmt_table(w, &rows)?;
+
+        // The slots the function uses if they're known, otherwise up to the last one written
+        let locals = frame.locals();
+        let slots = match self.output.local_slots().get(&frame.entry()) {
+            Some(&slots) => usize::from(slots).min(MAX_LOCAL_SLOTS),
+            None => locals
+                .iter()
+                .rposition(|&byte| byte != 0)
+                .map_or(0, |i| i / 4 + 1),
+        };
+        let rows = (0..slots)
+            .map(|slot| {
+                let value = frame.local::<i32>(slot as u64)?;
+                Ok(self.viz_row(slot * 4, 4, value as u32 as u64, value.into()))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        writeln!(w, "locals of {name}: {slots} slots")?;
+        fmt_table(w, &rows)?;
+
+        Ok(())
+    }
+
+    /// The cells of a value in [`Debugger::fmt_viz`], which is `width` bytes from `offset`
+    fn viz_row(&self, offset: usize, width: usize, bits: u64, value: i64) -> [String; 5] {
+        let (first, last) = (offset / 4, (offset + width - 1) / 4);
+        let slots = match first == last {
+            true => first.to_string(),
+            false => format!("{first}-{last}"),
+        };
+        let width_name = match width {
+            1 => "byte",
+            4 => "word",
+            _ => "dword",
+        };
+        let hex = format!("{bits:#0digits$x}", digits = 2 + width * 2);
+        // Bytes are too small to hold a pointer
+        let decimal = match (width > 1).then(|| self.describe(bits)).flatten() {
+            Some(description) => format!("{value} ({description})"),
+            None => value.to_string(),
+        };
+
+        [
+            slots,
+            offset.to_string(),
+            width_name.to_string(),
+            hex,
+            decimal,
+        ]
+    }
+
     pub fn fmt_breakpoints(&self, w: &mut impl Write) -> Result<()> {
         self.breakpoints
             .iter()
@@ -115,6 +457,10 @@ impl Debugger {
         }
 
         self.interpreter.reset();
+        self.previous = None;
+        if let Some(written) = &mut self.stdout.lock().unwrap().written {
+            written.clear();
+        }
         let position = self.interpreter.position();
         self.state = State::Running;
 
@@ -126,6 +472,7 @@ impl Debugger {
             Err("no program currently running")?
         }
 
+        self.previous = Some(self.snapshot());
         let Some(position) = self.interpreter.step()? else {
             self.state = State::Off;
             Err("program finished running")?
@@ -139,10 +486,13 @@ impl Debugger {
             Err("no program currently running")?
         }
 
-        let finished = if !self.breakpoints.is_empty() {
+        self.previous = Some(self.snapshot());
+        let finished = if self.watch.is_some() {
+            self.run_watching()?
+        } else if !self.breakpoints.is_empty() {
             self.interpreter.run_until(&self.breakpoints)?
         } else {
-            self.interpreter.run()?;
+            self.interpreter.run().into_result()?;
             true
         };
 
@@ -153,16 +503,331 @@ impl Debugger {
         Ok(self.interpreter.position())
     }
 
+    /// Makes [`Debugger::r#continue`] stop once the program writes `pattern` to stdout, at the
+    /// instruction after the write. The pattern may be split across several writes.
+    pub fn watch_stdout(&mut self, pattern: Vec<u8>) -> Result<()> {
+        if pattern.is_empty() {
+            Err("cannot watch for an empty pattern")?
+        }
+
+        self.stdout.lock().unwrap().written = Some(Vec::new());
+        self.watch = Some(pattern);
+
+        Ok(())
+    }
+
+    pub fn unwatch_stdout(&mut self) {
+        self.stdout.lock().unwrap().written = None;
+        self.watch = None;
+    }
+
+    /// Steps until a breakpoint or the watched pattern is written, returning true if the program
+    /// finished first
+    fn run_watching(&mut self) -> Result<bool> {
+        loop {
+            let Some(position) = self.interpreter.step()? else {
+                return Ok(true);
+            };
+
+            if self.watched() || self.breakpoints.contains(&position) {
+                return Ok(false);
+            }
+        }
+    }
+
+    /// Whether the watched pattern has been written since the last check
+    fn watched(&mut self) -> bool {
+        let Some(pattern) = &self.watch else {
+            return false;
+        };
+
+        let mut stdout = self.stdout.lock().unwrap();
+        let Some(written) = &mut stdout.written else {
+            return false;
+        };
+        let found = written
+            .windows(pattern.len())
+            .any(|window| window == pattern);
+
+        // Keep enough of the end to match the pattern if the rest of it is written later
+        let keep = if found { 0 } else { pattern.len() - 1 };
+        written.drain(..written.len().saturating_sub(keep));
+
+        found
+    }
+
+    /// Restarts and runs the program to completion up to `runs` times, ignoring breakpoints.
+    /// Stops at the first run which errors or exits with a non-zero status, keeping its state so
+    /// it can be inspected, and returns which run it was along with the failure.
+    pub fn stress(&mut self, runs: usize) -> Result<Option<(usize, String)>> {
+        self.previous = None;
+        for run in 1..=runs {
+            self.interpreter.reset();
+            self.state = State::Off;
+
+            let failure = match self.interpreter.run().into_result() {
+                Err(err) => err.to_string(),
+                Ok(_) => match self.interpreter.exit_code() {
+                    Some(code) if code != 0 => format!("exit {code}"),
+                    _ => continue,
+                },
+            };
+
+            return Ok(Some((run, failure)));
+        }
+
+        Ok(None)
+    }
+
+    /// Restarts and runs the program to completion, ignoring breakpoints, and records the stack
+    /// depth each time a function other than main returns. A function starts with an empty stack,
+    /// since its arguments are moved into its locals, so it should return with only the value it
+    /// returns left on the stack. Any other depth is reported.
+    pub fn balance(&mut self) -> Result<Vec<Imbalance>> {
+        let program: Vec<u8> = (&self.output).into();
+        let mut program = Program::new(program.as_slice());
+
+        self.interpreter.reset();
+        self.state = State::Off;
+        self.previous = None;
+
+        let mut imbalances: Vec<Imbalance> = Vec::new();
+        loop {
+            let position = self.interpreter.position();
+            let frames = self.interpreter.frames().len();
+
+            program.set_position(position);
+            let expected = match program.next_op()? {
+                Bytecode::Ret => Some(0),
+                Bytecode::RetB | Bytecode::RetW => Some(1),
+                Bytecode::RetD => Some(2),
+                Bytecode::RetN => Some(program.next::<u64>()? as usize),
+                _ => None,
+            };
+
+            if let (Some(expected), true) = (expected, frames > 1) {
+                let frame = self.interpreter.current_frame();
+                let depth = frame.depth();
+                if depth != expected {
+                    match imbalances
+                        .iter_mut()
+                        .find(|i| i.position == position && i.depth == depth)
+                    {
+                        Some(imbalance) => imbalance.count += 1,
+                        None => imbalances.push(Imbalance {
+                            function: frame.name(),
+                            position,
+                            expected,
+                            depth,
+                            count: 1,
+                        }),
+                    }
+                }
+            }
+
+            if self.interpreter.step()?.is_none() {
+                break;
+            }
+        }
+
+        Ok(imbalances)
+    }
+
+    pub fn fmt_balance(&mut self, w: &mut impl Write) -> Result<()> {
+        let imbalances = self.balance()?;
+        if imbalances.is_empty() {
+            writeln!(w, "every return was balanced")?;
+        }
+
+        for imbalance in imbalances {
+            writeln!(
+                w,
+                "\x1b[94m`{}`\x1b[0m returned at {} with {} slots on the stack, expected {} ({} times)",
+                imbalance.function,
+                self.symbolise(imbalance.position),
+                imbalance.depth,
+                imbalance.expected,
+                imbalance.count
+            )?;
+        }
+
+        Ok(())
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        let frame = self.interpreter.current_frame();
+
+        Snapshot {
+            heap: self.interpreter.heap().live(),
+            frame: (self.interpreter.frames().len(), frame.entry()),
+            locals: frame.locals().to_vec(),
+        }
+    }
+
+    /// Compares the heap and the current frame's locals at the previous stop with now, so it's
+    /// clear what the code run since then modified
+    pub fn diff(&self) -> Result<Vec<Change>> {
+        let Some(previous) = &self.previous else {
+            Err("no previous stop to compare with")?
+        };
+
+        let mut changes = Vec::new();
+
+        let mut before = previous.heap.clone();
+        let mut after = self.interpreter.heap().live();
+        before.sort_by_key(|(address, _)| *address);
+        after.sort_by_key(|(address, _)| *address);
+
+        // An address handed out again with a different size is a new allocation
+        for (address, memory) in &before {
+            match after.iter().find(|(have, _)| have == address) {
+                Some((_, now)) if now.len() == memory.len() => {}
+                _ => changes.push(Change::Freed {
+                    address: *address,
+                    len: memory.len(),
+                }),
+            }
+        }
+
+        for (address, now) in &after {
+            let Some((_, memory)) = before
+                .iter()
+                .find(|(have, memory)| have == address && memory.len() == now.len())
+            else {
+                changes.push(Change::Allocated {
+                    address: *address,
+                    len: now.len(),
+                });
+                continue;
+            };
+
+            // Group the changed bytes into runs
+            let mut i = 0;
+            while i < now.len() {
+                if memory[i] == now[i] {
+                    i += 1;
+                    continue;
+                }
+
+                let start = i;
+                while i < now.len() && memory[i] != now[i] {
+                    i += 1;
+                }
+                changes.push(Change::Heap {
+                    address: *address,
+                    offset: start,
+                    before: memory[start..i].to_vec(),
+                    after: now[start..i].to_vec(),
+                });
+            }
+        }
+
+        let frame = self.interpreter.current_frame();
+        if previous.frame != (self.interpreter.frames().len(), frame.entry()) {
+            changes.push(Change::Frame {
+                before: previous.frame.1,
+                after: frame.entry(),
+            });
+            return Ok(changes);
+        }
+
+        // The locals are only allocated as far as they've been written, so either may be shorter
+        let len = previous.locals.len().max(frame.locals().len());
+        let mut before = previous.locals.clone();
+        let mut after = frame.locals().to_vec();
+        before.resize(len, 0);
+        after.resize(len, 0);
+        for (slot, (before, after)) in std::iter::zip(
+            before.chunks(size_of::<i32>()),
+            after.chunks(size_of::<i32>()),
+        )
+        .enumerate()
+        .filter(|(_, (before, after))| before != after)
+        {
+            changes.push(Change::Local {
+                slot: slot as u64,
+                before: <i32 as Number>::from_le_bytes(before),
+                after: <i32 as Number>::from_le_bytes(after),
+            });
+        }
+
+        Ok(changes)
+    }
+
+    pub fn fmt_diff(&self, w: &mut impl Write) -> Result<()> {
+        let changes = self.diff()?;
+        if changes.is_empty() {
+            writeln!(w, "nothing changed")?;
+        }
+
+        fn hex(bytes: &[u8]) -> String {
+            bytes
+                .iter()
+                .map(|byte| format!("{byte:02x}"))
+                .collect::<Vec<_>>()
+                .join(" ")
+        }
+
+        for change in changes {
+            match change {
+                Change::Allocated { address, len } => {
+                    writeln!(w, "\x1b[94m{address:#x}\x1b[0m: allocated {len} bytes")?
+                }
+                Change::Freed { address, len } => {
+                    writeln!(w, "\x1b[94m{address:#x}\x1b[0m: freed {len} bytes")?
+                }
+                Change::Heap {
+                    address,
+                    offset,
+                    before,
+                    after,
+                } => writeln!(
+                    w,
+                    "\x1b[94m{address:#x}+{offset}\x1b[0m: {} -> \x1b[93m{}\x1b[0m",
+                    hex(&before),
+                    hex(&after)
+                )?,
+                Change::Local {
+                    slot,
+                    before,
+                    after,
+                } => writeln!(
+                    w,
+                    "\x1b[94mlocal {slot}\x1b[0m: {before} -> \x1b[93m{after}\x1b[0m"
+                )?,
+                Change::Frame { before, after } => writeln!(
+                    w,
+                    "frame changed from `{}` to `{}`, locals not compared",
+                    self.symbolise(before),
+                    self.symbolise(after)
+                )?,
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn set_breakpoint(&mut self, position: u64) -> Result<()> {
-        match self.lines.get(&position) {
-            Some(_) => self.breakpoints.insert(position),
-            None => Err("invalid breakpoint, position must be at the start of an instruction")?,
+        match self.positions.binary_search(&position) {
+            Ok(_) => self.breakpoints.insert(position),
+            Err(_) => Err("invalid breakpoint, position must be at the start of an instruction")?,
         };
 
         Ok(())
     }
 
-    pub fn set_label_breakpoint(&mut self, label: &str) -> Result<()> {
+    /// Parses a position written as an offset, a label, or a label and an offset from it such as
+    /// `add+9`
+    pub fn resolve(&self, position: &str) -> Result<u64> {
+        if let Ok(position) = position.parse::<u64>() {
+            return Ok(position);
+        }
+
+        let (label, offset) = match position.split_once('+') {
+            Some((label, offset)) => (label, offset.parse::<u64>()?),
+            None => (position, 0),
+        };
+
         let Some(position) = self
             .output
             .labels()
@@ -170,33 +835,422 @@ impl Debugger {
             .find(|(_, have)| label == have.as_str())
             .map(|(&position, _)| position)
         else {
-            Err("invalid label, could not find position")?
+            if self.output.labels().is_empty() {
+                Err("invalid label, the program has no labels so give a position instead")?
+            }
+            Err(format!("invalid label, could not find position: {label}"))?
         };
 
-        self.set_breakpoint(position)
+        Ok(position + offset)
+    }
+
+    /// Describes `position` by the closest label at or before it, such as `add+9`
+    pub fn symbolise(&self, position: u64) -> String {
+        self.output.symbolise(position)
     }
 
     pub fn delete_breakpoint(&mut self, position: u64) {
-        self.lines.remove(&position);
+        self.breakpoints.remove(&position);
+    }
+
+    /// The position of the current instruction
+    pub fn position(&self) -> u64 {
+        self.interpreter.position()
     }
 
     pub fn output(&self) -> &Output {
         &self.output
     }
 
-    pub fn stack(&self) -> &OperandStack {
-        &self.current_frame().opstack
+    /// The current frame, which displays as its operand stack
+    pub fn frame(&self) -> FrameView<'_> {
+        self.interpreter.current_frame()
     }
 
-    pub fn variable<N: Number>(&self, i: u64) -> N {
-        self.current_frame().locals.read(i)
+    pub fn variable<N: Number>(&self, i: u64) -> Result<N> {
+        self.frame().local(i)
     }
 
     pub fn peek<N: Number>(&self) -> Option<N> {
-        self.current_frame().opstack.peek()
+        self.frame().peek()
+    }
+}
+
+/// Writes the rows from [`Debugger::viz_row`] as a table, with a header and a border
+fn fmt_table(w: &mut impl Write, rows: &[[String; 5]]) -> Result<()> {
+    const HEADER: [&str; 5] = ["slot", "offset", "width", "hex", "decimal"];
+
+    let mut widths = HEADER.map(str::len);
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    let border: Vec<String> = widths.iter().map(|&width| "-".repeat(width + 2)).collect();
+    let border = format!("+{}+", border.join("+"));
+    writeln!(w, "{border}")?;
+    fmt_row(w, &HEADER.map(String::from), widths)?;
+    writeln!(w, "{border}")?;
+    for row in rows {
+        fmt_row(w, row, widths)?;
+    }
+    writeln!(w, "{border}")?;
+
+    Ok(())
+}
+
+fn fmt_row(w: &mut impl Write, row: &[String; 5], widths: [usize; 5]) -> Result<()> {
+    // Numbers line up on the right, other than the decimal which may have a description
+    let cells: Vec<String> = row
+        .iter()
+        .zip(widths)
+        .enumerate()
+        .map(|(i, (cell, width))| match i {
+            4 => format!(" {cell:<width$} "),
+            _ => format!(" {cell:>width$} "),
+        })
+        .collect();
+    writeln!(w, "|{}|", cells.join("|"))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::assembler::Assembler;
+    use crate::Result;
+
+    use super::{Change, Debugger, Found};
+
+    #[test]
+    fn test_watch_stdout() -> Result<()> {
+        let src = "
+.entry main
+
+.data a .string \"one\\n\"
+.data b .string \"two\\n\"
+
+main:
+    push 1
+    dataptr a
+    push.d a.len
+    push 4
+    system
+    pop
+    push 1
+    dataptr b
+    push.d b.len
+    push 4
+    system
+    pop
+    ret
+";
+        let output = Assembler::new().assemble(src)?;
+        let mut debugger = Debugger::new(output)?;
+        debugger.stdout.lock().unwrap().inner = Box::new(std::io::sink());
+        assert!(debugger.watch_stdout(Vec::new()).is_err());
+
+        // The pattern is split across the two writes, so it stops after the second
+        debugger.watch_stdout(b"e\ntw".to_vec())?;
+        debugger.run()?;
+        assert_eq!(debugger.r#continue()?, debugger.resolve("main+59")?);
+
+        // It isn't written again, so the program runs to the end
+        debugger.r#continue()?;
+        assert!(debugger.step().is_err());
+
+        debugger.unwatch_stdout();
+        debugger.run()?;
+        debugger.r#continue()?;
+        assert!(debugger.step().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolve_symbolise() -> Result<()> {
+        let src = "
+.entry main
+
+main:
+    push 1
+    push 2
+    call add
+    ret
+
+add:
+    load 0
+    load 1
+    add
+    ret.w
+";
+        let output = Assembler::new().assemble(src)?;
+        let debugger = Debugger::new(output)?;
+
+        // main is at 8, each push is 5 bytes and call is 9, so add is at 8 + 5 + 5 + 9 + 1
+        assert_eq!(debugger.resolve("main")?, 8);
+        assert_eq!(debugger.resolve("main+10")?, 18);
+        assert_eq!(debugger.resolve("28")?, 28);
+        assert_eq!(debugger.resolve("add+9")?, 37);
+        assert!(debugger.resolve("sub+1").is_err());
+
+        assert_eq!(debugger.symbolise(8), "main");
+        assert_eq!(debugger.symbolise(18), "main+10");
+        assert_eq!(debugger.symbolise(37), "add+9");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_stress() -> Result<()> {
+        let output = Assembler::new().assemble(".entry main\nmain:\n    push 0\n    ret.w\n")?;
+        let mut debugger = Debugger::new(output)?;
+        assert_eq!(debugger.stress(3)?, None);
+
+        // The failing run is kept so it can be inspected
+        let output = Assembler::new().assemble(".entry main\nmain:\n    push 3\n    ret.w\n")?;
+        let mut debugger = Debugger::new(output)?;
+        assert_eq!(debugger.stress(3)?, Some((1, String::from("exit 3"))));
+        assert_eq!(debugger.peek::<i32>(), Some(3));
+        assert_eq!(debugger.symbolise(debugger.position()), "main+5");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_balance() -> Result<()> {
+        let src = "
+.entry main
+
+main:
+    push 1
+    call leaky
+    push 2
+    call leaky
+    call balanced
+    ret
+
+; Leaves its argument on the stack as well as the value it returns
+leaky:
+    load 0
+    push 1
+    ret.w
+
+balanced:
+    push 7
+    ret.w
+";
+        let output = Assembler::new().assemble(src)?;
+        let mut debugger = Debugger::new(output)?;
+
+        let imbalances = debugger.balance()?;
+        assert_eq!(imbalances.len(), 1);
+        let imbalance = &imbalances[0];
+        assert_eq!(imbalance.function, "leaky");
+        assert_eq!(debugger.symbolise(imbalance.position), "leaky+14");
+        assert_eq!(
+            (imbalance.expected, imbalance.depth, imbalance.count),
+            (1, 2, 2)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_find() -> Result<()> {
+        let src = "
+.entry main
+
+.data message .string \"Hello, World!\"
+
+main:
+    ret
+";
+        let output = Assembler::new().assemble(src)?;
+        let debugger = Debugger::new(output)?;
+
+        let found = debugger.find(b"World");
+        assert_eq!(
+            found,
+            [Found {
+                address: 15,
+                location: String::from("message+7"),
+                context: b"Hello, World!".to_vec(),
+                start: 7,
+            }]
+        );
+        assert!(debugger.find(b"world").is_empty());
+        assert!(debugger.find(b"").is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_describe() -> Result<()> {
+        let src = "
+.entry main
+
+.data record .dword 0
+
+main:
+    push.d 64
+    alloc
+    store.d 0
+    dataptr record
+    store.d 2
+    load.d 0
+    push.d 4
+    add.d
+    push.d record
+    ret
+";
+        let output = Assembler::new().assemble(src)?;
+        let mut debugger = Debugger::new(output)?;
+        debugger.set_breakpoint(debugger.resolve("main+65")?)?;
+        debugger.run()?;
+        debugger.r#continue()?;
+
+        let address = debugger.variable::<i64>(0)?;
+        let mut variable = Vec::new();
+        debugger.fmt_variable::<i64>(&mut variable, 0)?;
+        debugger.fmt_variable::<i64>(&mut variable, 2)?;
+        let dataptr = debugger.variable::<i64>(2)?;
+        assert_eq!(
+            String::from_utf8(variable)?,
+            format!("{address} (heap#0, 64B)\n{dataptr} (record)\n")
+        );
+
+        let mut stack = Vec::new();
+        debugger.fmt_stack(&mut stack)?;
+        let stack = String::from_utf8(stack)?;
+        let annotations: Vec<&str> = stack.lines().skip(2).collect();
+        assert_eq!(
+            annotations,
+            [
+                format!("   0: {} (heap#0+4, 64B)", address + 4),
+                String::from("   2: 8 (record)")
+            ]
+        );
+
+        assert_eq!(debugger.describe(0), None);
+        assert_eq!(debugger.describe(12).as_deref(), Some("record+4"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_viz() -> Result<()> {
+        let src = "
+.entry main
+
+main:
+    push 3
+    push -2
+    call f
+    ret
+f:
+    push.b -1
+    push.d 4294967296
+    load 1
+    ret
+";
+        let output = Assembler::new().assemble(src)?;
+        let mut debugger = Debugger::new(output)?;
+        debugger.set_breakpoint(debugger.resolve("f+20")?)?;
+        debugger.run()?;
+        debugger.r#continue()?;
+
+        let mut viz = Vec::new();
+        debugger.fmt_viz(&mut viz, "bd".parse()?)?;
+        let want = "\
+operand stack of f: 4 slots, 16 bytes, top first
++------+--------+-------+--------------------+------------+
+| slot | offset | width |                hex | decimal    |
++------+--------+-------+--------------------+------------+
+|    3 |     12 |  word |         0xfffffffe | -2         |
+|  1-2 |      4 | dword | 0x0000000100000000 | 4294967296 |
+|    0 |      0 |  byte |               0xff | -1         |
++------+--------+-------+--------------------+------------+
+locals of f: 2 slots
++------+--------+-------+------------+---------+
+| slot | offset | width |        hex | decimal |
++------+--------+-------+------------+---------+
+|    0 |      0 |  word | 0x00000003 | 3       |
+|    1 |      4 |  word | 0xfffffffe | -2      |
++------+--------+-------+------------+---------+
+";
+        assert_eq!(String::from_utf8(viz)?, want);
+
+        Ok(())
     }
 
-    fn current_frame(&self) -> &Frame {
-        &self.interpreter.frames().last().unwrap()
+    #[test]
+    fn test_diff() -> Result<()> {
+        let src = "
+.entry main
+
+main:
+    push.d 8
+    alloc
+    store.d 0
+    load.d 0
+    push.d 2
+    push 7
+    astore
+    push 1
+    store 2
+    load.d 0
+    free
+    ret
+";
+        let output = Assembler::new().assemble(src)?;
+        let mut debugger = Debugger::new(output)?;
+        debugger.set_breakpoint(debugger.resolve("main+10")?)?;
+        debugger.set_breakpoint(debugger.resolve("main+66")?)?;
+
+        debugger.run()?;
+        assert!(debugger.diff().is_err());
+
+        // Up to the store of the pointer
+        debugger.r#continue()?;
+        let changes = debugger.diff()?;
+        let [Change::Allocated { address, len: 8 }] = changes[..] else {
+            panic!("unexpected changes: {changes:?}");
+        };
+
+        // Up to the free, writing into the allocation and the locals
+        debugger.r#continue()?;
+        // A half of the pointer which is zero is unchanged
+        let mut want = vec![
+            Change::Heap {
+                address,
+                offset: 2,
+                before: vec![0],
+                after: vec![7],
+            },
+            Change::Local {
+                slot: 0,
+                before: 0,
+                after: address as i32,
+            },
+            Change::Local {
+                slot: 1,
+                before: 0,
+                after: (address >> 32) as i32,
+            },
+            Change::Local {
+                slot: 2,
+                before: 0,
+                after: 1,
+            },
+        ];
+        want.retain(|change| !matches!(change, Change::Local { after: 0, .. }));
+        assert_eq!(debugger.diff()?, want);
+
+        debugger.step()?;
+        assert_eq!(debugger.diff()?, [Change::Freed { address, len: 8 }]);
+
+        Ok(())
     }
 }
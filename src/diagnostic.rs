@@ -0,0 +1,230 @@
+use std::fmt::Write;
+
+const RED: &str = "\x1b[1;31m";
+const BLUE: &str = "\x1b[1;34m";
+const CYAN: &str = "\x1b[1;36m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+/// Suggests the closest candidate to a misspelt `word`, if any is close enough to be a typo
+pub fn did_you_mean<'a>(
+    word: &str,
+    candidates: impl IntoIterator<Item = &'a str>,
+) -> Option<String> {
+    let max = (word.chars().count() / 3).max(1);
+
+    candidates
+        .into_iter()
+        .filter(|candidate| *candidate != word)
+        .map(|candidate| (distance(word, candidate), candidate))
+        .filter(|(distance, _)| *distance <= max)
+        .min()
+        .map(|(_, candidate)| format!("did you mean `{candidate}`?"))
+}
+
+/// The edit distance between `a` and `b`, counting a swap of adjacent characters as one edit
+fn distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+
+    // d[i][j] is the distance between the first i characters of a and the first j of b
+    let mut d = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in d.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, cell) in d[0].iter_mut().enumerate() {
+        *cell = j;
+    }
+
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            d[i][j] = (d[i - 1][j] + 1)
+                .min(d[i][j - 1] + 1)
+                .min(d[i - 1][j - 1] + cost);
+
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                d[i][j] = d[i][j].min(d[i - 2][j - 2] + 1);
+            }
+        }
+    }
+
+    d[a.len()][b.len()]
+}
+
+/// The one-based line and column of `offset` within `src`
+pub fn line_column(src: &str, offset: usize) -> (usize, usize) {
+    let offset = offset.min(src.len());
+    let line_start = src[..offset].rfind('\n').map_or(0, |i| i + 1);
+    let line = src[..offset].matches('\n').count() + 1;
+    let column = src[line_start..offset].chars().count() + 1;
+
+    (line, column)
+}
+
+/// A byte range within the source
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+/// A named piece of source, such as the program or a file it includes
+#[derive(Debug)]
+pub struct Source {
+    pub name: String,
+    pub text: String,
+}
+
+impl Source {
+    /// Describes where `span` starts as `name:line:column`
+    pub fn location(&self, span: Span) -> String {
+        let (line, column) = line_column(&self.text, span.start);
+        format!("{}:{line}:{column}", self.name)
+    }
+}
+
+/// An assembler error which points at the source it was raised for
+#[derive(Debug)]
+pub struct Diagnostic {
+    pub message: String,
+    pub span: Span,
+    pub help: Option<String>,
+    /// Further context, such as the macro expansions the error was raised within
+    pub notes: Vec<String>,
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.message.fmt(f)?;
+        if let Some(help) = &self.help {
+            write!(f, " ({help})")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for Diagnostic {}
+
+impl Diagnostic {
+    pub fn new(message: String, span: Span) -> Self {
+        let help = None;
+        let notes = Vec::new();
+        Self {
+            message,
+            span,
+            help,
+            notes,
+        }
+    }
+
+    pub fn with_help(mut self, help: String) -> Self {
+        self.help = Some(help);
+        self
+    }
+
+    /// The one-based line and column of the start of the span
+    pub fn location(&self, src: &str) -> (usize, usize) {
+        line_column(src, self.span.start)
+    }
+
+    /// Renders the message with the offending source line and a caret under the span, in colour
+    /// if `colour` is set
+    pub fn render(&self, src: &str, colour: bool) -> String {
+        let paint = |code: &'static str| if colour { code } else { "" };
+        let (red, blue, cyan, bold, reset) = (
+            paint(RED),
+            paint(BLUE),
+            paint(CYAN),
+            paint(BOLD),
+            paint(RESET),
+        );
+
+        let (line, column) = self.location(src);
+        let text = src.lines().nth(line - 1).unwrap_or_default();
+        let gutter = " ".repeat(line.to_string().len());
+
+        // Underline to the end of the span, or the end of the line if the span covers several
+        let start = self.span.start.min(src.len());
+        let end = self.span.end.clamp(start, src.len());
+        let width = src[start..end]
+            .lines()
+            .next()
+            .map_or(0, |s| s.chars().count())
+            .max(1);
+        let indent = " ".repeat(column - 1);
+
+        let mut out = String::new();
+        writeln!(out, "{red}error{reset}{bold}: {}{reset}", self.message).unwrap();
+        writeln!(out, "{gutter}{blue}-->{reset} {line}:{column}").unwrap();
+        writeln!(out, "{gutter} {blue}|{reset}").unwrap();
+        writeln!(out, "{blue}{line} |{reset} {text}").unwrap();
+        writeln!(
+            out,
+            "{gutter} {blue}|{reset} {indent}{red}{}{reset}",
+            "^".repeat(width)
+        )
+        .unwrap();
+        if let Some(help) = &self.help {
+            writeln!(out, "{gutter} {blue}={reset} {cyan}help{reset}: {help}").unwrap();
+        }
+        for note in &self.notes {
+            writeln!(out, "{gutter} {blue}={reset} {bold}note{reset}: {note}").unwrap();
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{did_you_mean, distance, Diagnostic, Span};
+
+    #[test]
+    fn test_did_you_mean() {
+        assert_eq!(distance("kitten", "sitting"), 3);
+        assert_eq!(distance("", "abc"), 3);
+        assert_eq!(distance("jmp.lw", "jmp.le"), 1);
+        assert_eq!(distance("mian", "main"), 1);
+
+        let candidates = ["jmp.le", "jmp.lt", "loop", "load"];
+        assert_eq!(
+            did_you_mean("jmp.lw", candidates).as_deref(),
+            Some("did you mean `jmp.le`?")
+        );
+        assert_eq!(
+            did_you_mean("lop", candidates).as_deref(),
+            Some("did you mean `loop`?")
+        );
+        assert_eq!(did_you_mean("xyz", candidates), None);
+    }
+
+    #[test]
+    fn test_render() {
+        let src = "main:\n    push 1\n    jmp lop\n";
+        let start = src.find("lop").unwrap();
+        let span = Span {
+            start,
+            end: start + 3,
+        };
+        let diagnostic = Diagnostic::new("could not resolve label: lop".into(), span)
+            .with_help("did you mean `loop`?".into());
+
+        assert_eq!(diagnostic.location(src), (3, 9));
+        assert_eq!(
+            diagnostic.render(src, false),
+            "\
+error: could not resolve label: lop
+ --> 3:9
+  |
+3 |     jmp lop
+  |         ^^^
+  = help: did you mean `loop`?
+"
+        );
+        assert!(diagnostic
+            .render(src, true)
+            .contains("\x1b[1;31m^^^\x1b[0m"));
+    }
+}
@@ -0,0 +1,224 @@
+//! Comparing two assembled programs instruction by instruction, such as the outputs of two builds.
+//! The instructions are grouped by the label before them and aligned within each label, so a
+//! change to one function doesn't show up as changes to every function after it. Jump, call and
+//! `dataptr` targets are compared by the label they point at rather than their position.
+
+use std::collections::HashMap;
+
+use crate::output::Output;
+use crate::{Instr, Result};
+
+/// How one instruction differs between the two programs. Each holds the position of the
+/// instruction and how it's written, with its target symbolised.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    /// Only in the second program
+    Added(u64, String),
+    /// Only in the first program
+    Removed(u64, String),
+    /// In the same place in both programs but different, as it was and then as it is
+    Changed((u64, String), (u64, String)),
+}
+
+/// The changes to the instructions following a label
+#[derive(Debug, Clone, PartialEq)]
+pub struct LabelDiff {
+    pub label: String,
+    pub changes: Vec<Change>,
+}
+
+impl std::fmt::Display for LabelDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{}:", self.label)?;
+        for change in &self.changes {
+            match change {
+                Change::Added(position, instr) => writeln!(f, "+ {position:>5}: {instr}")?,
+                Change::Removed(position, instr) => writeln!(f, "- {position:>5}: {instr}")?,
+                Change::Changed((before, was), (after, is)) => {
+                    writeln!(f, "- {before:>5}: {was}")?;
+                    writeln!(f, "+ {after:>5}: {is}")?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The labels with changed instructions, in the order they appear in `a` followed by those only
+/// in `b`. Instructions before the first label are grouped under `entry`.
+pub fn diff(a: &Output, b: &Output) -> Result<Vec<LabelDiff>> {
+    let (before, mut after) = (group(a)?, group(b)?);
+
+    let mut labels = Vec::new();
+    for (label, was) in before {
+        let is = after
+            .iter()
+            .position(|(have, _)| *have == label)
+            .map(|i| after.remove(i).1)
+            .unwrap_or_default();
+        labels.push((label, was, is));
+    }
+    labels.extend(after.into_iter().map(|(label, is)| (label, Vec::new(), is)));
+
+    Ok(labels
+        .into_iter()
+        .map(|(label, was, is)| {
+            let changes = align(&was, &is);
+            LabelDiff { label, changes }
+        })
+        .filter(|diff| !diff.changes.is_empty())
+        .collect())
+}
+
+type Listing = Vec<(u64, String)>;
+
+/// Splits the text into the instructions following each label, in order
+fn group(output: &Output) -> Result<Vec<(String, Listing)>> {
+    let labels: HashMap<u64, &str> = output
+        .labels()
+        .iter()
+        .map(|(&position, label)| (position, label.as_str()))
+        .collect();
+
+    let mut groups: Vec<(String, Listing)> = Vec::new();
+    for (position, instr) in output.instructions()? {
+        if let Some(label) = labels.get(&position) {
+            groups.push((label.to_string(), Vec::new()));
+        }
+        if groups.is_empty() {
+            groups.push((String::from("entry"), Vec::new()));
+        }
+
+        let instr = match instr {
+            Instr::Jump(op, target) => format!("{op} {}", output.symbolise(target)),
+            Instr::Call(target) => format!("{} {}", instr.op(), output.symbolise(target)),
+            Instr::DataPtr(target) | Instr::PanicMsg(target) => {
+                format!("{} {}", instr.op(), output.symbolise(target))
+            }
+            instr => instr.to_string(),
+        };
+        groups.last_mut().unwrap().1.push((position, instr));
+    }
+
+    Ok(groups)
+}
+
+/// Finds the changes between two listings from their longest common subsequence. A run of
+/// removals next to a run of additions is paired up into changes.
+fn align(was: &[(u64, String)], is: &[(u64, String)]) -> Vec<Change> {
+    // The length of the longest common subsequence of was[i..] and is[j..]
+    let mut lcs = vec![vec![0; is.len() + 1]; was.len() + 1];
+    for i in (0..was.len()).rev() {
+        for j in (0..is.len()).rev() {
+            lcs[i][j] = match was[i].1 == is[j].1 {
+                true => lcs[i + 1][j + 1] + 1,
+                false => lcs[i + 1][j].max(lcs[i][j + 1]),
+            };
+        }
+    }
+
+    let mut changes = Vec::new();
+    let (mut removed, mut added) = (Vec::new(), Vec::new());
+    let (mut i, mut j) = (0, 0);
+    while i < was.len() || j < is.len() {
+        if i < was.len() && j < is.len() && was[i].1 == is[j].1 {
+            pair(&mut changes, &mut removed, &mut added);
+            i += 1;
+            j += 1;
+        } else if j == is.len() || (i < was.len() && lcs[i + 1][j] >= lcs[i][j + 1]) {
+            removed.push(was[i].clone());
+            i += 1;
+        } else {
+            added.push(is[j].clone());
+            j += 1;
+        }
+    }
+    pair(&mut changes, &mut removed, &mut added);
+
+    changes
+}
+
+fn pair(changes: &mut Vec<Change>, removed: &mut Listing, added: &mut Listing) {
+    let paired = removed.len().min(added.len());
+    let mut removed = removed.drain(..);
+    let mut added = added.drain(..);
+    for _ in 0..paired {
+        changes.push(Change::Changed(
+            removed.next().unwrap(),
+            added.next().unwrap(),
+        ));
+    }
+    changes.extend(removed.map(|(position, instr)| Change::Removed(position, instr)));
+    changes.extend(added.map(|(position, instr)| Change::Added(position, instr)));
+}
+
+#[cfg(test)]
+mod test {
+    use crate::assembler::Assembler;
+    use crate::Result;
+
+    use super::{diff, Change, LabelDiff};
+
+    #[test]
+    fn test_diff() -> Result<()> {
+        let a = Assembler::new().assemble(
+            "
+.entry main
+
+main:
+    push 1
+    call double
+    ret
+double:
+    load 0
+    push 2
+    mul
+    ret.w
+unused:
+    ret
+",
+        )?;
+        // The new instruction in main moves double along, which its call follows
+        let b = Assembler::new().assemble(
+            "
+.entry main
+
+main:
+    push 1
+    push 2
+    call double
+    ret
+double:
+    load 0
+    dup
+    add
+    ret.w
+",
+        )?;
+
+        assert!(diff(&a, &a)?.is_empty());
+        assert_eq!(
+            diff(&a, &b)?,
+            [
+                LabelDiff {
+                    label: String::from("main"),
+                    changes: vec![Change::Added(13, String::from("push 2"))],
+                },
+                LabelDiff {
+                    label: String::from("double"),
+                    changes: vec![
+                        Change::Changed((32, String::from("push 2")), (37, String::from("dup"))),
+                        Change::Changed((37, String::from("mul")), (38, String::from("add"))),
+                    ],
+                },
+                LabelDiff {
+                    label: String::from("unused"),
+                    changes: vec![Change::Removed(39, String::from("ret"))],
+                },
+            ]
+        );
+
+        Ok(())
+    }
+}
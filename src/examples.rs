@@ -0,0 +1,86 @@
+//! Example programs embedded in the crate, so `stack examples` can list and run them without a
+//! copy of the repository. Each is a file in the examples directory which doesn't include any
+//! others, as there's nothing on disk for an `#include` to find.
+
+use crate::assembler::Assembler;
+use crate::output::Output;
+use crate::Result;
+
+/// An example program and what it shows
+#[derive(Debug)]
+pub struct Example {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub src: &'static str,
+}
+
+pub const EXAMPLES: &[Example] = &[
+    Example {
+        name: "fib",
+        description: "returns the 8th Fibonacci number, calculated recursively",
+        src: include_str!("../examples/fib.b"),
+    },
+    Example {
+        name: "echo",
+        description: "copies stdin to stdout a byte at a time",
+        src: include_str!("../examples/echo.b"),
+    },
+    Example {
+        name: "heapsort",
+        description: "sorts an array of words from the data in place and prints them",
+        src: include_str!("../examples/heapsort.b"),
+    },
+    Example {
+        name: "brainfuck",
+        description: "a brainfuck interpreter running a program which prints \"Hello World!\"",
+        src: include_str!("../examples/brainfuck.b"),
+    },
+];
+
+impl Example {
+    pub fn assemble(&self) -> Result<Output> {
+        Assembler::new()
+            .with_source_name(format!("examples/{}.b", self.name))
+            .assemble(self.src)
+    }
+}
+
+/// The example called `name`
+pub fn find(name: &str) -> Option<&'static Example> {
+    EXAMPLES.iter().find(|example| example.name == name)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::Result;
+
+    use super::{find, EXAMPLES};
+
+    #[test]
+    fn test_examples() -> Result<()> {
+        // Each example assembles without its neighbours on disk
+        for example in EXAMPLES {
+            example.assemble()?;
+        }
+
+        assert!(find("missing").is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "arrays")]
+    fn test_heapsort() -> Result<()> {
+        use crate::interpreter::Interpreter;
+
+        let output = find("heapsort").unwrap().assemble()?;
+        let mut interpreter = Interpreter::new(&output, None, None)?.with_captured_output();
+        interpreter.run().into_result()?;
+        assert_eq!(
+            interpreter.captured_stdout().unwrap(),
+            b"4 5 8 9 15 26 31 35 93 97\n"
+        );
+
+        Ok(())
+    }
+}
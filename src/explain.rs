@@ -0,0 +1,389 @@
+//! Explaining each instruction in plain words as it runs, for using the interpreter to teach how
+//! a stack machine works. The table in [`effect`] gives the values each instruction pops and
+//! pushes, which are read from the stack around the step to show a worked example, such as
+//! `pop 2 words, add them, push the result (3 + 4 = 7)`.
+
+use crate::program::{Bytecode, Instr};
+use crate::stack::OperandStack;
+
+/// The type of a value on the operand stack
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Byte,
+    Word,
+    DoubleWord,
+    Float,
+    DoubleFloat,
+}
+
+impl Kind {
+    fn name(self) -> &'static str {
+        match self {
+            Kind::Byte => "byte",
+            Kind::Word => "word",
+            Kind::DoubleWord => "double word",
+            Kind::Float => "float",
+            Kind::DoubleFloat => "double float",
+        }
+    }
+
+    fn pop(self, stack: &mut OperandStack) -> Value {
+        match self {
+            Kind::Byte => Value::Int(stack.pop::<i8>().into()),
+            Kind::Word => Value::Int(stack.pop::<i32>().into()),
+            Kind::DoubleWord => Value::Int(stack.pop::<i64>()),
+            Kind::Float => Value::Float(f32::from_bits(stack.pop::<i32>() as u32)),
+            Kind::DoubleFloat => Value::DoubleFloat(f64::from_bits(stack.pop::<i64>() as u64)),
+        }
+    }
+}
+
+/// A value read from the operand stack
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Value {
+    Int(i64),
+    Float(f32),
+    DoubleFloat(f64),
+}
+
+impl std::fmt::Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::Int(value) => value.fmt(f),
+            Value::Float(value) => write!(f, "{value:?}"),
+            Value::DoubleFloat(value) => write!(f, "{value:?}"),
+        }
+    }
+}
+
+/// How the worked example of an operation is written
+#[derive(Clone, Copy)]
+enum Notation {
+    /// Between the values, such as `3 + 4`
+    Infix(&'static str),
+    /// Like a function, such as `min(3, 4)`
+    Call(&'static str),
+}
+
+/// An instruction which pops its operands and pushes a result computed from them
+#[derive(Clone, Copy)]
+struct Operation {
+    /// What it does with the values it popped, such as `add them`
+    action: &'static str,
+    notation: Notation,
+}
+
+/// The values `op` pops, from the bottom, and the value it pushes. Operations also say how to
+/// describe them. Instructions which reach outside of the stack, such as to the heap, aren't
+/// included, since what they pop isn't worth showing on its own.
+fn effect(op: Bytecode) -> Option<(Vec<Kind>, Option<Kind>, Option<Operation>)> {
+    use Kind::*;
+    use Notation::*;
+
+    let operation = |action, notation| Some(Operation { action, notation });
+    let binary =
+        |kind, action, notation| (vec![kind, kind], Some(kind), operation(action, notation));
+    let unary = |from, to, action, notation| (vec![from], Some(to), operation(action, notation));
+
+    let effect = match op {
+        Bytecode::Add | Bytecode::AddB | Bytecode::AddD | Bytecode::AddF | Bytecode::AddDF => {
+            binary(kind(op), "add them", Infix("+"))
+        }
+        Bytecode::Sub | Bytecode::SubB | Bytecode::SubD | Bytecode::SubF | Bytecode::SubDF => {
+            binary(kind(op), "subtract the top from the one below", Infix("-"))
+        }
+        Bytecode::Mul | Bytecode::MulB | Bytecode::MulD | Bytecode::MulF | Bytecode::MulDF => {
+            binary(kind(op), "multiply them", Infix("*"))
+        }
+        Bytecode::Div | Bytecode::DivB | Bytecode::DivD | Bytecode::DivF | Bytecode::DivDF => {
+            binary(kind(op), "divide the one below by the top", Infix("/"))
+        }
+        Bytecode::Rem | Bytecode::RemB | Bytecode::RemD => binary(
+            kind(op),
+            "take the remainder of dividing the one below by the top",
+            Infix("%"),
+        ),
+        Bytecode::And | Bytecode::AndB | Bytecode::AndD => {
+            binary(kind(op), "and their bits", Infix("&"))
+        }
+        Bytecode::Or | Bytecode::OrB | Bytecode::OrD => {
+            binary(kind(op), "or their bits", Infix("|"))
+        }
+        Bytecode::Xor | Bytecode::XorB | Bytecode::XorD => {
+            binary(kind(op), "xor their bits", Infix("^"))
+        }
+        Bytecode::Min | Bytecode::MinD => binary(kind(op), "keep the smaller", Call("min")),
+        Bytecode::Max | Bytecode::MaxD => binary(kind(op), "keep the larger", Call("max")),
+        Bytecode::Cmp | Bytecode::CmpB | Bytecode::CmpD | Bytecode::CmpF | Bytecode::CmpDF => (
+            vec![kind(op), kind(op)],
+            Some(Word),
+            operation("compare them as -1, 0 or 1", Call("cmp")),
+        ),
+        Bytecode::Shl | Bytecode::ShlD => (
+            vec![kind(op), Word],
+            Some(kind(op)),
+            operation("shift the one below left by the top", Infix("<<")),
+        ),
+        Bytecode::Shr | Bytecode::ShrD => (
+            vec![kind(op), Word],
+            Some(kind(op)),
+            operation(
+                "shift the one below right by the top with its sign",
+                Infix(">>"),
+            ),
+        ),
+        Bytecode::ShrU | Bytecode::ShrDU => (
+            vec![kind(op), Word],
+            Some(kind(op)),
+            operation(
+                "shift the one below right by the top with zeroes",
+                Infix(">>>"),
+            ),
+        ),
+        Bytecode::Neg | Bytecode::NegB | Bytecode::NegD => {
+            unary(kind(op), kind(op), "negate it", Call("neg"))
+        }
+        Bytecode::Not | Bytecode::NotB | Bytecode::NotD => {
+            unary(kind(op), kind(op), "flip its bits", Call("not"))
+        }
+        Bytecode::Abs | Bytecode::AbsD => {
+            unary(kind(op), kind(op), "take its absolute value", Call("abs"))
+        }
+        Bytecode::B2W => unary(Byte, Word, "widen it to a word", Call("b2w")),
+        Bytecode::W2D => unary(Word, DoubleWord, "widen it to a double word", Call("w2d")),
+        Bytecode::D2W => unary(DoubleWord, Word, "narrow it to a word", Call("d2w")),
+        Bytecode::W2B => unary(Word, Byte, "narrow it to a byte", Call("w2b")),
+        Bytecode::Load
+        | Bytecode::LoadB
+        | Bytecode::LoadD
+        | Bytecode::Dup
+        | Bytecode::DupB
+        | Bytecode::DupD
+        | Bytecode::Over
+        | Bytecode::OverB
+        | Bytecode::OverD => (vec![], Some(kind(op)), None),
+        Bytecode::Store
+        | Bytecode::StoreB
+        | Bytecode::StoreD
+        | Bytecode::Pop
+        | Bytecode::PopB
+        | Bytecode::PopD => (vec![kind(op)], None, None),
+        Bytecode::Halt | Bytecode::RetB | Bytecode::RetW | Bytecode::RetD => {
+            (vec![kind(op)], None, None)
+        }
+        _ => return None,
+    };
+
+    Some(effect)
+}
+
+/// The type of the values `op` works on, from the width in its mnemonic
+fn kind(op: Bytecode) -> Kind {
+    let mnemonic = op.to_string();
+    let float = mnemonic.ends_with(".f");
+    let double = mnemonic.contains(".d");
+    match (float, double) {
+        (true, true) => Kind::DoubleFloat,
+        (true, false) => Kind::Float,
+        (false, true) => Kind::DoubleWord,
+        _ if mnemonic.ends_with(".b") => Kind::Byte,
+        _ => Kind::Word,
+    }
+}
+
+/// Reads the values `op` is about to pop from the top of `stack`, from the bottom
+pub(crate) fn popped(op: Bytecode, stack: &OperandStack) -> Vec<Value> {
+    let Some((pops, ..)) = effect(op) else {
+        return Vec::new();
+    };
+
+    let mut stack = stack.clone();
+    let mut values: Vec<Value> = pops.iter().rev().map(|kind| kind.pop(&mut stack)).collect();
+    values.reverse();
+    values
+}
+
+/// Reads the value `op` pushed to the top of `stack`
+pub(crate) fn pushed(op: Bytecode, stack: &OperandStack) -> Option<Value> {
+    let (_, push, _) = effect(op)?;
+    Some(push?.pop(&mut stack.clone()))
+}
+
+/// Describes `instr` in a sentence. `popped` and `pushed` are the values it popped and pushed,
+/// which are left out if they're empty, such as when the instruction trapped. Jump and call
+/// targets are written with `locate`, such as by their label.
+pub(crate) fn explain(
+    instr: Instr,
+    popped: &[Value],
+    pushed: Option<Value>,
+    locate: impl Fn(u64) -> String,
+) -> String {
+    let op = instr.op();
+    let name = kind(op).name();
+    // The values shown after a description, such as ` (7)`
+    let shown = |values: &[Value]| match values {
+        [] => String::new(),
+        values => {
+            let values: Vec<String> = values.iter().map(Value::to_string).collect();
+            format!(" ({})", values.join(", "))
+        }
+    };
+    let pushed = pushed.as_slice();
+
+    if let Some((pops, _, Some(Operation { action, notation }))) = effect(op) {
+        let example = match (popped, pushed) {
+            ([a, b], [result]) => match notation {
+                Notation::Infix(operator) => format!(" ({a} {operator} {b} = {result})"),
+                Notation::Call(name) => format!(" ({name}({a}, {b}) = {result})"),
+            },
+            ([a], [result]) => match notation {
+                Notation::Infix(operator) => format!(" ({operator}{a} = {result})"),
+                Notation::Call(name) => format!(" ({name}({a}) = {result})"),
+            },
+            _ => String::new(),
+        };
+        return format!("pop {}, {action}, push the result{example}", count(&pops));
+    }
+
+    match instr {
+        Instr::Push(n) => format!("push the word {n}"),
+        Instr::PushB(n) => format!("push the byte {n}"),
+        Instr::PushD(n) => format!("push the double word {n}"),
+        Instr::PushF(bits) => format!("push the float {:?}", f32::from_bits(bits)),
+        Instr::PushDF(bits) => format!("push the double float {:?}", f64::from_bits(bits)),
+        Instr::Local(Bytecode::Store | Bytecode::StoreB | Bytecode::StoreD, slot) => {
+            format!("pop a {name}{} and store it in local {slot}", shown(popped))
+        }
+        Instr::Local(_, slot) => format!("push the {name} in local {slot}{}", shown(pushed)),
+        Instr::Scratch(Bytecode::SStore, slot) => {
+            format!("pop a double word and store it in scratch slot {slot}")
+        }
+        Instr::Scratch(_, slot) => format!("push the double word in scratch slot {slot}"),
+        Instr::Jump(Bytecode::Jmp, target) => format!("jump to {}", locate(target)),
+        Instr::Jump(op, target) => {
+            let condition = match op {
+                Bytecode::JmpEq => "equal",
+                Bytecode::JmpNe => "not equal",
+                Bytecode::JmpLt => "less",
+                Bytecode::JmpLe => "less or equal",
+                Bytecode::JmpGt => "greater",
+                _ => "greater or equal",
+            };
+            let target = locate(target);
+            format!("pop the result of a comparison and jump to {target} if it was {condition}")
+        }
+        Instr::Call(target) => {
+            let target = locate(target);
+            format!("call {target}, moving the stack into its locals")
+        }
+        Instr::DataPtr(offset) => format!("push the address of the data at {offset}"),
+        Instr::PanicMsg(_) => String::from("stop the program with an error and its message"),
+        Instr::RetN(count) => format!("return the top {count} slots to the caller"),
+        Instr::Fields(Bytecode::Pack, fields) => {
+            format!("pop a pointer and the fields {fields}, and write them as a record")
+        }
+        Instr::Fields(_, fields) => {
+            format!("pop a pointer and push the fields {fields} of the record it points to")
+        }
+        Instr::IncLocal(slot, n) => format!("add {n} to the word in local {slot}"),
+        Instr::Op(op) => match op {
+            Bytecode::Dup | Bytecode::DupB | Bytecode::DupD => {
+                format!("copy the {name} on top{}", shown(pushed))
+            }
+            Bytecode::Over | Bytecode::OverB | Bytecode::OverD => {
+                format!("copy the {name} below the top{}", shown(pushed))
+            }
+            Bytecode::Swap | Bytecode::SwapB | Bytecode::SwapD => {
+                format!("swap the top two {name}s")
+            }
+            Bytecode::Pop | Bytecode::PopB | Bytecode::PopD => {
+                format!("pop a {name}{} and discard it", shown(popped))
+            }
+            Bytecode::Ret => String::from("return to the caller"),
+            Bytecode::RetB | Bytecode::RetW | Bytecode::RetD => {
+                format!("return a {name}{} to the caller", shown(popped))
+            }
+            Bytecode::Halt => format!("pop a word{} and exit with it", shown(popped)),
+            Bytecode::Panic => String::from("stop the program with an error"),
+            Bytecode::Nop => String::from("do nothing"),
+            Bytecode::System => String::from("pop a system call number and make the call"),
+            Bytecode::CallPtr => String::from("pop a function's position and call it"),
+            Bytecode::JmpPtr => String::from("pop a position and jump to it"),
+            Bytecode::Alloc => {
+                String::from("pop a size, allocate that many bytes and push a pointer to them")
+            }
+            Bytecode::Free => String::from("pop a pointer and free the allocation"),
+            Bytecode::Get | Bytecode::GetB | Bytecode::GetD => {
+                format!("pop a pointer and push the {name} it points to")
+            }
+            Bytecode::GetBe | Bytecode::GetDBe => {
+                format!("pop a pointer and push the big endian {name} it points to")
+            }
+            Bytecode::ALoad | Bytecode::ALoadB | Bytecode::ALoadD => {
+                format!("pop a pointer and an offset, and push the {name} there")
+            }
+            Bytecode::ALoadBe | Bytecode::ALoadDBe => {
+                format!("pop a pointer and an offset, and push the big endian {name} there")
+            }
+            Bytecode::AStore | Bytecode::AStoreB | Bytecode::AStoreD => {
+                format!("pop a pointer, an offset and a {name}, and write the {name} there")
+            }
+            Bytecode::AStoreBe | Bytecode::AStoreDBe => format!(
+                "pop a pointer, an offset and a {name}, and write the {name} there big endian"
+            ),
+            Bytecode::NewArr | Bytecode::NewArrB | Bytecode::NewArrD => {
+                format!("pop a length and push a new array of that many {name}s")
+            }
+            Bytecode::ALen => String::from("pop an array and push its length"),
+            Bytecode::AGet | Bytecode::AGetB | Bytecode::AGetD => {
+                format!("pop an array and an index, and push the {name} at that index")
+            }
+            Bytecode::APut | Bytecode::APutB | Bytecode::APutD => {
+                format!("pop an array, an index and a {name}, and put the {name} at that index")
+            }
+            Bytecode::NewMap => String::from("push a new, empty map"),
+            Bytecode::MapPut | Bytecode::MapPutS => {
+                String::from("pop a map, a key and a value, and put the value under the key")
+            }
+            Bytecode::MapGet | Bytecode::MapGetS => {
+                String::from("pop a map and a key, and push the value under the key")
+            }
+            Bytecode::MapDel | Bytecode::MapDelS => {
+                String::from("pop a map and a key, and remove the key")
+            }
+            Bytecode::MapLen => String::from("pop a map and push how many keys it has"),
+            Bytecode::FreeMap => String::from("pop a map and free it"),
+            Bytecode::ToR | Bytecode::ToRD => {
+                format!("move a {name} from the operand stack to the return stack")
+            }
+            Bytecode::FromR | Bytecode::FromRD => {
+                format!("move a {name} from the return stack to the operand stack")
+            }
+            Bytecode::RPeek | Bytecode::RPeekD => {
+                format!("copy the {name} on top of the return stack to the operand stack")
+            }
+            Bytecode::MemCpy => String::from("pop two pointers and a length, and copy the bytes"),
+            Bytecode::MemSet => {
+                String::from("pop a pointer, a byte and a length, and fill the bytes with it")
+            }
+            op => format!("run {op}"),
+        },
+    }
+}
+
+/// How many values of which kinds, such as `2 words` or `a double word and a word`
+fn count(kinds: &[Kind]) -> String {
+    match kinds {
+        [kind] => format!("a {}", kind.name()),
+        [first, rest @ ..] if rest.iter().all(|kind| kind == first) => {
+            format!("{} {}s", kinds.len(), first.name())
+        }
+        kinds => {
+            let kinds: Vec<String> = kinds
+                .iter()
+                .map(|kind| format!("a {}", kind.name()))
+                .collect();
+            kinds.join(" and ")
+        }
+    }
+}
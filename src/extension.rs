@@ -0,0 +1,159 @@
+//! Optional families of instructions, which each own ranges of opcodes and are enabled by the
+//! cargo feature of the same name. The assembler records the families a program uses in its
+//! output, so a build without one can reject the program before it runs rather than partway
+//! through. Every family is enabled by default.
+//!
+//! The families were split out after their instructions had been numbered among the core ones,
+//! and renumbering them would break every assembled program, so they keep those opcodes. Each
+//! also has a block of [`BLOCK_SIZE`] opcodes from [`RESERVED`] up which the core instructions
+//! never take, where any instructions it gains are numbered.
+
+use std::ops::RangeInclusive;
+
+use crate::program::Bytecode;
+use crate::Result;
+
+/// The first opcode reserved for extensions, which the core instructions stay below
+pub const RESERVED: u8 = 0xc0;
+/// The number of opcodes reserved for each extension
+pub const BLOCK_SIZE: u8 = 16;
+
+const fn block(i: u8) -> RangeInclusive<u8> {
+    RESERVED + i * BLOCK_SIZE..=RESERVED + (i + 1) * BLOCK_SIZE - 1
+}
+
+const FLOATS: &[RangeInclusive<u8>] = &[Bytecode::PushF as u8..=Bytecode::CmpDF as u8, block(0)];
+const ARRAYS: &[RangeInclusive<u8>] = &[Bytecode::NewArr as u8..=Bytecode::APutD as u8, block(1)];
+const MAPS: &[RangeInclusive<u8>] = &[Bytecode::NewMap as u8..=Bytecode::FreeMap as u8, block(2)];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Extension {
+    /// `push.f`, `add.f` and the other `f32` and `f64` instructions
+    Floats,
+    /// `newarr`, `alen`, `aget` and `aput`
+    Arrays,
+    /// `newmap`, `mapput` and the other map instructions
+    Maps,
+}
+
+impl std::fmt::Display for Extension {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Extension::Floats => "floats",
+            Extension::Arrays => "arrays",
+            Extension::Maps => "maps",
+        };
+
+        write!(f, "{name}")
+    }
+}
+
+impl Extension {
+    pub const ALL: [Extension; 3] = [Extension::Floats, Extension::Arrays, Extension::Maps];
+
+    /// The opcodes the extension owns: those it was numbered with, then its reserved block
+    pub fn opcodes(&self) -> &'static [RangeInclusive<u8>] {
+        match self {
+            Extension::Floats => FLOATS,
+            Extension::Arrays => ARRAYS,
+            Extension::Maps => MAPS,
+        }
+    }
+
+    /// The extension `op` belongs to, or `None` for the core instructions
+    pub fn of(op: Bytecode) -> Option<Extension> {
+        Self::owning(op as u8)
+    }
+
+    /// The extension which owns the opcode `op`, including those reserved but not yet used
+    pub fn owning(op: u8) -> Option<Extension> {
+        Self::ALL.into_iter().find(|extension| {
+            extension
+                .opcodes()
+                .iter()
+                .any(|opcodes| opcodes.contains(&op))
+        })
+    }
+
+    /// Whether this build was compiled with the extension's feature
+    pub fn is_enabled(&self) -> bool {
+        match self {
+            Extension::Floats => cfg!(feature = "floats"),
+            Extension::Arrays => cfg!(feature = "arrays"),
+            Extension::Maps => cfg!(feature = "maps"),
+        }
+    }
+
+    /// The extensions as a bit for each, for the output format
+    pub(crate) fn to_bits(extensions: &[Extension]) -> u16 {
+        extensions
+            .iter()
+            .fold(0, |bits, &extension| bits | 1 << extension as u16)
+    }
+
+    pub(crate) fn from_bits(bits: u16) -> Result<Vec<Extension>> {
+        let extensions: Vec<Extension> = Self::ALL
+            .into_iter()
+            .filter(|&extension| bits & 1 << extension as u16 != 0)
+            .collect();
+        if Self::to_bits(&extensions) != bits {
+            Err(format!("program requires unknown extensions: {bits:#06x}"))?
+        }
+
+        Ok(extensions)
+    }
+}
+
+/// Checks this build enables each of `extensions`, such as those a program requires
+pub fn check_enabled(extensions: &[Extension]) -> Result<()> {
+    if let Some(extension) = extensions.iter().find(|extension| !extension.is_enabled()) {
+        Err(format!(
+            "program requires the {extension} extension, which this build doesn't enable"
+        ))?
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::program::Bytecode;
+
+    use super::{Extension, RESERVED};
+
+    #[test]
+    fn test_extension_of() {
+        assert_eq!(Extension::of(Bytecode::AddDF), Some(Extension::Floats));
+        assert_eq!(Extension::of(Bytecode::ALen), Some(Extension::Arrays));
+        assert_eq!(Extension::of(Bytecode::MapGetS), Some(Extension::Maps));
+        assert_eq!(Extension::of(Bytecode::Add), None);
+
+        // Every opcode belongs to at most one extension
+        for op in 0..=u8::MAX {
+            let owners = Extension::ALL
+                .iter()
+                .filter(|extension| {
+                    extension
+                        .opcodes()
+                        .iter()
+                        .any(|opcodes| opcodes.contains(&op))
+                })
+                .count();
+            assert!(owners <= 1, "{op}");
+        }
+
+        // The core instructions stay out of the reserved blocks
+        assert!(Bytecode::all().all(|op| (op as u8) < RESERVED));
+        assert_eq!(Extension::owning(0xc3), Some(Extension::Floats));
+        assert_eq!(Extension::owning(0xef), Some(Extension::Maps));
+        assert_eq!(Extension::owning(0xf0), None);
+
+        let bits = Extension::to_bits(&[Extension::Floats, Extension::Maps]);
+        assert_eq!(bits, 0b101);
+        assert_eq!(
+            Extension::from_bits(bits).unwrap(),
+            [Extension::Floats, Extension::Maps]
+        );
+        assert!(Extension::from_bits(0b1000).is_err());
+    }
+}
@@ -1,3 +1,4 @@
+use std::cell::Cell;
 use std::cmp::Ordering;
 use std::fs::File;
 use std::io::{self, Read, Write};
@@ -5,100 +6,224 @@ use std::mem;
 use std::os::fd::FromRawFd;
 use std::sync::Arc;
 
+use crate::assembler::Assembler;
+use crate::gas::{Gas, GasTable};
 use crate::heap::Heap;
-use crate::locals::Locals;
-use crate::program::{Bytecode, Program};
+use crate::interpreter::{
+    ArithmeticOverflow, DivisionByZero, IndexOutOfBounds, Interpreter, InterpreterConfig,
+    RunOutcome,
+};
+use crate::limits::LimitExceeded;
+use crate::locals::{Locals, Scratch};
+use crate::objects::{MapKey, Objects};
+use crate::output::Output;
+use crate::program::{Bytecode, Fields, Program};
 use crate::stack::OperandStack;
-use crate::{Number, Result, SharedWriter};
+use crate::{Number, Result, SharedReader, SharedWriter};
 
-pub enum FrameResult {
-    Call(Frame),
+/// The number of word sized slots a frame's return stack can hold
+const RSTACK_SLOTS: usize = 32;
+const SLOT_SIZE: usize = size_of::<i32>();
+
+// Helpers for byte strings given as a pointer and length, numbered well clear of the host system
+// calls
+const CONCAT: i32 = 1000;
+const SLICE: i32 = 1001;
+const COMPARE: i32 = 1002;
+const META: i32 = 1003;
+const EXEC: i32 = 1004;
+const ASM_COMPILE: i32 = 1005;
+// Console helpers which go through the interpreter's stdin and stdout
+const GETC: i32 = 1006;
+const PUTC: i32 = 1007;
+const READLINE: i32 = 1008;
+const PUTLINE: i32 = 1009;
+// The error code of the last system call which failed
+const ERRNO: i32 = 1010;
+
+/// The error code for a file descriptor which isn't open
+const EBADF: i32 = 9;
+/// The error code for a failed read or write which the host didn't give a code for
+const EIO: i32 = 5;
 
+/// System calls from this number up, other than the helpers above, are handled by the embedder
+pub const HOST_SYSCALLS: i32 = 1000;
+
+/// Whether `call` is handled by the interpreter itself, so can't be registered by the embedder
+pub fn is_builtin_syscall(call: i32) -> bool {
+    call < HOST_SYSCALLS
+        || [
+            CONCAT,
+            SLICE,
+            COMPARE,
+            META,
+            EXEC,
+            ASM_COMPILE,
+            GETC,
+            PUTC,
+            READLINE,
+            PUTLINE,
+            ERRNO,
+        ]
+        .contains(&call)
+}
+
+pub enum FrameResult {
     // The following hold the position of their instruction
+    Call(u64, Frame),
     Ret(u64),
+    RetB(u64),
     RetW(u64),
     RetD(u64),
-    Panic(u64),
+    /// Also holds the number of slots returned
+    RetN(u64, usize),
+    /// Also holds the message of `panic` with an operand
+    Panic(u64, Option<String>),
+    /// The program made the exit system call, or ran `halt`, with this status
+    Exit(i32),
+    /// The program made a system call numbered from [`HOST_SYSCALLS`]
+    Syscall(i32),
 }
 
 pub struct Frame {
     pub opstack: OperandStack,
     pub locals: Locals,
+    /// The slots stashed by `tor`, kept apart from the operand stack so they survive calls
+    rstack: Vec<u8>,
     heap: Arc<Heap>,
     /// The position of the first instruction of the frame
     pub entry: u64,
     /// The position of the first instruction after the call
     pub ret: u64,
+    stdin: Option<SharedReader>,
     stdout: Option<SharedWriter>,
     stderr: Option<SharedWriter>,
+    config: Arc<InterpreterConfig>,
 }
 
 impl Frame {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         locals: Locals,
         opstack: OperandStack,
         heap: Arc<Heap>,
         entry: u64,
         ret: u64,
+        stdin: Option<SharedReader>,
         stdout: Option<SharedWriter>,
         stderr: Option<SharedWriter>,
+        config: Arc<InterpreterConfig>,
     ) -> Self {
+        let rstack = Vec::new();
         Self {
             opstack,
             locals,
+            rstack,
             heap,
             entry,
             ret,
+            stdin,
             stdout,
             stderr,
+            config,
         }
     }
 
-    pub fn run(&mut self, pc: &mut Program<Vec<u8>>) -> Result<FrameResult> {
+    pub fn heap(&self) -> &Arc<Heap> {
+        &self.heap
+    }
+
+    /// A copy of the frame which allocates from `heap`
+    pub fn fork(&self, heap: Arc<Heap>) -> Self {
+        Self {
+            opstack: self.opstack.clone(),
+            locals: self.locals.clone(),
+            rstack: self.rstack.clone(),
+            heap,
+            entry: self.entry,
+            ret: self.ret,
+            stdin: self.stdin.as_ref().map(Arc::clone),
+            stdout: self.stdout.as_ref().map(Arc::clone),
+            stderr: self.stderr.as_ref().map(Arc::clone),
+            config: Arc::clone(&self.config),
+        }
+    }
+
+    /// Allocates from `heap` instead, as will the frames of any calls it makes
+    pub fn with_heap(mut self, heap: Arc<Heap>) -> Self {
+        self.heap = heap;
+        self
+    }
+
+    pub fn run(
+        &mut self,
+        pc: &mut Program<Vec<u8>>,
+        gas: &mut Gas,
+        scratch: &mut Scratch,
+        objects: &mut Objects,
+    ) -> Result<FrameResult> {
         loop {
-            if let Some(fr) = self.step(pc)? {
+            if let Some(fr) = self.step(pc, gas, scratch, objects)? {
                 return Ok(fr);
             }
         }
     }
 
-    pub fn step(&mut self, pc: &mut Program<Vec<u8>>) -> Result<Option<FrameResult>> {
+    pub fn step(
+        &mut self,
+        pc: &mut Program<Vec<u8>>,
+        gas: &mut Gas,
+        scratch: &mut Scratch,
+        objects: &mut Objects,
+    ) -> Result<Option<FrameResult>> {
         let position = pc.position();
 
-        match pc.next_op()? {
-            Bytecode::ALoad => self.aload::<i32>()?,
-            Bytecode::ALoadB => self.aload::<i8>()?,
-            Bytecode::ALoadD => self.aload::<i64>()?,
-            Bytecode::AStore => self.astore::<i32>()?,
-            Bytecode::AStoreB => self.astore::<i8>()?,
-            Bytecode::AStoreD => self.astore::<i64>()?,
+        let op = pc.next_op()?;
+        gas.charge_instruction(op)?;
+        #[cfg(feature = "metrics")]
+        if let Some(metrics) = &self.config.metrics {
+            metrics.record_instruction();
+        }
+
+        match op {
+            Bytecode::ALoad => self.aload::<i32>(false)?,
+            Bytecode::ALoadB => self.aload::<i8>(false)?,
+            Bytecode::ALoadD => self.aload::<i64>(false)?,
+            Bytecode::AStore => self.astore::<i32>(false)?,
+            Bytecode::AStoreB => self.astore::<i8>(false)?,
+            Bytecode::AStoreD => self.astore::<i64>(false)?,
             Bytecode::Add => self.opstack.add::<i32>(),
             Bytecode::AddB => self.opstack.add::<i8>(),
             Bytecode::AddD => self.opstack.add::<i64>(),
-            Bytecode::Alloc => self.alloc()?,
+            Bytecode::Alloc => self.alloc(gas)?,
             Bytecode::Cmp => self.opstack.cmp::<i32>(),
             Bytecode::CmpD => self.opstack.cmp::<i64>(),
+            Bytecode::CmpB => self.opstack.cmp::<i8>(),
             Bytecode::DataPtr => self.dataptr(pc)?,
-            Bytecode::Div => self.opstack.div::<i32>(),
-            Bytecode::DivD => self.opstack.div::<i64>(),
+            Bytecode::Div => self.divide::<i32>(op, position)?,
+            Bytecode::DivD => self.divide::<i64>(op, position)?,
+            Bytecode::DivB => self.divide::<i8>(op, position)?,
             Bytecode::Dup => self.opstack.dup::<i32>(),
             Bytecode::DupD => self.opstack.dup::<i64>(),
+            Bytecode::DupB => self.opstack.dup::<i8>(),
             Bytecode::Free => self.free()?,
-            Bytecode::Get => self.get::<i32>(pc),
-            Bytecode::GetB => self.get::<i8>(pc),
-            Bytecode::GetD => self.get::<i64>(pc),
-            Bytecode::Jmp => self.jmp(pc, &[])?,
-            Bytecode::JmpEq => self.jmp(pc, &[Ordering::Equal])?,
-            Bytecode::JmpGe => self.jmp(pc, &[Ordering::Greater, Ordering::Equal])?,
-            Bytecode::JmpGt => self.jmp(pc, &[Ordering::Greater])?,
-            Bytecode::JmpLe => self.jmp(pc, &[Ordering::Less, Ordering::Equal])?,
-            Bytecode::JmpLt => self.jmp(pc, &[Ordering::Less])?,
-            Bytecode::JmpNe => self.jmp(pc, &[Ordering::Greater, Ordering::Less])?,
+            Bytecode::Get => self.get::<i32>(pc, false)?,
+            Bytecode::GetB => self.get::<i8>(pc, false)?,
+            Bytecode::GetD => self.get::<i64>(pc, false)?,
+            Bytecode::Jmp => self.jmp(pc, position, &[])?,
+            Bytecode::JmpEq => self.jmp(pc, position, &[Ordering::Equal])?,
+            Bytecode::JmpGe => self.jmp(pc, position, &[Ordering::Greater, Ordering::Equal])?,
+            Bytecode::JmpGt => self.jmp(pc, position, &[Ordering::Greater])?,
+            Bytecode::JmpLe => self.jmp(pc, position, &[Ordering::Less, Ordering::Equal])?,
+            Bytecode::JmpLt => self.jmp(pc, position, &[Ordering::Less])?,
+            Bytecode::JmpNe => self.jmp(pc, position, &[Ordering::Greater, Ordering::Less])?,
+            Bytecode::JmpPtr => pc.jump(position, self.opstack.pop::<u64>())?,
             Bytecode::Load => self.load::<i32>(pc)?,
             Bytecode::LoadB => self.load::<i8>(pc)?,
             Bytecode::LoadD => self.load::<i64>(pc)?,
             Bytecode::Mul => self.opstack.mul::<i32>(),
             Bytecode::MulD => self.opstack.mul::<i64>(),
+            Bytecode::MulB => self.opstack.mul::<i8>(),
             Bytecode::Pop => self.opstack.drop::<i32>(),
             Bytecode::PopB => self.opstack.drop::<i8>(),
             Bytecode::PopD => self.opstack.drop::<i64>(),
@@ -111,18 +236,230 @@ impl Frame {
             Bytecode::Sub => self.opstack.sub::<i32>(),
             Bytecode::SubB => self.opstack.sub::<i8>(),
             Bytecode::SubD => self.opstack.sub::<i64>(),
-            Bytecode::System => self.system()?,
+            Bytecode::NewArr => self.newarr::<i32>(gas)?,
+            Bytecode::NewArrB => self.newarr::<i8>(gas)?,
+            Bytecode::NewArrD => self.newarr::<i64>(gas)?,
+            Bytecode::ALen => self.alen()?,
+            Bytecode::AGet => self.aget::<i32>()?,
+            Bytecode::AGetB => self.aget::<i8>()?,
+            Bytecode::AGetD => self.aget::<i64>()?,
+            Bytecode::APut => self.aput::<i32>()?,
+            Bytecode::APutB => self.aput::<i8>()?,
+            Bytecode::APutD => self.aput::<i64>()?,
+            Bytecode::NewMap => self.opstack.push(objects.new_map()),
+            Bytecode::MapPut => self.mapput(pc, objects, false)?,
+            Bytecode::MapPutS => self.mapput(pc, objects, true)?,
+            Bytecode::MapGet => self.mapget(pc, objects, false)?,
+            Bytecode::MapGetS => self.mapget(pc, objects, true)?,
+            Bytecode::MapDel => self.mapdel(pc, objects, false)?,
+            Bytecode::MapDelS => self.mapdel(pc, objects, true)?,
+            Bytecode::MapLen => {
+                let len = objects.map(self.opstack.pop())?.len();
+                self.opstack.push(len as u64);
+            }
+            Bytecode::FreeMap => objects.free_map(self.opstack.pop())?,
+            Bytecode::GetBe => self.get::<i32>(pc, true)?,
+            Bytecode::GetDBe => self.get::<i64>(pc, true)?,
+            Bytecode::ALoadBe => self.aload::<i32>(true)?,
+            Bytecode::ALoadDBe => self.aload::<i64>(true)?,
+            Bytecode::AStoreBe => self.astore::<i32>(true)?,
+            Bytecode::AStoreDBe => self.astore::<i64>(true)?,
+            Bytecode::Pack => self.pack(pc)?,
+            Bytecode::Unpack => self.unpack(pc)?,
+            Bytecode::ToR => self.tor(1)?,
+            Bytecode::ToRD => self.tor(2)?,
+            Bytecode::FromR => self.fromr(1, false)?,
+            Bytecode::FromRD => self.fromr(2, false)?,
+            Bytecode::RPeek => self.fromr(1, true)?,
+            Bytecode::RPeekD => self.fromr(2, true)?,
+            Bytecode::Swap => self.opstack.swap::<i32>(),
+            Bytecode::SwapD => self.opstack.swap::<i64>(),
+            Bytecode::SwapB => self.opstack.swap::<i8>(),
+            Bytecode::Over => self.opstack.over::<i32>(),
+            Bytecode::OverD => self.opstack.over::<i64>(),
+            Bytecode::OverB => self.opstack.over::<i8>(),
+            Bytecode::Rem => self.divide::<i32>(op, position)?,
+            Bytecode::RemB => self.divide::<i8>(op, position)?,
+            Bytecode::RemD => self.divide::<i64>(op, position)?,
+            Bytecode::And => self.opstack.and::<i32>(),
+            Bytecode::AndD => self.opstack.and::<i64>(),
+            Bytecode::AndB => self.opstack.and::<i8>(),
+            Bytecode::Or => self.opstack.or::<i32>(),
+            Bytecode::OrD => self.opstack.or::<i64>(),
+            Bytecode::OrB => self.opstack.or::<i8>(),
+            Bytecode::Xor => self.opstack.xor::<i32>(),
+            Bytecode::XorD => self.opstack.xor::<i64>(),
+            Bytecode::XorB => self.opstack.xor::<i8>(),
+            Bytecode::Not => self.opstack.not::<i32>(),
+            Bytecode::NotD => self.opstack.not::<i64>(),
+            Bytecode::NotB => self.opstack.not::<i8>(),
+            Bytecode::Neg => self.opstack.neg::<i32>(),
+            Bytecode::NegD => self.opstack.neg::<i64>(),
+            Bytecode::NegB => self.opstack.neg::<i8>(),
+            Bytecode::Min => self.opstack.min::<i32>(),
+            Bytecode::MinD => self.opstack.min::<i64>(),
+            Bytecode::Max => self.opstack.max::<i32>(),
+            Bytecode::MaxD => self.opstack.max::<i64>(),
+            Bytecode::Abs => self.opstack.abs::<i32>(),
+            Bytecode::AbsD => self.opstack.abs::<i64>(),
+            Bytecode::B2W => self.opstack.convert::<i8, i32>(),
+            Bytecode::W2D => self.opstack.convert::<i32, i64>(),
+            Bytecode::D2W => self.opstack.convert::<i64, i32>(),
+            Bytecode::W2B => self.opstack.convert::<i32, i8>(),
+            Bytecode::Shl => self.opstack.shift::<i32>(Number::shl),
+            Bytecode::ShlD => self.opstack.shift::<i64>(Number::shl),
+            Bytecode::Shr => self.opstack.shift::<i32>(Number::shr),
+            Bytecode::ShrD => self.opstack.shift::<i64>(Number::shr),
+            Bytecode::ShrU => self.opstack.shift::<i32>(Number::shr_logical),
+            Bytecode::ShrDU => self.opstack.shift::<i64>(Number::shr_logical),
+            Bytecode::PushF => self.push::<i32>(pc)?,
+            Bytecode::PushDF => self.push::<i64>(pc)?,
+            Bytecode::AddF => self.opstack.float::<f32>(|a, b| a + b),
+            Bytecode::AddDF => self.opstack.float::<f64>(|a, b| a + b),
+            Bytecode::SubF => self.opstack.float::<f32>(|a, b| a - b),
+            Bytecode::SubDF => self.opstack.float::<f64>(|a, b| a - b),
+            Bytecode::MulF => self.opstack.float::<f32>(|a, b| a * b),
+            Bytecode::MulDF => self.opstack.float::<f64>(|a, b| a * b),
+            Bytecode::DivF => self.opstack.float::<f32>(|a, b| a / b),
+            Bytecode::DivDF => self.opstack.float::<f64>(|a, b| a / b),
+            Bytecode::CmpF => self.opstack.cmp_float::<f32>(),
+            Bytecode::CmpDF => self.opstack.cmp_float::<f64>(),
+            Bytecode::SLoad => self.opstack.push(scratch.read(pc.next()?)?),
+            Bytecode::SStore => scratch.write(pc.next()?, self.opstack.pop())?,
+            Bytecode::System => {
+                let result = self.system(pc, gas, objects)?;
+                self.check_stack(op, position)?;
+                return Ok(result);
+            }
 
-            Bytecode::Call => return self.call(pc).map(Some),
-            Bytecode::Panic => return Ok(Some(FrameResult::Panic(position))),
+            Bytecode::Call => {
+                let entry = pc.next::<u64>()?;
+                return Ok(Some(self.call(position, entry, pc)));
+            }
+            Bytecode::CallPtr => {
+                let entry = self.opstack.pop::<u64>();
+                // The call clears the stack, and with it the record of popping past its end
+                self.check_stack(op, position)?;
+                if self.config.protect_program {
+                    pc.check_call(position, entry)?;
+                }
+                return Ok(Some(self.call(position, entry, pc)));
+            }
+            Bytecode::Panic => return Ok(Some(FrameResult::Panic(position, None))),
+            Bytecode::PanicMsg => {
+                let message = self.panic_message(pc)?;
+                return Ok(Some(FrameResult::Panic(position, Some(message))));
+            }
+            Bytecode::Halt => {
+                let code = self.opstack.pop();
+                self.check_stack(op, position)?;
+                return Ok(Some(FrameResult::Exit(code)));
+            }
+            Bytecode::Nop => {}
+            Bytecode::MemCpy => self.memcpy()?,
+            Bytecode::MemSet => self.memset()?,
+            Bytecode::IncLocal => self.inc_local(pc)?,
             Bytecode::Ret => return Ok(Some(FrameResult::Ret(position))),
-            Bytecode::RetW => return Ok(Some(FrameResult::RetW(position))),
-            Bytecode::RetD => return Ok(Some(FrameResult::RetD(position))),
+            Bytecode::RetB | Bytecode::RetW | Bytecode::RetD => {
+                return self.ret(op, position).map(Some)
+            }
+            Bytecode::RetN => return self.ret_n(pc, position).map(Some),
         }
 
+        self.check_stack(op, position)?;
+
         Ok(None)
     }
 
+    /// Checks the operand stack after `op`, which every instruction leaving the frame must do
+    /// before it leaves, since a call takes the stack and its flags with it
+    fn check_stack(&mut self, op: Bytecode, position: u64) -> Result<()> {
+        if self.opstack.underflowed() {
+            Err(format!("stack underflow in {op} at {position}"))?
+        }
+        if self.opstack.take_wrapped() && self.config.checked_arithmetic {
+            Err(ArithmeticOverflow { op, position })?
+        }
+
+        let limits = &self.config.limits;
+        if self.opstack.exceeds(limits.stack_size) {
+            Err(LimitExceeded::new("stack size", limits.stack_size))?
+        }
+
+        Ok(())
+    }
+
+    /// Runs `div` or `rem`, trapping rather than panicking when the divisor is zero
+    fn divide<T: Number>(&mut self, op: Bytecode, position: u64) -> Result<()> {
+        if self.opstack.peek::<T>() == Some(T::default()) {
+            Err(DivisionByZero { op, position })?
+        }
+
+        match op {
+            Bytecode::Rem | Bytecode::RemB | Bytecode::RemD => self.opstack.rem::<T>(),
+            _ => self.opstack.div::<T>(),
+        }
+
+        Ok(())
+    }
+
+    /// Returns the value on top of the stack, trapping if the stack is too short to hold one
+    fn ret(&self, op: Bytecode, position: u64) -> Result<FrameResult> {
+        let present = match op {
+            Bytecode::RetB => self.opstack.peek::<i8>().is_some(),
+            Bytecode::RetW => self.opstack.peek::<i32>().is_some(),
+            _ => self.opstack.peek::<i64>().is_some(),
+        };
+        if !present {
+            Err(format!("stack underflow in {op} at {position}"))?
+        }
+
+        Ok(match op {
+            Bytecode::RetB => FrameResult::RetB(position),
+            Bytecode::RetW => FrameResult::RetW(position),
+            _ => FrameResult::RetD(position),
+        })
+    }
+
+    fn ret_n(&mut self, pc: &mut Program<Vec<u8>>, position: u64) -> Result<FrameResult> {
+        let count = pc.next::<u64>()? as usize;
+        let depth = self.opstack.depth();
+        if count > depth {
+            Err(format!("ret.n {count} with {depth} slots on the stack"))?
+        }
+
+        Ok(FrameResult::RetN(position, count))
+    }
+
+    /// Moves `slots` slots from the operand stack to the return stack
+    fn tor(&mut self, slots: usize) -> Result<()> {
+        if self.rstack.len() + slots * SLOT_SIZE > RSTACK_SLOTS * SLOT_SIZE {
+            Err(format!(
+                "return stack overflow, it holds {RSTACK_SLOTS} slots"
+            ))?
+        }
+
+        let bytes = self.opstack.pop_slots(slots);
+        self.rstack.extend(bytes);
+
+        Ok(())
+    }
+
+    /// Moves `slots` slots from the return stack to the operand stack, or copies them if `keep`
+    /// is set
+    fn fromr(&mut self, slots: usize, keep: bool) -> Result<()> {
+        let Some(from) = self.rstack.len().checked_sub(slots * SLOT_SIZE) else {
+            Err("return stack underflow")?
+        };
+
+        self.opstack.push_slots(&self.rstack[from..]);
+        if !keep {
+            self.rstack.truncate(from);
+        }
+
+        Ok(())
+    }
+
     fn push<T: Number>(&mut self, pc: &mut Program<Vec<u8>>) -> Result<()> {
         let val = pc.next::<T>()?;
         self.opstack.push(val);
@@ -130,27 +467,80 @@ impl Frame {
     }
 
     fn load<T: Number>(&mut self, pc: &mut Program<Vec<u8>>) -> Result<()> {
+        let position = pc.position() - 1;
         let i = pc.next::<u64>()?;
-        let val = self.locals.read::<T>(i);
+        self.check_local::<T>(i)?;
+        let val = self
+            .locals
+            .read::<T>(i)
+            .map_err(|err| err.at(self.entry.to_string(), position))?;
         self.opstack.push(val);
         Ok(())
     }
 
     fn store<T: Number>(&mut self, pc: &mut Program<Vec<u8>>) -> Result<()> {
+        let position = pc.position() - 1;
         let i = pc.next::<u64>()?;
+        self.check_local::<T>(i)?;
         let val = self.opstack.pop();
-        self.locals.write::<T>(i, val);
+        self.locals
+            .write::<T>(i, val)
+            .map_err(|err| err.at(self.entry.to_string(), position))?;
+        Ok(())
+    }
+
+    /// Adds the immediate to the word in a local slot, wrapping on overflow
+    fn inc_local(&mut self, pc: &mut Program<Vec<u8>>) -> Result<()> {
+        let position = pc.position() - 1;
+        let i = pc.next::<u64>()?;
+        let n = pc.next::<i32>()?;
+        self.check_local::<i32>(i)?;
+        let val = self
+            .locals
+            .read::<i32>(i)
+            .map_err(|err| err.at(self.entry.to_string(), position))?;
+        self.locals
+            .write(i, val.wrapping_add(n))
+            .map_err(|err| err.at(self.entry.to_string(), position))?;
         Ok(())
     }
 
-    fn get<T: Number>(&mut self, pc: &mut Program<Vec<u8>>) {
+    /// Checks a value of type `T` in slot `i` is within the configured local slots
+    fn check_local<T: Number>(&self, i: u64) -> Result<()> {
+        let slots = self.config.limits.local_slots;
+        match i.checked_add(T::SIZE.div_ceil(SLOT_SIZE) as u64) {
+            Some(end) if end <= slots as u64 => Ok(()),
+            _ => Err(LimitExceeded::new("local slots", slots))?,
+        }
+    }
+
+    /// Reads a value from the program's data, in big endian byte order if `be` is set
+    fn get<T: Number>(&mut self, pc: &mut Program<Vec<u8>>, be: bool) -> Result<()> {
         let offset = self.opstack.pop::<u64>();
         let ptr = self.opstack.pop::<u64>(); // offset within the output file, not an actual pointer
-        let value = pc.get::<T>((ptr + offset) as usize);
+        let address = ptr.wrapping_add(offset);
+        let Some(mut value) = usize::try_from(address).ok().and_then(|a| pc.get::<T>(a)) else {
+            // The get instruction has no operand, so it started at the previous byte
+            let position = pc.position() - 1;
+            Err(format!(
+                "data read out of bounds: {} bytes at {address} at {position}",
+                T::SIZE
+            ))?
+        };
+        if be {
+            value = T::from_be_bytes(value.to_le_bytes().as_ref());
+        }
         self.opstack.push(value);
+
+        Ok(())
     }
 
-    fn jmp(&mut self, pc: &mut Program<Vec<u8>>, conditions: &[Ordering]) -> Result<()> {
+    fn jmp(
+        &mut self,
+        pc: &mut Program<Vec<u8>>,
+        position: u64,
+        conditions: &[Ordering],
+    ) -> Result<()> {
         let pos = pc.next::<u64>()?;
 
         let jmp = conditions.is_empty() || {
@@ -159,69 +549,367 @@ impl Frame {
         };
 
         if jmp {
-            pc.set_position(pos);
+            pc.jump(position, pos)?;
         }
 
         Ok(())
     }
 
-    fn alloc(&mut self) -> Result<()> {
+    fn alloc(&mut self, gas: &mut Gas) -> Result<()> {
         let size = self.opstack.pop::<u64>();
-        let ptr = self.heap.alloc(size as usize);
-        self.opstack.push(ptr as u64);
+        gas.charge_alloc(size)?;
+        // A heap with a limit hands out a null pointer once it's full, as does the host when it
+        // can't allocate the size asked for
+        let ptr = usize::try_from(size)
+            .ok()
+            .and_then(|size| self.heap.alloc(size))
+            .unwrap_or(0);
+        self.opstack.push(ptr);
+        #[cfg(feature = "metrics")]
+        if let Some(metrics) = &self.config.metrics {
+            metrics.set_heap_bytes(self.heap.allocated());
+        }
+
+        Ok(())
+    }
+
+    /// Allocates an array of `len` elements, preceded by its length as a double word
+    fn newarr<T: Number>(&mut self, gas: &mut Gas) -> Result<()> {
+        let len = self.opstack.pop::<u64>();
+        let Some(size) = len
+            .checked_mul(T::SIZE as u64)
+            .and_then(|size| size.checked_add(u64::SIZE as u64))
+        else {
+            Err(format!("array too large: {len}"))?
+        };
+
+        self.opstack.push(size);
+        self.alloc(gas)?;
+        let ptr = self.opstack.peek::<u64>().unwrap();
+        self.heap.write(ptr, 0, &len.to_le_bytes());
+
+        Ok(())
+    }
+
+    fn array_len(&self, ptr: u64) -> Result<u64> {
+        match self.heap.size(ptr) {
+            Some(size) if size >= u64::SIZE => {
+                let mut len = [0; u64::SIZE];
+                self.heap.read(ptr, 0, &mut len);
+                Ok(u64::from_le_bytes(len))
+            }
+            _ => Err(format!("invalid array: {ptr}"))?,
+        }
+    }
+
+    /// The offset of element `index` within the array, checking it is within both the length and
+    /// the allocation, in case the length has been overwritten
+    fn element<T: Number>(&self, ptr: u64, index: u64) -> Result<usize> {
+        let len = self.array_len(ptr)?;
+        if index >= len {
+            Err(IndexOutOfBounds { index, len })?
+        }
+
+        let size = self.heap.size(ptr).unwrap_or_default() as u64;
+        match index
+            .checked_mul(T::SIZE as u64)
+            .and_then(|offset| offset.checked_add(u64::SIZE as u64))
+        {
+            Some(offset) if offset + T::SIZE as u64 <= size => Ok(offset as usize),
+            _ => Err(format!("invalid array: {ptr}"))?,
+        }
+    }
+
+    fn alen(&mut self) -> Result<()> {
+        let ptr = self.opstack.pop::<u64>();
+        let len = self.array_len(ptr)?;
+        self.opstack.push(len);
+
+        Ok(())
+    }
+
+    fn aget<T: Number>(&mut self) -> Result<()> {
+        let index = self.opstack.pop::<u64>();
+        let ptr = self.opstack.pop::<u64>();
+        let offset = self.element::<T>(ptr, index)?;
+
+        let mut dst = T::default().to_le_bytes();
+        self.heap.read(ptr, offset, dst.as_mut());
+        self.opstack.push(T::from_le_bytes(dst.as_ref()));
+
+        Ok(())
+    }
+
+    fn aput<T: Number>(&mut self) -> Result<()> {
+        let value = self.opstack.pop::<T>();
+        let index = self.opstack.pop::<u64>();
+        let ptr = self.opstack.pop::<u64>();
+        let offset = self.element::<T>(ptr, index)?;
+
+        self.heap.write(ptr, offset, value.to_le_bytes().as_ref());
+
+        Ok(())
+    }
+
+    /// Pops a map key, which is a double word or, if `bytes` is set, the pointer and length of a
+    /// byte string
+    fn map_key(&mut self, pc: &mut Program<Vec<u8>>, bytes: bool) -> Result<MapKey> {
+        if !bytes {
+            return Ok(MapKey::Int(self.opstack.pop()));
+        }
+
+        let len = self.opstack.pop::<u64>() as usize;
+        let address = self.opstack.pop::<u64>();
+        let ptr = self.translate(pc, address, len)?;
+        let key = unsafe { std::slice::from_raw_parts(ptr, len) };
+
+        Ok(MapKey::Bytes(key.to_vec()))
+    }
+
+    fn mapput(
+        &mut self,
+        pc: &mut Program<Vec<u8>>,
+        objects: &mut Objects,
+        bytes: bool,
+    ) -> Result<()> {
+        let value = self.opstack.pop::<i64>();
+        let key = self.map_key(pc, bytes)?;
+        let handle = self.opstack.pop::<u64>();
+        objects.map(handle)?.insert(key, value);
+
+        Ok(())
+    }
+
+    /// Pushes the value, or 0 if the key is missing, then whether it was found
+    fn mapget(
+        &mut self,
+        pc: &mut Program<Vec<u8>>,
+        objects: &mut Objects,
+        bytes: bool,
+    ) -> Result<()> {
+        let key = self.map_key(pc, bytes)?;
+        let handle = self.opstack.pop::<u64>();
+        let value = objects.map(handle)?.get(&key).copied();
+
+        self.opstack.push(value.unwrap_or_default());
+        self.opstack.push(i32::from(value.is_some()));
+
+        Ok(())
+    }
+
+    /// Pushes whether the key was found
+    fn mapdel(
+        &mut self,
+        pc: &mut Program<Vec<u8>>,
+        objects: &mut Objects,
+        bytes: bool,
+    ) -> Result<()> {
+        let key = self.map_key(pc, bytes)?;
+        let handle = self.opstack.pop::<u64>();
+        let removed = objects.map(handle)?.remove(&key).is_some();
+        self.opstack.push(i32::from(removed));
 
         Ok(())
     }
 
     fn free(&mut self) -> Result<()> {
         let ptr = self.opstack.pop::<u64>();
-        self.heap.free(ptr as *const u8);
+        if !self.heap.free(ptr) {
+            Err(format!(
+                "free of {ptr}, which is not the start of a heap allocation"
+            ))?
+        }
+        #[cfg(feature = "metrics")]
+        if let Some(metrics) = &self.config.metrics {
+            metrics.set_heap_bytes(self.heap.allocated());
+        }
 
         Ok(())
     }
 
     fn dataptr(&mut self, pc: &mut Program<Vec<u8>>) -> Result<()> {
         let offset = pc.next::<u64>()?;
-        let ptr = pc.getptr(offset as usize);
-        self.opstack.push(ptr as u64);
+
+        // Deterministic programs refer to the data by its offset, since the host address of the
+        // program can change between runs
+        let ptr = if self.config.deterministic {
+            offset
+        } else {
+            pc.getptr(offset as usize) as u64
+        };
+        self.opstack.push(ptr);
 
         Ok(())
     }
 
-    fn astore<T: Number>(&mut self) -> Result<()> {
+    /// Reads the message of `panic` from the data section, up to a `\0` byte or the end of the
+    /// data
+    fn panic_message(&mut self, pc: &mut Program<Vec<u8>>) -> Result<String> {
+        let offset = pc.next::<u64>()? as usize;
+        let message: Vec<u8> = (offset..)
+            .map_while(|offset| pc.get::<u8>(offset))
+            .take_while(|&byte| byte != 0)
+            .collect();
+
+        Ok(String::from_utf8_lossy(&message).into_owned())
+    }
+
+    /// Writes a value to the heap, in big endian byte order if `be` is set
+    fn astore<T: Number>(&mut self, be: bool) -> Result<()> {
         let data = self.opstack.pop::<T>();
         let offset = self.opstack.pop::<u64>();
         let ptr = self.opstack.pop::<u64>();
-        let src = data.to_le_bytes();
+        let src = match be {
+            true => data.to_be_bytes(),
+            false => data.to_le_bytes(),
+        };
 
-        if !self
-            .heap
-            .write(ptr as *const u8, offset as usize, src.as_ref())
-        {
+        if !self.heap.write(ptr, offset as usize, src.as_ref()) {
             Err("{id}: no write")?;
         }
 
         Ok(())
     }
 
-    fn aload<T: Number>(&mut self) -> Result<()> {
+    /// Pops a length, a source pointer and a destination pointer, then copies that many bytes
+    /// from the source to the destination. The two may overlap.
+    fn memcpy(&mut self) -> Result<()> {
+        let len = self.opstack.pop::<u64>() as usize;
+        let src = self.opstack.pop::<u64>();
+        let dst = self.opstack.pop::<u64>();
+
+        // Checked before allocating the buffer, which would otherwise be as large as asked for
+        if self.heap.translate(src, len).is_none() {
+            Err("memcpy source is not within a heap allocation")?
+        }
+
+        let mut bytes = vec![0; len];
+        self.heap.read(src, 0, &mut bytes);
+        if !self.heap.write(dst, 0, &bytes) {
+            Err("memcpy destination is not within a heap allocation")?
+        }
+
+        Ok(())
+    }
+
+    /// Pops a length, a byte and a pointer, then sets that many bytes from the pointer to the byte
+    fn memset(&mut self) -> Result<()> {
+        let len = self.opstack.pop::<u64>() as usize;
+        let byte = self.opstack.pop::<i8>() as u8;
+        let dst = self.opstack.pop::<u64>();
+
+        if self.heap.translate(dst, len).is_none() || !self.heap.write(dst, 0, &vec![byte; len]) {
+            Err("memset destination is not within a heap allocation")?
+        }
+
+        Ok(())
+    }
+
+    /// Reads a value from the heap, in big endian byte order if `be` is set
+    fn aload<T: Number>(&mut self, be: bool) -> Result<()> {
         let offset = self.opstack.pop::<u64>();
         let ptr = self.opstack.pop::<u64>();
         let mut dst = T::default().to_le_bytes();
 
-        if !self
-            .heap
-            .read(ptr as *const u8, offset as usize, dst.as_mut())
-        {
+        if !self.heap.read(ptr, offset as usize, dst.as_mut()) {
             Err("{id}: no read")?;
         }
 
-        self.opstack.push(T::from_le_bytes(dst.as_ref()));
+        let value = match be {
+            true => T::from_be_bytes(dst.as_ref()),
+            false => T::from_le_bytes(dst.as_ref()),
+        };
+        self.opstack.push(value);
+
+        Ok(())
+    }
+
+    /// Pops a pointer and pushes each field of the record it points to, from the first
+    fn unpack(&mut self, pc: &mut Program<Vec<u8>>) -> Result<()> {
+        let fields = Fields(pc.next::<u64>()?);
+        let address = self.opstack.pop::<u64>();
+        let size = fields.widths().sum();
+
+        let ptr = self.translate(pc, address, size)?;
+        let mut src = unsafe { std::slice::from_raw_parts(ptr, size) };
+        for width in fields.widths() {
+            match width {
+                1 => self
+                    .opstack
+                    .push(<i8 as Number>::from_le_bytes(&src[..width])),
+                4 => self
+                    .opstack
+                    .push(<i32 as Number>::from_le_bytes(&src[..width])),
+                _ => self
+                    .opstack
+                    .push(<i64 as Number>::from_le_bytes(&src[..width])),
+            }
+            src = &src[width..];
+        }
+
+        Ok(())
+    }
+
+    /// Pops each field of a record, from the last, then a pointer, and writes the fields to it
+    fn pack(&mut self, pc: &mut Program<Vec<u8>>) -> Result<()> {
+        let fields = Fields(pc.next::<u64>()?);
+        let widths = fields.widths().collect::<Vec<_>>();
+
+        let mut record = Vec::new();
+        for &width in widths.iter().rev() {
+            let field = match width {
+                1 => self.opstack.pop::<i8>().to_le_bytes().to_vec(),
+                4 => self.opstack.pop::<i32>().to_le_bytes().to_vec(),
+                _ => self.opstack.pop::<i64>().to_le_bytes().to_vec(),
+            };
+            record.splice(0..0, field);
+        }
+        let address = self.opstack.pop::<u64>();
+
+        if self.config.protect_program && self.heap.translate(address, record.len()).is_none() {
+            Err("pack destination is not within a heap allocation")?
+        }
+
+        let ptr = self.translate(pc, address, record.len())?;
+        unsafe { std::slice::from_raw_parts_mut(ptr, record.len()) }.copy_from_slice(&record);
 
         Ok(())
     }
 
-    fn system(&mut self) -> Result<()> {
+    /// Converts an address used by the program into a host pointer to `size` bytes, which have to
+    /// lie within a live allocation or the program itself
+    fn translate(&self, pc: &mut Program<Vec<u8>>, address: u64, size: usize) -> Result<*mut u8> {
+        if address == 0 {
+            Err("invalid ptr")?
+        }
+
+        if let Some(ptr) = self.heap.translate(address, size) {
+            return Ok(ptr);
+        }
+
+        // Anything outside the heap has to be within the program, which is referred to by its host
+        // address unless addresses are deterministic
+        let offset = match self.config.deterministic {
+            true => Some(address),
+            false => address.checked_sub(pc.getptr(0) as u64),
+        };
+        let Some(offset) = offset.filter(|offset| {
+            offset
+                .checked_add(size as u64)
+                .is_some_and(|end| end <= pc.len() as u64)
+        }) else {
+            Err(format!("invalid ptr: {address}"))?
+        };
+
+        Ok(pc.getptr(offset as usize) as *mut u8)
+    }
+
+    fn system(
+        &mut self,
+        pc: &mut Program<Vec<u8>>,
+        gas: &mut Gas,
+        objects: &mut Objects,
+    ) -> Result<Option<FrameResult>> {
         // System call numbers from
         // https://github.com/apple-oss-distributions/xnu/blob/main/bsd/kern/syscalls.master
         const EXIT: i32 = 1;
@@ -231,34 +919,48 @@ impl Frame {
         const CLOSE: i32 = 6;
         const FSYNC: i32 = 95;
 
+        const STDIN: i32 = 0;
         const STDOUT: i32 = 1;
-        const _STDERR: i32 = 2;
+        const STDERR: i32 = 2;
 
         let call = self.opstack.pop::<i32>();
+        gas.charge_syscall(call)?;
+        #[cfg(feature = "metrics")]
+        if let Some(metrics) = &self.config.metrics {
+            metrics.record_syscall();
+        }
 
         match call {
             EXIT => {
                 let code = self.opstack.pop::<i32>();
-                std::process::exit(code)
+                return Ok(Some(FrameResult::Exit(code)));
             }
             READ => {
                 let size = self.opstack.pop::<u64>() as usize;
-                let ptr = self.opstack.pop::<u64>() as *mut u8;
+                let address = self.opstack.pop::<u64>();
                 let fd = self.opstack.pop::<i32>();
 
-                if ptr.is_null() {
-                    Err("invalid ptr")?
+                if self.config.protect_program && self.heap.translate(address, size).is_none() {
+                    Err("read destination is not within a heap allocation")?
                 }
 
+                let ptr = self.translate(pc, address, size)?;
                 let dst = unsafe { std::slice::from_raw_parts_mut(ptr, size) };
-                let mut src = unsafe { File::from_raw_fd(fd) };
-                let result = src.read(dst);
-                mem::forget(src); // Avoid closing the file descriptor
+                let result: io::Result<usize> = match &self.stdin {
+                    _ if !objects.is_open(fd) => self.bad_fd("read", fd)?,
+                    Some(stdin) if fd == STDIN => stdin.lock().unwrap().read(dst),
+                    _ => {
+                        let mut src = unsafe { File::from_raw_fd(fd) };
+                        let result = src.read(dst);
+                        mem::forget(src); // Avoid closing the file descriptor
+                        result
+                    }
+                };
 
                 let n = match result {
                     Ok(n) => n as i32,
                     Err(e) => {
-                        eprintln!("read error: {e}");
+                        objects.set_errno(e.raw_os_error().unwrap_or(EIO));
                         -1
                     }
                 };
@@ -267,31 +969,32 @@ impl Frame {
             }
             WRITE => {
                 let size = self.opstack.pop::<u64>() as usize;
-                let ptr = self.opstack.pop::<u64>() as *const u8;
+                let address = self.opstack.pop::<u64>();
                 let fd = self.opstack.pop::<i32>();
 
-                if ptr.is_null() {
-                    Err("invalid ptr")?
-                }
-
+                let ptr = self.translate(pc, address, size)?;
                 let src = unsafe { std::slice::from_raw_parts(ptr, size) };
 
-                let result: io::Result<usize>;
-                // TODO: try using let chains after switching to rust 2024 edition
-                if fd == STDOUT && self.stdout.is_some() {
-                    let stdout = self.stdout.as_ref().unwrap();
-                    let mut stdout = stdout.lock().unwrap();
-                    result = stdout.write(src);
-                } else {
-                    let mut dst = unsafe { File::from_raw_fd(fd) };
-                    result = dst.write(src);
-                    mem::forget(dst); // Avoid closing the file descriptor
-                }
+                let writer = match fd {
+                    STDOUT => self.stdout.as_ref(),
+                    STDERR => self.stderr.as_ref(),
+                    _ => None,
+                };
+                let result: io::Result<usize> = match writer {
+                    _ if !objects.is_open(fd) => self.bad_fd("write", fd)?,
+                    Some(writer) => writer.lock().unwrap().write(src),
+                    None => {
+                        let mut dst = unsafe { File::from_raw_fd(fd) };
+                        let result = dst.write(src);
+                        mem::forget(dst); // Avoid closing the file descriptor
+                        result
+                    }
+                };
 
                 let n = match result {
                     Ok(n) => n as i32,
                     Err(e) => {
-                        eprintln!("write error: {e}");
+                        objects.set_errno(e.raw_os_error().unwrap_or(EIO));
                         -1
                     }
                 };
@@ -302,38 +1005,291 @@ impl Frame {
             CLOSE => {
                 let fd = self.opstack.pop::<i32>();
 
-                // Dropping the file will close it
-                unsafe { File::from_raw_fd(fd) };
+                // The only descriptors in the table are the host's standard streams, which are
+                // left open for the host
+                if !objects.close(fd) {
+                    if let Err(e) = self.bad_fd::<()>("close", fd)? {
+                        objects.set_errno(e.raw_os_error().unwrap_or(EIO));
+                    }
+                }
             }
             FSYNC => {
                 let fd = self.opstack.pop::<i32>();
 
-                let f = unsafe { File::from_raw_fd(fd) };
+                let result = match objects.is_open(fd) {
+                    true => {
+                        let f = unsafe { File::from_raw_fd(fd) };
+                        let result = f.sync_all();
+                        mem::forget(f); // Avoid closing the file descriptor
+                        result
+                    }
+                    false => self.bad_fd("fsync", fd)?,
+                };
 
-                let r = if let Err(_) = f.sync_all() { -1 } else { 0 };
+                let r = match result {
+                    Ok(()) => 0,
+                    Err(e) => {
+                        objects.set_errno(e.raw_os_error().unwrap_or(EIO));
+                        -1
+                    }
+                };
 
                 self.opstack.push::<i32>(r);
             }
+            CONCAT => {
+                let b = self.bytes(pc)?;
+                let mut a = self.bytes(pc)?;
+                a.extend(b);
+                self.push_bytes(gas, &a)?;
+            }
+            SLICE => {
+                let end = self.opstack.pop::<u64>();
+                let start = self.opstack.pop::<u64>();
+                let len = self.opstack.pop::<u64>();
+                let ptr = self.opstack.pop::<u64>();
+
+                if start > end || end > len {
+                    Err(format!("slice out of range: {start}..{end} of {len} bytes"))?
+                }
+
+                let Some(from) = ptr.checked_add(start) else {
+                    Err(format!("slice out of bounds: {start} bytes past {ptr}"))?
+                };
+
+                self.opstack.push(from);
+                self.opstack.push(end - start);
+            }
+            COMPARE => {
+                let b = self.bytes(pc)?;
+                let a = self.bytes(pc)?;
+                self.opstack.push(a.cmp(&b) as i32);
+            }
+            META => {
+                let key = self.bytes(pc)?;
+                match objects.meta(&key) {
+                    Some(value) => self.push_bytes(gas, value.as_bytes())?,
+                    None => {
+                        self.opstack.push(0u64);
+                        self.opstack.push(0u64);
+                    }
+                }
+            }
+            EXEC => {
+                let fuel = self.opstack.pop::<u64>();
+                let program = self.bytes(pc)?;
+                self.exec(gas, &program, fuel)?;
+            }
+            ASM_COMPILE => {
+                let src = self.bytes(pc)?;
+                let program = String::from_utf8(src).map_err(Into::into).and_then(|src| {
+                    Assembler::new()
+                        .with_prelude(true)
+                        .with_allow_include(false)
+                        .assemble(&src)
+                });
+                match program {
+                    Ok(output) => self.push_bytes(gas, &output.serialise())?,
+                    Err(_) => {
+                        self.opstack.push(0u64);
+                        self.opstack.push(0u64);
+                    }
+                }
+            }
+            GETC => {
+                self.flush_stdout()?;
+                let mut byte = [0];
+                let c = match self.read_stdin(&mut byte) {
+                    Ok(1) => byte[0] as i32,
+                    // The end of the input, or an error reading it
+                    _ => -1,
+                };
+                self.opstack.push(c);
+            }
+            PUTC => {
+                let c = self.opstack.pop::<i32>();
+                self.write_stdout(&[c as u8])?;
+            }
+            READLINE => {
+                self.flush_stdout()?;
+                let mut line = Vec::new();
+                let mut byte = [0];
+                let found = loop {
+                    match self.read_stdin(&mut byte) {
+                        Ok(1) if byte[0] == b'\n' => break true,
+                        Ok(1) => line.push(byte[0]),
+                        // The last line may not end with a newline
+                        _ => break !line.is_empty(),
+                    }
+                };
+                if line.ends_with(b"\r") {
+                    line.pop();
+                }
+
+                self.push_bytes(gas, &line)?;
+                self.opstack.push(found as i32);
+            }
+            PUTLINE => {
+                let mut line = self.bytes(pc)?;
+                line.push(b'\n');
+                self.write_stdout(&line)?;
+            }
+            ERRNO => self.opstack.push(objects.errno()),
+            // Left for the interpreter to find the embedder's handler
+            call if !is_builtin_syscall(call) => return Ok(Some(FrameResult::Syscall(call))),
             _ => Err(format!("invalid system call: {call}"))?,
         };
 
+        Ok(None)
+    }
+
+    /// Runs a serialised program in a new interpreter which may use up to `fuel` gas, then pushes
+    /// the value it returned or exited with and its status: 0 if it completed, 1 if it exited, 2
+    /// if it trapped or couldn't be loaded, and 3 if it ran out of fuel. The gas used by the
+    /// program is charged to this one.
+    fn exec(&mut self, gas: &mut Gas, program: &[u8], fuel: u64) -> Result<()> {
+        const MAX_DEPTH: usize = 16;
+
+        thread_local! {
+            static DEPTH: Cell<usize> = const { Cell::new(0) };
+        }
+
+        if DEPTH.get() >= MAX_DEPTH {
+            Err("exec nested too deeply")?
+        }
+
+        let table = GasTable {
+            limit: Some(fuel),
+            ..self.config.gas.clone().unwrap_or_default()
+        };
+        let config = InterpreterConfig {
+            protect_program: true,
+            deterministic: self.config.deterministic,
+            packed_stack: self.config.packed_stack,
+            gas: Some(table),
+            ..Default::default()
+        };
+
+        let child = Output::deserialise(program)
+            .and_then(|output| Interpreter::new(&output, self.stdout.clone(), self.stderr.clone()));
+        let (value, status, used) = match child {
+            Ok(child) => {
+                let mut child = child.with_config(config);
+                if let Some(stdin) = &self.stdin {
+                    child = child.with_stdin(Arc::clone(stdin));
+                }
+
+                DEPTH.set(DEPTH.get() + 1);
+                let outcome = child.run();
+                DEPTH.set(DEPTH.get() - 1);
+
+                let (value, status) = match outcome {
+                    RunOutcome::Completed { ret } => (ret.unwrap_or_default(), 0),
+                    RunOutcome::Exited { code } => (code as i64, 1),
+                    RunOutcome::Trapped { .. } => (0, 2),
+                    RunOutcome::FuelExhausted => (0, 3),
+                };
+                (value, status, child.gas_used())
+            }
+            Err(_) => (0, 2, 0),
+        };
+
+        gas.charge_exec(used)?;
+        self.opstack.push(value);
+        self.opstack.push::<i32>(status);
+
+        Ok(())
+    }
+
+    /// Reads from the interpreter's stdin, or the process's if it wasn't given one
+    fn read_stdin(&self, dst: &mut [u8]) -> io::Result<usize> {
+        match &self.stdin {
+            Some(stdin) => stdin.lock().unwrap().read(dst),
+            None => {
+                let mut src = unsafe { File::from_raw_fd(0) };
+                let result = src.read(dst);
+                mem::forget(src); // Avoid closing the file descriptor
+                result
+            }
+        }
+    }
+
+    /// Writes all of `src` to the interpreter's stdout, or the process's if it wasn't given one
+    fn write_stdout(&self, src: &[u8]) -> io::Result<()> {
+        match &self.stdout {
+            Some(stdout) => stdout.lock().unwrap().write_all(src),
+            None => {
+                let mut dst = unsafe { File::from_raw_fd(1) };
+                let result = dst.write_all(src);
+                mem::forget(dst); // Avoid closing the file descriptor
+                result
+            }
+        }
+    }
+
+    /// The result of a system call given a file descriptor the program doesn't have open, which
+    /// fails with `EBADF`, or traps instead with [`InterpreterConfig::strict_fds`]
+    fn bad_fd<T>(&self, call: &str, fd: i32) -> Result<io::Result<T>> {
+        if self.config.strict_fds {
+            Err(format!("bad file descriptor {fd} in {call}"))?
+        }
+
+        Ok(Err(io::Error::from_raw_os_error(EBADF)))
+    }
+
+    /// Flushes stdout before reading from the console, so a prompt without a newline is shown
+    fn flush_stdout(&self) -> io::Result<()> {
+        match &self.stdout {
+            Some(stdout) => stdout.lock().unwrap().flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// Pops a length and pointer, and copies the bytes they refer to
+    fn bytes(&mut self, pc: &mut Program<Vec<u8>>) -> Result<Vec<u8>> {
+        let len = self.opstack.pop::<u64>() as usize;
+        let address = self.opstack.pop::<u64>();
+        if len == 0 {
+            return Ok(Vec::new());
+        }
+
+        let ptr = self.translate(pc, address, len)?;
+        Ok(unsafe { std::slice::from_raw_parts(ptr, len) }.to_vec())
+    }
+
+    /// Copies `bytes` into a new allocation and pushes its pointer and length
+    fn push_bytes(&mut self, gas: &mut Gas, bytes: &[u8]) -> Result<()> {
+        let len = bytes.len() as u64;
+        self.opstack.push(len);
+        self.alloc(gas)?;
+        let ptr = self.opstack.peek::<u64>().unwrap();
+        match self.heap.write(ptr, 0, bytes) {
+            true => self.opstack.push(len),
+            // The heap is full, so there is no string
+            false => self.opstack.push(0u64),
+        }
+
         Ok(())
     }
 
-    fn call(&mut self, pc: &mut Program<Vec<u8>>) -> Result<FrameResult> {
-        let mut locals = Locals::default();
+    /// Moves the operand stack into the locals of a new frame for the function at `entry`, which
+    /// returns to the instruction after the call
+    fn call(&mut self, position: u64, entry: u64, pc: &Program<Vec<u8>>) -> FrameResult {
+        let ret = pc.position();
+        let mut locals = Locals::sized(pc.local_slots(entry));
         locals.copy_from_slice(self.opstack.as_slice());
         self.opstack.clear(); // TODO: would be nicer to avoid clearing the opstack
 
-        let entry = pc.next::<u64>()?;
-        let ret = pc.position();
-        let opstack = OperandStack::default();
+        let opstack = OperandStack::new(self.config.packed_stack);
         let heap = Arc::clone(&self.heap);
+        let stdin = self.stdin.as_ref().map(Arc::clone);
         let stdout = self.stdout.as_ref().map(Arc::clone);
         let stderr = self.stderr.as_ref().map(Arc::clone);
+        let config = Arc::clone(&self.config);
 
-        let frame = Frame::new(locals, opstack, heap, entry, ret, stdout, stderr);
+        let frame = Frame::new(
+            locals, opstack, heap, entry, ret, stdin, stdout, stderr, config,
+        );
 
-        Ok(FrameResult::Call(frame))
+        FrameResult::Call(position, frame)
     }
 }
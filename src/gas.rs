@@ -0,0 +1,134 @@
+use std::collections::HashMap;
+
+use crate::program::Bytecode;
+use crate::Result;
+
+/// The cost of executing each part of a program.
+#[derive(Debug, Clone)]
+pub struct GasTable {
+    /// The cost of any instruction missing from [`GasTable::instructions`]
+    pub default: u64,
+    pub instructions: HashMap<Bytecode, u64>,
+    /// The cost of a system call by number, charged on top of the `system` instruction
+    pub syscalls: HashMap<i32, u64>,
+    /// The cost of each byte requested by `alloc`
+    pub alloc_byte: u64,
+    /// Execution stops with an error once more than this much gas has been used
+    pub limit: Option<u64>,
+}
+
+impl Default for GasTable {
+    fn default() -> Self {
+        let default = 1;
+        let instructions = HashMap::new();
+        let syscalls = HashMap::new();
+        let alloc_byte = 0;
+        let limit = None;
+
+        Self {
+            default,
+            instructions,
+            syscalls,
+            alloc_byte,
+            limit,
+        }
+    }
+}
+
+/// The error raised once a program uses more gas than [`GasTable::limit`]
+#[derive(Debug)]
+pub struct OutOfGas {
+    pub used: u64,
+}
+
+impl std::fmt::Display for OutOfGas {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "out of gas: used {}", self.used)
+    }
+}
+
+impl std::error::Error for OutOfGas {}
+
+/// Tracks the gas used by a running program. Nothing is charged without a [`GasTable`].
+#[derive(Debug, Default, Clone)]
+pub struct Gas {
+    table: Option<GasTable>,
+    used: u64,
+    /// The instructions charged for, which are counted even without a table
+    instructions: u64,
+}
+
+impl Gas {
+    pub fn new(table: Option<GasTable>) -> Self {
+        let used = 0;
+        let instructions = 0;
+        Self {
+            table,
+            used,
+            instructions,
+        }
+    }
+
+    pub fn used(&self) -> u64 {
+        self.used
+    }
+
+    pub fn instructions(&self) -> u64 {
+        self.instructions
+    }
+
+    pub fn reset(&mut self) {
+        self.used = 0;
+        self.instructions = 0;
+    }
+
+    pub fn charge_instruction(&mut self, op: Bytecode) -> Result<()> {
+        self.instructions += 1;
+        let Some(table) = &self.table else {
+            return Ok(());
+        };
+
+        let cost = table
+            .instructions
+            .get(&op)
+            .copied()
+            .unwrap_or(table.default);
+        self.charge(cost)
+    }
+
+    pub fn charge_syscall(&mut self, call: i32) -> Result<()> {
+        let Some(table) = &self.table else {
+            return Ok(());
+        };
+
+        let cost = table.syscalls.get(&call).copied().unwrap_or_default();
+        self.charge(cost)
+    }
+
+    pub fn charge_alloc(&mut self, size: u64) -> Result<()> {
+        let Some(table) = &self.table else {
+            return Ok(());
+        };
+
+        let cost = table.alloc_byte.saturating_mul(size);
+        self.charge(cost)
+    }
+
+    /// Charges the gas used by a program run with the exec system call
+    pub fn charge_exec(&mut self, used: u64) -> Result<()> {
+        if self.table.is_none() {
+            return Ok(());
+        }
+
+        self.charge(used)
+    }
+
+    fn charge(&mut self, cost: u64) -> Result<()> {
+        self.used = self.used.saturating_add(cost);
+
+        match self.table.as_ref().and_then(|table| table.limit) {
+            Some(limit) if self.used > limit => Err(OutOfGas { used: self.used })?,
+            _ => Ok(()),
+        }
+    }
+}
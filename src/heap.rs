@@ -1,16 +1,48 @@
+use std::alloc::{self, Layout};
+use std::ptr;
 use std::sync::Mutex;
 
+/// The first address handed out by a deterministic heap, well above any offset into the program
+const VIRTUAL_BASE: u64 = 1 << 32;
+/// Deterministic allocations are aligned to this many bytes
+const VIRTUAL_ALIGN: u64 = 8;
+
+#[derive(Clone)]
 pub struct Allocation {
     free: bool,
+    /// The address the program uses to refer to the allocation
+    address: u64,
     mem: Box<[u8]>,
 }
 
 impl Allocation {
-    pub fn new(size: usize) -> Self {
+    /// Returns None if the host can't allocate `size` bytes
+    pub fn new(size: usize, address: Option<u64>) -> Option<Self> {
         let free = false;
-        let mem = vec![0; size].into_boxed_slice();
+        let mem = Self::zeroed(size)?;
+        let address = address.unwrap_or(mem.as_ptr() as u64);
+
+        Some(Self { free, address, mem })
+    }
+
+    /// Allocates zeroed memory without panicking or aborting when there isn't enough of it
+    fn zeroed(size: usize) -> Option<Box<[u8]>> {
+        if size == 0 {
+            return Some(Box::default());
+        }
+
+        let layout = Layout::array::<u8>(size).ok()?;
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        if ptr.is_null() {
+            return None;
+        }
 
-        Self { free, mem }
+        Some(unsafe { Box::from_raw(ptr::slice_from_raw_parts_mut(ptr, size)) })
+    }
+
+    fn contains(&self, address: u64, size: usize) -> bool {
+        let end = self.address + self.mem.len() as u64;
+        address >= self.address && address.saturating_add(size as u64) <= end
     }
 }
 
@@ -18,10 +50,65 @@ impl Allocation {
 pub struct Heap {
     allocations: Mutex<Vec<Allocation>>,
     free: Mutex<Vec<usize>>,
+    /// The next address to hand out if allocations should not use their host address
+    next_address: Option<Mutex<u64>>,
+    /// The most bytes the live allocations may take up
+    limit: Option<usize>,
+    /// The most bytes the live allocations have taken up at once
+    peak: Mutex<usize>,
 }
 
 impl Heap {
-    pub fn alloc(&self, size: usize) -> *const u8 {
+    /// Creates a heap which hands out the same addresses for the same sequence of allocations,
+    /// regardless of where the memory lives on the host
+    pub fn deterministic() -> Self {
+        let next_address = Some(Mutex::new(VIRTUAL_BASE));
+
+        Self {
+            next_address,
+            ..Default::default()
+        }
+    }
+
+    /// Caps the bytes taken up by live allocations, past which [`Heap::alloc`] fails
+    pub fn with_limit(mut self, limit: Option<usize>) -> Self {
+        self.limit = limit;
+        self
+    }
+
+    /// Copies the allocations into a heap of their own, which hands out addresses as this one
+    /// would from here on
+    pub fn fork(&self) -> Self {
+        let allocations = Mutex::new(self.allocations.lock().unwrap().clone());
+        let free = Mutex::new(self.free.lock().unwrap().clone());
+        let next_address = self
+            .next_address
+            .as_ref()
+            .map(|next| Mutex::new(*next.lock().unwrap()));
+
+        let peak = Mutex::new(self.peak());
+
+        Self {
+            allocations,
+            free,
+            next_address,
+            limit: self.limit,
+            peak,
+        }
+    }
+
+    pub fn limit(&self) -> Option<usize> {
+        self.limit
+    }
+
+    /// The most bytes the live allocations have taken up at once
+    pub fn peak(&self) -> usize {
+        *self.peak.lock().unwrap()
+    }
+
+    /// Returns the address of a new allocation of `size` bytes, or None if it would take the heap
+    /// past its limit or the host can't allocate it
+    pub fn alloc(&self, size: usize) -> Option<u64> {
         let mut allocations = self.allocations.lock().unwrap();
         let mut free = self.free.lock().unwrap();
 
@@ -29,68 +116,156 @@ impl Heap {
         for (i, id) in free.iter().enumerate() {
             if let Some(alloc) = allocations.get(*id) {
                 if alloc.mem.len() >= size {
-                    found = Some((i, *id, alloc.mem.as_ptr()));
+                    found = Some((i, *id, alloc.address));
                     break;
                 }
             }
         }
 
-        if let Some((i, id, ptr)) = found {
+        // A reused allocation may be larger than asked for, and all of it counts
+        let used = allocations
+            .iter()
+            .filter(|alloc| !alloc.free)
+            .map(|alloc| alloc.mem.len())
+            .sum::<usize>();
+        let size_used = found.map_or(size, |(_, id, _)| allocations[id].mem.len());
+        let total = used.checked_add(size_used)?;
+        if self.limit.is_some_and(|limit| total > limit) {
+            return None;
+        }
+        let mut peak = self.peak.lock().unwrap();
+
+        if let Some((i, id, address)) = found {
+            *peak = (*peak).max(total);
             allocations[id].free = false;
             free.remove(i);
 
-            return ptr;
+            return Some(address);
         }
 
-        let alloc = Allocation::new(size);
-        let ptr = alloc.mem.as_ptr();
+        // The next deterministic address only moves on once the allocation has succeeded
+        let mut next = self.next_address.as_ref().map(|next| next.lock().unwrap());
+        let address = next.as_deref().copied();
+        let following = match address {
+            Some(address) => Some(
+                (size as u64)
+                    .max(1)
+                    .checked_next_multiple_of(VIRTUAL_ALIGN)
+                    .and_then(|size| address.checked_add(size))?,
+            ),
+            None => None,
+        };
+
+        let alloc = Allocation::new(size, address)?;
+        if let (Some(next), Some(following)) = (&mut next, following) {
+            **next = following;
+        }
+        *peak = (*peak).max(total);
+        let address = alloc.address;
         allocations.push(alloc);
 
-        ptr
+        Some(address)
+    }
+
+    /// Copies of the allocations which haven't been freed, with the address of each
+    pub fn live(&self) -> Vec<(u64, Vec<u8>)> {
+        let allocations = self.allocations.lock().unwrap();
+
+        allocations
+            .iter()
+            .filter(|alloc| !alloc.free)
+            .map(|alloc| (alloc.address, alloc.mem.to_vec()))
+            .collect()
     }
 
-    pub fn free(&self, ptr: *const u8) {
+    /// The number of bytes in allocations which haven't been freed
+    pub fn allocated(&self) -> usize {
+        let allocations = self.allocations.lock().unwrap();
+
+        allocations
+            .iter()
+            .filter(|alloc| !alloc.free)
+            .map(|alloc| alloc.mem.len())
+            .sum()
+    }
+
+    /// Frees the live allocation starting at `address`, returning false if there isn't one
+    pub fn free(&self, address: u64) -> bool {
         let mut allocations = self.allocations.lock().unwrap();
         let mut free = self.free.lock().unwrap();
 
         let Some((id, allocation)) = allocations
             .iter_mut()
             .enumerate()
-            .find(|(_, alloc)| alloc.mem.as_ptr() == ptr)
+            .find(|(_, alloc)| !alloc.free && alloc.address == address)
         else {
-            todo!()
+            return false;
         };
 
         allocation.free = true;
         free.push(id);
+        true
     }
 
-    pub fn read(&self, ptr: *const u8, offset: usize, dst: &mut [u8]) -> bool {
+    /// The size of the live allocation starting at `address`
+    pub fn size(&self, address: u64) -> Option<usize> {
         let allocations = self.allocations.lock().unwrap();
 
-        let Some(allocation) = allocations.iter().find(|alloc| alloc.mem.as_ptr() == ptr) else {
-            return false;
-        };
+        allocations
+            .iter()
+            .find(|alloc| !alloc.free && alloc.address == address)
+            .map(|alloc| alloc.mem.len())
+    }
 
-        let size = dst.len();
-        let src = &allocation.mem[offset..];
-        dst[..].copy_from_slice(&src[..size]);
+    /// Returns the host pointer for `size` bytes from `address`, if they lie within a single live
+    /// allocation
+    pub fn translate(&self, address: u64, size: usize) -> Option<*mut u8> {
+        let mut allocations = self.allocations.lock().unwrap();
 
-        true
+        allocations
+            .iter_mut()
+            .filter(|alloc| !alloc.free)
+            .find(|alloc| alloc.contains(address, size))
+            .map(|alloc| {
+                let offset = (address - alloc.address) as usize;
+                alloc.mem[offset..].as_mut_ptr()
+            })
     }
 
-    pub fn write(&self, ptr: *const u8, offset: usize, src: &[u8]) -> bool {
+    /// Copies into `dst` from `offset` bytes past `address`, returning false unless the bytes lie
+    /// within a single live allocation
+    pub fn read(&self, address: u64, offset: usize, dst: &mut [u8]) -> bool {
+        self.with_bytes(address, offset, dst.len(), |src| dst.copy_from_slice(src))
+    }
+
+    /// Copies `src` to `offset` bytes past `address`, returning false unless the bytes lie within
+    /// a single live allocation
+    pub fn write(&self, address: u64, offset: usize, src: &[u8]) -> bool {
+        self.with_bytes(address, offset, src.len(), |dst| dst.copy_from_slice(src))
+    }
+
+    fn with_bytes(
+        &self,
+        address: u64,
+        offset: usize,
+        size: usize,
+        f: impl FnOnce(&mut [u8]),
+    ) -> bool {
         let mut allocations = self.allocations.lock().unwrap();
 
+        let Some(address) = address.checked_add(offset as u64) else {
+            return false;
+        };
         let Some(allocation) = allocations
             .iter_mut()
-            .find(|alloc| alloc.mem.as_ptr() == ptr)
+            .filter(|alloc| !alloc.free)
+            .find(|alloc| alloc.contains(address, size))
         else {
             return false;
         };
 
-        let dst = &mut allocation.mem[offset..];
-        dst[..src.len()].copy_from_slice(src);
+        let from = (address - allocation.address) as usize;
+        f(&mut allocation.mem[from..from + size]);
 
         true
     }
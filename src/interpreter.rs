@@ -1,28 +1,356 @@
-use std::collections::HashSet;
-use std::sync::Arc;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
-use crate::frame::{Frame, FrameResult};
+use crate::explain;
+use crate::extension::check_enabled;
+use crate::frame::{is_builtin_syscall, Frame, FrameResult};
+use crate::gas::{Gas, GasTable, OutOfGas};
 use crate::heap::Heap;
-use crate::locals::Locals;
-use crate::output::Output;
-use crate::program::Program;
-use crate::stack::OperandStack;
-use crate::{Result, SharedWriter};
+use crate::limits::{LimitExceeded, Limits};
+use crate::locals::{LocalOutOfRange, Locals, Scratch};
+#[cfg(feature = "metrics")]
+use crate::metrics::Metrics;
+use crate::objects::Objects;
+use crate::output::{Hooks, Output};
+use crate::program::{Bytecode, Fields, Program};
+use crate::signing::Verifier;
+use crate::stack::{OperandStack, StackValue};
+use crate::verify::verify;
+use crate::{CaptureWriter, Number, PrefixWriter, Result, SharedReader, SharedWriter};
+
+pub use crate::frame::HOST_SYSCALLS;
 
 const MAIN_RETURN: u64 = 0;
 
 pub enum ReturnFrom {
-    Main,
+    Main(RunOutcome),
     Other,
 }
 
+/// The error raised when `aget` or `aput` is given an index past the end of the array
+#[derive(Debug)]
+pub struct IndexOutOfBounds {
+    pub index: u64,
+    pub len: u64,
+}
+
+impl std::fmt::Display for IndexOutOfBounds {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "index out of bounds: the len is {} but the index is {}",
+            self.len, self.index
+        )
+    }
+}
+
+impl std::error::Error for IndexOutOfBounds {}
+
+/// The error raised when integer arithmetic overflows, if [`InterpreterConfig::checked_arithmetic`]
+/// is set
+#[derive(Debug)]
+pub struct ArithmeticOverflow {
+    pub op: Bytecode,
+    /// The position of the instruction
+    pub position: u64,
+}
+
+impl std::fmt::Display for ArithmeticOverflow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "arithmetic overflow in {} at {}", self.op, self.position)
+    }
+}
+
+impl std::error::Error for ArithmeticOverflow {}
+
+/// The error raised when `div` or `rem` is given a divisor of zero
+#[derive(Debug)]
+pub struct DivisionByZero {
+    pub op: Bytecode,
+    /// The position of the instruction
+    pub position: u64,
+}
+
+impl std::fmt::Display for DivisionByZero {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "division by zero in {} at {}", self.op, self.position)
+    }
+}
+
+impl std::error::Error for DivisionByZero {}
+
+/// Counts of what a run has used so far, from [`Interpreter::stats`]
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Stats {
+    /// The instructions run, including those in hooks and one which trapped
+    pub instructions: u64,
+    /// The most bytes the live allocations in the main heap have taken up at once
+    pub peak_heap: usize,
+}
+
+/// How a run of the program ended
+#[derive(Debug, Clone, PartialEq)]
+pub enum RunOutcome {
+    /// Returned from main, with the word from `ret.w` or the double word from `ret.d`
+    Completed { ret: Option<i64> },
+    /// Made the exit system call with this status
+    Exited { code: i32 },
+    /// Stopped by an error or a panic. The backtrace holds the label of each frame, or its entry
+    /// if it has none, from main to the frame which stopped.
+    Trapped {
+        error: String,
+        backtrace: Vec<String>,
+    },
+    /// Used more gas than the limit in [`InterpreterConfig::gas`]
+    FuelExhausted,
+}
+
+impl RunOutcome {
+    /// Converts a trap or running out of gas into an error, for callers which only need to know
+    /// whether the run succeeded
+    pub fn into_result(self) -> Result<Self> {
+        match self {
+            RunOutcome::Trapped { error, .. } => Err(error)?,
+            RunOutcome::FuelExhausted => Err("out of gas")?,
+            outcome => Ok(outcome),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct InterpreterConfig {
+    /// Reject system calls which would write into the program's data or text instead of the heap,
+    /// and `call.ptr` to anything other than the start of a function
+    pub protect_program: bool,
+    /// Use addresses for heap allocations and data which don't depend on the host, so two runs
+    /// of the same program and input behave identically
+    pub deterministic: bool,
+    /// Charge for each instruction, system call and allocated byte
+    pub gas: Option<GasTable>,
+    /// Lay values out on the operand stack at their own width, rather than widening bytes to a
+    /// word slot. This is an experiment: `call`, `ret.n` and the return stack still move whole
+    /// slots, so programs must not mix byte values with wider ones across them.
+    pub packed_stack: bool,
+    /// How large the operand stack, locals, call stack and main heap can grow
+    pub limits: Limits,
+    /// Trap when integer arithmetic overflows, rather than wrapping. This covers `add`, `sub`,
+    /// `mul`, `div`, `rem`, `neg` and `abs` at each width, but not `inc`.
+    pub checked_arithmetic: bool,
+    /// Trap when a system call is given a file descriptor the program doesn't have open, rather
+    /// than failing with `EBADF`
+    pub strict_fds: bool,
+    /// Counters and gauges which are kept up to date while the program runs
+    #[cfg(feature = "metrics")]
+    pub metrics: Option<Arc<Metrics>>,
+}
+
+impl Default for InterpreterConfig {
+    fn default() -> Self {
+        let protect_program = true;
+        let deterministic = false;
+        let gas = None;
+        let packed_stack = false;
+        let limits = Limits::default();
+        let checked_arithmetic = false;
+        let strict_fds = false;
+
+        Self {
+            protect_program,
+            deterministic,
+            gas,
+            packed_stack,
+            limits,
+            checked_arithmetic,
+            strict_fds,
+            #[cfg(feature = "metrics")]
+            metrics: None,
+        }
+    }
+}
+
+impl InterpreterConfig {
+    pub fn deterministic() -> Self {
+        Self {
+            deterministic: true,
+            ..Default::default()
+        }
+    }
+}
+
+/// A read-only view of a frame on the call stack
+#[derive(Clone, Copy)]
+pub struct FrameView<'a> {
+    frame: &'a Frame,
+    label: Option<&'a str>,
+}
+
+impl std::fmt::Display for FrameView<'_> {
+    /// Shows the top of the operand stack
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.frame.opstack.fmt(f)
+    }
+}
+
+impl<'a> FrameView<'a> {
+    /// The position of the first instruction of the function
+    pub fn entry(&self) -> u64 {
+        self.frame.entry
+    }
+
+    /// The label of the function, if the program has one at its entry
+    pub fn label(&self) -> Option<&'a str> {
+        self.label
+    }
+
+    /// The label of the function, or its entry if it has none, such as in a stripped program
+    pub fn name(&self) -> String {
+        match self.label {
+            Some(label) => label.to_string(),
+            None => self.frame.entry.to_string(),
+        }
+    }
+
+    /// The position the function returns to
+    pub fn ret(&self) -> u64 {
+        self.frame.ret
+    }
+
+    /// The bytes on the operand stack, from the bottom
+    pub fn stack(&self) -> &'a [u8] {
+        self.frame.opstack.as_slice()
+    }
+
+    /// The number of word sized slots on the operand stack
+    pub fn depth(&self) -> usize {
+        self.frame.opstack.depth()
+    }
+
+    /// The values on the operand stack, from the bottom, read with the widths in `widths` first
+    /// and as words after them
+    pub fn values(&self, widths: Fields) -> Vec<StackValue> {
+        self.frame.opstack.to_values(widths)
+    }
+
+    /// The values on the operand stack like [`FrameView::values`], along with the offset of each
+    /// in bytes from the bottom
+    pub fn values_at(&self, widths: Fields) -> Vec<(usize, StackValue)> {
+        self.frame.opstack.to_values_at(widths)
+    }
+
+    pub fn peek<N: Number>(&self) -> Option<N> {
+        self.frame.opstack.peek()
+    }
+
+    pub fn local<N: Number>(&self, i: u64) -> Result<N> {
+        Ok(self.frame.locals.read(i)?)
+    }
+
+    /// The bytes of the local slots, as far as they have been allocated
+    pub fn locals(&self) -> &'a [u8] {
+        self.frame.locals.as_slice()
+    }
+}
+
+/// The operand stack of the frame making a system call, as seen by a handler registered with
+/// [`Interpreter::register_syscall`]
+pub struct Operands<'a> {
+    stack: &'a mut OperandStack,
+}
+
+impl Operands<'_> {
+    /// Pops a value, or returns an error if the stack doesn't hold enough bytes for one
+    pub fn pop<N: Number>(&mut self) -> Result<N> {
+        if self.stack.peek::<N>().is_none() {
+            Err("stack underflow in system call")?
+        }
+
+        Ok(self.stack.pop())
+    }
+
+    pub fn push<N: Number>(&mut self, value: N) {
+        self.stack.push(value);
+    }
+
+    pub fn peek<N: Number>(&self) -> Option<N> {
+        self.stack.peek()
+    }
+
+    /// The number of word sized slots on the operand stack
+    pub fn depth(&self) -> usize {
+        self.stack.depth()
+    }
+}
+
+type SharedCapture = Arc<Mutex<CaptureWriter>>;
+type SharedPrefix = Arc<Mutex<PrefixWriter>>;
+
+/// A system call implemented by the embedder
+pub type SyscallHandler = Arc<dyn Fn(&mut Operands) -> Result<()> + Send + Sync>;
+
+/// What the interpreter needs to know about a program besides its bytes
+struct Layout {
+    /// Where the data lies within the program
+    data: Range<usize>,
+    /// Where each instruction starts, in debug builds
+    positions: Option<Vec<u64>>,
+    local_slots: HashMap<u64, u16>,
+}
+
+impl Layout {
+    fn new(output: &Output) -> Self {
+        let data = u64::SIZE..u64::SIZE + output.data().len();
+        let positions = match cfg!(debug_assertions) {
+            true => output.instruction_positions().ok(),
+            false => None,
+        };
+        let local_slots = output.local_slots().clone();
+        Self {
+            data,
+            positions,
+            local_slots,
+        }
+    }
+}
+
 pub struct Interpreter {
     entry: u64,
+    /// The labels from the program, for [`FrameView::label`]
+    labels: HashMap<u64, String>,
     pc: Program<Vec<u8>>,
+    /// The program as it was loaded, since system calls may write into its data when it isn't
+    /// protected. It's copied by the first reset with an unprotected configuration, which comes
+    /// before any unprotected run.
+    image: Option<Vec<u8>>,
     frames: Vec<Frame>,
     heap: Arc<Heap>,
+    stdin: Option<SharedReader>,
     stdout: Option<SharedWriter>,
     stderr: Option<SharedWriter>,
+    /// Copies of what has been written to stdout and stderr, given
+    /// [`Interpreter::with_captured_output`]
+    captured: Option<(SharedCapture, SharedCapture)>,
+    /// The writers tagging stdout and stderr with the current frame, given
+    /// [`Interpreter::with_prefixed_output`]
+    prefixed: Option<(SharedPrefix, SharedPrefix)>,
+    config: Arc<InterpreterConfig>,
+    gas: Gas,
+    /// Slots for `sload` and `sstore`, shared by every frame
+    scratch: Scratch,
+    /// Maps and any other objects the program refers to by handle
+    objects: Objects,
+    hooks: Hooks,
+    /// Heaps created by the host, by name, which frames can be assigned to
+    heaps: HashMap<String, Arc<Heap>>,
+    /// The name of the heap used by calls to each function, by its entry
+    assignments: HashMap<u64, String>,
+    /// Handlers for system calls numbered from [`HOST_SYSCALLS`]
+    syscalls: HashMap<i32, SyscallHandler>,
+    /// The index of the hook frame while a hook is running, since calls within it don't run hooks
+    hook: Option<usize>,
+    exit_code: Option<i32>,
 }
 
 impl Interpreter {
@@ -31,150 +359,2329 @@ impl Interpreter {
         stdout: Option<SharedWriter>,
         stderr: Option<SharedWriter>,
     ) -> Result<Self> {
-        let mut pc = Program::new(output.into());
+        check_enabled(output.extensions())?;
+        Self::load(
+            output.into(),
+            Layout::new(output),
+            output.hooks(),
+            output.labels().clone(),
+            output.metadata().to_vec(),
+            stdout,
+            stderr,
+        )
+    }
+
+    /// Creates an interpreter like [`Interpreter::new`], but takes the program's sections,
+    /// labels and metadata rather than copying them
+    pub fn from_output(
+        output: Output,
+        stdout: Option<SharedWriter>,
+        stderr: Option<SharedWriter>,
+    ) -> Result<Self> {
+        check_enabled(output.extensions())?;
+        let layout = Layout::new(&output);
+        let hooks = output.hooks();
+        let (image, labels, metadata) = output.into_parts();
+        Self::load(image, layout, hooks, labels, metadata, stdout, stderr)
+    }
+
+    fn load(
+        image: Vec<u8>,
+        layout: Layout,
+        hooks: Hooks,
+        labels: HashMap<u64, String>,
+        metadata: Vec<(String, String)>,
+        stdout: Option<SharedWriter>,
+        stderr: Option<SharedWriter>,
+    ) -> Result<Self> {
+        // Functions only called through a pointer aren't in the local slots, so the entries are
+        // only known when there are labels
+        let entries: Option<Vec<u64>> = (!labels.is_empty()).then(|| {
+            labels
+                .keys()
+                .chain(layout.local_slots.keys())
+                .copied()
+                .collect()
+        });
+        let mut pc = Program::new(image)
+            .with_data(layout.data)
+            .with_local_slots(layout.local_slots);
+        if let Some(entries) = entries {
+            pc = pc.with_entries(entries);
+        }
+        // Catch jumps into the middle of an instruction as soon as they happen in debug builds.
+        // The text is decoded again as it runs, so a program which doesn't decode fails then.
+        if let Some(positions) = layout.positions {
+            pc = pc.with_instructions(positions);
+        }
 
         let entry = pc.next::<u64>()?;
         pc.set_position(entry);
 
         let heap = Arc::<Heap>::default();
+        let config = Arc::<InterpreterConfig>::default();
 
         let main = Frame::new(
-            Locals::default(),
+            Locals::sized(pc.local_slots(entry)),
             OperandStack::default(),
             Arc::clone(&heap),
             entry,
             MAIN_RETURN,
+            None,
             stdout.as_ref().map(Arc::clone),
             stderr.as_ref().map(Arc::clone),
+            Arc::clone(&config),
         );
         let frames = vec![main];
+        let stdin = None;
+        let captured = None;
+        let prefixed = None;
+        let gas = Gas::default();
+        let scratch = Scratch::default();
+        let objects = Objects::new(metadata);
+        // Only needed once the program runs unprotected, so it's copied then
+        let image = None;
+        let heaps = HashMap::new();
+        let assignments = HashMap::new();
+        let syscalls = HashMap::new();
+        let hook = None;
+        let exit_code = None;
 
         Ok(Self {
             entry,
+            labels,
             pc,
+            image,
             frames,
             heap,
+            stdin,
             stdout,
             stderr,
+            captured,
+            prefixed,
+            config,
+            gas,
+            scratch,
+            objects,
+            hooks,
+            heaps,
+            assignments,
+            syscalls,
+            hook,
+            exit_code,
         })
     }
 
+    /// Creates an interpreter like [`Interpreter::new`], but only for a program with a signature
+    /// accepted by `verifier`, for hosts which should only run bytecode they have approved
+    pub fn new_verified(
+        output: &Output,
+        stdout: Option<SharedWriter>,
+        stderr: Option<SharedWriter>,
+        verifier: &dyn Verifier,
+    ) -> Result<Self> {
+        output.verify(verifier)?;
+        Self::new(output, stdout, stderr)
+    }
+
+    /// Creates an interpreter like [`Interpreter::new`], but only for a program which passes
+    /// [`verify`], so a corrupt program is rejected before it runs. The first problem found is
+    /// returned as a [`VerifyError`](crate::verify::VerifyError).
+    pub fn new_checked(
+        output: &Output,
+        stdout: Option<SharedWriter>,
+        stderr: Option<SharedWriter>,
+    ) -> Result<Self> {
+        if let Some(err) = verify(output).into_iter().next() {
+            Err(err)?
+        }
+        Self::new(output, stdout, stderr)
+    }
+
+    /// Replaces the configuration and resets the interpreter so the main frame picks it up
+    pub fn with_config(mut self, config: InterpreterConfig) -> Self {
+        self.gas = Gas::new(config.gas.clone());
+        self.config = Arc::new(config);
+        self.reset();
+        self
+    }
+
+    /// Reads from `stdin` instead of the process's standard input when the program reads from
+    /// file descriptor 0
+    pub fn with_stdin(mut self, stdin: SharedReader) -> Self {
+        self.stdin = Some(stdin);
+        self.reset();
+        self
+    }
+
+    /// Keeps a copy of everything the program writes to stdout and stderr, for
+    /// [`Interpreter::captured_stdout`] and [`Interpreter::captured_stderr`]. Output is still
+    /// passed on to the writers given to [`Interpreter::new`], but isn't written to the process's
+    /// streams where they were None.
+    // The capture can't be Send when the writer it forwards to isn't, just like the streams
+    #[allow(clippy::arc_with_non_send_sync)]
+    pub fn with_captured_output(mut self) -> Self {
+        let capture = |writer: &Option<SharedWriter>| {
+            Arc::new(Mutex::new(match writer {
+                Some(writer) => CaptureWriter::forward(Arc::clone(writer)),
+                None => CaptureWriter::new(),
+            }))
+        };
+        let stdout = capture(&self.stdout);
+        let stderr = capture(&self.stderr);
+
+        self.stdout = Some(Arc::clone(&stdout) as SharedWriter);
+        self.stderr = Some(Arc::clone(&stderr) as SharedWriter);
+        self.captured = Some((stdout, stderr));
+        self.reset();
+        self
+    }
+
+    /// Starts each line the program writes to stdout and stderr with the label of the frame
+    /// which started it, or its entry if it has none, such as `[main] `. Output is passed on to
+    /// the writers given to [`Interpreter::new`], or the process's streams where they were None.
+    // The prefixes can't be Send when the writers they forward to aren't, just like the streams
+    #[allow(clippy::arc_with_non_send_sync)]
+    pub fn with_prefixed_output(mut self) -> Self {
+        let stdout = self
+            .stdout
+            .take()
+            .unwrap_or_else(|| Arc::new(Mutex::new(io::stdout())));
+        let stderr = self
+            .stderr
+            .take()
+            .unwrap_or_else(|| Arc::new(Mutex::new(io::stderr())));
+        let stdout = Arc::new(Mutex::new(PrefixWriter::new(stdout)));
+        let stderr = Arc::new(Mutex::new(PrefixWriter::new(stderr)));
+
+        self.stdout = Some(Arc::clone(&stdout) as SharedWriter);
+        self.stderr = Some(Arc::clone(&stderr) as SharedWriter);
+        self.prefixed = Some((stdout, stderr));
+        self.reset();
+        self
+    }
+
+    /// What the program has written to stdout since it was last reset, if the interpreter was
+    /// created [`Interpreter::with_captured_output`]
+    pub fn captured_stdout(&self) -> Option<Vec<u8>> {
+        let (stdout, _) = self.captured.as_ref()?;
+        Some(stdout.lock().unwrap().bytes().to_vec())
+    }
+
+    /// What the program has written to stderr since it was last reset, if the interpreter was
+    /// created [`Interpreter::with_captured_output`]
+    pub fn captured_stderr(&self) -> Option<Vec<u8>> {
+        let (_, stderr) = self.captured.as_ref()?;
+        Some(stderr.lock().unwrap().bytes().to_vec())
+    }
+
+    /// Restarts the program from its entry with an empty heap and the data it was loaded with,
+    /// clearing any captured output
     pub fn reset(&mut self) {
+        if let Some((stdout, stderr)) = &self.captured {
+            stdout.lock().unwrap().clear();
+            stderr.lock().unwrap().clear();
+        }
+        match &self.image {
+            Some(image) => self.pc.restore(image),
+            None if !self.config.protect_program => self.image = Some(self.pc.image().to_vec()),
+            None => {}
+        }
         self.pc.set_position(self.entry);
         self.frames.clear();
+        self.gas.reset();
+        self.scratch = Scratch::default();
+        self.objects.clear();
+        self.hook = None;
+        self.exit_code = None;
+        self.heap = Arc::new(Self::new_heap(&self.config, self.config.limits.heap_size));
+        for heap in self.heaps.values_mut() {
+            *heap = Arc::new(Self::new_heap(&self.config, heap.limit()));
+        }
 
         let main = Frame::new(
-            Locals::default(),
-            OperandStack::default(),
+            Locals::sized(self.pc.local_slots(self.entry)),
+            OperandStack::new(self.config.packed_stack),
             Arc::clone(&self.heap),
             self.entry,
             MAIN_RETURN,
+            self.stdin.as_ref().map(Arc::clone),
             self.stdout.as_ref().map(Arc::clone),
             self.stderr.as_ref().map(Arc::clone),
+            Arc::clone(&self.config),
         );
 
-        self.frames.push(main)
+        self.frames.push(main);
+        self.record_frames();
     }
 
     pub fn position(&self) -> u64 {
         self.pc.position()
     }
 
-    pub fn frames(&self) -> &Vec<Frame> {
-        &self.frames
+    fn new_heap(config: &InterpreterConfig, limit: Option<usize>) -> Heap {
+        let heap = match config.deterministic {
+            true => Heap::deterministic(),
+            false => Heap::default(),
+        };
+
+        heap.with_limit(limit)
     }
 
-    pub fn run(&mut self) -> Result<()> {
-        while let Some(mut current) = self.frames.pop() {
-            let fr = current.run(&mut self.pc)?;
-            match self.handle_frame_result(fr, current)? {
-                Some(ReturnFrom::Main) => break,
-                _ => {}
-            }
+    /// Copies the interpreter as it stands, so the copy can be run on from here without
+    /// affecting this one, such as to try out a branch of the program. The heaps, frames and
+    /// data are copied while the config and standard streams are shared. Only deterministic
+    /// interpreters can be forked, since the program would otherwise hold host addresses of
+    /// memory belonging to this interpreter.
+    pub fn fork(&self) -> Result<Self> {
+        if !self.config.deterministic {
+            Err("only deterministic interpreters can be forked")?
+        }
+
+        let heap = Arc::new(self.heap.fork());
+        let heaps: HashMap<String, Arc<Heap>> = self
+            .heaps
+            .iter()
+            .map(|(name, heap)| (name.clone(), Arc::new(heap.fork())))
+            .collect();
+
+        // Each frame moves over to the copy of the heap it was using
+        let frames = self
+            .frames
+            .iter()
+            .map(|frame| {
+                let copy = self
+                    .heaps
+                    .iter()
+                    .find(|(_, heap)| Arc::ptr_eq(heap, frame.heap()))
+                    .map_or(&heap, |(name, _)| &heaps[name]);
+                frame.fork(Arc::clone(copy))
+            })
+            .collect();
+
+        Ok(Self {
+            entry: self.entry,
+            labels: self.labels.clone(),
+            pc: self.pc.clone(),
+            image: self.image.clone(),
+            frames,
+            heap,
+            stdin: self.stdin.as_ref().map(Arc::clone),
+            stdout: self.stdout.as_ref().map(Arc::clone),
+            stderr: self.stderr.as_ref().map(Arc::clone),
+            captured: self.captured.clone(),
+            prefixed: self.prefixed.clone(),
+            config: Arc::clone(&self.config),
+            gas: self.gas.clone(),
+            scratch: self.scratch.clone(),
+            objects: self.objects.clone(),
+            hooks: self.hooks,
+            heaps,
+            assignments: self.assignments.clone(),
+            syscalls: self.syscalls.clone(),
+            hook: self.hook,
+            exit_code: self.exit_code,
+        })
+    }
+
+    /// Creates a heap called `name` holding at most `limit` bytes of live allocations, for
+    /// [`Interpreter::assign_heap`]. Once it's full, `alloc` pushes a null pointer rather than
+    /// taking memory from frames using other heaps.
+    pub fn create_heap(&mut self, name: &str, limit: Option<usize>) -> Result<()> {
+        if self.heaps.contains_key(name) {
+            Err(format!("heap already exists: {name}"))?
         }
 
+        let heap = Arc::new(Self::new_heap(&self.config, limit));
+        self.heaps.insert(name.to_string(), heap);
+
         Ok(())
     }
 
-    /// Returns true if returning from the main routine
-    pub fn run_until(&mut self, breakpoints: &HashSet<u64>) -> Result<bool> {
-        loop {
-            let Some(position) = self.step()? else {
-                return Ok(true);
-            };
+    /// Handles the system call numbered `call` with `handler`, which pops its arguments from and
+    /// pushes its results to the operand stack of the calling frame. Numbers below
+    /// [`HOST_SYSCALLS`] and those of the interpreter's own helpers can't be registered. An error
+    /// returned by the handler stops the program.
+    pub fn register_syscall(
+        &mut self,
+        call: i32,
+        handler: impl Fn(&mut Operands) -> Result<()> + Send + Sync + 'static,
+    ) -> Result<()> {
+        if is_builtin_syscall(call) {
+            Err(format!("system call is reserved: {call}"))?
+        }
 
-            if breakpoints.contains(&position) {
-                break;
-            }
+        if self.syscalls.contains_key(&call) {
+            Err(format!("system call already registered: {call}"))?
         }
+        self.syscalls.insert(call, Arc::new(handler));
 
-        Ok(false)
+        Ok(())
     }
 
-    /// Results None if returning from the main routine
-    pub fn step(&mut self) -> Result<Option<u64>> {
-        let Some(mut current) = self.frames.pop() else {
-            unreachable!()
+    /// Makes calls to the function at `label`, and any calls they make in turn, allocate from the
+    /// heap called `heap`. Pointers into one heap aren't valid in frames using another.
+    pub fn assign_heap(&mut self, label: &str, heap: &str) -> Result<()> {
+        if !self.heaps.contains_key(heap) {
+            Err(format!("no such heap: {heap}"))?
+        }
+
+        let Some((&entry, _)) = self.labels.iter().find(|(_, have)| *have == label) else {
+            Err(format!("invalid label, could not find position: {label}"))?
         };
+        self.assignments.insert(entry, heap.to_string());
 
-        if let Some(fr) = current.step(&mut self.pc)? {
-            match self.handle_frame_result(fr, current)? {
-                Some(ReturnFrom::Main) => return Ok(None),
-                _ => {}
-            }
-        } else {
-            self.frames.push(current);
+        Ok(())
+    }
+
+    /// The bytes taken up by live allocations in the heap called `name`
+    pub fn heap_used(&self, name: &str) -> Option<usize> {
+        self.heaps.get(name).map(|heap| heap.allocated())
+    }
+
+    /// The frames on the call stack, from main to the current frame
+    pub fn frames(&self) -> impl DoubleEndedIterator<Item = FrameView<'_>> + ExactSizeIterator {
+        self.frames.iter().map(|frame| FrameView {
+            frame,
+            label: self.labels.get(&frame.entry).map(String::as_str),
+        })
+    }
+
+    /// The frame currently running, or the one which stopped the program
+    pub fn current_frame(&self) -> FrameView<'_> {
+        self.frames().last().unwrap()
+    }
+
+    pub(crate) fn heap(&self) -> &Heap {
+        &self.heap
+    }
+
+    /// Converts an address pushed by `dataptr` back into a position in the program
+    pub(crate) fn program_position(&self, address: u64) -> u64 {
+        match self.config.deterministic {
+            true => address,
+            false => address.wrapping_sub(self.pc.getptr(0) as u64),
         }
+    }
 
-        Ok(Some(self.pc.position()))
+    /// The status passed to the exit system call, or the word returned from main with `ret.w`
+    pub fn exit_code(&self) -> Option<i32> {
+        self.exit_code
     }
 
-    fn handle_frame_result(
+    /// The values of the slots used by `sload` and `sstore`
+    pub fn scratch(&self) -> &[i32] {
+        self.scratch.as_slice()
+    }
+
+    /// The gas used since the program started, according to [`InterpreterConfig::gas`]
+    pub fn gas_used(&self) -> u64 {
+        self.gas.used()
+    }
+
+    /// The instructions run and heap used since the program started
+    pub fn stats(&self) -> Stats {
+        Stats {
+            instructions: self.gas.instructions(),
+            peak_heap: self.heap.peak(),
+        }
+    }
+
+    /// Runs the program until it returns from main, exits, or stops with an error. The state is
+    /// kept afterwards so it can be inspected.
+    pub fn run(&mut self) -> RunOutcome {
+        let result = self.run_frames();
+        self.outcome(result)
+    }
+
+    /// Runs the program like [`Interpreter::run`], writing each instruction run within the
+    /// function at `label` to `w` before running it. With `nested`, the functions it calls are
+    /// traced too, indented by how deep they are. Returns an error if there is no such label.
+    pub fn run_traced(
         &mut self,
-        fr: FrameResult,
-        mut current: Frame,
-    ) -> Result<Option<ReturnFrom>> {
-        let last = self.frames.len().saturating_sub(1);
-        let main = self.entry == current.entry;
+        label: &str,
+        nested: bool,
+        w: &mut impl io::Write,
+    ) -> Result<RunOutcome> {
+        let Some(entry) = self
+            .labels
+            .iter()
+            .find_map(|(&entry, have)| (have == label).then_some(entry))
+        else {
+            Err(format!("unknown label: {label}"))?
+        };
 
-        let ret = match fr {
-            FrameResult::Call(next) => {
-                self.pc.set_position(next.entry);
-                self.frames.push(current);
-                self.frames.push(next);
-                None
+        let result = loop {
+            if let Some(depth) = self.traced_depth(entry, nested) {
+                if let Err(err) = self.trace(w, depth) {
+                    break Err(err);
+                }
             }
-            FrameResult::Ret(position)
-            | FrameResult::RetW(position)
-            | FrameResult::RetD(position)
-                if main =>
-            {
-                // Make it appear as if the pc is still pointing to the return instruction
-                self.pc.set_position(position);
-                self.frames.push(current);
-                Some(ReturnFrom::Main)
+
+            match self.step_frame() {
+                Ok(Some(outcome)) => break Ok(outcome),
+                Ok(None) => {}
+                Err(err) => break Err(err),
             }
-            FrameResult::Ret(_) => {
-                self.pc.set_position(current.ret);
-                Some(ReturnFrom::Other)
+        };
+
+        Ok(self.outcome(result))
+    }
+
+    /// How many calls the current frame is below the innermost frame of the function at `entry`,
+    /// or None if the instruction it's about to run isn't traced
+    fn traced_depth(&self, entry: u64, nested: bool) -> Option<usize> {
+        let depth = self
+            .frames
+            .iter()
+            .rev()
+            .position(|frame| frame.entry == entry)?;
+        (nested || depth == 0).then_some(depth)
+    }
+
+    /// Writes the instruction about to run, such as `loop+5: inc 0, 1`
+    fn trace(&self, w: &mut impl io::Write, depth: usize) -> Result<()> {
+        let position = self.pc.position();
+        let mut pc = Program::new(self.pc.image());
+        pc.set_position(position);
+        let instr = pc.next_instr()?;
+
+        let location = self.locate(position);
+        write!(w, "{:indent$}{location}: {instr}", "", indent = depth * 2)?;
+        if let Some(label) = instr.target().and_then(|target| self.labels.get(&target)) {
+            write!(w, " ; {label}")?;
+        }
+        writeln!(w)?;
+
+        Ok(())
+    }
+
+    /// The position written as the label before it and the offset from it, such as `loop+5`
+    fn locate(&self, position: u64) -> String {
+        let label = self
+            .labels
+            .iter()
+            .filter(|(&start, _)| start <= position)
+            .max_by_key(|(&start, _)| start);
+        match label {
+            Some((&start, label)) if start == position => label.clone(),
+            Some((&start, label)) => format!("{label}+{}", position - start),
+            None => position.to_string(),
+        }
+    }
+
+    /// Runs the program like [`Interpreter::run`], writing each instruction to `w` after it
+    /// runs along with what it did in plain words, such as
+    /// `main+10: add ; pop 2 words, add them, push the result (3 + 4 = 7)`. Meant for learning
+    /// how a stack machine works rather than for debugging, since it's slow.
+    pub fn run_explained(&mut self, w: &mut impl io::Write) -> Result<RunOutcome> {
+        let result = loop {
+            let position = self.pc.position();
+            let mut pc = Program::new(self.pc.image());
+            pc.set_position(position);
+            let instr = match pc.next_instr() {
+                Ok(instr) => instr,
+                Err(err) => break Err(err),
+            };
+            let popped = match self.frames.last() {
+                Some(frame) => explain::popped(instr.op(), &frame.opstack),
+                None => Vec::new(),
+            };
+
+            let depth = self.frames.len();
+            let step = self.step_frame();
+            // What it pushed is on top of the same frame, unless it called or returned, and
+            // neither is shown if it trapped
+            let (popped, pushed) = match (&step, self.frames.last()) {
+                (Ok(None), Some(frame)) if self.frames.len() == depth => {
+                    (popped, explain::pushed(instr.op(), &frame.opstack))
+                }
+                (Ok(_), _) => (popped, None),
+                (Err(_), _) => (Vec::new(), None),
+            };
+
+            let explanation =
+                explain::explain(instr, &popped, pushed, |target| self.locate(target));
+            if let Err(err) = writeln!(w, "{}: {instr} ; {explanation}", self.locate(position)) {
+                break Err(err.into());
             }
-            FrameResult::RetW(_) => {
-                self.pc.set_position(current.ret);
-                self.frames[last].opstack.push::<i32>(current.opstack.pop());
-                Some(ReturnFrom::Other)
+
+            match step {
+                Ok(Some(outcome)) => break Ok(outcome),
+                Ok(None) => {}
+                Err(err) => break Err(err),
             }
-            FrameResult::RetD(_) => {
-                self.pc.set_position(current.ret);
-                self.frames[last].opstack.push::<i64>(current.opstack.pop());
-                Some(ReturnFrom::Other)
+        };
+
+        Ok(self.outcome(result))
+    }
+
+    /// Runs the program like [`Interpreter::run`], writing an event to `w` as each function is
+    /// entered and left in the Chrome trace event format, which Perfetto and `chrome://tracing`
+    /// show as a timeline. Timestamps are in microseconds from the start of the run. Every
+    /// function is on the one thread, as the program has a single thread of execution.
+    pub fn run_chrome_trace(&mut self, w: &mut impl io::Write) -> Result<RunOutcome> {
+        let start = Instant::now();
+        // Naming the thread first means each event after it can be written with a leading comma
+        write!(
+            w,
+            r#"[{{"name":"thread_name","ph":"M","pid":1,"tid":1,"args":{{"name":"main"}}}}"#
+        )?;
+
+        let mut open = Vec::new();
+        let result = loop {
+            if let Err(err) = self.trace_calls(w, &mut open, start) {
+                break Err(err);
             }
-            FrameResult::Panic(_) => {
-                // Push the frame back on so we can inspect it
-                self.frames.push(current);
-                Err("panic")?
+
+            match self.step_frame() {
+                Ok(Some(outcome)) => break Ok(outcome),
+                Ok(None) => {}
+                Err(err) => break Err(err),
             }
         };
 
+        // Whatever was still running ends with the run, however it ended
+        let ts = start.elapsed().as_secs_f64() * 1e6;
+        while let Some(entry) = open.pop() {
+            self.trace_event(w, entry, "E", ts)?;
+        }
+        writeln!(w)?;
+        writeln!(w, "]")?;
+
+        Ok(self.outcome(result))
+    }
+
+    /// Writes an event for each function left and entered since `open` was brought up to date
+    /// with the frames
+    fn trace_calls(
+        &self,
+        w: &mut impl io::Write,
+        open: &mut Vec<u64>,
+        start: Instant,
+    ) -> Result<()> {
+        let entries: Vec<u64> = self.frames.iter().map(|frame| frame.entry).collect();
+        let kept = open
+            .iter()
+            .zip(&entries)
+            .take_while(|(open, entry)| open == entry)
+            .count();
+
+        let ts = start.elapsed().as_secs_f64() * 1e6;
+        while open.len() > kept {
+            let entry = open.pop().unwrap();
+            self.trace_event(w, entry, "E", ts)?;
+        }
+        for &entry in &entries[kept..] {
+            self.trace_event(w, entry, "B", ts)?;
+            open.push(entry);
+        }
+
+        Ok(())
+    }
+
+    /// Writes the start, `B`, or end, `E`, of the function at `entry`
+    fn trace_event(&self, w: &mut impl io::Write, entry: u64, phase: &str, ts: f64) -> Result<()> {
+        let name = match self.labels.get(&entry) {
+            Some(label) => label.clone(),
+            None => entry.to_string(),
+        };
+        // Labels are identifiers, so quoting them as Rust strings also gives valid JSON
+        writeln!(w, ",")?;
+        write!(
+            w,
+            r#"{{"name":{name:?},"ph":"{phase}","ts":{ts:.3},"pid":1,"tid":1}}"#
+        )?;
+
+        Ok(())
+    }
+
+    /// Turns the result of running the program into how it ended
+    fn outcome(&self, result: Result<RunOutcome>) -> RunOutcome {
+        match result {
+            Ok(outcome) => outcome,
+            Err(err) if err.is::<OutOfGas>() => RunOutcome::FuelExhausted,
+            Err(mut err) => {
+                // The frame only knows its entry, so name it by its label
+                if let Some(err) = err.downcast_mut::<LocalOutOfRange>() {
+                    err.frame = Some(self.current_frame().name());
+                }
+
+                RunOutcome::Trapped {
+                    error: err.to_string(),
+                    backtrace: self.frames().map(|frame| frame.name()).collect(),
+                }
+            }
+        }
+    }
+
+    fn run_frames(&mut self) -> Result<RunOutcome> {
+        while let Some(mut current) = self.frames.pop() {
+            let fr = match current.run(
+                &mut self.pc,
+                &mut self.gas,
+                &mut self.scratch,
+                &mut self.objects,
+            ) {
+                Ok(fr) => fr,
+                Err(err) => {
+                    // Push the frame back on so we can inspect it
+                    self.frames.push(current);
+                    return Err(err);
+                }
+            };
+            if let Some(ReturnFrom::Main(outcome)) = self.handle_frame_result(fr, current)? {
+                return Ok(outcome);
+            }
+        }
+
+        Ok(RunOutcome::Completed { ret: None })
+    }
+
+    /// Returns true if returning from the main routine
+    pub fn run_until(&mut self, breakpoints: &HashSet<u64>) -> Result<bool> {
+        loop {
+            let Some(position) = self.step()? else {
+                return Ok(true);
+            };
+
+            if breakpoints.contains(&position) {
+                break;
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// Results None if returning from the main routine
+    pub fn step(&mut self) -> Result<Option<u64>> {
+        match self.step_frame()? {
+            Some(_) => Ok(None),
+            None => Ok(Some(self.pc.position())),
+        }
+    }
+
+    /// Runs one instruction, returning how the program ended if it returned from main
+    fn step_frame(&mut self) -> Result<Option<RunOutcome>> {
+        let Some(mut current) = self.frames.pop() else {
+            unreachable!()
+        };
+
+        let fr = match current.step(
+            &mut self.pc,
+            &mut self.gas,
+            &mut self.scratch,
+            &mut self.objects,
+        ) {
+            Ok(fr) => fr,
+            Err(err) => {
+                self.frames.push(current);
+                return Err(err);
+            }
+        };
+
+        if let Some(fr) = fr {
+            if let Some(ReturnFrom::Main(outcome)) = self.handle_frame_result(fr, current)? {
+                return Ok(Some(outcome));
+            }
+        } else {
+            self.frames.push(current);
+        }
+
+        Ok(None)
+    }
+
+    fn handle_frame_result(
+        &mut self,
+        fr: FrameResult,
+        mut current: Frame,
+    ) -> Result<Option<ReturnFrom>> {
+        let last = self.frames.len().saturating_sub(1);
+        let main = self.entry == current.entry;
+        let entry = current.entry;
+
+        // A hook goes back to where the call or return it ran around was going
+        if self.hook == Some(self.frames.len()) {
+            match fr {
+                FrameResult::Ret(_) => {
+                    self.hook = None;
+                    self.pc.set_position(current.ret);
+                    self.record_frames();
+                    return Ok(Some(ReturnFrom::Other));
+                }
+                FrameResult::RetB(position)
+                | FrameResult::RetW(position)
+                | FrameResult::RetD(position)
+                | FrameResult::RetN(position, _) => {
+                    self.pc.set_position(position);
+                    self.frames.push(current);
+                    return Err("hooks must return with ret".into());
+                }
+                _ => {}
+            }
+        }
+
+        // Stop while the frame can still be inspected if it's calling or returning into the
+        // middle of an instruction
+        let jump = match &fr {
+            FrameResult::Call(position, next) => Some((*position, next.entry)),
+            FrameResult::Ret(position)
+            | FrameResult::RetB(position)
+            | FrameResult::RetW(position)
+            | FrameResult::RetD(position)
+            | FrameResult::RetN(position, _)
+                if !main =>
+            {
+                Some((*position, current.ret))
+            }
+            _ => None,
+        };
+        if let Some((from, to)) = jump {
+            if let Err(err) = self.pc.check_jump(from, to) {
+                self.pc.set_position(from);
+                self.frames.push(current);
+                return Err(err);
+            }
+        }
+
+        // The frames on the call stack are the current one and those below it
+        if let (FrameResult::Call(position, _), Some(depth)) = (&fr, self.config.limits.call_depth)
+        {
+            if self.frames.len() + 1 >= depth {
+                self.pc.set_position(*position);
+                self.frames.push(current);
+                return Err(LimitExceeded::new("call depth", depth).into());
+            }
+        }
+
+        let ret = match fr {
+            FrameResult::Call(_, mut next) => {
+                if let Some(heap) = self.assignments.get(&next.entry) {
+                    next = next.with_heap(Arc::clone(&self.heaps[heap]));
+                }
+                self.pc.set_position(next.entry);
+                self.frames.push(current);
+                self.frames.push(next);
+                if let Some(hook) = self.hooks.enter {
+                    // The hook returns to the start of the callee
+                    let ret = self.pc.position();
+                    self.call_hook(hook, ret, ret);
+                }
+                None
+            }
+            FrameResult::Ret(position)
+            | FrameResult::RetB(position)
+            | FrameResult::RetW(position)
+            | FrameResult::RetD(position)
+            | FrameResult::RetN(position, _)
+                if main =>
+            {
+                // A word returned from main is the exit status. It's left on the stack so it can
+                // still be inspected.
+                let ret = match fr {
+                    FrameResult::RetW(_) => {
+                        self.exit_code = current.opstack.peek::<i32>();
+                        self.exit_code.map(i64::from)
+                    }
+                    FrameResult::RetB(_) => current.opstack.peek::<i8>().map(i64::from),
+                    FrameResult::RetD(_) => current.opstack.peek::<i64>(),
+                    _ => None,
+                };
+
+                // Make it appear as if the pc is still pointing to the return instruction
+                self.pc.set_position(position);
+                self.frames.push(current);
+                Some(ReturnFrom::Main(RunOutcome::Completed { ret }))
+            }
+            FrameResult::Ret(_) => {
+                self.pc.set_position(current.ret);
+                Some(ReturnFrom::Other)
+            }
+            FrameResult::RetB(_) => {
+                self.pc.set_position(current.ret);
+                self.frames[last].opstack.push::<i8>(current.opstack.pop());
+                Some(ReturnFrom::Other)
+            }
+            FrameResult::RetW(_) => {
+                self.pc.set_position(current.ret);
+                self.frames[last].opstack.push::<i32>(current.opstack.pop());
+                Some(ReturnFrom::Other)
+            }
+            FrameResult::RetD(_) => {
+                self.pc.set_position(current.ret);
+                self.frames[last].opstack.push::<i64>(current.opstack.pop());
+                Some(ReturnFrom::Other)
+            }
+            FrameResult::RetN(_, count) => {
+                self.pc.set_position(current.ret);
+                let slots = current.opstack.pop_slots(count);
+                self.frames[last].opstack.push_slots(&slots);
+                Some(ReturnFrom::Other)
+            }
+            FrameResult::Exit(code) => {
+                self.exit_code = Some(code);
+                self.frames.push(current);
+                Some(ReturnFrom::Main(RunOutcome::Exited { code }))
+            }
+            FrameResult::Syscall(call) => {
+                let result = match self.syscalls.get(&call) {
+                    Some(handler) => handler(&mut Operands {
+                        stack: &mut current.opstack,
+                    }),
+                    None => Err(format!("invalid system call: {call}").into()),
+                };
+                self.frames.push(current);
+                result?;
+                None
+            }
+            FrameResult::Panic(position, message) => {
+                // Push the frame back on and point at the panic so we can inspect it
+                self.pc.set_position(position);
+                self.frames.push(current);
+                match message {
+                    Some(message) => Err(format!("panic at {position}: {message}"))?,
+                    None => Err("panic")?,
+                }
+            }
+        };
+
+        if let (Some(ReturnFrom::Other), Some(hook)) = (&ret, self.hooks.exit) {
+            self.call_hook(hook, entry, self.pc.position());
+        }
+        self.record_frames();
+
         Ok(ret)
     }
+
+    /// Pushes a frame for the hook at `hook` with `entry` in its first two slots, returning to
+    /// `ret`. Does nothing if a hook is already running.
+    fn call_hook(&mut self, hook: u64, entry: u64, ret: u64) {
+        if self.hook.is_some() {
+            return;
+        }
+
+        let mut locals = Locals::sized(self.pc.local_slots(hook));
+        locals.copy_from_slice(&entry.to_le_bytes());
+        let frame = Frame::new(
+            locals,
+            OperandStack::new(self.config.packed_stack),
+            Arc::clone(&self.heap),
+            hook,
+            ret,
+            self.stdin.as_ref().map(Arc::clone),
+            self.stdout.as_ref().map(Arc::clone),
+            self.stderr.as_ref().map(Arc::clone),
+            Arc::clone(&self.config),
+        );
+
+        self.hook = Some(self.frames.len());
+        self.frames.push(frame);
+        self.pc.set_position(hook);
+    }
+
+    /// Brings the frame count metric and the tag on prefixed output up to date after the call
+    /// stack has changed
+    fn record_frames(&self) {
+        if let Some((stdout, stderr)) = &self.prefixed {
+            let frame = self.current_frame();
+            let tag = frame
+                .label()
+                .map_or_else(|| frame.entry().to_string(), String::from);
+            stdout.lock().unwrap().set_tag(&tag);
+            stderr.lock().unwrap().set_tag(&tag);
+        }
+
+        #[cfg(feature = "metrics")]
+        if let Some(metrics) = &self.config.metrics {
+            metrics.set_frames(self.frames.len());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    use crate::assembler::Assembler;
+    use crate::limits::Limits;
+    use crate::output::Output;
+    use crate::program::{Bytecode, Fields};
+    use crate::signing::KeyedHash;
+    use crate::verify::VerifyError;
+    use crate::{Number, Result, SharedWriter};
+
+    use super::{GasTable, Interpreter, InterpreterConfig, RunOutcome, StackValue, Stats};
+
+    #[test]
+    fn test_deterministic() -> Result<()> {
+        let src = "
+.entry main
+
+.data message .string \"abc\"
+
+main:
+    push 1
+    dataptr message
+    push.d 3
+    push 4
+    system
+    pop
+    push.d 12
+    alloc
+    push.d 1
+    alloc
+    dataptr message
+    ret
+";
+        let output = Assembler::new().assemble(src)?;
+
+        let mut stacks = Vec::new();
+        for _ in 0..2 {
+            let mut interpreter = Interpreter::new(&output, None, None)?
+                .with_config(InterpreterConfig::deterministic())
+                .with_captured_output();
+            interpreter.run().into_result()?;
+
+            assert_eq!(interpreter.captured_stdout().unwrap(), b"abc");
+
+            stacks.push(interpreter.current_frame().stack().to_vec());
+        }
+
+        assert_eq!(stacks[0], stacks[1]);
+
+        #[rustfmt::skip]
+        let want: Vec<u8> = vec![
+            0, 0, 0, 0, 1, 0, 0, 0,  // First allocation
+            16, 0, 0, 0, 1, 0, 0, 0, // Second allocation, aligned to 8 bytes
+            8, 0, 0, 0, 0, 0, 0, 0,  // Data offset
+        ];
+        assert_eq!(want, stacks[0]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_frames() -> Result<()> {
+        let src = "
+.entry main
+
+main:
+    push 1
+    push 2
+    call add
+    ret
+
+add:
+    load 0
+    load 1
+    add
+    ret.w
+";
+        let output = Assembler::new().assemble(src)?;
+        let mut interpreter = Interpreter::new(&output, None, None)?;
+
+        // Step into add and past its loads
+        for _ in 0..5 {
+            interpreter.step()?;
+        }
+
+        let frames: Vec<_> = interpreter.frames().collect();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].label(), Some("main"));
+        assert_eq!(frames[1].label(), Some("add"));
+        assert_eq!(frames[1].entry(), 28);
+        assert_eq!(frames[1].ret(), 27);
+        assert_eq!(frames[1].local::<i32>(1)?, 2);
+        assert_eq!(frames[1].depth(), 2);
+        assert_eq!(frames[1].peek::<i32>(), Some(2));
+        assert_eq!(frames[0].stack(), []);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_stats() -> Result<()> {
+        let src = "
+.entry main
+
+main:
+    push.d 16
+    alloc
+    push.d 8
+    alloc
+    free
+    free
+    push.d 4
+    alloc
+    free
+    ret
+";
+        let output = Assembler::new().assemble(src)?;
+        let mut interpreter = Interpreter::new(&output, None, None)?;
+        interpreter.run().into_result()?;
+
+        // The peak is both allocations before either was freed
+        let want = Stats {
+            instructions: 10,
+            peak_heap: 24,
+        };
+        assert_eq!(interpreter.stats(), want);
+
+        interpreter.reset();
+        assert_eq!(interpreter.stats(), Stats::default());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_gas() -> Result<()> {
+        let src = "
+.entry main
+
+main:
+    push 0
+    store 0
+loop:
+    load 0
+    push 1
+    add
+    dup
+    store 0
+    push 3
+    cmp
+    jmp.lt loop
+    push 1
+    push.d 10
+    alloc
+    push.d 0
+    push 4
+    system
+    pop
+    ret
+";
+        let output = Assembler::new().assemble(src)?;
+
+        // 2 instructions before the loop, 8 in each of the 3 iterations and 8 after
+        let config = InterpreterConfig {
+            gas: Some(GasTable::default()),
+            ..Default::default()
+        };
+        let stdout = Arc::new(Mutex::new(Vec::new()));
+        let mut interpreter =
+            Interpreter::new(&output, Some(stdout.clone()), None)?.with_config(config);
+        interpreter.run().into_result()?;
+        assert_eq!(interpreter.gas_used(), 34);
+
+        let mut table = GasTable::default();
+        table.instructions.insert(Bytecode::Add, 10);
+        table.syscalls.insert(4, 100);
+        table.alloc_byte = 2;
+        let config = InterpreterConfig {
+            gas: Some(table.clone()),
+            ..Default::default()
+        };
+        let mut interpreter =
+            Interpreter::new(&output, Some(stdout.clone()), None)?.with_config(config);
+        interpreter.run().into_result()?;
+        assert_eq!(interpreter.gas_used(), 34 + 3 * 9 + 20 + 100);
+
+        table.limit = Some(100);
+        let config = InterpreterConfig {
+            gas: Some(table),
+            ..Default::default()
+        };
+        let mut interpreter = Interpreter::new(&output, Some(stdout), None)?.with_config(config);
+        assert_eq!(interpreter.run(), RunOutcome::FuelExhausted);
+        assert_eq!(interpreter.gas_used(), 2 + 3 * 17 + 3 + 20 + 3 + 100);
+
+        Ok(())
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn test_metrics() -> Result<()> {
+        use crate::metrics::Metrics;
+
+        let src = "
+.entry main
+
+main:
+    push.d 16
+    alloc
+    call f
+    push 1
+    push.d 8
+    alloc
+    push.d 0
+    push 4
+    system
+    pop
+    ret
+
+f:
+    push.d 4
+    alloc
+    free
+    ret
+";
+        let output = Assembler::new().assemble(src)?;
+
+        let metrics = Arc::new(Metrics::default());
+        let config = InterpreterConfig {
+            metrics: Some(Arc::clone(&metrics)),
+            ..Default::default()
+        };
+        let stdout = Arc::new(Mutex::new(Vec::new()));
+        let mut interpreter = Interpreter::new(&output, Some(stdout), None)?.with_config(config);
+        interpreter.run().into_result()?;
+
+        assert_eq!(metrics.instructions(), 15);
+        assert_eq!(metrics.syscalls(), 1);
+        assert_eq!(metrics.heap_bytes(), 24);
+        assert_eq!(metrics.frames(), 1);
+        assert!(metrics
+            .render()
+            .contains("# TYPE stack_heap_bytes gauge\nstack_heap_bytes 24\n"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_named_heaps() -> Result<()> {
+        let src = "
+.entry main
+
+main:
+    call tenant
+    store.d 0
+    call tenant
+    store.d 2
+    push.d 64
+    alloc
+    load.d 0
+    load.d 2
+    ret
+
+tenant:
+    push.d 16
+    alloc
+    ret.d
+";
+        let output = Assembler::new().assemble(src)?;
+        let mut interpreter = Interpreter::new(&output, None, None)?;
+        interpreter.create_heap("tenant", Some(24))?;
+        interpreter.assign_heap("tenant", "tenant")?;
+        assert!(interpreter.create_heap("tenant", None).is_err());
+        assert!(interpreter.assign_heap("main", "other").is_err());
+        interpreter.run().into_result()?;
+
+        // The second allocation doesn't fit in the tenant's heap, while main's is unaffected
+        let pointers = interpreter
+            .current_frame()
+            .stack()
+            .chunks(8)
+            .map(<u64 as Number>::from_le_bytes)
+            .collect::<Vec<_>>();
+        assert!(pointers[0] != 0);
+        assert!(pointers[1] != 0);
+        assert_eq!(pointers[2], 0);
+        assert_eq!(interpreter.heap_used("tenant"), Some(16));
+        assert_eq!(interpreter.heap().allocated(), 64);
+
+        interpreter.reset();
+        assert_eq!(interpreter.heap_used("tenant"), Some(0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fork() -> Result<()> {
+        let src = "
+.entry main
+
+main:
+    push.d 8
+    alloc
+    store.d 0
+    load.d 0
+    push.d 0
+    push 1
+    astore
+    load.d 0
+    push.d 0
+    push 2
+    astore
+    push 3
+    ret.w
+";
+        let output = Assembler::new().assemble(src)?;
+        let interpreter = Interpreter::new(&output, None, None)?;
+        assert!(interpreter.fork().is_err());
+
+        let mut interpreter = interpreter.with_config(InterpreterConfig::deterministic());
+        // Stop after the first store
+        for _ in 0..7 {
+            interpreter.step()?;
+        }
+        let position = interpreter.position();
+
+        let mut fork = interpreter.fork()?;
+        assert_eq!(fork.position(), position);
+        assert_eq!(fork.run(), RunOutcome::Completed { ret: Some(3) });
+        assert_eq!(fork.heap().live()[0].1[..4], [2, 0, 0, 0]);
+
+        // The original is left where it was
+        assert_eq!(interpreter.position(), position);
+        assert_eq!(interpreter.heap().live()[0].1[..4], [1, 0, 0, 0]);
+        assert_eq!(interpreter.run(), RunOutcome::Completed { ret: Some(3) });
+        assert_eq!(interpreter.heap().live(), fork.heap().live());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_register_syscall() -> Result<()> {
+        let src = "
+.entry main
+
+main:
+    push 3
+    push.d 4
+    push 2000
+    system
+    push 2001
+    system
+    ret
+";
+        let output = Assembler::new().assemble(src)?;
+        let mut interpreter = Interpreter::new(&output, None, None)?;
+
+        // Multiplies a word by a double word
+        interpreter.register_syscall(2000, |operands| {
+            let b = operands.pop::<i64>()?;
+            let a = operands.pop::<i32>()?;
+            operands.push(i64::from(a) * b);
+            Ok(())
+        })?;
+        assert!(interpreter.register_syscall(2000, |_| Ok(())).is_err());
+        assert!(interpreter.register_syscall(5, |_| Ok(())).is_err());
+        assert!(interpreter.register_syscall(1003, |_| Ok(())).is_err());
+
+        let RunOutcome::Trapped { error, .. } = interpreter.run() else {
+            panic!("expected the unregistered system call to trap");
+        };
+        assert_eq!(error, "invalid system call: 2001");
+        assert_eq!(interpreter.current_frame().peek::<i64>(), Some(12));
+
+        interpreter.reset();
+        interpreter.register_syscall(2001, |operands| {
+            let depth = operands.depth() as i32;
+            operands.push(depth);
+            Ok(())
+        })?;
+        assert_eq!(interpreter.run(), RunOutcome::Completed { ret: None });
+        assert_eq!(interpreter.current_frame().peek::<i32>(), Some(2));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_exec() -> Result<()> {
+        let run = |child: &str| -> Result<(i64, i32)> {
+            let child = Assembler::new().assemble(child)?.serialise();
+            let bytes: String = child
+                .iter()
+                .map(|b| format!(" .byte {}", *b as i8))
+                .collect();
+            let src = format!(
+                "
+.entry main
+
+.data child{bytes}
+
+main:
+    dataptr child
+    push.d sizeof child
+    push.d 100
+    push 1004
+    system
+    store 0
+    store.d 1
+    load.d 1
+    load 0
+    ret.w
+"
+            );
+            let output = Assembler::new().assemble(&src)?;
+            let mut interpreter = Interpreter::new(&output, None, None)?;
+            let RunOutcome::Completed { ret: Some(status) } = interpreter.run() else {
+                Err("parent did not complete")?
+            };
+            Ok((interpreter.frames[0].locals.read::<i64>(1)?, status as i32))
+        };
+
+        let completes = "
+.entry main
+main:
+    push.d 3
+    push.d 4
+    add.d
+    ret.d
+";
+        assert_eq!(run(completes)?, (7, 0));
+
+        let exits = "
+.entry main
+main:
+    push 5
+    push 1
+    system
+";
+        assert_eq!(run(exits)?, (5, 1));
+
+        let loops = "
+.entry main
+main:
+    jmp main
+";
+        assert_eq!(run(loops)?, (0, 3));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_new_checked() -> Result<()> {
+        let output = Assembler::new().assemble(".entry main\nmain:\n    push 1\n    ret")?;
+        Interpreter::new_checked(&output, None, None)?
+            .run()
+            .into_result()?;
+
+        // A jump into its own operand is rejected before anything runs
+        let mut text = vec![Bytecode::Jmp as u8];
+        text.extend(9u64.to_le_bytes());
+        let output = Output::new(8, Vec::new(), text, HashMap::new());
+        let Err(err) = Interpreter::new_checked(&output, None, None) else {
+            panic!("expected the jump to be rejected");
+        };
+        assert!(matches!(
+            err.downcast_ref::<VerifyError>(),
+            Some(VerifyError::InvalidTarget {
+                position: 8,
+                target: 9
+            })
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_call_ptr_entries() -> Result<()> {
+        let src = "
+.entry main
+
+main:
+    push.d f
+    push.d 5
+    add.d
+    call.ptr
+    ret
+
+f:
+    push 1
+    push 2
+    ret.w
+";
+        let output = Assembler::new().assemble(src)?;
+        let mut interpreter = Interpreter::new(&output, None, None)?;
+        let RunOutcome::Trapped { error, .. } = interpreter.run() else {
+            panic!("expected call.ptr to trap");
+        };
+        // call.ptr is followed by ret, just before f
+        let Some((&f, _)) = output.labels().iter().find(|(_, label)| *label == "f") else {
+            Err("f has no label")?
+        };
+        assert_eq!(
+            error,
+            format!(
+                "call.ptr from {} to {}, which is not the start of a function",
+                f - 2,
+                f + 5
+            )
+        );
+
+        // Without protection any instruction can be called
+        let config = InterpreterConfig {
+            protect_program: false,
+            ..Default::default()
+        };
+        let mut interpreter = Interpreter::new(&output, None, None)?.with_config(config);
+        let outcome = interpreter.run().into_result()?;
+        assert_eq!(outcome, RunOutcome::Completed { ret: None });
+        assert_eq!(interpreter.current_frame().stack(), 2i32.to_le_bytes());
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    fn test_misaligned_jump() -> Result<()> {
+        // Jump and call into the operand of the first instruction
+        for op in [Bytecode::Jmp, Bytecode::Call] {
+            let mut text = vec![op as u8];
+            text.extend(9u64.to_le_bytes());
+            let output = Output::new(8, Vec::new(), text, HashMap::new());
+            let mut interpreter = Interpreter::new(&output, None, None)?;
+
+            let RunOutcome::Trapped { error, .. } = interpreter.run() else {
+                panic!("expected {op} to trap");
+            };
+            assert_eq!(
+                error,
+                "jump from 8 to 9, which is not the start of an instruction"
+            );
+            assert_eq!(interpreter.current_frame().entry(), 8);
+        }
+
+        // The same goes for a call through a pointer
+        let mut text = vec![Bytecode::PushD as u8];
+        text.extend(9u64.to_le_bytes());
+        text.push(Bytecode::CallPtr as u8);
+        let output = Output::new(8, Vec::new(), text, HashMap::new());
+        let mut interpreter = Interpreter::new(&output, None, None)?;
+
+        let RunOutcome::Trapped { error, .. } = interpreter.run() else {
+            panic!("expected call.ptr to trap");
+        };
+        assert_eq!(
+            error,
+            "jump from 17 to 9, which is not the start of an instruction"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_packed_stack() -> Result<()> {
+        // Mixes byte, word and double word values within each frame, but only passes words and
+        // double words between them
+        let src = "
+.entry main
+
+main:
+    push 7
+    push.d 10
+    call sum
+    push 1
+    add
+    ret.w
+
+sum:
+    push 0
+    store 3
+    push.b 0
+    store.b 4
+loop:
+    load 3
+    load 0
+    add
+    store 3
+    load.b 4
+    push.b 1
+    add.b
+    store.b 4
+    load.d 1
+    push.d 1
+    sub.d
+    dup.d
+    store.d 1
+    push.d 0
+    cmp.d
+    jmp.gt loop
+    load 3
+    ret.w
+";
+        let output = Assembler::new().assemble(src)?;
+
+        let mut outcomes = Vec::new();
+        for packed_stack in [false, true] {
+            let config = InterpreterConfig {
+                packed_stack,
+                ..Default::default()
+            };
+            let mut interpreter = Interpreter::new(&output, None, None)?.with_config(config);
+            outcomes.push(interpreter.run());
+        }
+
+        assert_eq!(outcomes[0], RunOutcome::Completed { ret: Some(71) });
+        assert_eq!(outcomes[0], outcomes[1]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_out_of_bounds() -> Result<()> {
+        let src = "
+.entry main
+
+.data message .string \"abc\"
+
+main:
+    push.d message
+    push.d 2
+    get
+    ret
+";
+        let output = Assembler::new().assemble(src)?;
+        let mut interpreter = Interpreter::new(&output, None, None)?;
+
+        let RunOutcome::Trapped { error, .. } = interpreter.run() else {
+            panic!("expected the read to trap");
+        };
+        assert_eq!(error, "data read out of bounds: 4 bytes at 10 at 29");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reset_restores_data() -> Result<()> {
+        let src = "
+.entry main
+
+.data letter .string \"a\"
+
+main:
+    push 1
+    dataptr letter
+    push.d 1
+    push 4
+    system
+    pop
+    push 0
+    dataptr letter
+    push.d 1
+    push 3
+    system
+    pop
+    ret
+";
+        let output = Assembler::new().assemble(src)?;
+        let stdin = Arc::new(Mutex::new(&b"bc"[..]));
+        let stdout = Arc::new(Mutex::new(Vec::new()));
+        let config = InterpreterConfig {
+            protect_program: false,
+            ..Default::default()
+        };
+        let mut interpreter =
+            Interpreter::new(&output, Some(Arc::clone(&stdout) as SharedWriter), None)?
+                .with_stdin(stdin)
+                .with_config(config);
+
+        // The first run reads into the data, which the second must not see
+        interpreter.run().into_result()?;
+        interpreter.reset();
+        interpreter.run().into_result()?;
+
+        assert_eq!(*stdout.lock().unwrap(), b"aa");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_run_traced() -> Result<()> {
+        let src = "
+.entry main
+
+main:
+    push 1
+    call double
+    call double
+    ret.w
+
+double:
+    load 0
+    call add_self
+    ret.w
+
+add_self:
+    load 0
+    dup
+    add
+    ret.w
+";
+        let output = Assembler::new().assemble(src)?;
+
+        let mut interpreter = Interpreter::new(&output, None, None)?;
+        let mut trace = Vec::new();
+        let outcome = interpreter.run_traced("double", false, &mut trace)?;
+        assert_eq!(outcome, RunOutcome::Completed { ret: Some(4) });
+        let want = "\
+double: load 0
+double+9: call 51 ; add_self
+double+18: ret.w
+double: load 0
+double+9: call 51 ; add_self
+double+18: ret.w
+";
+        assert_eq!(String::from_utf8(trace)?, want);
+
+        interpreter.reset();
+        let mut trace = Vec::new();
+        interpreter.run_traced("double", true, &mut trace)?;
+        let want = "\
+double: load 0
+double+9: call 51 ; add_self
+  add_self: load 0
+  add_self+9: dup
+  add_self+10: add
+  add_self+11: ret.w
+double+18: ret.w
+";
+        assert!(String::from_utf8(trace)?.starts_with(want));
+
+        assert!(interpreter
+            .run_traced("missing", false, &mut Vec::new())
+            .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_run_explained() -> Result<()> {
+        let src = "
+.entry main
+
+main:
+    push 3
+    push 4
+    call sum
+    store 0
+    push.b -1
+    b2w
+    load 0
+    div
+    ret.w
+
+sum:
+    load 0
+    load 1
+    add
+    ret.w
+";
+        let output = Assembler::new().assemble(src)?;
+        let mut interpreter = Interpreter::new(&output, None, None)?;
+        let mut explained = Vec::new();
+        let outcome = interpreter.run_explained(&mut explained)?;
+        assert_eq!(outcome, RunOutcome::Completed { ret: Some(0) });
+
+        let want = "\
+main: push 3 ; push the word 3
+main+5: push 4 ; push the word 4
+main+10: call 50 ; call sum, moving the stack into its locals
+sum: load 0 ; push the word in local 0 (3)
+sum+9: load 1 ; push the word in local 1 (4)
+sum+18: add ; pop 2 words, add them, push the result (3 + 4 = 7)
+sum+19: ret.w ; return a word (7) to the caller
+main+19: store 0 ; pop a word (7) and store it in local 0
+main+28: push.b -1 ; push the byte -1
+main+30: b2w ; pop a byte, widen it to a word, push the result (b2w(-1) = -1)
+main+31: load 0 ; push the word in local 0 (7)
+main+40: div ; pop 2 words, divide the one below by the top, push the result (-1 / 7 = 0)
+main+41: ret.w ; return a word (0) to the caller
+";
+        assert_eq!(String::from_utf8(explained)?, want);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_run_chrome_trace() -> Result<()> {
+        let src = "
+.entry main
+
+main:
+    push 1
+    call double
+    call double
+    ret.w
+
+double:
+    load 0
+    call add_self
+    ret.w
+
+add_self:
+    load 0
+    dup
+    panic
+";
+        let output = Assembler::new().assemble(src)?;
+        let mut interpreter = Interpreter::new(&output, None, None)?;
+        let mut trace = Vec::new();
+        let outcome = interpreter.run_chrome_trace(&mut trace)?;
+        assert!(matches!(outcome, RunOutcome::Trapped { .. }));
+
+        // The timestamps vary between runs, so only the names and phases are compared
+        let trace = String::from_utf8(trace)?;
+        let events: Vec<&str> = trace
+            .lines()
+            .skip(1)
+            .filter_map(|line| line.split_once(r#","ts""#))
+            .map(|(event, _)| event)
+            .collect();
+        assert!(trace.starts_with(r#"[{"name":"thread_name","ph":"M""#));
+        assert!(trace.ends_with("\n]\n"));
+        assert_eq!(
+            events,
+            [
+                r#"{"name":"main","ph":"B""#,
+                r#"{"name":"double","ph":"B""#,
+                r#"{"name":"add_self","ph":"B""#,
+                r#"{"name":"add_self","ph":"E""#,
+                r#"{"name":"double","ph":"E""#,
+                r#"{"name":"main","ph":"E""#,
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_console() -> Result<()> {
+        let src = "
+.entry main
+
+.data prompt .string \"name? \"
+.data hello .string \"hello \"
+
+main:
+    dataptr prompt
+    push.d sizeof prompt
+    @PRINT
+    pop
+    push @SYS_READLINE
+    system
+    pop
+    store.d 2
+    store.d 0
+    dataptr hello
+    push.d sizeof hello
+    load.d 0
+    load.d 2
+    push @SYS_CONCAT
+    system
+    push @SYS_PUTLINE
+    system
+    push @SYS_GETC
+    system
+    dup
+    push @SYS_PUTC
+    system
+    push @SYS_READLINE
+    system
+    push @SYS_READLINE
+    system
+    ret
+";
+        let output = Assembler::new().with_prelude(true).assemble(src)?;
+        let stdin = Arc::new(Mutex::new(&b"world\r\nxyz"[..]));
+        let mut interpreter = Interpreter::new(&output, None, None)?
+            .with_stdin(stdin)
+            .with_captured_output();
+        interpreter.run().into_result()?;
+
+        assert_eq!(
+            interpreter.captured_stdout().unwrap(),
+            b"name? hello world\nx"
+        );
+        // The rest of the last line, which has no newline, and then the end of the input
+        let values = interpreter
+            .current_frame()
+            .values("wddwddw".parse::<Fields>()?);
+        assert_eq!(values[0], StackValue::Word(b'x' as i32));
+        assert_eq!(values[2], StackValue::DoubleWord(2));
+        assert_eq!(values[3], StackValue::Word(1));
+        assert_eq!(values[5], StackValue::DoubleWord(0));
+        assert_eq!(values[6], StackValue::Word(0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_from_output() -> Result<()> {
+        let src = "
+.entry main
+
+.data value .word 9
+
+main:
+    push.d value
+    push.d 0
+    get
+    call double
+    ret.w
+
+double:
+    load 0
+    push 2
+    mul
+    ret.w";
+        let output = Assembler::new().assemble(src)?;
+        let mut borrowed = Interpreter::new(&output, None, None)?;
+        let mut owned = Interpreter::from_output(output, None, None)?;
+        assert_eq!(owned.current_frame().label(), Some("main"));
+
+        borrowed.run().into_result()?;
+        owned.run().into_result()?;
+        assert_eq!(owned.exit_code(), Some(18));
+        assert_eq!(owned.exit_code(), borrowed.exit_code());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_new_verified() -> Result<()> {
+        let src = "
+.entry main
+
+main:
+    push 5
+    ret";
+        let key = KeyedHash::new("key");
+        let mut output = Assembler::new().assemble(src)?;
+        assert!(Interpreter::new_verified(&output, None, None, &key).is_err());
+
+        output.sign(&key);
+        let mut interpreter = Interpreter::new_verified(&output, None, None, &key)?;
+        interpreter.run().into_result()?;
+        assert_eq!(interpreter.current_frame().stack(), 5i32.to_le_bytes());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_bad_fds() -> Result<()> {
+        let src = "
+.entry main
+.data message .string \"hi\"
+main:
+    push 7
+    dataptr message
+    push.d message.len
+    push @SYS_WRITE
+    system
+    push @SYS_ERRNO
+    system
+    push @STDOUT
+    push @SYS_CLOSE
+    system
+    push @STDOUT
+    dataptr message
+    push.d message.len
+    push @SYS_WRITE
+    system
+    ret
+";
+        let output = Assembler::new().with_prelude(true).assemble(src)?;
+        let mut interpreter = Interpreter::new(&output, None, None)?.with_captured_output();
+        interpreter.run().into_result()?;
+
+        // Closing stdout takes it away from the program, but the host's stream stays open
+        let values = interpreter.current_frame().values("www".parse::<Fields>()?);
+        let want = [-1, 9, -1].map(StackValue::Word);
+        assert_eq!(values, want);
+        assert_eq!(interpreter.captured_stdout().unwrap(), b"");
+
+        let config = InterpreterConfig {
+            strict_fds: true,
+            ..Default::default()
+        };
+        let mut interpreter = Interpreter::new(&output, None, None)?.with_config(config);
+        let err = interpreter.run().into_result().unwrap_err();
+        assert_eq!(err.to_string(), "bad file descriptor 7 in write");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_captured_output() -> Result<()> {
+        let src = "
+.entry main
+
+.data out .string \"out\"
+.data err .string \"err\"
+
+main:
+    push 1
+    dataptr out
+    push.d 3
+    push 4
+    system
+    pop
+    push 2
+    dataptr err
+    push.d 3
+    push 4
+    system
+    pop
+    ret
+";
+        let output = Assembler::new().assemble(src)?;
+
+        let mut interpreter = Interpreter::new(&output, None, None)?;
+        assert_eq!(interpreter.captured_stdout(), None);
+
+        let stdout = Arc::new(Mutex::new(Vec::new()));
+        interpreter = Interpreter::new(&output, Some(stdout.clone()), None)?.with_captured_output();
+        interpreter.run().into_result()?;
+        assert_eq!(interpreter.captured_stdout().unwrap(), b"out");
+        assert_eq!(interpreter.captured_stderr().unwrap(), b"err");
+        assert_eq!(*stdout.lock().unwrap(), b"out");
+
+        // Resetting clears what was captured, but not what was passed on
+        interpreter.reset();
+        assert_eq!(interpreter.captured_stdout().unwrap(), b"");
+        interpreter.run().into_result()?;
+        assert_eq!(interpreter.captured_stdout().unwrap(), b"out");
+        assert_eq!(*stdout.lock().unwrap(), b"outout");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_prefixed_output() -> Result<()> {
+        let src = "
+.entry main
+
+.data a .string \"a\\n\"
+.data b .string \"b\\n\"
+
+main:
+    call print_a
+    call print_b
+    call print_a
+    ret
+
+print_a:
+    push 1
+    dataptr a
+    push.d 2
+    push 4
+    system
+    pop
+    ret
+
+print_b:
+    push 1
+    dataptr b
+    push.d 2
+    push 4
+    system
+    pop
+    ret
+";
+        let output = Assembler::new().assemble(src)?;
+
+        let stdout = Arc::new(Mutex::new(Vec::new()));
+        let mut interpreter =
+            Interpreter::new(&output, Some(stdout.clone()), None)?.with_prefixed_output();
+        interpreter.run().into_result()?;
+
+        let have = String::from_utf8(stdout.lock().unwrap().clone())?;
+        assert_eq!(have, "[print_a] a\n[print_b] b\n[print_a] a\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_run_outcome() -> Result<()> {
+        for (src, want) in [
+            (
+                ".entry main\nmain:\n    push.d -3\n    ret.d",
+                RunOutcome::Completed { ret: Some(-3) },
+            ),
+            (
+                ".entry main\nmain:\n    push 7\n    push 1\n    system",
+                RunOutcome::Exited { code: 7 },
+            ),
+            (
+                ".entry main\nmain:\n    call fail\n    ret\nfail:\n    panic",
+                RunOutcome::Trapped {
+                    error: String::from("panic"),
+                    backtrace: vec![String::from("main"), String::from("fail")],
+                },
+            ),
+            (
+                ".entry main\n.data msg .cstring \"bad input\"\nmain:\n    panic msg\n    ret",
+                RunOutcome::Trapped {
+                    error: String::from("panic at 18: bad input"),
+                    backtrace: vec![String::from("main")],
+                },
+            ),
+        ] {
+            let output = Assembler::new().assemble(src)?;
+            let mut interpreter = Interpreter::new(&output, None, None)?;
+            assert_eq!(interpreter.run(), want);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_limits() -> Result<()> {
+        let limits = Limits {
+            stack_size: 16,
+            local_slots: 2,
+            call_depth: Some(3),
+            heap_size: Some(16),
+            ..Default::default()
+        };
+
+        let trapped = |error: &str, backtrace: &[&str]| RunOutcome::Trapped {
+            error: String::from(error),
+            backtrace: backtrace.iter().map(|label| label.to_string()).collect(),
+        };
+        for (src, want) in [
+            (
+                ".entry main\nmain:\n    push 1\n    push 2\n    push 3\n    push 4\n    push 5\n    ret",
+                trapped("program exceeds configured stack size (16)", &["main"]),
+            ),
+            (
+                ".entry main\nmain:\n    push.d 1\n    store.d 0\n    push.d 2\n    store.d 1\n    ret",
+                trapped("program exceeds configured local slots (2)", &["main"]),
+            ),
+            (
+                ".entry main\nmain:\n    call f\n    ret\nf:\n    call g\n    ret\ng:\n    call f\n    ret",
+                trapped("program exceeds configured call depth (3)", &["main", "f", "g"]),
+            ),
+        ] {
+            let output = Assembler::new().assemble(src)?;
+            let config = InterpreterConfig {
+                limits,
+                ..InterpreterConfig::deterministic()
+            };
+            let mut interpreter = Interpreter::new(&output, None, None)?.with_config(config);
+            assert_eq!(interpreter.run(), want);
+        }
+
+        // The second allocation would take the heap past its limit, so it's null
+        let output = Assembler::new().assemble(
+            ".entry main\nmain:\n    push.d 8\n    alloc\n    push.d 16\n    alloc\n    ret",
+        )?;
+        let config = InterpreterConfig {
+            limits,
+            ..InterpreterConfig::deterministic()
+        };
+        let mut interpreter = Interpreter::new(&output, None, None)?.with_config(config);
+        interpreter.run().into_result()?;
+        assert_eq!(interpreter.current_frame().peek::<u64>(), Some(0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_alloc_too_large() -> Result<()> {
+        // Without a limit, the host still can't allocate these, so they're null, and a deterministic
+        // heap doesn't run out of addresses for them
+        for (size, config) in [
+            ("-1", InterpreterConfig::default()),
+            ("0x7ffffffffffffff0", InterpreterConfig::default()),
+            ("-1", InterpreterConfig::deterministic()),
+            ("-5", InterpreterConfig::deterministic()),
+        ] {
+            let src = format!(".entry main\nmain:\n    push.d {size}\n    alloc\n    ret");
+            let output = Assembler::new().assemble(&src)?;
+            let mut interpreter = Interpreter::new(&output, None, None)?.with_config(config);
+            interpreter.run().into_result()?;
+            assert_eq!(interpreter.current_frame().peek::<u64>(), Some(0), "{size}");
+            assert_eq!(interpreter.heap().allocated(), 0);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_local_out_of_range() -> Result<()> {
+        let src = "
+.entry main
+
+main:
+    push 1
+    push 2
+    call add
+    ret
+
+add:
+    load 0
+    load 1
+    add
+    store 5
+    ret
+";
+        let output = Assembler::new().assemble(src)?;
+        let Some((&add, _)) = output.labels().iter().find(|(_, label)| *label == "add") else {
+            Err("add has no label")?
+        };
+
+        // Only the arguments' slots are allocated when the function is recorded as using fewer
+        let output = output.with_local_slots(HashMap::from([(add, 1)]));
+        let mut interpreter = Interpreter::new(&output, None, None)?;
+        let RunOutcome::Trapped { error, backtrace } = interpreter.run() else {
+            Err("expected a trap")?
+        };
+        assert_eq!(
+            error,
+            format!(
+                "local 5 out of range for frame `add` with 2 locals at {}",
+                add + 19
+            )
+        );
+        assert_eq!(backtrace, ["main", "add"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_division_by_zero() -> Result<()> {
+        for (src, want) in [
+            (
+                "push 7\n    push 0\n    div",
+                "division by zero in div at 18",
+            ),
+            (
+                "push.b 7\n    push.b 0\n    rem.b",
+                "division by zero in rem.b at 12",
+            ),
+        ] {
+            let output =
+                Assembler::new().assemble(&format!(".entry main\nmain:\n    {src}\n    ret"))?;
+            let mut interpreter = Interpreter::new(&output, None, None)?;
+            let RunOutcome::Trapped { error, backtrace } = interpreter.run() else {
+                panic!("expected {src} to trap");
+            };
+            assert_eq!(error, want);
+            assert_eq!(backtrace, ["main"]);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_stack_underflow() -> Result<()> {
+        // Instructions which leave the frame check the stack before they do
+        for (src, want) in [
+            ("halt", "stack underflow in halt at 8"),
+            ("call.ptr", "stack underflow in call.ptr at 8"),
+            ("pop\n    push 0\n    halt", "stack underflow in pop at 8"),
+            ("ret.w", "stack underflow in ret.w at 8"),
+            ("push.b 1\n    ret.d", "stack underflow in ret.d at 10"),
+        ] {
+            let output =
+                Assembler::new().assemble(&format!(".entry main\nmain:\n    {src}\n    ret"))?;
+            let mut interpreter = Interpreter::new(&output, None, None)?;
+            let RunOutcome::Trapped { error, backtrace } = interpreter.run() else {
+                panic!("expected {src} to trap");
+            };
+            assert_eq!(error, want);
+            assert_eq!(backtrace, ["main"]);
+        }
+
+        // A callee returning a value it doesn't have traps rather than returning zero
+        let output =
+            Assembler::new().assemble(".entry main\nmain:\n    call f\n    ret\nf:\n    ret.w")?;
+        let mut interpreter = Interpreter::new(&output, None, None)?;
+        let RunOutcome::Trapped { error, backtrace } = interpreter.run() else {
+            panic!("expected ret.w to trap");
+        };
+        assert_eq!(error, "stack underflow in ret.w at 18");
+        assert_eq!(backtrace, ["main", "f"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_checked_arithmetic() -> Result<()> {
+        let src = "
+.entry main
+main:
+    push 2147483647
+    push 1
+    add
+    ret
+";
+        let output = Assembler::new().assemble(src)?;
+
+        // Without checking the sum wraps around
+        let mut interpreter = Interpreter::new(&output, None, None)?;
+        interpreter.run().into_result()?;
+        assert_eq!(interpreter.current_frame().peek::<i32>(), Some(i32::MIN));
+
+        let config = InterpreterConfig {
+            checked_arithmetic: true,
+            ..Default::default()
+        };
+        let mut interpreter = Interpreter::new(&output, None, None)?.with_config(config.clone());
+        let RunOutcome::Trapped { error, backtrace } = interpreter.run() else {
+            panic!("expected the add to trap");
+        };
+        assert_eq!(error, "arithmetic overflow in add at 18");
+        assert_eq!(backtrace, ["main"]);
+
+        // Dividing the smallest value by -1 overflows too, while arithmetic which fits doesn't
+        for (src, overflows) in [
+            (
+                "push.d -9223372036854775808\n    push.d -1\n    div.d",
+                true,
+            ),
+            ("push.b -128\n    neg.b", true),
+            ("push -2147483648\n    abs", true),
+            ("push 46340\n    push 46340\n    mul", false),
+            ("push.b 100\n    push.b 27\n    add.b", false),
+        ] {
+            let output =
+                Assembler::new().assemble(&format!(".entry main\nmain:\n    {src}\n    ret"))?;
+            let mut interpreter =
+                Interpreter::new(&output, None, None)?.with_config(config.clone());
+            let trapped = match interpreter.run() {
+                RunOutcome::Trapped { error, .. } => error.starts_with("arithmetic overflow"),
+                _ => false,
+            };
+            assert_eq!(trapped, overflows, "{src}");
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "arrays")]
+    fn test_index_out_of_bounds() -> Result<()> {
+        use super::IndexOutOfBounds;
+
+        let src = "
+.entry main
+
+main:
+    push.d 2
+    newarr
+    push.d 5
+    aget
+    ret
+";
+        let output = Assembler::new().assemble(src)?;
+        let mut interpreter = Interpreter::new(&output, None, None)?;
+
+        let err = loop {
+            if let Err(err) = interpreter.step() {
+                break err;
+            }
+        };
+        let err = err.downcast_ref::<IndexOutOfBounds>().unwrap();
+        assert_eq!((err.index, err.len), (5, 2));
+        assert_eq!(
+            err.to_string(),
+            "index out of bounds: the len is 2 but the index is 5"
+        );
+
+        Ok(())
+    }
 }
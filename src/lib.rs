@@ -1,19 +1,45 @@
 use std::sync::{Arc, Mutex};
 
 pub mod assembler;
+pub mod batch;
+pub mod build;
+mod capture;
+mod compress;
 pub mod debugger;
+pub mod diagnostic;
+pub mod diff;
+pub mod examples;
+mod explain;
+pub mod extension;
 mod frame;
+pub mod gas;
 mod heap;
 pub mod interpreter;
+pub mod limits;
 mod locals;
+pub mod manifest;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+mod objects;
+mod optimiser;
 pub mod output;
+mod prefix;
 mod program;
+pub mod signing;
+mod ssa;
 mod stack;
 mod tokeniser;
+pub mod verify;
+
+pub use capture::CaptureWriter;
+pub use prefix::PrefixWriter;
+pub use program::{Bytecode, Fields, Instr};
+pub use stack::StackValue;
 
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 
 pub type SharedWriter = Arc<Mutex<dyn std::io::Write>>;
+pub type SharedReader = Arc<Mutex<dyn std::io::Read>>;
 
 #[allow(dead_code)]
 pub trait Number:
@@ -27,6 +53,11 @@ pub trait Number:
     + std::ops::Sub<Output = Self>
     + std::ops::Mul<Output = Self>
     + std::ops::Div<Output = Self>
+    + std::ops::Rem<Output = Self>
+    + std::ops::BitAnd<Output = Self>
+    + std::ops::BitOr<Output = Self>
+    + std::ops::BitXor<Output = Self>
+    + std::ops::Not<Output = Self>
 {
     const SIZE: usize;
     type Bytes: IntoIterator<Item = u8> + AsRef<[u8]> + AsMut<[u8]>;
@@ -34,6 +65,23 @@ pub trait Number:
     fn to_le_bytes(&self) -> Self::Bytes;
     fn from_le_bytes(bytes: &[u8]) -> Self;
     fn from_be_bytes(bytes: &[u8]) -> Self;
+    /// Shifts left by `n` bits, modulo the width of the type
+    fn shl(self, n: u32) -> Self;
+    /// Shifts right by `n` bits, modulo the width of the type, copying the sign bit
+    fn shr(self, n: u32) -> Self;
+    /// Shifts right by `n` bits, modulo the width of the type, filling with zeroes
+    fn shr_logical(self, n: u32) -> Self;
+    /// Each of the following wraps on overflow, and also gives whether it did
+    fn overflowing_add(self, rhs: Self) -> (Self, bool);
+    fn overflowing_sub(self, rhs: Self) -> (Self, bool);
+    fn overflowing_mul(self, rhs: Self) -> (Self, bool);
+    fn overflowing_div(self, rhs: Self) -> (Self, bool);
+    fn overflowing_rem(self, rhs: Self) -> (Self, bool);
+    fn overflowing_neg(self) -> (Self, bool);
+    /// Widens to a double word, extending the sign of signed types
+    fn to_i64(self) -> i64;
+    /// Keeps the low bytes of `n`
+    fn truncate(n: i64) -> Self;
 }
 
 macro_rules! impl_number {
@@ -58,6 +106,52 @@ macro_rules! impl_number {
             fn from_be_bytes(bytes: &[u8]) -> Self {
                 <$ty>::from_be_bytes(bytes.try_into().unwrap())
             }
+
+            fn shl(self, n: u32) -> Self {
+                self.wrapping_shl(n)
+            }
+
+            fn shr(self, n: u32) -> Self {
+                self.wrapping_shr(n)
+            }
+
+            fn shr_logical(self, n: u32) -> Self {
+                let bits = <$ty>::BITS;
+                let mask = u64::MAX >> (u64::BITS - bits);
+                ((self as u64 & mask) >> (n % bits)) as $ty
+            }
+
+            fn overflowing_add(self, rhs: Self) -> (Self, bool) {
+                <$ty>::overflowing_add(self, rhs)
+            }
+
+            fn overflowing_sub(self, rhs: Self) -> (Self, bool) {
+                <$ty>::overflowing_sub(self, rhs)
+            }
+
+            fn overflowing_mul(self, rhs: Self) -> (Self, bool) {
+                <$ty>::overflowing_mul(self, rhs)
+            }
+
+            fn overflowing_div(self, rhs: Self) -> (Self, bool) {
+                <$ty>::overflowing_div(self, rhs)
+            }
+
+            fn overflowing_rem(self, rhs: Self) -> (Self, bool) {
+                <$ty>::overflowing_rem(self, rhs)
+            }
+
+            fn overflowing_neg(self) -> (Self, bool) {
+                <$ty>::overflowing_neg(self)
+            }
+
+            fn to_i64(self) -> i64 {
+                self as i64
+            }
+
+            fn truncate(n: i64) -> Self {
+                n as $ty
+            }
         }
         )*
     };
@@ -65,8 +159,50 @@ macro_rules! impl_number {
 
 impl_number!(u8, i8, i16, i32, i64, u64);
 
+/// A floating point value, which is held on the stack and in memory as the bits of the
+/// [`Number`] of the same width
+pub trait Float:
+    Copy
+    + PartialOrd
+    + std::fmt::Debug
+    + std::str::FromStr
+    + std::ops::Add<Output = Self>
+    + std::ops::Sub<Output = Self>
+    + std::ops::Mul<Output = Self>
+    + std::ops::Div<Output = Self>
+{
+    type Bits: Number;
+    fn from_bits(bits: Self::Bits) -> Self;
+    fn to_bits(self) -> Self::Bits;
+}
+
+impl Float for f32 {
+    type Bits = i32;
+
+    fn from_bits(bits: i32) -> Self {
+        f32::from_bits(bits as u32)
+    }
+
+    fn to_bits(self) -> i32 {
+        f32::to_bits(self) as i32
+    }
+}
+
+impl Float for f64 {
+    type Bits = i64;
+
+    fn from_bits(bits: i64) -> Self {
+        f64::from_bits(bits as u64)
+    }
+
+    fn to_bits(self) -> i64 {
+        f64::to_bits(self) as i64
+    }
+}
+
 pub trait Bytes {
     fn read_u64(&mut self) -> Result<u64>;
+    fn read_u32(&mut self) -> Result<u32>;
     fn read_u16(&mut self) -> Result<u16>;
     fn read_n(&mut self, n: usize) -> Result<Vec<u8>>;
 }
@@ -75,21 +211,31 @@ impl<T> Bytes for T
 where
     T: std::io::Read,
 {
+    // Readers such as pipes may return fewer bytes than asked for, so keep reading until the
+    // buffer is full
+
     fn read_u64(&mut self) -> Result<u64> {
         let mut buf = [0u8; size_of::<u64>()];
-        let n = self.read(&mut buf)?;
-        if n < size_of::<u64>() {
-            Err(format!("read less than expected bytes: {n}"))?;
+        if let Err(err) = self.read_exact(&mut buf) {
+            Err(format!("could not read u64: {err}"))?;
         }
 
         Ok(u64::from_le_bytes(buf))
     }
 
+    fn read_u32(&mut self) -> Result<u32> {
+        let mut buf = [0u8; size_of::<u32>()];
+        if let Err(err) = self.read_exact(&mut buf) {
+            Err(format!("could not read u32: {err}"))?;
+        }
+
+        Ok(u32::from_le_bytes(buf))
+    }
+
     fn read_u16(&mut self) -> Result<u16> {
         let mut buf = [0u8; size_of::<u16>()];
-        let n = self.read(&mut buf)?;
-        if n < size_of::<u16>() {
-            Err(format!("read less than expected bytes: {n}"))?;
+        if let Err(err) = self.read_exact(&mut buf) {
+            Err(format!("could not read u16: {err}"))?;
         }
 
         Ok(u16::from_le_bytes(buf))
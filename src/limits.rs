@@ -0,0 +1,154 @@
+//! The limits on how large a program can be and how much it can use while running. The defaults
+//! are the most the serialised format and the interpreter support, and can be lowered to hold an
+//! untrusted program to less. Going over a limit is an error naming it, such as
+//! `program exceeds configured text size (65535)`.
+
+use crate::Result;
+
+/// The most bytes of data or text an uncompressed program can hold, since the length of each is
+/// serialised as a u16. Compressed programs store it as a u32.
+pub const MAX_SECTION_SIZE: usize = u16::MAX as usize;
+/// The most bytes of data or text a compressed program can hold
+pub const MAX_COMPRESSED_SECTION_SIZE: usize = u32::MAX as usize;
+/// The bytes in a frame's operand stack
+pub const MAX_STACK_SIZE: usize = 512;
+/// The word sized local slots in a frame
+pub const MAX_LOCAL_SLOTS: usize = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// The most bytes of data a program can have
+    pub data_size: usize,
+    /// The most bytes of text a program can have
+    pub text_size: usize,
+    /// The most bytes on a frame's operand stack
+    pub stack_size: usize,
+    /// The most word sized local slots a frame can use
+    pub local_slots: usize,
+    /// The most frames on the call stack, including main, or none for no limit
+    pub call_depth: Option<usize>,
+    /// The most bytes the main heap can have allocated, or none for no limit
+    pub heap_size: Option<usize>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        let data_size = MAX_SECTION_SIZE;
+        let text_size = MAX_SECTION_SIZE;
+        let stack_size = MAX_STACK_SIZE;
+        let local_slots = MAX_LOCAL_SLOTS;
+        let call_depth = None;
+        let heap_size = None;
+
+        Self {
+            data_size,
+            text_size,
+            stack_size,
+            local_slots,
+            call_depth,
+            heap_size,
+        }
+    }
+}
+
+impl Limits {
+    /// Checks each limit is within what the serialised format and the interpreter support
+    pub fn validate(&self) -> Result<()> {
+        let supported = [
+            ("data size", self.data_size, MAX_COMPRESSED_SECTION_SIZE),
+            ("text size", self.text_size, MAX_COMPRESSED_SECTION_SIZE),
+            ("stack size", self.stack_size, MAX_STACK_SIZE),
+            ("local slots", self.local_slots, MAX_LOCAL_SLOTS),
+        ];
+        for (limit, value, max) in supported {
+            if value > max {
+                Err(format!(
+                    "configured {limit} ({value}) is more than is supported ({max})"
+                ))?
+            }
+        }
+
+        if self.call_depth == Some(0) {
+            Err("configured call depth must leave room for main")?
+        }
+
+        Ok(())
+    }
+
+    /// Checks the sections of a program fit within the limits
+    pub fn check_program(&self, data: usize, text: usize) -> Result<()> {
+        if data > self.data_size {
+            Err(LimitExceeded::new("data size", self.data_size))?
+        }
+        if text > self.text_size {
+            Err(LimitExceeded::new("text size", self.text_size))?
+        }
+
+        Ok(())
+    }
+}
+
+/// The error raised when a program goes over one of its [`Limits`]
+#[derive(Debug)]
+pub struct LimitExceeded {
+    /// What was limited, such as `text size`
+    pub limit: &'static str,
+    /// The configured limit
+    pub max: usize,
+}
+
+impl LimitExceeded {
+    pub fn new(limit: &'static str, max: usize) -> Self {
+        Self { limit, max }
+    }
+}
+
+impl std::fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "program exceeds configured {} ({})",
+            self.limit, self.max
+        )
+    }
+}
+
+impl std::error::Error for LimitExceeded {}
+
+#[cfg(test)]
+mod test {
+    use super::{LimitExceeded, Limits};
+
+    #[test]
+    fn test_validate() {
+        assert!(Limits::default().validate().is_ok());
+
+        let limits = Limits {
+            stack_size: 1024,
+            ..Default::default()
+        };
+        assert_eq!(
+            limits.validate().unwrap_err().to_string(),
+            "configured stack size (1024) is more than is supported (512)"
+        );
+
+        let limits = Limits {
+            call_depth: Some(0),
+            ..Default::default()
+        };
+        assert!(limits.validate().is_err());
+    }
+
+    #[test]
+    fn test_check_program() {
+        let limits = Limits {
+            text_size: 16,
+            ..Default::default()
+        };
+        assert!(limits.check_program(100, 16).is_ok());
+
+        let err = limits.check_program(0, 17).unwrap_err();
+        assert!(err.is::<LimitExceeded>());
+        assert_eq!(err.to_string(), "program exceeds configured text size (16)");
+    }
+}
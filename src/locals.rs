@@ -1,4 +1,7 @@
-use crate::Number;
+use std::ops::Range;
+
+use crate::limits::MAX_LOCAL_SLOTS;
+use crate::{Number, Result};
 
 const SLOT_SIZE: usize = std::mem::size_of::<i32>();
 macro_rules! slot {
@@ -9,28 +12,185 @@ macro_rules! slot {
     }};
 }
 
-const LOCALS_SIZE: usize = std::mem::size_of::<i32>() * 128;
+const LOCALS_SIZE: usize = SLOT_SIZE * MAX_LOCAL_SLOTS;
+
+/// The locals of a frame, which are only allocated once one is written, since many functions
+/// take no arguments and keep nothing in locals. A function known to use fewer than all of the
+/// slots only allocates those, and more for its arguments if they take more, and using a slot
+/// past them is an error.
+#[derive(Clone)]
 pub struct Locals {
-    locals: Box<[u8; LOCALS_SIZE]>,
+    locals: Vec<u8>,
+    /// The number of bytes to allocate with the first write
+    size: usize,
 }
 
 impl Default for Locals {
     fn default() -> Self {
-        let locals = Box::new([0u8; LOCALS_SIZE]);
-        Self { locals }
+        Self::sized(None)
     }
 }
 
 impl Locals {
-    pub fn read<T: Number>(&self, i: u64) -> T {
-        T::from_le_bytes(&self.locals[slot!(T, i as usize)])
+    /// Locals for a function using `slots` slots, or every slot if that isn't known
+    pub fn sized(slots: Option<usize>) -> Self {
+        let locals = Vec::new();
+        let size = slots.map_or(LOCALS_SIZE, |slots| (slots * SLOT_SIZE).min(LOCALS_SIZE));
+        Self { locals, size }
+    }
+
+    pub fn read<T: Number>(&self, i: u64) -> std::result::Result<T, LocalOutOfRange> {
+        let range = self.range::<T>(i)?;
+        match self.locals.get(range.clone()) {
+            Some(bytes) => Ok(T::from_le_bytes(bytes)),
+            None => Ok(self.read_unallocated(range)),
+        }
+    }
+
+    /// Reads a value which is at least partly past the slots allocated so far, which are zero
+    #[cold]
+    fn read_unallocated<T: Number>(&self, range: Range<usize>) -> T {
+        assert!(
+            range.end <= LOCALS_SIZE,
+            "local slot out of bounds: {}",
+            range.start / SLOT_SIZE
+        );
+        let mut bytes = [0; std::mem::size_of::<u64>()];
+        let written = self.locals.get(range.start..).unwrap_or_default();
+        bytes[..written.len()].copy_from_slice(written);
+        T::from_le_bytes(&bytes[..T::SIZE])
     }
 
-    pub fn write<T: Number>(&mut self, i: u64, value: T) {
-        self.locals[slot!(T, i as usize)].copy_from_slice(value.to_le_bytes().as_ref());
+    pub fn write<T: Number>(
+        &mut self,
+        i: u64,
+        value: T,
+    ) -> std::result::Result<(), LocalOutOfRange> {
+        let range = self.range::<T>(i)?;
+        if let Some(bytes) = self.locals.get_mut(range.clone()) {
+            bytes.copy_from_slice(value.to_le_bytes().as_ref());
+            return Ok(());
+        }
+
+        self.grow(range.end);
+        self.locals[range].copy_from_slice(value.to_le_bytes().as_ref());
+        Ok(())
+    }
+
+    /// The number of slots the frame has, which is more than it was sized for if its arguments
+    /// took more
+    pub fn slots(&self) -> usize {
+        self.size.max(self.locals.len()) / SLOT_SIZE
+    }
+
+    /// The bytes of a value of type `T` in slot `i`, if they're within the frame's slots
+    fn range<T: Number>(&self, i: u64) -> std::result::Result<Range<usize>, LocalOutOfRange> {
+        let slots = self.slots();
+        match i.checked_add(T::SIZE.div_ceil(SLOT_SIZE) as u64) {
+            Some(end) if end <= slots as u64 => Ok(slot!(T, i as usize)),
+            _ => Err(LocalOutOfRange::new(i, slots)),
+        }
     }
 
     pub fn copy_from_slice(&mut self, slice: &[u8]) {
+        if slice.len() > self.locals.len() {
+            self.grow(slice.len());
+        }
         self.locals[..slice.len()].copy_from_slice(slice);
     }
+
+    /// The bytes of the slots allocated so far
+    pub fn as_slice(&self) -> &[u8] {
+        &self.locals
+    }
+
+    /// Allocates at least the first `len` bytes
+    #[cold]
+    fn grow(&mut self, len: usize) {
+        assert!(
+            len <= LOCALS_SIZE,
+            "local slot out of bounds: {}",
+            len.div_ceil(SLOT_SIZE) - 1
+        );
+        self.locals.resize(len.max(self.size), 0);
+    }
+}
+
+/// The error raised when a frame reads or writes a local past the slots it has
+#[derive(Debug)]
+pub struct LocalOutOfRange {
+    pub local: u64,
+    /// The number of slots the frame has
+    pub slots: usize,
+    /// The label of the frame, or its entry if it has none
+    pub frame: Option<String>,
+    /// The position of the instruction which used the local
+    pub position: Option<u64>,
+}
+
+impl LocalOutOfRange {
+    pub fn new(local: u64, slots: usize) -> Self {
+        let frame = None;
+        let position = None;
+        Self {
+            local,
+            slots,
+            frame,
+            position,
+        }
+    }
+
+    /// Records the frame and instruction the local was used by
+    pub fn at(mut self, frame: String, position: u64) -> Self {
+        self.frame = Some(frame);
+        self.position = Some(position);
+        self
+    }
+}
+
+impl std::fmt::Display for LocalOutOfRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "local {} out of range", self.local)?;
+        if let Some(frame) = &self.frame {
+            write!(f, " for frame `{frame}`")?;
+        }
+        write!(f, " with {} locals", self.slots)?;
+        if let Some(position) = self.position {
+            write!(f, " at {position}")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for LocalOutOfRange {}
+
+pub const SCRATCH_SLOTS: u64 = 8;
+
+/// Word sized slots kept by the interpreter rather than a frame, so they aren't cleared by calls
+#[derive(Default, Clone)]
+pub struct Scratch {
+    slots: [i32; SCRATCH_SLOTS as usize],
+}
+
+impl Scratch {
+    pub fn read(&self, i: u64) -> Result<i32> {
+        match self.slots.get(i as usize) {
+            Some(value) => Ok(*value),
+            None => Err(format!("invalid scratch slot: {i}"))?,
+        }
+    }
+
+    pub fn write(&mut self, i: u64, value: i32) -> Result<()> {
+        match self.slots.get_mut(i as usize) {
+            Some(slot) => *slot = value,
+            None => Err(format!("invalid scratch slot: {i}"))?,
+        }
+
+        Ok(())
+    }
+
+    pub fn as_slice(&self) -> &[i32] {
+        &self.slots
+    }
 }
@@ -0,0 +1,242 @@
+//! A small subset of TOML used by manifest files: top level keys, `[table]` and `[[array]]`
+//! headers, and string, integer, boolean and single line array values.
+
+use std::collections::HashMap;
+use std::iter::Peekable;
+use std::str::Chars;
+
+use crate::Result;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+    Array(Vec<Value>),
+}
+
+pub type Table = HashMap<String, Value>;
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Manifest {
+    /// Keys before the first header
+    pub root: Table,
+    /// Tables declared with `[name]`
+    pub tables: HashMap<String, Table>,
+    /// Tables declared with `[[name]]`, in the order they appear
+    pub arrays: HashMap<String, Vec<Table>>,
+}
+
+enum Section {
+    Root,
+    Table(String),
+    Array(String),
+}
+
+impl Manifest {
+    pub fn parse(src: &str) -> Result<Self> {
+        let mut manifest = Manifest::default();
+        let mut section = Section::Root;
+
+        for (i, line) in src.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            Self::parse_line(&mut manifest, &mut section, line)
+                .map_err(|err| format!("line {}: {err}", i + 1))?;
+        }
+
+        Ok(manifest)
+    }
+
+    fn parse_line(&mut self, section: &mut Section, line: &str) -> Result<()> {
+        if let Some(name) = line.strip_prefix("[[") {
+            let Some(name) = name.strip_suffix("]]") else {
+                Err("expected ]] after array name")?
+            };
+
+            let name = name.trim().to_string();
+            self.arrays
+                .entry(name.clone())
+                .or_default()
+                .push(Table::new());
+            *section = Section::Array(name);
+
+            return Ok(());
+        }
+
+        if let Some(name) = line.strip_prefix('[') {
+            let Some(name) = name.strip_suffix(']') else {
+                Err("expected ] after table name")?
+            };
+
+            let name = name.trim().to_string();
+            if self.tables.insert(name.clone(), Table::new()).is_some() {
+                Err(format!("duplicate table: {name}"))?
+            }
+            *section = Section::Table(name);
+
+            return Ok(());
+        }
+
+        let Some((key, value)) = line.split_once('=') else {
+            Err(format!("expected key = value, found: {line}"))?
+        };
+
+        let key = key.trim();
+        if key.is_empty() {
+            Err("missing key")?
+        }
+
+        let mut chars = value.trim().chars().peekable();
+        let value = parse_value(&mut chars)?;
+        skip_whitespace(&mut chars);
+        match chars.next() {
+            None | Some('#') => {}
+            Some(c) => Err(format!("unexpected character after value: {c}"))?,
+        }
+
+        let table = match section {
+            Section::Root => &mut self.root,
+            Section::Table(name) => self.tables.get_mut(name).unwrap(),
+            Section::Array(name) => self.arrays.get_mut(name).unwrap().last_mut().unwrap(),
+        };
+
+        if table.insert(key.to_string(), value).is_some() {
+            Err(format!("duplicate key: {key}"))?
+        }
+
+        Ok(())
+    }
+}
+
+fn skip_whitespace(chars: &mut Peekable<Chars>) {
+    while chars.next_if(|c| c.is_whitespace()).is_some() {}
+}
+
+fn parse_value(chars: &mut Peekable<Chars>) -> Result<Value> {
+    skip_whitespace(chars);
+
+    match chars.peek() {
+        Some('"') => {
+            chars.next();
+            let mut s = String::new();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => match chars.next() {
+                        Some('n') => s.push('\n'),
+                        Some('t') => s.push('\t'),
+                        Some('\\') => s.push('\\'),
+                        Some('"') => s.push('"'),
+                        Some(c) => Err(format!("unknown escape: \\{c}"))?,
+                        None => Err("unterminated string")?,
+                    },
+                    Some(c) => s.push(c),
+                    None => Err("unterminated string")?,
+                }
+            }
+
+            Ok(Value::String(s))
+        }
+        Some('[') => {
+            chars.next();
+            let mut values = Vec::new();
+            loop {
+                skip_whitespace(chars);
+                if chars.next_if_eq(&']').is_some() {
+                    break;
+                }
+
+                values.push(parse_value(chars)?);
+
+                skip_whitespace(chars);
+                match chars.next() {
+                    Some(',') => {}
+                    Some(']') => break,
+                    _ => Err("expected , or ] in array")?,
+                }
+            }
+
+            Ok(Value::Array(values))
+        }
+        Some(_) => {
+            let mut word = String::new();
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace() && !matches!(c, ',' | ']')) {
+                word.push(c);
+            }
+
+            match word.as_str() {
+                "true" => Ok(Value::Boolean(true)),
+                "false" => Ok(Value::Boolean(false)),
+                _ => match word.replace('_', "").parse::<i64>() {
+                    Ok(n) => Ok(Value::Integer(n)),
+                    Err(_) => Err(format!("invalid value: {word}"))?,
+                },
+            }
+        }
+        None => Err("missing value")?,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Manifest, Value};
+    use crate::Result;
+
+    #[test]
+    fn test_parse() -> Result<()> {
+        let src = r#"
+# Comment
+workers = 4
+
+[defaults]
+deterministic = true
+
+[[job]]
+program = "a.out" # trailing comment
+args = ["one", "two \"quoted\""]
+
+[[job]]
+program = "b.out"
+args = []
+"#;
+        let manifest = Manifest::parse(src)?;
+
+        assert_eq!(manifest.root["workers"], Value::Integer(4));
+        assert_eq!(
+            manifest.tables["defaults"]["deterministic"],
+            Value::Boolean(true)
+        );
+
+        let jobs = &manifest.arrays["job"];
+        assert_eq!(jobs.len(), 2);
+        assert_eq!(jobs[0]["program"], Value::String("a.out".into()));
+        assert_eq!(
+            jobs[0]["args"],
+            Value::Array(vec![
+                Value::String("one".into()),
+                Value::String("two \"quoted\"".into())
+            ])
+        );
+        assert_eq!(jobs[1]["args"], Value::Array(vec![]));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        for (src, want) in [
+            ("a = ", "line 1: missing value"),
+            ("a = 1\na = 2", "line 2: duplicate key: a"),
+            ("[[job]\n", "line 1: expected ]] after array name"),
+            ("a = \"b", "line 1: unterminated string"),
+            ("a = b", "line 1: invalid value: b"),
+        ] {
+            let err = Manifest::parse(src).unwrap_err();
+            assert_eq!(err.to_string(), want);
+        }
+    }
+}
@@ -0,0 +1,90 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Counters and gauges describing a running interpreter. Share one with the interpreter through
+/// [`crate::interpreter::InterpreterConfig::metrics`] and read it from another thread, or render
+/// it in the Prometheus text format with [`Metrics::render`].
+#[derive(Debug, Default)]
+pub struct Metrics {
+    instructions: AtomicU64,
+    syscalls: AtomicU64,
+    heap_bytes: AtomicU64,
+    frames: AtomicU64,
+}
+
+impl Metrics {
+    /// The number of instructions executed
+    pub fn instructions(&self) -> u64 {
+        self.instructions.load(Ordering::Relaxed)
+    }
+
+    /// The number of system calls made
+    pub fn syscalls(&self) -> u64 {
+        self.syscalls.load(Ordering::Relaxed)
+    }
+
+    /// The number of bytes in live heap allocations
+    pub fn heap_bytes(&self) -> u64 {
+        self.heap_bytes.load(Ordering::Relaxed)
+    }
+
+    /// The number of frames on the call stack
+    pub fn frames(&self) -> u64 {
+        self.frames.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn record_instruction(&self) {
+        self.instructions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_syscall(&self) {
+        self.syscalls.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_heap_bytes(&self, bytes: usize) {
+        self.heap_bytes.store(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_frames(&self, frames: usize) {
+        self.frames.store(frames as u64, Ordering::Relaxed);
+    }
+
+    /// Renders the metrics in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let metrics = [
+            (
+                "stack_instructions_total",
+                "counter",
+                "Instructions executed",
+                self.instructions(),
+            ),
+            (
+                "stack_syscalls_total",
+                "counter",
+                "System calls made",
+                self.syscalls(),
+            ),
+            (
+                "stack_heap_bytes",
+                "gauge",
+                "Bytes in live heap allocations",
+                self.heap_bytes(),
+            ),
+            (
+                "stack_frames",
+                "gauge",
+                "Frames on the call stack",
+                self.frames(),
+            ),
+        ];
+
+        let mut out = String::new();
+        for (name, kind, help, value) in metrics {
+            writeln!(out, "# HELP {name} {help}").unwrap();
+            writeln!(out, "# TYPE {name} {kind}").unwrap();
+            writeln!(out, "{name} {value}").unwrap();
+        }
+
+        out
+    }
+}
@@ -0,0 +1,154 @@
+use std::collections::HashMap;
+
+use crate::Result;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum MapKey {
+    Int(i64),
+    Bytes(Vec<u8>),
+}
+
+/// The file descriptors a program starts with: stdin, stdout and stderr
+const STANDARD_FDS: [i32; 3] = [0, 1, 2];
+
+/// Objects which live in the interpreter rather than the heap, referred to by the program through
+/// a handle. Handles start at 1 so 0 is never valid.
+#[derive(Clone)]
+pub struct Objects {
+    maps: Vec<Option<HashMap<MapKey, i64>>>,
+    /// The file descriptors the program may use, which are handles to the host's own
+    fds: Vec<i32>,
+    /// The error code of the last system call which failed, or 0 if none has
+    errno: i32,
+    /// The program's metadata, which is kept when the objects are cleared
+    metadata: Vec<(String, String)>,
+}
+
+impl Default for Objects {
+    fn default() -> Self {
+        Self::new(Vec::new())
+    }
+}
+
+impl Objects {
+    pub fn new(metadata: Vec<(String, String)>) -> Self {
+        let maps = Vec::new();
+        let fds = STANDARD_FDS.to_vec();
+        let errno = 0;
+        Self {
+            maps,
+            fds,
+            errno,
+            metadata,
+        }
+    }
+
+    /// Frees every object, and opens the standard file descriptors again
+    pub fn clear(&mut self) {
+        self.maps.clear();
+        self.fds = STANDARD_FDS.to_vec();
+        self.errno = 0;
+    }
+
+    /// Whether `fd` is in the table of file descriptors the program may use
+    pub fn is_open(&self, fd: i32) -> bool {
+        self.fds.contains(&fd)
+    }
+
+    /// Takes `fd` out of the table, returning false if it wasn't there
+    pub fn close(&mut self, fd: i32) -> bool {
+        let open = self.is_open(fd);
+        self.fds.retain(|&open| open != fd);
+        open
+    }
+
+    pub fn errno(&self) -> i32 {
+        self.errno
+    }
+
+    pub fn set_errno(&mut self, errno: i32) {
+        self.errno = errno;
+    }
+
+    pub fn meta(&self, key: &[u8]) -> Option<&str> {
+        self.metadata
+            .iter()
+            .find(|(k, _)| k.as_bytes() == key)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn new_map(&mut self) -> u64 {
+        // Reuse the slot of a freed map if there is one
+        match self.maps.iter().position(Option::is_none) {
+            Some(i) => {
+                self.maps[i] = Some(HashMap::new());
+                i as u64 + 1
+            }
+            None => {
+                self.maps.push(Some(HashMap::new()));
+                self.maps.len() as u64
+            }
+        }
+    }
+
+    pub fn free_map(&mut self, handle: u64) -> Result<()> {
+        self.map(handle)?;
+        self.maps[handle as usize - 1] = None;
+
+        Ok(())
+    }
+
+    pub fn map(&mut self, handle: u64) -> Result<&mut HashMap<MapKey, i64>> {
+        match handle
+            .checked_sub(1)
+            .and_then(|i| self.maps.get_mut(i as usize))
+        {
+            Some(Some(map)) => Ok(map),
+            _ => Err(format!("invalid map: {handle}"))?,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{MapKey, Objects};
+    use crate::Result;
+
+    #[test]
+    fn test_maps() -> Result<()> {
+        let mut objects = Objects::default();
+        let a = objects.new_map();
+        let b = objects.new_map();
+        assert_eq!((a, b), (1, 2));
+
+        objects.map(a)?.insert(MapKey::Int(1), 10);
+        objects.map(a)?.insert(MapKey::Bytes(b"one".to_vec()), 11);
+        assert_eq!(objects.map(a)?.len(), 2);
+        assert!(objects.map(b)?.is_empty());
+
+        // A freed handle is invalid until it's reused by a new map
+        objects.free_map(a)?;
+        assert_eq!(objects.map(a).unwrap_err().to_string(), "invalid map: 1");
+        assert!(objects.map(0).is_err());
+        assert_eq!(objects.new_map(), a);
+        assert!(objects.map(a)?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fds() {
+        let mut objects = Objects::default();
+        assert!(objects.is_open(1));
+        assert!(!objects.is_open(3));
+
+        assert!(objects.close(1));
+        assert!(!objects.is_open(1));
+        assert!(!objects.close(1));
+
+        objects.set_errno(9);
+        objects.clear();
+        assert!(objects.is_open(1));
+        assert_eq!(objects.errno(), 0);
+    }
+}
@@ -0,0 +1,1039 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::limits::MAX_LOCAL_SLOTS;
+use crate::program::{Bytecode, Fields, Instr, Program};
+use crate::ssa::Block;
+use crate::{Number, Result};
+
+/// Callees with a body of at most this many bytes are inlined at their call sites
+const INLINE_THRESHOLD: usize = 48;
+const SLOT_SIZE: u64 = std::mem::size_of::<i32>() as u64;
+/// The number of slots in a frame's locals array
+const LOCALS_SLOTS: u64 = MAX_LOCAL_SLOTS as u64;
+
+#[derive(Debug, Clone)]
+struct Instruction {
+    op: Bytecode,
+    operand: Vec<u8>,
+    /// The label the operand refers to, backpatched by the assembler once optimisation is done
+    reference: Option<String>,
+}
+
+impl Instruction {
+    fn new(op: Bytecode, operand: Vec<u8>) -> Self {
+        let reference = None;
+        Self {
+            op,
+            operand,
+            reference,
+        }
+    }
+
+    fn size(&self) -> usize {
+        1 + self.operand.len()
+    }
+
+    fn local(&self) -> u64 {
+        <u64 as Number>::from_le_bytes(&self.operand[..u64::SIZE])
+    }
+
+    /// The range of bytes in the locals array read or written by the instruction
+    fn local_bytes(&self) -> Option<(u64, u64)> {
+        let size = match self.op {
+            Bytecode::Load | Bytecode::Store | Bytecode::IncLocal => i32::SIZE,
+            Bytecode::LoadB | Bytecode::StoreB => i8::SIZE,
+            Bytecode::LoadD | Bytecode::StoreD => i64::SIZE,
+            _ => return None,
+        };
+
+        let from = self.local() * SLOT_SIZE;
+        Some((from, from + size as u64))
+    }
+
+    /// The number of slots popped and pushed, or None if it cannot be known statically
+    fn stack_effect(&self) -> Option<(i64, i64)> {
+        let effect = match self.op {
+            Bytecode::ALoad | Bytecode::ALoadB | Bytecode::ALoadBe => (4, 1),
+            Bytecode::ALoadD | Bytecode::ALoadDBe => (4, 2),
+            Bytecode::AStore | Bytecode::AStoreB | Bytecode::AStoreBe => (5, 0),
+            Bytecode::AStoreD | Bytecode::AStoreDBe => (6, 0),
+            Bytecode::Add | Bytecode::AddB => (2, 1),
+            Bytecode::AddD => (4, 2),
+            Bytecode::Alloc => (2, 2),
+            Bytecode::Cmp | Bytecode::CmpB => (2, 1),
+            Bytecode::CmpD => (4, 1),
+            Bytecode::DataPtr => (0, 2),
+            Bytecode::Div | Bytecode::DivB => (2, 1),
+            Bytecode::DivD => (4, 2),
+            Bytecode::Rem | Bytecode::RemB => (2, 1),
+            Bytecode::RemD => (4, 2),
+            Bytecode::And
+            | Bytecode::AndB
+            | Bytecode::Or
+            | Bytecode::OrB
+            | Bytecode::Xor
+            | Bytecode::XorB => (2, 1),
+            Bytecode::AndD | Bytecode::OrD | Bytecode::XorD => (4, 2),
+            Bytecode::Not | Bytecode::NotB => (1, 1),
+            Bytecode::NotD => (2, 2),
+            // The number of bits is always a word
+            Bytecode::Shl | Bytecode::Shr | Bytecode::ShrU => (2, 1),
+            Bytecode::ShlD | Bytecode::ShrD | Bytecode::ShrDU => (3, 2),
+            Bytecode::PushF => (0, 1),
+            Bytecode::PushDF => (0, 2),
+            Bytecode::AddF | Bytecode::SubF | Bytecode::MulF | Bytecode::DivF => (2, 1),
+            Bytecode::AddDF | Bytecode::SubDF | Bytecode::MulDF | Bytecode::DivDF => (4, 2),
+            Bytecode::CmpF => (2, 1),
+            Bytecode::CmpDF => (4, 1),
+            Bytecode::Neg | Bytecode::NegB => (1, 1),
+            Bytecode::NegD => (2, 2),
+            Bytecode::Min | Bytecode::Max => (2, 1),
+            Bytecode::MinD | Bytecode::MaxD => (4, 2),
+            Bytecode::Abs => (1, 1),
+            Bytecode::AbsD => (2, 2),
+            Bytecode::B2W | Bytecode::W2B => (1, 1),
+            Bytecode::W2D => (1, 2),
+            Bytecode::D2W => (2, 1),
+            Bytecode::Dup | Bytecode::DupB => (1, 2),
+            Bytecode::DupD => (2, 4),
+            Bytecode::Free => (2, 0),
+            Bytecode::Get | Bytecode::GetB | Bytecode::GetBe => (4, 1),
+            Bytecode::GetD | Bytecode::GetDBe => (4, 2),
+            Bytecode::Jmp => (0, 0),
+            Bytecode::JmpEq
+            | Bytecode::JmpGe
+            | Bytecode::JmpGt
+            | Bytecode::JmpLe
+            | Bytecode::JmpLt
+            | Bytecode::JmpNe => (1, 0),
+            Bytecode::JmpPtr => (2, 0),
+            Bytecode::Load | Bytecode::LoadB => (0, 1),
+            Bytecode::LoadD => (0, 2),
+            Bytecode::Mul | Bytecode::MulB => (2, 1),
+            Bytecode::MulD => (4, 2),
+            Bytecode::Pop | Bytecode::PopB => (1, 0),
+            Bytecode::PopD => (2, 0),
+            Bytecode::Push | Bytecode::PushB => (0, 1),
+            Bytecode::PushD => (0, 2),
+            Bytecode::NewArr | Bytecode::NewArrB | Bytecode::NewArrD => (2, 2),
+            Bytecode::ALen => (2, 2),
+            Bytecode::AGet | Bytecode::AGetB => (4, 1),
+            Bytecode::AGetD => (4, 2),
+            Bytecode::APut | Bytecode::APutB => (5, 0),
+            Bytecode::APutD => (6, 0),
+            Bytecode::NewMap => (0, 2),
+            Bytecode::MapPut => (6, 0),
+            Bytecode::MapPutS => (8, 0),
+            Bytecode::MapGet => (4, 3),
+            Bytecode::MapGetS => (6, 3),
+            Bytecode::MapDel => (4, 1),
+            Bytecode::MapDelS => (6, 1),
+            Bytecode::MapLen => (2, 2),
+            Bytecode::FreeMap => (2, 0),
+            Bytecode::SLoad => (0, 1),
+            Bytecode::SStore => (1, 0),
+            Bytecode::Store | Bytecode::StoreB => (1, 0),
+            Bytecode::StoreD => (2, 0),
+            Bytecode::Sub | Bytecode::SubB => (2, 1),
+            Bytecode::SubD => (4, 2),
+            Bytecode::Panic | Bytecode::PanicMsg | Bytecode::Ret | Bytecode::Nop => (0, 0),
+            Bytecode::Halt => (1, 0),
+            Bytecode::MemCpy => (6, 0),
+            Bytecode::MemSet => (5, 0),
+            Bytecode::IncLocal => (0, 0),
+            Bytecode::RetB | Bytecode::RetW => (1, 0),
+            Bytecode::RetD => (2, 0),
+            Bytecode::RetN => (self.local() as i64, 0),
+            Bytecode::Pack => (2 + Fields(self.local()).slots() as i64, 0),
+            Bytecode::ToR => (1, 0),
+            Bytecode::ToRD => (2, 0),
+            Bytecode::FromR | Bytecode::RPeek => (0, 1),
+            Bytecode::FromRD | Bytecode::RPeekD => (0, 2),
+            Bytecode::Swap | Bytecode::SwapB => (2, 2),
+            Bytecode::SwapD => (4, 4),
+            Bytecode::Over | Bytecode::OverB => (2, 3),
+            Bytecode::OverD => (4, 6),
+            Bytecode::Unpack => (2, Fields(self.local()).slots() as i64),
+
+            // The effect of a system call depends on the call number and the effect of a call
+            // depends on the callee
+            Bytecode::System | Bytecode::Call | Bytecode::CallPtr => return None,
+        };
+
+        Some(effect)
+    }
+
+    fn is_ret(&self) -> bool {
+        matches!(
+            self.op,
+            Bytecode::Ret | Bytecode::RetB | Bytecode::RetW | Bytecode::RetD | Bytecode::RetN
+        )
+    }
+
+    /// Whether the instruction reads a local. `inc` also writes it back.
+    fn is_load(&self) -> bool {
+        matches!(
+            self.op,
+            Bytecode::Load | Bytecode::LoadB | Bytecode::LoadD | Bytecode::IncLocal
+        )
+    }
+
+    /// The decoded instruction, or None if its operand is a label which hasn't been resolved
+    fn instr(&self) -> Option<Instr> {
+        if self.reference.is_some() {
+            return None;
+        }
+
+        let mut bytes = vec![self.op as u8];
+        bytes.extend(&self.operand);
+        Program::new(&bytes[..]).next_instr().ok()
+    }
+}
+
+impl From<Instr> for Instruction {
+    fn from(instr: Instr) -> Self {
+        match instr {
+            Instr::Push(n) => Instruction::new(Bytecode::Push, n.to_le_bytes().to_vec()),
+            Instr::PushD(n) => Instruction::new(Bytecode::PushD, n.to_le_bytes().to_vec()),
+            instr => Instruction::new(instr.op(), vec![]),
+        }
+    }
+}
+
+/// The result of walking every instruction reachable from a function entry
+struct Function {
+    /// The depth of the operand stack, in slots, before each instruction
+    depths: HashMap<usize, i64>,
+    /// The first slot of the locals array which is never read or written
+    free_slot: u64,
+}
+
+/// Rewrites the text section produced by the assembler before labels are backpatched.
+pub struct Optimiser {
+    instructions: Vec<Instruction>,
+    /// Text labels mapped to the index of the instruction they point at
+    labels: HashMap<String, usize>,
+}
+
+impl Optimiser {
+    pub fn new(
+        text: &[u8],
+        labels: HashMap<String, usize>,
+        unresolved: HashMap<u64, String>,
+    ) -> Result<Self> {
+        let mut instructions = Vec::new();
+        let mut indexes = HashMap::new(); // Position -> Index
+
+        let mut pc = Program::new(text);
+        while (pc.position() as usize) < text.len() {
+            indexes.insert(pc.position() as usize, instructions.len());
+
+            let op = pc.next_op()?;
+            let mut instruction = Instruction::new(op, Vec::new());
+            instruction.reference = unresolved.get(&pc.position()).cloned();
+            for _ in 0..op.operand_size() {
+                instruction.operand.push(pc.next::<u8>()?);
+            }
+
+            instructions.push(instruction);
+        }
+        indexes.insert(text.len(), instructions.len());
+
+        let labels = labels
+            .into_iter()
+            .map(|(label, offset)| match indexes.get(&offset) {
+                Some(&i) => Ok((label, i)),
+                None => Err(format!("label is not at an instruction boundary: {label}")),
+            })
+            .collect::<std::result::Result<_, _>>()?;
+
+        Ok(Self {
+            instructions,
+            labels,
+        })
+    }
+
+    /// Returns the text, the label offsets and the positions of the unresolved label references.
+    pub fn finish(self) -> (Vec<u8>, HashMap<String, usize>, HashMap<u64, String>) {
+        let mut text = Vec::new();
+        let mut offsets = Vec::with_capacity(self.instructions.len() + 1);
+        let mut unresolved = HashMap::new();
+
+        for instruction in self.instructions {
+            offsets.push(text.len());
+            text.push(instruction.op as u8);
+            if let Some(label) = instruction.reference {
+                unresolved.insert(text.len() as u64, label);
+            }
+            text.extend(instruction.operand);
+        }
+        offsets.push(text.len());
+
+        let labels = self
+            .labels
+            .into_iter()
+            .map(|(label, i)| (label, offsets[i]))
+            .collect();
+
+        (text, labels, unresolved)
+    }
+
+    /// Replaces calls to small straight-line functions with the body of the function. The
+    /// arguments are stored into unused locals of the caller and the callee's load and store
+    /// indexes are shifted to match. Returns the number of call sites inlined.
+    pub fn inline(&mut self, entry: &str) -> usize {
+        // Call site -> (depth, first free slot), or None if callers disagree
+        let mut sites: HashMap<usize, Option<(i64, u64)>> = HashMap::new();
+        for entry in self.functions(entry) {
+            let Some(function) = self.analyse(entry) else {
+                continue;
+            };
+
+            for (&i, &depth) in &function.depths {
+                if self.instructions[i].op != Bytecode::Call {
+                    continue;
+                }
+
+                let site = (depth, function.free_slot);
+                sites
+                    .entry(i)
+                    .and_modify(|have| {
+                        if *have != Some(site) {
+                            *have = None
+                        }
+                    })
+                    .or_insert(Some(site));
+            }
+        }
+
+        let mut replacements = HashMap::new();
+        for (i, site) in sites {
+            let Some((depth, base)) = site else {
+                continue;
+            };
+
+            let Some(callee) = self.call_target(&self.instructions[i]) else {
+                continue;
+            };
+
+            if let Some(body) = self.inline_body(callee, depth, base) {
+                replacements.insert(i, body);
+            }
+        }
+
+        let inlined = replacements.len();
+        if inlined > 0 {
+            self.replace(replacements);
+        }
+
+        inlined
+    }
+
+    /// Removes values which are pushed and then immediately popped, and replaces a store followed
+    /// by a load of the same local with a dup and a store. Returns the number of rewrites.
+    pub fn peephole(&mut self) -> usize {
+        let mut replacements = HashMap::new();
+
+        let mut i = 0;
+        while i + 1 < self.instructions.len() {
+            let (a, b) = (&self.instructions[i], &self.instructions[i + 1]);
+
+            // The second instruction could be jumped to directly
+            if self.labels.values().any(|&label| label == i + 1) {
+                i += 1;
+                continue;
+            }
+
+            let replacement = match (a.op, b.op) {
+                (
+                    Bytecode::Push
+                    | Bytecode::PushB
+                    | Bytecode::Load
+                    | Bytecode::LoadB
+                    | Bytecode::Dup
+                    | Bytecode::DupB,
+                    Bytecode::Pop | Bytecode::PopB,
+                )
+                | (Bytecode::PushD | Bytecode::LoadD | Bytecode::DupD, Bytecode::PopD)
+                    if a.reference.is_none() =>
+                {
+                    vec![]
+                }
+                (Bytecode::Store, Bytecode::Load) if a.operand == b.operand => {
+                    vec![Instruction::new(Bytecode::Dup, vec![]), a.clone()]
+                }
+                (Bytecode::StoreD, Bytecode::LoadD) if a.operand == b.operand => {
+                    vec![Instruction::new(Bytecode::DupD, vec![]), a.clone()]
+                }
+                _ => {
+                    i += 1;
+                    continue;
+                }
+            };
+
+            replacements.insert(i, replacement);
+            replacements.insert(i + 1, vec![]);
+            i += 2;
+        }
+
+        let rewrites = replacements.len() / 2;
+        if rewrites > 0 {
+            self.replace(replacements);
+        }
+
+        rewrites
+    }
+
+    /// Lifts each run of straight-line stack code within a basic block to SSA, folding constants
+    /// and dropping values which are never used, and lowers it again if that makes it smaller.
+    /// Returns the number of runs rewritten.
+    pub fn fold(&mut self) -> usize {
+        let targets: HashSet<usize> = self.labels.values().copied().collect();
+        let mut replacements = HashMap::new();
+        let mut folded = 0;
+
+        let mut i = 0;
+        while i < self.instructions.len() {
+            // A run ends at the next instruction which could be jumped to directly
+            let instrs: Vec<Instr> = self.instructions[i..]
+                .iter()
+                .enumerate()
+                .take_while(|&(j, _)| j == 0 || !targets.contains(&(i + j)))
+                .map_while(|(_, instruction)| instruction.instr())
+                .collect();
+
+            let (block, n) = Block::lift(&instrs);
+            if n == 0 {
+                i += 1;
+                continue;
+            }
+
+            let size: usize = instrs[..n].iter().map(Instr::size).sum();
+            if let Some(lowered) = block.lower() {
+                if lowered.iter().map(Instr::size).sum::<usize>() < size {
+                    let lowered = lowered.into_iter().map(Instruction::from).collect();
+                    replacements.insert(i, lowered);
+                    replacements.extend((i + 1..i + n).map(|j| (j, vec![])));
+                    folded += 1;
+                }
+            }
+
+            i += n;
+        }
+
+        if folded > 0 {
+            self.replace(replacements);
+        }
+
+        folded
+    }
+
+    /// Replaces stores to locals which are never loaded by the function with pops. Returns the
+    /// number of stores removed.
+    pub fn dead_stores(&mut self, entry: &str) -> usize {
+        // Store -> whether every function reaching it never loads the stored bytes
+        let mut stores: HashMap<usize, bool> = HashMap::new();
+        for entry in self.functions(entry) {
+            let reachable = self.reachable(entry);
+
+            let loaded: HashSet<u64> = reachable
+                .iter()
+                .map(|&i| &self.instructions[i])
+                .filter(|instruction| instruction.is_load())
+                .filter_map(Instruction::local_bytes)
+                .flat_map(|(from, to)| from..to)
+                .collect();
+
+            for &i in &reachable {
+                let instruction = &self.instructions[i];
+                if instruction.is_load() {
+                    continue;
+                }
+
+                let Some((from, to)) = instruction.local_bytes() else {
+                    continue;
+                };
+
+                let dead = !(from..to).any(|byte| loaded.contains(&byte));
+                *stores.entry(i).or_insert(dead) &= dead;
+            }
+        }
+
+        let replacements: HashMap<usize, Vec<Instruction>> = stores
+            .into_iter()
+            .filter(|&(_, dead)| dead)
+            .map(|(i, _)| {
+                let op = match self.instructions[i].op {
+                    Bytecode::StoreD => Bytecode::PopD,
+                    Bytecode::StoreB => Bytecode::PopB,
+                    _ => Bytecode::Pop,
+                };
+                (i, vec![Instruction::new(op, vec![])])
+            })
+            .collect();
+
+        let removed = replacements.len();
+        if removed > 0 {
+            self.replace(replacements);
+        }
+
+        removed
+    }
+
+    /// Builds the instructions which replace a call to `callee`, if it can be inlined.
+    fn inline_body(&self, callee: usize, depth: i64, base: u64) -> Option<Vec<Instruction>> {
+        let mut body = Vec::new();
+        let mut size = 0;
+        let mut callee_depth = 0;
+        // Bytes of the callee's locals which hold a value, starting with the arguments
+        let mut written: HashSet<u64> = (0..depth as u64 * SLOT_SIZE).collect();
+        let mut slots = depth as u64;
+
+        let mut i = callee;
+        let ret = loop {
+            let instruction = self.instructions.get(i)?;
+            if instruction.is_ret() {
+                break instruction;
+            }
+
+            match instruction.op {
+                Bytecode::Call
+                | Bytecode::CallPtr
+                | Bytecode::System
+                | Bytecode::Panic
+                | Bytecode::PanicMsg
+                | Bytecode::Halt
+                | Bytecode::Jmp
+                | Bytecode::JmpEq
+                | Bytecode::JmpGe
+                | Bytecode::JmpGt
+                | Bytecode::JmpLe
+                | Bytecode::JmpLt
+                | Bytecode::JmpNe
+                | Bytecode::JmpPtr => return None,
+                // The callee's return stack starts empty, while the caller's may not be
+                Bytecode::ToR
+                | Bytecode::ToRD
+                | Bytecode::FromR
+                | Bytecode::FromRD
+                | Bytecode::RPeek
+                | Bytecode::RPeekD => return None,
+                _ => {}
+            }
+
+            let (pops, pushes) = instruction.stack_effect()?;
+            callee_depth -= pops;
+            if callee_depth < 0 {
+                return None;
+            }
+            callee_depth += pushes;
+
+            let mut instruction = instruction.clone();
+            if let Some((from, to)) = instruction.local_bytes() {
+                // A fresh frame would read zeroes from locals which haven't been written, while
+                // the caller's locals may hold anything
+                if instruction.is_load() {
+                    if !(from..to).all(|byte| written.contains(&byte)) {
+                        return None;
+                    }
+                } else {
+                    written.extend(from..to);
+                }
+
+                slots = slots.max(to.div_ceil(SLOT_SIZE));
+                let local = instruction.local() + base;
+                instruction.operand[..u64::SIZE].copy_from_slice(&local.to_le_bytes());
+            }
+
+            size += instruction.size();
+            if size > INLINE_THRESHOLD {
+                return None;
+            }
+
+            body.push(instruction);
+            i += 1;
+        };
+
+        // Anything left on the callee's operand stack other than the return value would be
+        // discarded by the ret
+        let (pops, _) = ret.stack_effect()?;
+        if callee_depth != pops || base + slots > LOCALS_SLOTS {
+            return None;
+        }
+
+        // The caller's operand stack becomes the callee's locals
+        let mut replacement: Vec<Instruction> = (0..depth as u64)
+            .rev()
+            .map(|slot| Instruction::new(Bytecode::Store, (base + slot).to_le_bytes().to_vec()))
+            .collect();
+        replacement.extend(body);
+
+        Some(replacement)
+    }
+
+    /// Walks every instruction reachable from `entry`, tracking the depth of the operand stack.
+    /// Returns None if the depth cannot be determined.
+    fn analyse(&self, entry: usize) -> Option<Function> {
+        let mut depths = HashMap::new();
+        let mut free_slot = 0;
+        let mut queue = vec![(entry, 0)];
+
+        while let Some((i, depth)) = queue.pop() {
+            let instruction = self.instructions.get(i)?;
+
+            match depths.get(&i) {
+                Some(&have) if have == depth => continue,
+                Some(_) => return None,
+                None => depths.insert(i, depth),
+            };
+
+            if let Some((_, to)) = instruction.local_bytes() {
+                free_slot = u64::max(free_slot, to.div_ceil(SLOT_SIZE));
+            }
+
+            let next = match instruction.op {
+                // The caller's operand stack is cleared by the call
+                Bytecode::Call => self.ret_slots(self.call_target(instruction)?)?,
+                _ => {
+                    let (pops, pushes) = instruction.stack_effect()?;
+                    if depth < pops {
+                        return None;
+                    }
+                    depth - pops + pushes
+                }
+            };
+
+            for successor in self.successors(i) {
+                queue.push((successor, next));
+            }
+        }
+
+        Some(Function { depths, free_slot })
+    }
+
+    /// The number of slots returned by the function, if all of its return instructions agree.
+    fn ret_slots(&self, entry: usize) -> Option<i64> {
+        let mut slots = None;
+
+        for i in self.reachable(entry) {
+            let instruction = &self.instructions[i];
+            if instruction.is_ret() {
+                let (pops, _) = instruction.stack_effect()?;
+                if *slots.get_or_insert(pops) != pops {
+                    return None;
+                }
+            }
+        }
+
+        slots
+    }
+
+    /// The entry of the program and the target of every call.
+    fn functions(&self, entry: &str) -> HashSet<usize> {
+        let mut functions = HashSet::new();
+        if let Some(&i) = self.labels.get(entry) {
+            functions.insert(i);
+        }
+        for instruction in &self.instructions {
+            if let Some(i) = self.call_target(instruction) {
+                functions.insert(i);
+            }
+        }
+
+        functions
+    }
+
+    /// Every instruction which can be executed within the frame of the function at `entry`.
+    fn reachable(&self, entry: usize) -> HashSet<usize> {
+        let mut seen = HashSet::new();
+        let mut queue = vec![entry];
+
+        while let Some(i) = queue.pop() {
+            if i >= self.instructions.len() || !seen.insert(i) {
+                continue;
+            }
+
+            queue.extend(self.successors(i));
+        }
+
+        seen
+    }
+
+    fn successors(&self, i: usize) -> Vec<usize> {
+        let instruction = &self.instructions[i];
+        let target = || {
+            instruction
+                .reference
+                .as_ref()
+                .and_then(|label| self.labels.get(label).copied())
+        };
+
+        let successors = match instruction.op {
+            Bytecode::Ret
+            | Bytecode::RetB
+            | Bytecode::RetW
+            | Bytecode::RetD
+            | Bytecode::RetN
+            | Bytecode::Panic
+            | Bytecode::PanicMsg
+            | Bytecode::Halt => vec![],
+            Bytecode::Jmp => target().into_iter().collect(),
+            Bytecode::JmpEq
+            | Bytecode::JmpGe
+            | Bytecode::JmpGt
+            | Bytecode::JmpLe
+            | Bytecode::JmpLt
+            | Bytecode::JmpNe => [Some(i + 1), target()].into_iter().flatten().collect(),
+            // The target is only known at run time, but it can only sensibly be a label
+            Bytecode::JmpPtr => self.labels.values().copied().collect(),
+            _ => vec![i + 1],
+        };
+
+        successors
+            .into_iter()
+            .filter(|&i| i < self.instructions.len())
+            .collect()
+    }
+
+    fn call_target(&self, instruction: &Instruction) -> Option<usize> {
+        if instruction.op != Bytecode::Call {
+            return None;
+        }
+
+        instruction
+            .reference
+            .as_ref()
+            .and_then(|label| self.labels.get(label).copied())
+    }
+
+    /// Replaces the instructions at the given indexes, keeping labels pointing at the start of
+    /// the replacement.
+    fn replace(&mut self, mut replacements: HashMap<usize, Vec<Instruction>>) {
+        let mut instructions = Vec::with_capacity(self.instructions.len());
+        let mut indexes = Vec::with_capacity(self.instructions.len() + 1);
+
+        for (i, instruction) in std::mem::take(&mut self.instructions)
+            .into_iter()
+            .enumerate()
+        {
+            indexes.push(instructions.len());
+            match replacements.remove(&i) {
+                Some(replacement) => instructions.extend(replacement),
+                None => instructions.push(instruction),
+            }
+        }
+        indexes.push(instructions.len());
+
+        self.labels.values_mut().for_each(|i| *i = indexes[*i]);
+        self.instructions = instructions;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::assembler::Assembler;
+    use crate::interpreter::Interpreter;
+    use crate::program::{Bytecode, Program};
+    use crate::Result;
+
+    fn optimise(src: &str) -> Result<Vec<Bytecode>> {
+        let (_, ops) = run(src, true)?;
+        Ok(ops)
+    }
+
+    fn run(src: &str, optimise: bool) -> Result<(Vec<u8>, Vec<Bytecode>)> {
+        let output = Assembler::new().with_optimise(optimise).assemble(src)?;
+
+        let program: Vec<u8> = (&output).into();
+        let mut ops = Vec::new();
+        let mut pc = Program::new(&program[..]);
+        pc.set_position(size_of::<u64>() as u64); // The programs have no data section
+        while let Ok(op) = pc.next_op() {
+            ops.push(op);
+            pc.set_position(pc.position() + op.operand_size() as u64);
+        }
+
+        let mut interpreter = Interpreter::new(&output, None, None)?;
+        interpreter.run().into_result()?;
+        let stack = interpreter.current_frame().stack().to_vec();
+
+        Ok((stack, ops))
+    }
+
+    #[test]
+    fn test_inline() -> Result<()> {
+        let src = "
+.entry main
+
+main:
+    push 3
+    store 0
+    push 1
+    push 2
+    call add
+    load 0
+    call add
+    store 1
+    push.d 7
+    call square
+    load 1
+    ret
+
+add:
+    load 0
+    load 1
+    add
+    ret.w
+
+square:
+    load.d 0
+    store.d 2
+    load.d 2
+    load.d 2
+    mul.d
+    ret.d
+";
+
+        let (want, ops) = run(src, false)?;
+        assert_eq!(ops.iter().filter(|&&op| op == Bytecode::Call).count(), 3);
+
+        let (have, ops) = run(src, true)?;
+        assert!(!ops.contains(&Bytecode::Call));
+        assert_eq!(want, have);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_inline_skips_unsafe_callees() -> Result<()> {
+        let src = "
+.entry main
+
+main:
+    push 5
+    call factorial
+    push 1
+    push 2
+    push 3
+    call first
+    push 9
+    call uninitialised
+    push 4
+    call stashed
+    ret
+
+factorial:
+    load 0
+    push 1
+    cmp
+    jmp.gt recurse
+    push 1
+    ret.w
+recurse:
+    load 0
+    push 1
+    sub
+    call factorial
+    load 0
+    mul
+    ret.w
+
+; Leaves values behind on its operand stack
+first:
+    load 0
+    load 1
+    load 2
+    ret.w
+
+; Reads a local which would be zeroed in a fresh frame
+uninitialised:
+    load 5
+    ret.w
+
+; Uses its own return stack
+stashed:
+    load 0
+    tor
+    fromr
+    ret.w
+";
+
+        let (want, _) = run(src, false)?;
+        let (have, ops) = run(src, true)?;
+        assert_eq!(ops.iter().filter(|&&op| op == Bytecode::Call).count(), 5);
+        assert_eq!(want, have);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_inline_keeps_hooked_calls() -> Result<()> {
+        let src = "
+.entry main
+.onenter enter
+
+main:
+    push 1
+    push 2
+    call add
+    push 3
+    call add
+    sload 0
+    ret
+
+add:
+    load 0
+    load 1
+    add
+    ret.w
+
+; Counts the calls into scratch slot 0
+enter:
+    sload 0
+    push 1
+    add
+    sstore 0
+    ret
+";
+
+        // The hook runs for every call, so none can be inlined away
+        let (want, _) = run(src, false)?;
+        let (have, ops) = run(src, true)?;
+        assert_eq!(ops.iter().filter(|&&op| op == Bytecode::Call).count(), 2);
+        assert_eq!(want, have);
+        assert_eq!(have, [6, 0, 0, 0, 2, 0, 0, 0]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_peephole() -> Result<()> {
+        let src = "
+.entry main
+
+main:
+    push 1
+    pop
+    push.d 2
+    pop.d
+    push 3
+    store 0
+    load 0
+    load 0
+    add
+    push.d 4
+    store.d 2
+    load.d 2
+    load.d 2
+    add.d
+    ret
+";
+        let (want, _) = run(src, false)?;
+        let (have, ops) = run(src, true)?;
+        // The dup and add left by the peephole pass are folded into a single push
+        assert_eq!(ops, vec![Bytecode::Push, Bytecode::PushD, Bytecode::Ret]);
+        assert_eq!(want, have);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_peephole_keeps_jump_targets() -> Result<()> {
+        let src = "
+.entry main
+
+main:
+    push 5
+    push 1
+    jmp.ne skip
+    push 2
+skip:
+    pop
+    ret
+";
+        let ops = optimise(src)?;
+        assert!(ops.contains(&Bytecode::Pop));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_dead_stores() -> Result<()> {
+        let src = "
+.entry main
+
+main:
+    push 1
+    store 0
+    push 2
+    store 1
+    load 1
+    push 3
+    call id
+    ret
+
+id:
+    push 4
+    store 1
+    load 0
+    ret.w
+";
+        let (want, _) = run(src, false)?;
+        let (have, ops) = run(src, true)?;
+        // The arguments of the inlined call are only stored to be loaded once, and `id` is no
+        // longer reachable so it is left alone
+        assert_eq!(
+            ops,
+            vec![
+                Bytecode::Push,
+                Bytecode::Ret,
+                Bytecode::Push,
+                Bytecode::Store,
+                Bytecode::Load,
+                Bytecode::RetW,
+            ]
+        );
+        assert_eq!(want, have);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fold() -> Result<()> {
+        let src = "
+.entry main
+
+main:
+    push 6
+    push 7
+    mul
+    load 0
+    push 2
+    push 3
+    add
+    add
+    push.d 100
+    push.d 7
+    rem.d
+    push 9
+    pop
+    ret
+";
+        let (want, _) = run(src, false)?;
+        let (have, ops) = run(src, true)?;
+        assert_eq!(
+            ops,
+            vec![
+                Bytecode::Push,
+                Bytecode::Load,
+                Bytecode::Push,
+                Bytecode::Add,
+                Bytecode::PushD,
+                Bytecode::Ret,
+            ]
+        );
+        assert_eq!(want, have);
+
+        Ok(())
+    }
+}
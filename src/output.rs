@@ -1,13 +1,58 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fmt::Write;
 use std::io::Read;
 
-use crate::program::{Bytecode, Program};
-use crate::{Bytes, Number, Result};
+use crate::compress::{compress, decompress};
+use crate::extension::Extension;
+use crate::program::{Bytecode, Fields, Instr, Program};
+use crate::signing::{Signer, Verifier};
+use crate::{Bytes, Float, Number, Result};
+
+/// Where an instruction which came from a macro expansion or an included file was written
+#[derive(Debug, Clone, PartialEq)]
+pub struct Origin {
+    /// The instruction's own location, unless it was written in a macro body
+    pub location: Option<String>,
+    /// The expansions and includes the instruction was assembled within, innermost first
+    pub expansions: Vec<String>,
+}
+
+/// Functions the interpreter calls around every call and return, set with `.onenter` and `.onexit`
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Hooks {
+    /// Called with the callee's entry before its first instruction
+    pub enter: Option<u64>,
+    /// Called with the callee's entry once it has returned
+    pub exit: Option<u64>,
+}
+
+impl Hooks {
+    fn is_empty(&self) -> bool {
+        self.enter.is_none() && self.exit.is_none()
+    }
+}
+
+/// Set in the serialised entry when the data and text are compressed, since the entry is never
+/// that far into the program
+const COMPRESSED: u64 = 1 << 63;
+
+type Labels = HashMap<u64, String>;
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct Output {
     labels: HashMap<u64, String>,
+    /// Debug information for instructions which didn't come straight from the program source
+    origins: HashMap<u64, Origin>,
+    /// Keys and values describing the program, such as its name and version
+    metadata: Vec<(String, String)>,
+    hooks: Hooks,
+    /// Produced by a [`Signer`] over the entry, data and text
+    signature: Option<Vec<u8>>,
+    /// The number of local slots used by each function, by its entry, from
+    /// [`Output::analyse_locals`]
+    local_slots: HashMap<u64, u16>,
+    /// The optional instruction families the program uses, from [`Output::analyse_extensions`]
+    extensions: Vec<Extension>,
     entry: u64,
     data: Vec<u8>,
     text: Vec<u8>,
@@ -16,6 +61,7 @@ pub struct Output {
 impl std::fmt::Display for Output {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         self.fmt_entry(f).map_err(|_| std::fmt::Error)?;
+        self.fmt_metadata(f).map_err(|_| std::fmt::Error)?;
         writeln!(f)?;
 
         self.fmt_data(f).map_err(|_| std::fmt::Error)?;
@@ -39,33 +85,457 @@ impl From<&Output> for Vec<u8> {
 }
 
 impl From<Output> for Vec<u8> {
+    /// Builds the program in the data's buffer, rather than a new one
     fn from(output: Output) -> Self {
-        (&output).into()
+        let mut program = output.data;
+        program.reserve(size_of::<u64>() + output.text.len());
+        program.splice(0..0, output.entry.to_le_bytes());
+        program.extend(output.text);
+        program
     }
 }
 
 impl Output {
     pub fn new(entry: u64, data: Vec<u8>, text: Vec<u8>, labels: HashMap<u64, String>) -> Self {
+        let origins = HashMap::new();
+        let metadata = Vec::new();
+        let hooks = Hooks::default();
+        let signature = None;
+        let local_slots = HashMap::new();
+        let extensions = Vec::new();
         Self {
             entry,
             data,
             text,
             labels,
+            origins,
+            metadata,
+            hooks,
+            signature,
+            local_slots,
+            extensions,
+        }
+    }
+
+    pub fn with_origins(mut self, origins: HashMap<u64, Origin>) -> Self {
+        self.origins = origins;
+        self
+    }
+
+    pub fn with_metadata(mut self, metadata: Vec<(String, String)>) -> Self {
+        self.metadata = metadata;
+        self
+    }
+
+    pub fn with_hooks(mut self, hooks: Hooks) -> Self {
+        self.hooks = hooks;
+        self
+    }
+
+    pub fn with_local_slots(mut self, local_slots: HashMap<u64, u16>) -> Self {
+        self.local_slots = local_slots;
+        self
+    }
+
+    pub fn with_extensions(mut self, extensions: Vec<Extension>) -> Self {
+        self.extensions = extensions;
+        self
+    }
+
+    pub fn hooks(&self) -> Hooks {
+        self.hooks
+    }
+
+    /// The number of local slots used by each function, by its entry, which the interpreter
+    /// allocates for calls to it rather than every slot
+    pub fn local_slots(&self) -> &HashMap<u64, u16> {
+        &self.local_slots
+    }
+
+    /// The optional instruction families the program requires, which the interpreter checks the
+    /// build enables before running it
+    pub fn extensions(&self) -> &[Extension] {
+        &self.extensions
+    }
+
+    /// Signs the bytes the interpreter runs, which are the entry, data and text. Labels, origins,
+    /// metadata and hooks aren't covered by the signature.
+    pub fn sign(&mut self, signer: &dyn Signer) {
+        let program: Vec<u8> = (&*self).into();
+        self.signature = Some(signer.sign(&program));
+    }
+
+    pub fn signature(&self) -> Option<&[u8]> {
+        self.signature.as_deref()
+    }
+
+    /// Checks the program was signed and that its signature is accepted by `verifier`
+    pub fn verify(&self, verifier: &dyn Verifier) -> Result<()> {
+        let Some(signature) = &self.signature else {
+            Err("program is not signed")?
+        };
+
+        let program: Vec<u8> = self.into();
+        if !verifier.verify(&program, signature) {
+            Err("program signature is not valid")?
         }
+
+        Ok(())
+    }
+
+    pub fn metadata(&self) -> &[(String, String)] {
+        &self.metadata
+    }
+
+    pub fn meta(&self, key: &str) -> Option<&str> {
+        self.metadata
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| value.as_str())
     }
 
     pub fn labels(&self) -> &HashMap<u64, String> {
         &self.labels
     }
 
+    /// Removes the labels and origins, which are only needed for debugging, so the program can be
+    /// shipped without them. It runs the same, while backtraces and the debugger show positions
+    /// in place of labels. The metadata, hooks, local slots and signature are kept.
+    pub fn strip(&mut self) {
+        self.labels.clear();
+        self.origins.clear();
+    }
+
+    /// Renames each label to what `rename` gives for it. Fails if two labels would end up with
+    /// the same name, as they could then no longer be told apart.
+    pub fn rename_labels(&mut self, mut rename: impl FnMut(&str) -> String) -> Result<()> {
+        let mut names = HashSet::new();
+        let mut labels = HashMap::with_capacity(self.labels.len());
+        for (&position, label) in &self.labels {
+            let name = rename(label);
+            if !names.insert(name.clone()) {
+                Err(format!("more than one label renamed to {name}"))?
+            }
+            labels.insert(position, name);
+        }
+        self.labels = labels;
+
+        Ok(())
+    }
+
+    /// Renames the labels to `L0`, `L1` and so on in the order they appear, and removes the
+    /// origins, so the program can still be debugged by function without giving away the names
+    /// and files it was written with
+    pub fn obfuscate_labels(&mut self) {
+        let mut positions: Vec<u64> = self.labels.keys().copied().collect();
+        positions.sort();
+        self.labels = positions
+            .into_iter()
+            .enumerate()
+            .map(|(i, position)| (position, format!("L{i}")))
+            .collect();
+        self.origins.clear();
+    }
+
+    /// Splits the program into its entry, data and text laid out as the interpreter runs them,
+    /// its labels and its metadata, without copying any of them
+    pub(crate) fn into_parts(mut self) -> (Vec<u8>, Labels, Vec<(String, String)>) {
+        let labels = std::mem::take(&mut self.labels);
+        let metadata = std::mem::take(&mut self.metadata);
+        (self.into(), labels, metadata)
+    }
+
+    /// The position of the first instruction run
+    pub fn entry(&self) -> u64 {
+        self.entry
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn text(&self) -> &[u8] {
+        &self.text
+    }
+
+    /// Where the instruction at `position` came from, if it was assembled within a macro
+    /// expansion or an included file
+    pub fn origin(&self, position: u64) -> Option<&Origin> {
+        self.origins.get(&position)
+    }
+
+    /// The number of instructions in the text section
+    pub fn instruction_count(&self) -> Result<usize> {
+        Ok(self.instruction_positions()?.len())
+    }
+
+    /// Decodes the text into each instruction and its position in the program, in order. This
+    /// is the place to start for anything which inspects the text, rather than reading opcodes
+    /// and operands by hand.
+    pub fn instructions(&self) -> Result<Vec<(u64, Instr)>> {
+        let text_offset = (size_of::<u64>() + self.data.len()) as u64;
+
+        let mut instructions = Vec::new();
+        let mut pc = Program::new(self.text.as_slice());
+        while (pc.position() as usize) < self.text.len() {
+            let position = text_offset + pc.position();
+            instructions.push((position, pc.next_instr()?));
+        }
+
+        Ok(instructions)
+    }
+
+    /// The position of each instruction in the program, in order. Use this rather than decoding
+    /// the text to check whether a position is the start of an instruction.
+    pub fn instruction_positions(&self) -> Result<Vec<u64>> {
+        Ok(self
+            .instructions()?
+            .into_iter()
+            .map(|(position, _)| position)
+            .collect())
+    }
+
+    /// Finds loops which can never be left: a backward `jmp` where nothing between its target
+    /// and the jump branches, calls, returns, makes a system call or jumps out. Returns the
+    /// position of the start of each loop and of the jump which closes it.
+    pub fn infinite_loops(&self) -> Result<Vec<(u64, u64)>> {
+        let instructions = self.instructions()?;
+
+        let mut loops = Vec::new();
+        for (i, &(end, instr)) in instructions.iter().enumerate() {
+            let Instr::Jump(Bytecode::Jmp, start) = instr else {
+                continue;
+            };
+            if start > end {
+                continue;
+            }
+
+            let Some(first) = instructions[..=i].iter().position(|&(p, _)| p == start) else {
+                // The target isn't an instruction, which isn't a question for this check
+                continue;
+            };
+
+            let exits = instructions[first..=i]
+                .iter()
+                .any(|&(_, instr)| match instr {
+                    Instr::Jump(Bytecode::Jmp, target) => target < start || target > end,
+                    Instr::Jump(..) | Instr::Call(_) | Instr::RetN(_) | Instr::PanicMsg(_) => true,
+                    Instr::Op(op) => matches!(
+                        op,
+                        Bytecode::Ret
+                            | Bytecode::RetB
+                            | Bytecode::RetW
+                            | Bytecode::RetD
+                            | Bytecode::System
+                            | Bytecode::Panic
+                            | Bytecode::Halt
+                            | Bytecode::CallPtr
+                            | Bytecode::JmpPtr
+                    ),
+                    _ => false,
+                });
+            if !exits {
+                loops.push((start, end));
+            }
+        }
+
+        Ok(loops)
+    }
+
+    /// Finds the number of local slots each function uses from the `load` and `store`
+    /// instructions reachable from its entry, without following calls. The functions are the
+    /// entry, the hooks and the targets of `call`. Those which jump through a pointer, or to a
+    /// position which isn't an instruction, are left out, as are any only called through one.
+    pub fn analyse_locals(&self) -> Result<HashMap<u64, u16>> {
+        let instructions = self.instructions()?;
+        let index = instructions
+            .iter()
+            .enumerate()
+            .map(|(i, &(position, _))| (position, i))
+            .collect::<HashMap<u64, usize>>();
+
+        let calls = instructions.iter().filter_map(|&(_, instr)| match instr {
+            Instr::Call(target) => Some(target),
+            _ => None,
+        });
+        let entries = std::iter::once(self.entry)
+            .chain(self.hooks.enter)
+            .chain(self.hooks.exit)
+            .chain(calls)
+            .collect::<HashSet<u64>>();
+
+        let mut local_slots = HashMap::new();
+        'entries: for entry in entries {
+            let mut slots = 0;
+            let mut seen = HashSet::new();
+            let mut queue = vec![entry];
+            while let Some(position) = queue.pop() {
+                let Some(&i) = index.get(&position) else {
+                    continue 'entries;
+                };
+                if !seen.insert(i) {
+                    continue;
+                }
+
+                let next = instructions.get(i + 1).map(|&(position, _)| position);
+                match instructions[i].1 {
+                    Instr::Local(op, slot) => {
+                        let width = match op {
+                            Bytecode::LoadD | Bytecode::StoreD => 2,
+                            _ => 1,
+                        };
+                        slots = slots.max(slot.saturating_add(width));
+                        queue.extend(next);
+                    }
+                    Instr::IncLocal(slot, _) => {
+                        slots = slots.max(slot.saturating_add(1));
+                        queue.extend(next);
+                    }
+                    Instr::Jump(Bytecode::Jmp, target) => queue.push(target),
+                    Instr::Jump(_, target) => {
+                        queue.extend([Some(target), next].into_iter().flatten())
+                    }
+                    Instr::RetN(_) | Instr::PanicMsg(_) => {}
+                    Instr::Op(
+                        Bytecode::Ret
+                        | Bytecode::RetB
+                        | Bytecode::RetW
+                        | Bytecode::RetD
+                        | Bytecode::Panic
+                        | Bytecode::Halt,
+                    ) => {}
+                    Instr::Op(Bytecode::JmpPtr) => continue 'entries,
+                    _ => queue.extend(next),
+                }
+            }
+
+            local_slots.insert(entry, u16::try_from(slots).unwrap_or(u16::MAX));
+        }
+
+        Ok(local_slots)
+    }
+
+    /// Finds the extensions the instructions belong to, in the order of [`Extension::ALL`]
+    pub fn analyse_extensions(&self) -> Result<Vec<Extension>> {
+        let mut extensions: Vec<Extension> = self
+            .instructions()?
+            .into_iter()
+            .filter_map(|(_, instr)| Extension::of(instr.op()))
+            .collect();
+        extensions.sort();
+        extensions.dedup();
+
+        Ok(extensions)
+    }
+
+    /// Describes `position` by the closest label at or before it, such as `add+9`
+    pub fn symbolise(&self, position: u64) -> String {
+        let label = self
+            .labels
+            .iter()
+            .filter(|(&start, _)| start <= position)
+            .max_by_key(|(&start, _)| start);
+
+        match label {
+            Some((&start, label)) if start == position => label.clone(),
+            Some((&start, label)) => format!("{label}+{}", position - start),
+            None => position.to_string(),
+        }
+    }
+
+    /// Appends the data and text of `other` after this program's own, so host code can compose
+    /// programs from separately assembled fragments. Jump, call and `dataptr` operands, labels
+    /// and origins are moved along with the sections they point into. Positions pushed as
+    /// immediates, such as `push.d label`, can't be told apart from numbers and are left as is.
+    /// The entry stays this program's, as do its hooks and metadata keys when it has them. The
+    /// merged program is no longer signed, and its locals are analysed again.
+    pub fn merge(&mut self, mut other: Output) -> Result<()> {
+        other.relocate(
+            self.data.len() as u64,
+            (self.data.len() + self.text.len()) as u64,
+        )?;
+        self.relocate(0, other.data.len() as u64)?;
+
+        self.data.extend(other.data);
+        self.text.extend(other.text);
+        self.labels.extend(other.labels);
+        self.origins.extend(other.origins);
+        self.hooks.enter = self.hooks.enter.or(other.hooks.enter);
+        self.hooks.exit = self.hooks.exit.or(other.hooks.exit);
+        for (key, value) in other.metadata {
+            if self.meta(&key).is_none() {
+                self.metadata.push((key, value));
+            }
+        }
+        self.signature = None;
+        // The other program's entry is no longer a function unless it's called
+        self.local_slots = self.analyse_locals()?;
+        self.extensions = self.analyse_extensions()?;
+
+        Ok(())
+    }
+
+    /// Shifts positions in the data section forward by `data_shift` and positions in the text
+    /// section by `text_shift`, as if more bytes were inserted before each section
+    fn relocate(&mut self, data_shift: u64, text_shift: u64) -> Result<()> {
+        let text_offset = (size_of::<u64>() + self.data.len()) as u64;
+        let end = text_offset + self.text.len() as u64;
+        // Anything past the end of the program, such as `dataptr -1`, isn't a position
+        let shift = |position: u64| match position {
+            position if position < text_offset => position + data_shift,
+            position if position <= end => position + text_shift,
+            position => position,
+        };
+
+        for (position, instr) in self.instructions()? {
+            let target = match instr {
+                Instr::Jump(_, target)
+                | Instr::Call(target)
+                | Instr::DataPtr(target)
+                | Instr::PanicMsg(target) => target,
+                _ => continue,
+            };
+            let i = (position - text_offset) as usize + 1;
+            self.text[i..i + size_of::<u64>()].copy_from_slice(&shift(target).to_le_bytes());
+        }
+
+        self.entry = shift(self.entry);
+        self.hooks.enter = self.hooks.enter.map(shift);
+        self.hooks.exit = self.hooks.exit.map(shift);
+        self.labels = self
+            .labels
+            .drain()
+            .map(|(position, label)| (shift(position), label))
+            .collect();
+        self.origins = self
+            .origins
+            .drain()
+            .map(|(position, origin)| (shift(position), origin))
+            .collect();
+
+        Ok(())
+    }
+
     pub fn deserialise<R: Read>(mut r: R) -> Result<Self> {
         let entry = r.read_u64()?;
+        let compressed = entry & COMPRESSED != 0;
+        let entry = entry & !COMPRESSED;
 
         // Data and text
-        let len = r.read_u16()?;
-        let data = r.read_n(len as usize)?;
-        let len = r.read_u16()?;
-        let text = r.read_n(len as usize)?;
+        let mut section = || -> Result<Vec<u8>> {
+            match compressed {
+                true => {
+                    let len = r.read_u32()?;
+                    decompress(&r.read_n(len as usize)?)
+                }
+                false => {
+                    let len = r.read_u16()?;
+                    r.read_n(len as usize)
+                }
+            }
+        };
+        let data = section()?;
+        let text = section()?;
 
         // Label offsets
         let len = r.read_u16()?;
@@ -88,15 +558,91 @@ impl Output {
         assert!(offsets.len() == labels.len());
         let labels = std::iter::zip(offsets, labels).collect::<HashMap<u64, String>>();
 
+        // Origins, metadata, hooks, the signature, local slots and extensions are only written when
+        // there are any
+        let mut rest = Vec::new();
+        r.read_to_end(&mut rest)?;
+        let mut r = rest.as_slice();
+        let mut origins = HashMap::new();
+        let mut metadata = Vec::new();
+        if !r.is_empty() {
+            let len = r.read_u16()?;
+            for _ in 0..len {
+                let position = r.read_u64()?;
+                let location = Some(read_string(&mut r)?).filter(|location| !location.is_empty());
+                let len = r.read_u16()?;
+                let expansions = (0..len)
+                    .map(|_| read_string(&mut r))
+                    .collect::<Result<_>>()?;
+                origins.insert(
+                    position,
+                    Origin {
+                        location,
+                        expansions,
+                    },
+                );
+            }
+        }
+        if !r.is_empty() {
+            let len = r.read_u16()?;
+            for _ in 0..len {
+                let key = read_string(&mut r)?;
+                let value = read_string(&mut r)?;
+                metadata.push((key, value));
+            }
+        }
+        let mut hooks = Hooks::default();
+        if !r.is_empty() {
+            hooks.enter = Some(r.read_u64()?).filter(|&position| position != 0);
+            hooks.exit = Some(r.read_u64()?).filter(|&position| position != 0);
+        }
+        let mut signature = None;
+        if !r.is_empty() {
+            let len = r.read_u16()?;
+            signature = Some(r.read_n(len as usize)?).filter(|signature| !signature.is_empty());
+        }
+        let mut local_slots = HashMap::new();
+        if !r.is_empty() {
+            let len = r.read_u16()?;
+            for _ in 0..len {
+                let entry = r.read_u64()?;
+                let slots = r.read_u16()?;
+                local_slots.insert(entry, slots);
+            }
+        }
+        let mut extensions = Vec::new();
+        if !r.is_empty() {
+            extensions = Extension::from_bits(r.read_u16()?)?;
+        }
+
         Ok(Self {
             labels,
+            origins,
+            metadata,
+            hooks,
+            signature,
+            local_slots,
+            extensions,
             entry,
             data,
             text,
         })
     }
 
+    /// Serialises the program. The data and text must each fit in [`MAX_SECTION_SIZE`](crate::limits::MAX_SECTION_SIZE)
+    /// bytes, which the assembler checks with its [`Limits`](crate::limits::Limits).
     pub fn serialise(self) -> Vec<u8> {
+        self.serialise_with(false)
+    }
+
+    /// Serialises the program with its data and text compressed, which [`Output::deserialise`]
+    /// undoes. Programs with large or repetitive data become much smaller, at the cost of the
+    /// time taken to decompress them when they are loaded.
+    pub fn serialise_compressed(self) -> Vec<u8> {
+        self.serialise_with(true)
+    }
+
+    fn serialise_with(self, compressed: bool) -> Vec<u8> {
         let (offsets, labels) = self.labels.into_iter().collect::<(Vec<u64>, Vec<String>)>();
 
         let mut output = Vec::with_capacity(
@@ -112,13 +658,25 @@ impl Output {
         );
 
         // Entry
-        output.extend(self.entry.to_le_bytes());
+        let entry = match compressed {
+            true => self.entry | COMPRESSED,
+            false => self.entry,
+        };
+        output.extend(entry.to_le_bytes());
 
-        // Data and text
-        output.extend(u16::try_from(self.data.len()).unwrap().to_le_bytes());
-        output.extend(&self.data);
-        output.extend(u16::try_from(self.text.len()).unwrap().to_le_bytes());
-        output.extend(&self.text);
+        // Data and text, with the length of each as a u32 once compressed
+        for section in [&self.data, &self.text] {
+            if compressed {
+                let section = compress(section);
+                output.extend(u32::try_from(section.len()).unwrap().to_le_bytes());
+                output.extend(section);
+            } else {
+                let len = u16::try_from(section.len())
+                    .expect("section is longer than an uncompressed program can hold");
+                output.extend(len.to_le_bytes());
+                output.extend(section);
+            }
+        }
 
         // Label offsets
         output.extend(u16::try_from(offsets.len()).unwrap().to_le_bytes());
@@ -133,6 +691,62 @@ impl Output {
             output.extend(label.as_bytes());
         });
 
+        // Origins and metadata, which are also written when empty if a later section follows
+        // The local slots are written, empty if there aren't any, when the extensions follow them
+        let extended = !self.extensions.is_empty();
+        let sized = !self.local_slots.is_empty() || extended;
+        // The signature is written, empty if there isn't one, when the local slots follow it
+        let signed = self.signature.is_some() || sized;
+        if !self.origins.is_empty() || !self.metadata.is_empty() || !self.hooks.is_empty() || signed
+        {
+            output.extend(u16::try_from(self.origins.len()).unwrap().to_le_bytes());
+            for (position, origin) in self.origins {
+                output.extend(position.to_le_bytes());
+                write_string(&mut output, origin.location.as_deref().unwrap_or_default());
+                output.extend(
+                    u16::try_from(origin.expansions.len())
+                        .unwrap()
+                        .to_le_bytes(),
+                );
+                origin
+                    .expansions
+                    .iter()
+                    .for_each(|expansion| write_string(&mut output, expansion));
+            }
+        }
+
+        if !self.metadata.is_empty() || !self.hooks.is_empty() || signed {
+            output.extend(u16::try_from(self.metadata.len()).unwrap().to_le_bytes());
+            for (key, value) in &self.metadata {
+                write_string(&mut output, key);
+                write_string(&mut output, value);
+            }
+        }
+
+        // Hooks, with 0 for one which isn't set since that's the entry offset rather than code
+        if !self.hooks.is_empty() || signed {
+            output.extend(self.hooks.enter.unwrap_or_default().to_le_bytes());
+            output.extend(self.hooks.exit.unwrap_or_default().to_le_bytes());
+        }
+
+        if signed {
+            let signature = self.signature.unwrap_or_default();
+            output.extend(u16::try_from(signature.len()).unwrap().to_le_bytes());
+            output.extend(signature);
+        }
+
+        if sized {
+            output.extend(u16::try_from(self.local_slots.len()).unwrap().to_le_bytes());
+            for (entry, slots) in self.local_slots {
+                output.extend(entry.to_le_bytes());
+                output.extend(slots.to_le_bytes());
+            }
+        }
+
+        if extended {
+            output.extend(Extension::to_bits(&self.extensions).to_le_bytes());
+        }
+
         output
     }
 
@@ -143,6 +757,53 @@ impl Output {
             writeln!(f, ".entry {}", self.entry)?;
         }
 
+        for (directive, hook) in [(".onenter", self.hooks.enter), (".onexit", self.hooks.exit)] {
+            let Some(hook) = hook else { continue };
+            match self.labels.get(&hook) {
+                Some(label) => writeln!(f, "{directive} {label}")?,
+                None => writeln!(f, "{directive} {hook}")?,
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn fmt_metadata(&self, f: &mut impl Write) -> Result<()> {
+        for (key, value) in &self.metadata {
+            writeln!(f, ".meta {key} {value:?}")?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes a summary of the program: its entry, section sizes and metadata
+    pub fn fmt_info(&self, f: &mut impl Write) -> Result<()> {
+        match self.labels.get(&self.entry) {
+            Some(entry) => writeln!(f, "entry: {entry}")?,
+            None => writeln!(f, "entry: {}", self.entry)?,
+        }
+        writeln!(f, "data: {} bytes", self.data.len())?;
+        writeln!(
+            f,
+            "text: {} bytes, {} instructions",
+            self.text.len(),
+            self.instruction_count()?
+        )?;
+
+        if self.signature.is_some() {
+            writeln!(f, "signed")?;
+        }
+
+        if !self.extensions.is_empty() {
+            let extensions: Vec<String> =
+                self.extensions.iter().map(Extension::to_string).collect();
+            writeln!(f, "extensions: {}", extensions.join(", "))?;
+        }
+
+        for (key, value) in &self.metadata {
+            writeln!(f, "{key}: {value}")?;
+        }
+
         Ok(())
     }
 
@@ -215,9 +876,30 @@ impl Output {
             lines.insert(pos, line);
             write!(f, "{pos:POS_WIDTH$}: ")?;
 
+            if let Some((name, operand, ends)) = self.pseudo(pc.position() - 1) {
+                write!(f, "{name:INST_WIDTH$}{operand:OP_WIDTH$}")?;
+                if let Some(label) = self.labels.get(&operand) {
+                    write!(f, " ; {}", label)?;
+                }
+
+                // The instructions within the sequence are shown on the same line
+                let (end, inner) = ends.split_last().unwrap();
+                for start in inner {
+                    pc.set_position(*start);
+                    lines.insert(next_position(&pc), line);
+                }
+                pc.set_position(*end);
+
+                pos = next_position(&pc);
+                line += 1;
+                writeln!(f)?;
+                continue;
+            }
+
             match op {
                 Bytecode::Call => fmt_with_operand::<u64>(f, &mut pc, &self.labels, op)?,
                 Bytecode::DataPtr => fmt_with_operand::<u64>(f, &mut pc, &self.labels, op)?,
+                Bytecode::PanicMsg => fmt_with_operand::<u64>(f, &mut pc, &self.labels, op)?,
                 Bytecode::Jmp => fmt_with_operand::<u64>(f, &mut pc, &self.labels, op)?,
                 Bytecode::JmpEq => fmt_with_operand::<u64>(f, &mut pc, &self.labels, op)?,
                 Bytecode::JmpGe => fmt_with_operand::<u64>(f, &mut pc, &self.labels, op)?,
@@ -231,6 +913,29 @@ impl Output {
                 Bytecode::Push => fmt_with_operand::<i32>(f, &mut pc, &self.labels, op)?,
                 Bytecode::PushB => fmt_with_operand::<i8>(f, &mut pc, &self.labels, op)?,
                 Bytecode::PushD => fmt_with_operand::<i64>(f, &mut pc, &self.labels, op)?,
+                // `push.d.f` is wider than the column, so the operand is always given a space
+                Bytecode::PushF => {
+                    let value = <f32 as Float>::from_bits(pc.next().map_err(|_| std::fmt::Error)?);
+                    let value = format!("{value:?}");
+                    write!(f, "{op:INST_WIDTH$} {value:>0$}", OP_WIDTH - 1)?;
+                }
+                Bytecode::PushDF => {
+                    let value = <f64 as Float>::from_bits(pc.next().map_err(|_| std::fmt::Error)?);
+                    let value = format!("{value:?}");
+                    write!(f, "{op:INST_WIDTH$} {value:>0$}", OP_WIDTH - 1)?;
+                }
+                Bytecode::RetN => fmt_with_operand::<u64>(f, &mut pc, &self.labels, op)?,
+                Bytecode::Pack | Bytecode::Unpack => {
+                    let fields = Fields(pc.next::<u64>()?);
+                    write!(f, "{op:INST_WIDTH$}{fields:OP_WIDTH$}")?;
+                }
+                Bytecode::IncLocal => {
+                    let slot = pc.next::<u64>()?;
+                    let n = pc.next::<i32>()?;
+                    write!(f, "{op:INST_WIDTH$}{slot:OP_WIDTH$}, {n}")?;
+                }
+                Bytecode::SLoad => fmt_with_operand::<u64>(f, &mut pc, &self.labels, op)?,
+                Bytecode::SStore => fmt_with_operand::<u64>(f, &mut pc, &self.labels, op)?,
                 Bytecode::Store => fmt_with_operand::<u64>(f, &mut pc, &self.labels, op)?,
                 Bytecode::StoreB => fmt_with_operand::<u64>(f, &mut pc, &self.labels, op)?,
                 Bytecode::StoreD => fmt_with_operand::<u64>(f, &mut pc, &self.labels, op)?,
@@ -266,8 +971,100 @@ impl Output {
                 | Bytecode::System
                 | Bytecode::Panic
                 | Bytecode::Ret
+                | Bytecode::RetB
                 | Bytecode::RetW
-                | Bytecode::RetD => write!(f, "{op}")?,
+                | Bytecode::RetD
+                | Bytecode::NewArr
+                | Bytecode::NewArrB
+                | Bytecode::NewArrD
+                | Bytecode::ALen
+                | Bytecode::AGet
+                | Bytecode::AGetB
+                | Bytecode::AGetD
+                | Bytecode::APut
+                | Bytecode::APutB
+                | Bytecode::APutD
+                | Bytecode::NewMap
+                | Bytecode::MapPut
+                | Bytecode::MapPutS
+                | Bytecode::MapGet
+                | Bytecode::MapGetS
+                | Bytecode::MapDel
+                | Bytecode::MapDelS
+                | Bytecode::MapLen
+                | Bytecode::FreeMap
+                | Bytecode::GetBe
+                | Bytecode::GetDBe
+                | Bytecode::ALoadBe
+                | Bytecode::ALoadDBe
+                | Bytecode::AStoreBe
+                | Bytecode::AStoreDBe
+                | Bytecode::ToR
+                | Bytecode::ToRD
+                | Bytecode::FromR
+                | Bytecode::FromRD
+                | Bytecode::RPeek
+                | Bytecode::RPeekD
+                | Bytecode::Swap
+                | Bytecode::SwapD
+                | Bytecode::Over
+                | Bytecode::OverD
+                | Bytecode::Rem
+                | Bytecode::RemB
+                | Bytecode::RemD
+                | Bytecode::And
+                | Bytecode::AndD
+                | Bytecode::Or
+                | Bytecode::OrD
+                | Bytecode::Xor
+                | Bytecode::XorD
+                | Bytecode::Not
+                | Bytecode::NotD
+                | Bytecode::Shl
+                | Bytecode::ShlD
+                | Bytecode::Shr
+                | Bytecode::ShrD
+                | Bytecode::ShrU
+                | Bytecode::ShrDU
+                | Bytecode::AddF
+                | Bytecode::AddDF
+                | Bytecode::SubF
+                | Bytecode::SubDF
+                | Bytecode::MulF
+                | Bytecode::MulDF
+                | Bytecode::DivF
+                | Bytecode::DivDF
+                | Bytecode::CmpF
+                | Bytecode::CmpDF
+                | Bytecode::Neg
+                | Bytecode::NegD
+                | Bytecode::NegB
+                | Bytecode::MulB
+                | Bytecode::DivB
+                | Bytecode::CmpB
+                | Bytecode::DupB
+                | Bytecode::SwapB
+                | Bytecode::OverB
+                | Bytecode::AndB
+                | Bytecode::OrB
+                | Bytecode::XorB
+                | Bytecode::NotB
+                | Bytecode::B2W
+                | Bytecode::W2D
+                | Bytecode::D2W
+                | Bytecode::W2B
+                | Bytecode::CallPtr
+                | Bytecode::JmpPtr
+                | Bytecode::Nop
+                | Bytecode::Halt
+                | Bytecode::MemCpy
+                | Bytecode::MemSet
+                | Bytecode::Min
+                | Bytecode::MinD
+                | Bytecode::Max
+                | Bytecode::MaxD
+                | Bytecode::Abs
+                | Bytecode::AbsD => write!(f, "{op}")?,
             }
 
             pos = next_position(&pc);
@@ -277,12 +1074,107 @@ impl Output {
 
         Ok(lines)
     }
+
+    /// Lists the text one instruction per line with the bytes it is encoded as, such as
+    /// `  20: 1c 02 00 00 00              push 2`. Unlike `fmt_text`, sequences aren't shown as
+    /// pseudo-instructions, so every byte of the text appears in the listing.
+    pub fn fmt_text_bytes(&self, f: &mut impl Write) -> Result<()> {
+        const POS_WIDTH: usize = 4;
+        // The widest instruction is `inc`, with an opcode, a local slot and a word
+        const BYTES_WIDTH: usize = 3 * (1 + size_of::<u64>() + size_of::<i32>()) - 1;
+
+        let text_offset = (size_of::<u64>() + self.data.len()) as u64;
+        for (pos, instr) in self.instructions()? {
+            if let Some(label) = self.labels.get(&pos) {
+                writeln!(f, "{label}:")?;
+            }
+
+            let start = (pos - text_offset) as usize;
+            let bytes = self.text[start..start + instr.size()]
+                .iter()
+                .map(|b| format!("{b:02x}"))
+                .collect::<Vec<_>>()
+                .join(" ");
+            write!(f, "{pos:POS_WIDTH$}: {bytes:BYTES_WIDTH$}  {instr}")?;
+
+            if let Some(label) = instr.target().and_then(|target| self.labels.get(&target)) {
+                write!(f, " ; {label}")?;
+            }
+            writeln!(f)?;
+        }
+
+        Ok(())
+    }
+
+    /// Recognises the sequences the assembler expands pseudo-instructions into, starting at
+    /// `start` in the text. Returns the pseudo-instruction, its operand, and where each instruction
+    /// in the sequence ends.
+    fn pseudo(&self, start: u64) -> Option<(&'static str, u64, Vec<u64>)> {
+        let mut pc = Program::new(self.text.as_slice());
+        pc.set_position(start);
+
+        let mut ops = Vec::new();
+        let mut ends = Vec::new();
+        while ops.len() < 4 && (pc.position() as usize) < self.text.len() {
+            let op = pc.next_op().ok()?;
+            let operand = match op.operand_size() {
+                1 => pc.next::<i8>().ok()? as i64,
+                4 => pc.next::<i32>().ok()? as i64,
+                8 => pc.next::<i64>().ok()?,
+                _ => 0,
+            };
+
+            ops.push((op, operand));
+            ends.push(pc.position());
+        }
+
+        let (name, operand, len) = match ops.as_slice() {
+            [(Bytecode::Push, 0), (Bytecode::Store, a), ..] => ("zero", *a, 2),
+            [(Bytecode::Push, 0), (Bytecode::Cmp, _), (Bytecode::JmpEq, label), ..] => {
+                ("jmp.z", *label, 3)
+            }
+            [(Bytecode::Push, 0), (Bytecode::Cmp, _), (Bytecode::JmpNe, label), ..] => {
+                ("jmp.nz", *label, 3)
+            }
+            _ => return None,
+        };
+
+        // A jump into the middle of the sequence means it wasn't written as a pseudo-instruction
+        let offset = size_of::<u64>() as u64 + self.data.len() as u64;
+        if ends[..len - 1]
+            .iter()
+            .any(|end| self.labels.contains_key(&(end + offset)))
+        {
+            return None;
+        }
+
+        ends.truncate(len);
+
+        Some((name, operand as u64, ends))
+    }
+}
+
+fn read_string(r: &mut impl Read) -> Result<String> {
+    let len = r.read_u16()?;
+    let data = r.read_n(len as usize)?;
+    Ok(String::from_utf8(data)?)
+}
+
+fn write_string(output: &mut Vec<u8>, s: &str) {
+    output.extend(u16::try_from(s.len()).unwrap().to_le_bytes());
+    output.extend(s.as_bytes());
 }
 
 #[cfg(test)]
 mod test {
+    use std::collections::HashMap;
+
     use crate::assembler::Assembler;
+    use crate::extension::Extension;
+    use crate::interpreter::{Interpreter, RunOutcome};
+    use crate::signing::KeyedHash;
     use crate::Result;
+    use crate::{Bytecode, Fields, Instr};
 
     use super::Output;
 
@@ -336,6 +1228,338 @@ add:
         Ok(())
     }
 
+    #[test]
+    fn test_instruction_positions() -> Result<()> {
+        let src = "
+.entry main
+
+.data record .string \"abc\"
+
+main:
+    push.d record
+    push 22
+    pop
+    ret";
+
+        // The text starts after the entry and the 3 bytes of data
+        let output = Assembler::new().assemble(src)?;
+        assert_eq!(output.instruction_positions()?, [11, 20, 25, 26]);
+        assert_eq!(output.instruction_count()?, 4);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_infinite_loops() -> Result<()> {
+        let src = "
+.entry main
+
+main:
+    push 0
+    store 0
+spin:
+    load 0
+    push 1
+    add
+    store 0
+    jmp spin
+
+countdown:
+    load 0
+    push 1
+    sub
+    dup
+    store 0
+    push 0
+    cmp
+    jmp.gt countdown
+    ret
+
+poll:
+    push 0
+    push 1
+    system
+    jmp poll
+
+escape:
+    jmp out
+    jmp escape
+out:
+    ret
+";
+        let output = Assembler::new().assemble(src)?;
+
+        let loops = output.infinite_loops()?;
+        let loops: Vec<_> = loops
+            .into_iter()
+            .map(|(start, end)| (output.symbolise(start), output.symbolise(end)))
+            .collect();
+        assert_eq!(loops, [(String::from("spin"), String::from("spin+24"))]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge() -> Result<()> {
+        let main = "
+.entry main
+
+.data greeting .string \"hello\"
+
+main:
+    dataptr greeting
+    push.d greeting.len
+    pop.d
+    pop.d
+    push 1
+    jmp.eq end
+    call main
+end:
+    ret
+";
+        let fragment = "
+.entry square
+
+.data numbers .word 3 .word 4
+
+square:
+    dataptr numbers
+    pop.d
+    load 0
+    dup
+    jmp.gt positive
+    push -1
+    mul
+positive:
+    dup
+    mul
+    ret
+";
+        let mut have = Assembler::new().assemble(main)?;
+        have.merge(Assembler::new().assemble(fragment)?)?;
+
+        // The same as assembling the fragments as one program
+        let (_, rest) = fragment.split_once("\n\n").unwrap();
+        let want = Assembler::new().assemble(&format!("{main}{rest}"))?;
+
+        assert_eq!(want, have);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_analyse_locals() -> Result<()> {
+        let src = "
+.entry main
+
+main:
+    push 1
+    jmp.eq skip
+    load.d 3
+skip:
+    call f
+    call g
+    ret
+
+f:
+    store.b 0
+    jmp end
+    load 9
+end:
+    ret
+
+g:
+    push.d end
+    jmp.ptr
+";
+        let output = Assembler::new().assemble(src)?;
+        let slots = output
+            .analyse_locals()?
+            .into_iter()
+            .map(|(entry, slots)| (output.symbolise(entry), slots))
+            .collect::<HashMap<String, u16>>();
+
+        // The load after the unconditional jump is never reached, and g jumps through a pointer
+        let want = HashMap::from([(String::from("main"), 5), (String::from("f"), 1)]);
+        assert_eq!(slots, want);
+        assert_eq!(output.local_slots(), &output.analyse_locals()?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_instructions() -> Result<()> {
+        let src = "
+.entry main
+
+f:
+    load 0
+    ret.w
+
+main:
+    push.b 1
+    call f
+    jmp.eq main
+    ret";
+
+        let output = Assembler::new().assemble(src)?;
+        let instructions = output.instructions()?;
+        assert_eq!(
+            instructions,
+            [
+                (8, Instr::Local(Bytecode::Load, 0)),
+                (17, Instr::Op(Bytecode::RetW)),
+                (18, Instr::PushB(1)),
+                (20, Instr::Call(8)),
+                (29, Instr::Jump(Bytecode::JmpEq, 18)),
+                (38, Instr::Op(Bytecode::Ret)),
+            ]
+        );
+        assert_eq!(instructions[3].1.to_string(), "call 8");
+        assert_eq!(instructions[4].1.target(), Some(18));
+
+        // Records are shown with the letter for each field
+        let output = Assembler::new().assemble(".entry main\nmain:\n    unpack bwd\n    ret")?;
+        let instructions = output.instructions()?;
+        assert_eq!(
+            instructions[0].1,
+            Instr::Fields(Bytecode::Unpack, Fields(0b111001))
+        );
+        assert_eq!(instructions[0].1.to_string(), "unpack bwd");
+        assert!(Assembler::new()
+            .assemble(".entry main\nmain:\n    pack bx\n    ret")
+            .is_err());
+
+        // Floats are shown as decimals rather than their bits
+        let src = ".entry main\nmain:\n    push.f 1.5\n    push.d.f -2\n    ret";
+        let output = Assembler::new().assemble(src)?;
+        let instructions = output.instructions()?;
+        assert_eq!(instructions[0].1, Instr::PushF(1.5f32.to_bits()));
+        assert_eq!(instructions[0].1.to_string(), "push.f 1.5");
+        assert_eq!(instructions[1].1.to_string(), "push.d.f -2.0");
+        assert!(output.to_string().contains("push.d.f -2.0"));
+        assert!(Assembler::new()
+            .assemble(".entry main\nmain:\n    push.f 1.5x\n    ret")
+            .is_err());
+
+        let mut output = output;
+        output.text[0] = 0xff;
+        assert_eq!(
+            output.instructions().unwrap_err().to_string(),
+            "unexpected opcode: 255 at 0"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fmt_text_bytes() -> Result<()> {
+        let src = "
+.entry main
+
+main:
+    push 2
+    push.b -1
+    jmp main";
+
+        let output = Assembler::new().assemble(src)?;
+        let mut have = String::new();
+        output.fmt_text_bytes(&mut have)?;
+        let want = "\
+main:
+   8: 24 02 00 00 00                          push 2
+  13: 25 ff                                   push.b -1
+  15: 15 08 00 00 00 00 00 00 00              jmp 8 ; main
+";
+        assert_eq!(want, have);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_display_pseudo() -> Result<()> {
+        let src = "
+.entry main
+
+main:
+    zero 0
+loop:
+    inc 0
+    load 0
+    push 10
+    cmp
+    jmp.lt loop
+    load 0
+    jmp.nz done
+    push 0
+middle:
+    store 1
+done:
+    ret";
+
+        let output = Assembler::new().assemble(src)?;
+        let mut have = String::new();
+        let lines = output.fmt_text(&mut have)?;
+        let want = "\
+main:
+   8: zero      0
+loop:
+  22: inc       0, 1
+  35: load      0
+  44: push     10
+  49: cmp
+  50: jmp.lt   22 ; loop
+  59: load      0
+  68: jmp.nz   97 ; done
+  83: push      0
+middle:
+  88: store     1
+done:
+  97: ret
+";
+        assert_eq!(want, have);
+
+        // Each instruction in a pseudo-instruction maps to its line
+        assert_eq!(lines[&8], 1);
+        assert_eq!(lines[&13], 1);
+        assert_eq!(lines[&73], 9);
+        assert_eq!(lines[&74], 9);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_display_panic() -> Result<()> {
+        let src = "
+.entry main
+
+.data empty .cstring \"empty stack\"
+
+main:
+    push 0
+    jmp.z fail
+    panic
+fail:
+    panic empty
+    halt";
+
+        let output = Assembler::new().assemble(src)?;
+        let mut have = String::new();
+        output.fmt_text(&mut have)?;
+        let want = "\
+main:
+  20: push      0
+  25: jmp.z    41 ; fail
+  40: panic
+fail:
+  41: panic     8 ; empty
+  50: halt
+";
+        assert_eq!(want, have);
+
+        Ok(())
+    }
+
     #[test]
     fn test_serde_roundtrip() -> Result<()> {
         let src = "
@@ -365,6 +1589,198 @@ add:
 
         assert_eq!(want, have);
 
+        // Readers such as pipes can return fewer bytes than asked for
+        struct Trickle<'a>(&'a [u8]);
+        impl std::io::Read for Trickle<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                let n = buf.len().min(self.0.len()).min(1);
+                buf[..n].copy_from_slice(&self.0[..n]);
+                self.0 = &self.0[n..];
+                Ok(n)
+            }
+        }
+        let have = Output::deserialise(Trickle(&serialised))?;
+
+        assert_eq!(want, have);
+
+        // Origins are written after the labels
+        let src = "
+.entry main
+#define TWO { push 2 }
+main:
+    @TWO
+    ret";
+        let want = Assembler::new().assemble(src)?;
+        assert!(want.origin(8).is_some());
+        let serialised = want.clone().serialise();
+        let have = Output::deserialise(serialised.as_slice())?;
+
+        assert_eq!(want, have);
+
+        // Metadata is written after the origins, even when there are none
+        let src = "
+.entry main
+.meta version \"1.2.0\"
+.meta build 42
+main:
+    ret";
+        let want = Assembler::new().assemble(src)?;
+        assert_eq!(want.meta("version"), Some("1.2.0"));
+        assert_eq!(want.meta("build"), Some("42"));
+        let serialised = want.clone().serialise();
+        let have = Output::deserialise(serialised.as_slice())?;
+
+        assert_eq!(want, have);
+
+        // The listing shows the metadata as directives after the entry
+        assert!(have
+            .to_string()
+            .starts_with(".entry main\n.meta version \"1.2.0\"\n.meta build \"42\"\n"));
+
+        // Hooks are written last, after empty origins and metadata
+        let src = "
+.entry main
+.onexit trace
+main:
+    ret
+trace:
+    ret";
+        let want = Assembler::new().assemble(src)?;
+        assert_eq!(want.hooks().enter, None);
+        assert_eq!(want.hooks().exit, Some(9));
+        let serialised = want.clone().serialise();
+        let have = Output::deserialise(serialised.as_slice())?;
+
+        assert_eq!(want, have);
+        assert!(have.to_string().starts_with(".entry main\n.onexit trace\n"));
+
+        // Extensions are written after the local slots
+        let src = "
+.entry main
+main:
+    newmap
+    push.d 4
+    newarr
+    ret";
+        let want = Assembler::new().assemble(src)?;
+        assert_eq!(want.extensions(), [Extension::Arrays, Extension::Maps]);
+        let serialised = want.clone().serialise();
+        let have = Output::deserialise(serialised.as_slice())?;
+
+        assert_eq!(want, have);
+        let mut info = String::new();
+        have.fmt_info(&mut info)?;
+        assert!(info.ends_with("extensions: arrays, maps\n"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_serde_compressed() -> Result<()> {
+        let src = "
+.entry main
+
+.data table .byte 1, 2, 3, 4, 1, 2, 3, 4, 1, 2, 3, 4, 1, 2, 3, 4, 1, 2, 3, 4, 1, 2, 3, 4
+
+main:
+    push 1
+    push 1
+    push 1
+    push 1
+    add
+    add
+    add
+    ret";
+        let want = Assembler::new().assemble(src)?;
+        let serialised = want.clone().serialise_compressed();
+        assert!(serialised.len() < want.clone().serialise().len());
+        let have = Output::deserialise(serialised.as_slice())?;
+
+        assert_eq!(want, have);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_serde_signed() -> Result<()> {
+        let src = "
+.entry main
+
+main:
+    push 1
+    ret";
+        let key = KeyedHash::new("key");
+        let mut want = Assembler::new().assemble(src)?;
+        assert_eq!(
+            want.verify(&key).unwrap_err().to_string(),
+            "program is not signed"
+        );
+
+        want.sign(&key);
+        want.verify(&key)?;
+        let have = Output::deserialise(want.clone().serialise().as_slice())?;
+        assert_eq!(want, have);
+        have.verify(&key)?;
+
+        // Changing the text invalidates the signature, as does signing with another key
+        let mut serialised = want.clone().serialise();
+        serialised[12] ^= 1;
+        let tampered = Output::deserialise(serialised.as_slice())?;
+        assert_eq!(
+            tampered.verify(&key).unwrap_err().to_string(),
+            "program signature is not valid"
+        );
+        assert!(have.verify(&KeyedHash::new("other")).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_strip() -> Result<()> {
+        let src = "
+.entry main
+
+main:
+    push 2
+    call fail
+    ret
+fail:
+    panic
+";
+        let want = Assembler::new().assemble(src)?;
+
+        let mut stripped = want.clone();
+        stripped.strip();
+        let stripped = Output::deserialise(stripped.serialise().as_slice())?;
+        assert!(stripped.labels().is_empty());
+        assert_eq!(stripped.symbolise(23), "23");
+
+        // The program still runs, with positions in the backtrace in place of labels
+        let backtrace = |output: &Output| -> Result<Vec<String>> {
+            let mut interpreter = Interpreter::new(output, None, None)?;
+            match interpreter.run() {
+                RunOutcome::Trapped { backtrace, .. } => Ok(backtrace),
+                outcome => panic!("expected the panic to trap, got {outcome:?}"),
+            }
+        };
+        assert_eq!(backtrace(&want)?, ["main", "fail"]);
+        assert_eq!(backtrace(&stripped)?, ["8", "23"]);
+
+        let mut obfuscated = want.clone();
+        obfuscated.obfuscate_labels();
+        assert_eq!(backtrace(&obfuscated)?, ["L0", "L1"]);
+
+        let mut renamed = want.clone();
+        renamed.rename_labels(|label| label.to_uppercase())?;
+        assert_eq!(backtrace(&renamed)?, ["MAIN", "FAIL"]);
+        assert_eq!(
+            renamed
+                .rename_labels(|_| String::from("same"))
+                .unwrap_err()
+                .to_string(),
+            "more than one label renamed to same"
+        );
+
         Ok(())
     }
 }
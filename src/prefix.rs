@@ -0,0 +1,72 @@
+use std::io::{self, Write};
+
+use crate::SharedWriter;
+
+/// A writer which starts each line with a tag, such as the label of the frame which wrote it, so
+/// output from different parts of a program can be told apart
+pub struct PrefixWriter {
+    inner: SharedWriter,
+    tag: String,
+    /// Whether the next byte written starts a line
+    line_start: bool,
+}
+
+impl PrefixWriter {
+    pub fn new(inner: SharedWriter) -> Self {
+        let tag = String::new();
+        let line_start = true;
+        Self {
+            inner,
+            tag,
+            line_start,
+        }
+    }
+
+    /// Sets the tag for lines started from now on. A line which has already been started keeps
+    /// the tag it was started with.
+    pub fn set_tag(&mut self, tag: &str) {
+        tag.clone_into(&mut self.tag);
+    }
+}
+
+impl Write for PrefixWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut inner = self.inner.lock().unwrap();
+        for line in buf.split_inclusive(|&b| b == b'\n') {
+            if self.line_start {
+                write!(inner, "[{}] ", self.tag)?;
+            }
+            inner.write_all(line)?;
+            self.line_start = line.ends_with(b"\n");
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.lock().unwrap().flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+
+    use super::PrefixWriter;
+
+    #[test]
+    fn test_prefix_writer() -> std::io::Result<()> {
+        let inner = Arc::new(Mutex::new(Vec::new()));
+        let mut writer = PrefixWriter::new(inner.clone());
+        writer.set_tag("main");
+        writer.write_all(b"a\nb")?;
+        writer.set_tag("f");
+        writer.write_all(b"c\n\nd\n")?;
+
+        let have = String::from_utf8(inner.lock().unwrap().clone()).unwrap();
+        assert_eq!(have, "[main] a\n[main] bc\n[f] \n[f] d\n");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,49 @@
+; Standard definitions injected by `stackc --prelude`
+
+#define STDIN  0
+#define STDOUT 1
+#define STDERR 2
+
+#define SYS_EXIT  1
+#define SYS_READ  3
+#define SYS_WRITE 4
+#define SYS_OPEN  5
+#define SYS_CLOSE 6
+#define SYS_FSYNC 95
+
+; Byte strings are passed as [ptr.d, len.d]
+#define SYS_CONCAT      1000 ; [a, b] -> [ptr.d, len.d] of a new allocation
+#define SYS_SLICE       1001 ; [s, start.d, end.d] -> [ptr.d, len.d] within s
+#define SYS_COMPARE     1002 ; [a, b] -> -1, 0 or 1 comparing a and b lexicographically
+#define SYS_META        1003 ; [key] -> [ptr.d, len.d] of a copy of the .meta value, or [0, 0]
+#define SYS_EXEC        1004 ; [program, fuel.d] -> [value.d, status] of running a serialised program
+#define SYS_ASM_COMPILE 1005 ; [source] -> [ptr.d, len.d] of the serialised program, or [0, 0]
+
+; Console input and output through the interpreter's stdin and stdout
+#define SYS_GETC     1006 ; [] -> the next byte of input, or -1 at the end
+#define SYS_PUTC     1007 ; [c] -> [] writing the low byte of c
+#define SYS_READLINE 1008 ; [] -> [ptr.d, len.d, found] of the next line without its newline
+#define SYS_PUTLINE  1009 ; [s] -> [] writing s and a newline
+
+; The code of the last system call which returned -1, such as EBADF, or 0 if none has
+#define SYS_ERRNO 1010 ; [] -> the error code
+#define EBADF     9    ; the file descriptor isn't open
+
+; Writes [ptr.d, len.d] to stdout, pushing the number of bytes written
+#define PRINT {
+    call prelude_print
+}
+
+; Exits the process with the word on top of the stack
+#define EXIT {
+    push @SYS_EXIT
+    system
+}
+
+prelude_print:
+    push @STDOUT
+    load.d 0
+    load.d 2
+    push @SYS_WRITE
+    system
+    ret.w
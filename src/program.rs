@@ -1,8 +1,11 @@
 use crate::{Number, Result};
+use std::collections::{HashMap, HashSet};
 use std::io::{Cursor, Read};
+use std::ops::Range;
+use std::sync::Arc;
 
 #[repr(u8)]
-#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
 pub enum Bytecode {
     ALoad,
     ALoadB,
@@ -56,6 +59,107 @@ pub enum Bytecode {
     Ret,
     RetW,
     RetD,
+    RetN,
+    SLoad,
+    SStore,
+    NewArr,
+    NewArrB,
+    NewArrD,
+    ALen,
+    AGet,
+    AGetB,
+    AGetD,
+    APut,
+    APutB,
+    APutD,
+    NewMap,
+    MapPut,
+    MapPutS,
+    MapGet,
+    MapGetS,
+    MapDel,
+    MapDelS,
+    MapLen,
+    FreeMap,
+    GetBe,
+    GetDBe,
+    ALoadBe,
+    ALoadDBe,
+    AStoreBe,
+    AStoreDBe,
+    Pack,
+    Unpack,
+    ToR,
+    ToRD,
+    FromR,
+    FromRD,
+    RPeek,
+    RPeekD,
+    Swap,
+    SwapD,
+    Over,
+    OverD,
+    Rem,
+    RemB,
+    RemD,
+    And,
+    AndD,
+    Or,
+    OrD,
+    Xor,
+    XorD,
+    Not,
+    NotD,
+    Shl,
+    ShlD,
+    Shr,
+    ShrD,
+    ShrU,
+    ShrDU,
+    PushF,
+    PushDF,
+    AddF,
+    AddDF,
+    SubF,
+    SubDF,
+    MulF,
+    MulDF,
+    DivF,
+    DivDF,
+    CmpF,
+    CmpDF,
+    Neg,
+    NegD,
+    NegB,
+    MulB,
+    DivB,
+    CmpB,
+    DupB,
+    SwapB,
+    OverB,
+    AndB,
+    OrB,
+    XorB,
+    NotB,
+    B2W,
+    W2D,
+    D2W,
+    W2B,
+    CallPtr,
+    JmpPtr,
+    Nop,
+    Halt,
+    MemCpy,
+    MemSet,
+    IncLocal,
+    Min,
+    MinD,
+    Max,
+    MaxD,
+    Abs,
+    AbsD,
+    PanicMsg,
+    RetB,
 }
 
 impl std::fmt::Display for Bytecode {
@@ -113,19 +217,496 @@ impl std::fmt::Display for Bytecode {
             Bytecode::Ret => "ret".fmt(f),
             Bytecode::RetW => "ret.w".fmt(f),
             Bytecode::RetD => "ret.d".fmt(f),
+            Bytecode::RetN => "ret.n".fmt(f),
+            Bytecode::SLoad => "sload".fmt(f),
+            Bytecode::SStore => "sstore".fmt(f),
+            Bytecode::NewArr => "newarr".fmt(f),
+            Bytecode::NewArrB => "newarr.b".fmt(f),
+            Bytecode::NewArrD => "newarr.d".fmt(f),
+            Bytecode::ALen => "alen".fmt(f),
+            Bytecode::AGet => "aget".fmt(f),
+            Bytecode::AGetB => "aget.b".fmt(f),
+            Bytecode::AGetD => "aget.d".fmt(f),
+            Bytecode::APut => "aput".fmt(f),
+            Bytecode::APutB => "aput.b".fmt(f),
+            Bytecode::APutD => "aput.d".fmt(f),
+            Bytecode::NewMap => "newmap".fmt(f),
+            Bytecode::MapPut => "mapput".fmt(f),
+            Bytecode::MapPutS => "mapput.s".fmt(f),
+            Bytecode::MapGet => "mapget".fmt(f),
+            Bytecode::MapGetS => "mapget.s".fmt(f),
+            Bytecode::MapDel => "mapdel".fmt(f),
+            Bytecode::MapDelS => "mapdel.s".fmt(f),
+            Bytecode::MapLen => "maplen".fmt(f),
+            Bytecode::FreeMap => "freemap".fmt(f),
+            Bytecode::GetBe => "get.be".fmt(f),
+            Bytecode::GetDBe => "get.d.be".fmt(f),
+            Bytecode::ALoadBe => "aload.be".fmt(f),
+            Bytecode::ALoadDBe => "aload.d.be".fmt(f),
+            Bytecode::AStoreBe => "astore.be".fmt(f),
+            Bytecode::AStoreDBe => "astore.d.be".fmt(f),
+            Bytecode::Pack => "pack".fmt(f),
+            Bytecode::Unpack => "unpack".fmt(f),
+            Bytecode::ToR => "tor".fmt(f),
+            Bytecode::ToRD => "tor.d".fmt(f),
+            Bytecode::FromR => "fromr".fmt(f),
+            Bytecode::FromRD => "fromr.d".fmt(f),
+            Bytecode::RPeek => "rpeek".fmt(f),
+            Bytecode::RPeekD => "rpeek.d".fmt(f),
+            Bytecode::Swap => "swap".fmt(f),
+            Bytecode::SwapD => "swap.d".fmt(f),
+            Bytecode::Over => "over".fmt(f),
+            Bytecode::OverD => "over.d".fmt(f),
+            Bytecode::Rem => "rem".fmt(f),
+            Bytecode::RemB => "rem.b".fmt(f),
+            Bytecode::RemD => "rem.d".fmt(f),
+            Bytecode::And => "and".fmt(f),
+            Bytecode::AndD => "and.d".fmt(f),
+            Bytecode::Or => "or".fmt(f),
+            Bytecode::OrD => "or.d".fmt(f),
+            Bytecode::Xor => "xor".fmt(f),
+            Bytecode::XorD => "xor.d".fmt(f),
+            Bytecode::Not => "not".fmt(f),
+            Bytecode::NotD => "not.d".fmt(f),
+            Bytecode::Shl => "shl".fmt(f),
+            Bytecode::ShlD => "shl.d".fmt(f),
+            Bytecode::Shr => "shr".fmt(f),
+            Bytecode::ShrD => "shr.d".fmt(f),
+            Bytecode::ShrU => "shr.u".fmt(f),
+            Bytecode::ShrDU => "shr.d.u".fmt(f),
+            Bytecode::PushF => "push.f".fmt(f),
+            Bytecode::PushDF => "push.d.f".fmt(f),
+            Bytecode::AddF => "add.f".fmt(f),
+            Bytecode::AddDF => "add.d.f".fmt(f),
+            Bytecode::SubF => "sub.f".fmt(f),
+            Bytecode::SubDF => "sub.d.f".fmt(f),
+            Bytecode::MulF => "mul.f".fmt(f),
+            Bytecode::MulDF => "mul.d.f".fmt(f),
+            Bytecode::DivF => "div.f".fmt(f),
+            Bytecode::DivDF => "div.d.f".fmt(f),
+            Bytecode::CmpF => "cmp.f".fmt(f),
+            Bytecode::CmpDF => "cmp.d.f".fmt(f),
+            Bytecode::Neg => "neg".fmt(f),
+            Bytecode::NegD => "neg.d".fmt(f),
+            Bytecode::NegB => "neg.b".fmt(f),
+            Bytecode::MulB => "mul.b".fmt(f),
+            Bytecode::DivB => "div.b".fmt(f),
+            Bytecode::CmpB => "cmp.b".fmt(f),
+            Bytecode::DupB => "dup.b".fmt(f),
+            Bytecode::SwapB => "swap.b".fmt(f),
+            Bytecode::OverB => "over.b".fmt(f),
+            Bytecode::AndB => "and.b".fmt(f),
+            Bytecode::OrB => "or.b".fmt(f),
+            Bytecode::XorB => "xor.b".fmt(f),
+            Bytecode::NotB => "not.b".fmt(f),
+            Bytecode::B2W => "b2w".fmt(f),
+            Bytecode::W2D => "w2d".fmt(f),
+            Bytecode::D2W => "d2w".fmt(f),
+            Bytecode::W2B => "w2b".fmt(f),
+            Bytecode::CallPtr => "call.ptr".fmt(f),
+            Bytecode::JmpPtr => "jmp.ptr".fmt(f),
+            Bytecode::Nop => "nop".fmt(f),
+            Bytecode::Halt => "halt".fmt(f),
+            Bytecode::MemCpy => "memcpy".fmt(f),
+            Bytecode::MemSet => "memset".fmt(f),
+            Bytecode::IncLocal => "inc".fmt(f),
+            Bytecode::Min => "min".fmt(f),
+            Bytecode::MinD => "min.d".fmt(f),
+            Bytecode::Max => "max".fmt(f),
+            Bytecode::MaxD => "max.d".fmt(f),
+            Bytecode::Abs => "abs".fmt(f),
+            Bytecode::AbsD => "abs.d".fmt(f),
+            Bytecode::PanicMsg => "panic".fmt(f),
+            Bytecode::RetB => "ret.b".fmt(f),
+        }
+    }
+}
+
+impl Bytecode {
+    /// Every opcode, in the order of their encoding
+    pub fn all() -> impl Iterator<Item = Bytecode> {
+        (0..=u8::MAX).map_while(Bytecode::decode)
+    }
+
+    /// The opcode encoded as `op`, if there is one
+    pub fn decode(op: u8) -> Option<Bytecode> {
+        match op <= Bytecode::RetB as u8 {
+            // Safety: the opcodes are numbered from 0 up to the last one without gaps
+            true => Some(unsafe { std::mem::transmute::<u8, Bytecode>(op) }),
+            false => None,
+        }
+    }
+
+    /// The number of bytes following the opcode which make up its inline operand
+    pub fn operand_size(&self) -> usize {
+        match self {
+            Bytecode::Call
+            | Bytecode::DataPtr
+            | Bytecode::PanicMsg
+            | Bytecode::Jmp
+            | Bytecode::JmpEq
+            | Bytecode::JmpGe
+            | Bytecode::JmpGt
+            | Bytecode::JmpLe
+            | Bytecode::JmpLt
+            | Bytecode::JmpNe
+            | Bytecode::Load
+            | Bytecode::LoadB
+            | Bytecode::LoadD
+            | Bytecode::Pack
+            | Bytecode::PushD
+            | Bytecode::PushDF
+            | Bytecode::RetN
+            | Bytecode::SLoad
+            | Bytecode::SStore
+            | Bytecode::Store
+            | Bytecode::StoreB
+            | Bytecode::StoreD
+            | Bytecode::Unpack => u64::SIZE,
+            // The local slot followed by the word added to it
+            Bytecode::IncLocal => u64::SIZE + i32::SIZE,
+            Bytecode::Push | Bytecode::PushF => i32::SIZE,
+            Bytecode::PushB => i8::SIZE,
+
+            Bytecode::ALoad
+            | Bytecode::ALoadB
+            | Bytecode::ALoadD
+            | Bytecode::AStore
+            | Bytecode::AStoreB
+            | Bytecode::AStoreD
+            | Bytecode::Add
+            | Bytecode::AddB
+            | Bytecode::AddD
+            | Bytecode::Alloc
+            | Bytecode::Cmp
+            | Bytecode::CmpD
+            | Bytecode::Div
+            | Bytecode::DivD
+            | Bytecode::Dup
+            | Bytecode::DupD
+            | Bytecode::Free
+            | Bytecode::Get
+            | Bytecode::GetB
+            | Bytecode::GetD
+            | Bytecode::Mul
+            | Bytecode::MulD
+            | Bytecode::Pop
+            | Bytecode::PopB
+            | Bytecode::PopD
+            | Bytecode::Sub
+            | Bytecode::SubB
+            | Bytecode::SubD
+            | Bytecode::System
+            | Bytecode::Panic
+            | Bytecode::Ret
+            | Bytecode::RetB
+            | Bytecode::RetW
+            | Bytecode::RetD
+            | Bytecode::NewArr
+            | Bytecode::NewArrB
+            | Bytecode::NewArrD
+            | Bytecode::ALen
+            | Bytecode::AGet
+            | Bytecode::AGetB
+            | Bytecode::AGetD
+            | Bytecode::APut
+            | Bytecode::APutB
+            | Bytecode::APutD
+            | Bytecode::NewMap
+            | Bytecode::MapPut
+            | Bytecode::MapPutS
+            | Bytecode::MapGet
+            | Bytecode::MapGetS
+            | Bytecode::MapDel
+            | Bytecode::MapDelS
+            | Bytecode::MapLen
+            | Bytecode::FreeMap
+            | Bytecode::GetBe
+            | Bytecode::GetDBe
+            | Bytecode::ALoadBe
+            | Bytecode::ALoadDBe
+            | Bytecode::AStoreBe
+            | Bytecode::AStoreDBe
+            | Bytecode::ToR
+            | Bytecode::ToRD
+            | Bytecode::FromR
+            | Bytecode::FromRD
+            | Bytecode::RPeek
+            | Bytecode::RPeekD
+            | Bytecode::Swap
+            | Bytecode::SwapD
+            | Bytecode::Over
+            | Bytecode::OverD
+            | Bytecode::Rem
+            | Bytecode::RemB
+            | Bytecode::RemD
+            | Bytecode::And
+            | Bytecode::AndD
+            | Bytecode::Or
+            | Bytecode::OrD
+            | Bytecode::Xor
+            | Bytecode::XorD
+            | Bytecode::Not
+            | Bytecode::NotD
+            | Bytecode::Shl
+            | Bytecode::ShlD
+            | Bytecode::Shr
+            | Bytecode::ShrD
+            | Bytecode::ShrU
+            | Bytecode::ShrDU
+            | Bytecode::AddF
+            | Bytecode::AddDF
+            | Bytecode::SubF
+            | Bytecode::SubDF
+            | Bytecode::MulF
+            | Bytecode::MulDF
+            | Bytecode::DivF
+            | Bytecode::DivDF
+            | Bytecode::CmpF
+            | Bytecode::CmpDF
+            | Bytecode::Neg
+            | Bytecode::NegD
+            | Bytecode::NegB
+            | Bytecode::MulB
+            | Bytecode::DivB
+            | Bytecode::CmpB
+            | Bytecode::DupB
+            | Bytecode::SwapB
+            | Bytecode::OverB
+            | Bytecode::AndB
+            | Bytecode::OrB
+            | Bytecode::XorB
+            | Bytecode::NotB
+            | Bytecode::B2W
+            | Bytecode::W2D
+            | Bytecode::D2W
+            | Bytecode::W2B
+            | Bytecode::CallPtr
+            | Bytecode::JmpPtr
+            | Bytecode::Nop
+            | Bytecode::Halt
+            | Bytecode::MemCpy
+            | Bytecode::MemSet
+            | Bytecode::Min
+            | Bytecode::MinD
+            | Bytecode::Max
+            | Bytecode::MaxD
+            | Bytecode::Abs
+            | Bytecode::AbsD => 0,
         }
     }
 }
 
+/// A decoded instruction with its inline operand, if it has one
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Instr {
+    /// An instruction without an operand
+    Op(Bytecode),
+    Push(i32),
+    PushB(i8),
+    PushD(i64),
+    /// `push.f` with the bits of the value
+    PushF(u32),
+    /// `push.d.f` with the bits of the value
+    PushDF(u64),
+    /// `load`, `load.b`, `load.d`, `store`, `store.b` or `store.d` with the local slot
+    Local(Bytecode, u64),
+    /// `sload` or `sstore` with the scratch slot
+    Scratch(Bytecode, u64),
+    /// `jmp` or a conditional jump with the target position
+    Jump(Bytecode, u64),
+    /// `call` with the target position
+    Call(u64),
+    /// `dataptr` with the offset into the data section
+    DataPtr(u64),
+    /// `panic` with the offset of its message in the data section
+    PanicMsg(u64),
+    /// `ret.n` with the number of slots returned
+    RetN(u64),
+    /// `pack` or `unpack` with the fields of the record
+    Fields(Bytecode, Fields),
+    /// `inc` with the local slot and the word added to it
+    IncLocal(u64, i32),
+}
+
+impl std::fmt::Display for Instr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Instr::Op(op) => op.fmt(f),
+            Instr::Push(n) => write!(f, "{} {n}", Bytecode::Push),
+            Instr::PushB(n) => write!(f, "{} {n}", Bytecode::PushB),
+            Instr::PushD(n) => write!(f, "{} {n}", Bytecode::PushD),
+            Instr::PushF(bits) => write!(f, "{} {:?}", Bytecode::PushF, f32::from_bits(*bits)),
+            Instr::PushDF(bits) => write!(f, "{} {:?}", Bytecode::PushDF, f64::from_bits(*bits)),
+            Instr::Local(op, slot) | Instr::Scratch(op, slot) => write!(f, "{op} {slot}"),
+            Instr::Jump(op, target) => write!(f, "{op} {target}"),
+            Instr::Call(target) => write!(f, "{} {target}", Bytecode::Call),
+            Instr::DataPtr(offset) => write!(f, "{} {offset}", Bytecode::DataPtr),
+            Instr::PanicMsg(offset) => write!(f, "{} {offset}", Bytecode::PanicMsg),
+            Instr::RetN(count) => write!(f, "{} {count}", Bytecode::RetN),
+            Instr::Fields(op, fields) => write!(f, "{op} {fields}"),
+            Instr::IncLocal(slot, n) => write!(f, "{} {slot}, {n}", Bytecode::IncLocal),
+        }
+    }
+}
+
+impl Instr {
+    pub fn op(&self) -> Bytecode {
+        match self {
+            Instr::Op(op)
+            | Instr::Local(op, _)
+            | Instr::Scratch(op, _)
+            | Instr::Jump(op, _)
+            | Instr::Fields(op, _) => *op,
+            Instr::Push(_) => Bytecode::Push,
+            Instr::PushB(_) => Bytecode::PushB,
+            Instr::PushD(_) => Bytecode::PushD,
+            Instr::PushF(_) => Bytecode::PushF,
+            Instr::PushDF(_) => Bytecode::PushDF,
+            Instr::Call(_) => Bytecode::Call,
+            Instr::DataPtr(_) => Bytecode::DataPtr,
+            Instr::PanicMsg(_) => Bytecode::PanicMsg,
+            Instr::RetN(_) => Bytecode::RetN,
+            Instr::IncLocal(..) => Bytecode::IncLocal,
+        }
+    }
+
+    /// The number of bytes the instruction takes up, including the opcode
+    pub fn size(&self) -> usize {
+        1 + self.op().operand_size()
+    }
+
+    /// The position control may transfer to, for jumps and calls
+    pub fn target(&self) -> Option<u64> {
+        match self {
+            Instr::Jump(_, target) | Instr::Call(target) => Some(*target),
+            _ => None,
+        }
+    }
+}
+
+/// The widths of the fields of a record read by `unpack` and written by `pack`, written in the
+/// source as a letter for each field such as `bwd`. Each field takes two bits from the lowest:
+/// 1 for a byte, 2 for a word and 3 for a double word, ending at the first 0.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Fields(pub u64);
+
+impl std::fmt::Display for Fields {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let fields: String = self
+            .widths()
+            .map(|width| match width {
+                1 => 'b',
+                4 => 'w',
+                _ => 'd',
+            })
+            .collect();
+        f.pad(&fields)
+    }
+}
+
+impl std::str::FromStr for Fields {
+    type Err = Box<dyn std::error::Error>;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.is_empty() || s.len() > Fields::MAX {
+            Err(format!("expected 1 to {} fields: {s}", Fields::MAX))?
+        }
+
+        let mut fields = 0;
+        for (i, c) in s.chars().enumerate() {
+            let field: u64 = match c {
+                'b' => 1,
+                'w' => 2,
+                'd' => 3,
+                _ => Err(format!("expected fields written as b, w or d: {s}"))?,
+            };
+            fields |= field << (i * 2);
+        }
+
+        Ok(Self(fields))
+    }
+}
+
+impl Fields {
+    pub const MAX: usize = u64::BITS as usize / 2;
+
+    /// The number of bytes each field takes up, in order
+    pub fn widths(self) -> impl Iterator<Item = usize> {
+        (0..Self::MAX)
+            .map(move |i| (self.0 >> (i * 2)) & 0b11)
+            .take_while(|&field| field != 0)
+            .map(|field| match field {
+                1 => i8::SIZE,
+                2 => i32::SIZE,
+                _ => i64::SIZE,
+            })
+    }
+
+    /// The number of slots the fields take up on the operand stack
+    pub fn slots(self) -> usize {
+        self.widths().map(|width| width.div_ceil(4)).sum()
+    }
+}
+
 #[derive(Clone)]
 pub struct Program<T: AsRef<[u8]>> {
     counter: Cursor<T>,
+    /// Where the data section lies within the program, which `get` is restricted to
+    data: Option<Range<usize>>,
+    /// The position of each instruction, which [`Program::jump`] must land on
+    instructions: Option<Arc<HashSet<u64>>>,
+    /// The entry of each function, which [`Program::check_call`] must land on
+    entries: Option<Arc<HashSet<u64>>>,
+    /// The number of local slots used by each function, by its entry
+    local_slots: Arc<HashMap<u64, u16>>,
 }
 
 impl<T: AsRef<[u8]>> Program<T> {
     pub fn new(src: T) -> Self {
         let counter = Cursor::new(src);
-        Self { counter }
+        let data = None;
+        let instructions = None;
+        let entries = None;
+        let local_slots = Arc::default();
+        Self {
+            counter,
+            data,
+            instructions,
+            entries,
+            local_slots,
+        }
+    }
+
+    /// Size the locals of frames for the functions in `local_slots` to the slots they use
+    pub fn with_local_slots(mut self, local_slots: HashMap<u64, u16>) -> Self {
+        self.local_slots = Arc::new(local_slots);
+        self
+    }
+
+    /// The number of local slots used by the function at `entry`, if it's known
+    pub fn local_slots(&self, entry: u64) -> Option<usize> {
+        self.local_slots.get(&entry).map(|&slots| slots as usize)
+    }
+
+    /// Check that every jump lands on one of `instructions`, rather than in the middle of an
+    /// instruction where its operand would be read as opcodes
+    pub fn with_instructions(mut self, instructions: impl IntoIterator<Item = u64>) -> Self {
+        self.instructions = Some(Arc::new(instructions.into_iter().collect()));
+        self
+    }
+
+    /// Check that calls through a pointer land on one of `entries`, rather than partway through a
+    /// function
+    pub fn with_entries(mut self, entries: impl IntoIterator<Item = u64>) -> Self {
+        self.entries = Some(Arc::new(entries.into_iter().collect()));
+        self
+    }
+
+    /// Restrict reads with [`Program::get`] to `data` rather than the whole program
+    pub fn with_data(mut self, data: Range<usize>) -> Self {
+        self.data = Some(data);
+        self
     }
 
     pub fn set_position(&mut self, position: u64) {
@@ -136,6 +717,34 @@ impl<T: AsRef<[u8]>> Program<T> {
         self.counter.position()
     }
 
+    /// Moves to `to` for a jump, call or return made by the instruction at `from`
+    pub fn jump(&mut self, from: u64, to: u64) -> Result<()> {
+        self.check_jump(from, to)?;
+        self.counter.set_position(to);
+        Ok(())
+    }
+
+    /// Returns an error if `to` isn't the start of an instruction, when the positions of the
+    /// instructions are known
+    pub fn check_jump(&self, from: u64, to: u64) -> Result<()> {
+        match &self.instructions {
+            Some(instructions) if !instructions.contains(&to) => Err(format!(
+                "jump from {from} to {to}, which is not the start of an instruction"
+            ))?,
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns an error if `to` isn't the entry of a function, when the entries are known
+    pub fn check_call(&self, from: u64, to: u64) -> Result<()> {
+        match &self.entries {
+            Some(entries) if !entries.contains(&to) => Err(format!(
+                "call.ptr from {from} to {to}, which is not the start of a function"
+            ))?,
+            _ => Ok(()),
+        }
+    }
+
     pub fn next<N: Number>(&mut self) -> Result<N> {
         let mut buf = [0u8; 8];
         let n = self.counter.read(&mut buf[0..N::SIZE])?;
@@ -150,21 +759,79 @@ impl<T: AsRef<[u8]>> Program<T> {
     }
 
     pub fn next_op(&mut self) -> Result<Bytecode> {
+        let position = self.position();
         let op = self.next::<u8>()?;
-        assert!(
-            op <= Bytecode::RetD as u8,
-            "unexpected opcode: {op} at {position}",
-            position = self.counter.position()
-        );
-        let op = unsafe { std::mem::transmute::<u8, Bytecode>(op) };
-        Ok(op)
+        match Bytecode::decode(op) {
+            Some(op) => Ok(op),
+            None => Err(format!("unexpected opcode: {op} at {position}"))?,
+        }
     }
 
-    pub fn get<N: Number>(&mut self, offset: usize) -> N {
-        N::from_le_bytes(&self.counter.get_ref().as_ref()[offset..offset + N::SIZE])
+    /// Decodes the instruction at the current position
+    pub fn next_instr(&mut self) -> Result<Instr> {
+        let op = self.next_op()?;
+
+        let instr = match op {
+            Bytecode::Push => Instr::Push(self.next()?),
+            Bytecode::PushB => Instr::PushB(self.next()?),
+            Bytecode::PushD => Instr::PushD(self.next()?),
+            Bytecode::PushF => Instr::PushF(self.next::<i32>()? as u32),
+            Bytecode::PushDF => Instr::PushDF(self.next()?),
+            Bytecode::Load
+            | Bytecode::LoadB
+            | Bytecode::LoadD
+            | Bytecode::Store
+            | Bytecode::StoreB
+            | Bytecode::StoreD => Instr::Local(op, self.next()?),
+            Bytecode::SLoad | Bytecode::SStore => Instr::Scratch(op, self.next()?),
+            Bytecode::Jmp
+            | Bytecode::JmpEq
+            | Bytecode::JmpGe
+            | Bytecode::JmpGt
+            | Bytecode::JmpLe
+            | Bytecode::JmpLt
+            | Bytecode::JmpNe => Instr::Jump(op, self.next()?),
+            Bytecode::Call => Instr::Call(self.next()?),
+            Bytecode::DataPtr => Instr::DataPtr(self.next()?),
+            Bytecode::PanicMsg => Instr::PanicMsg(self.next()?),
+            Bytecode::RetN => Instr::RetN(self.next()?),
+            Bytecode::Pack | Bytecode::Unpack => Instr::Fields(op, Fields(self.next()?)),
+            Bytecode::IncLocal => Instr::IncLocal(self.next()?, self.next()?),
+            op => Instr::Op(op),
+        };
+
+        Ok(instr)
     }
 
-    pub fn getptr(&mut self, offset: usize) -> *const u8 {
+    /// Reads a value from the data section, or `None` if any of its bytes are outside of it
+    pub fn get<N: Number>(&mut self, offset: usize) -> Option<N> {
+        let program = self.counter.get_ref().as_ref();
+        let data = self.data.clone().unwrap_or(0..program.len());
+        let end = offset.checked_add(N::SIZE)?;
+        if offset < data.start || end > data.end {
+            return None;
+        }
+
+        Some(N::from_le_bytes(&program[offset..end]))
+    }
+
+    /// The length of the whole program, including the entry and data
+    pub fn len(&self) -> usize {
+        self.counter.get_ref().as_ref().len()
+    }
+
+    pub fn getptr(&self, offset: usize) -> *const u8 {
         self.counter.get_ref().as_ref()[offset..].as_ptr()
     }
 }
+
+impl Program<Vec<u8>> {
+    /// Overwrites the program with `image` in place, so pointers into it stay valid
+    pub fn restore(&mut self, image: &[u8]) {
+        self.counter.get_mut().copy_from_slice(image);
+    }
+
+    pub fn image(&self) -> &[u8] {
+        self.counter.get_ref()
+    }
+}
@@ -0,0 +1,370 @@
+//! A small SSA form for straight-line runs of stack code. The slots on the operand stack when a
+//! block is entered are its parameters, and every value computed within the block is defined
+//! exactly once. Lifting a block propagates constants, and lowering only emits the values left on
+//! the stack at the end of it, so anything computed and then dropped is eliminated.
+
+use crate::program::{Bytecode, Instr};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Width {
+    Word,
+    DoubleWord,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Value {
+    /// A slot on the operand stack when the block is entered, counting down from the top
+    Param(usize),
+    Const(Width, i64),
+    /// The result of the node at this index
+    Node(usize),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Node {
+    pub op: Bytecode,
+    pub args: [Value; 2],
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Block {
+    /// The width of each parameter read by the block, starting from the top of the stack
+    pub params: Vec<Width>,
+    pub nodes: Vec<Node>,
+    /// The values pushed by the block which are left on the stack, from the bottom
+    pub stack: Vec<(Width, Value)>,
+}
+
+impl Block {
+    /// Lifts the longest prefix of `instrs` which can be represented, returning the block and the
+    /// number of instructions it covers.
+    pub fn lift(instrs: &[Instr]) -> (Self, usize) {
+        let mut block = Self::default();
+
+        for (i, instr) in instrs.iter().enumerate() {
+            let mut next = block.clone();
+            if next.step(instr).is_none() {
+                return (block, i);
+            }
+            block = next;
+        }
+
+        (block, instrs.len())
+    }
+
+    fn step(&mut self, instr: &Instr) -> Option<()> {
+        use Width::*;
+
+        match *instr {
+            Instr::Push(n) => self.push(Word, Value::Const(Word, n.into())),
+            Instr::PushD(n) => self.push(DoubleWord, Value::Const(DoubleWord, n)),
+            Instr::Op(op) => match op {
+                Bytecode::Pop => self.pop(Word).map(drop)?,
+                Bytecode::PopD => self.pop(DoubleWord).map(drop)?,
+                Bytecode::Dup | Bytecode::DupD => {
+                    let width = Self::width(op);
+                    let a = self.pop(width)?;
+                    self.push(width, a);
+                    self.push(width, a);
+                }
+                Bytecode::Swap | Bytecode::SwapD => {
+                    let width = Self::width(op);
+                    let b = self.pop(width)?;
+                    let a = self.pop(width)?;
+                    self.push(width, b);
+                    self.push(width, a);
+                }
+                Bytecode::Over | Bytecode::OverD => {
+                    let width = Self::width(op);
+                    let b = self.pop(width)?;
+                    let a = self.pop(width)?;
+                    self.push(width, a);
+                    self.push(width, b);
+                    self.push(width, a);
+                }
+                Bytecode::Add
+                | Bytecode::AddD
+                | Bytecode::Sub
+                | Bytecode::SubD
+                | Bytecode::Mul
+                | Bytecode::MulD
+                | Bytecode::And
+                | Bytecode::AndD
+                | Bytecode::Or
+                | Bytecode::OrD
+                | Bytecode::Xor
+                | Bytecode::XorD => {
+                    let width = Self::width(op);
+                    let b = self.pop(width)?;
+                    let a = self.pop(width)?;
+                    let value = match Self::fold(op, a, b) {
+                        Some(n) => Value::Const(width, n),
+                        None => self.node(op, [a, b]),
+                    };
+                    self.push(width, value);
+                }
+                // These may trap, so they are only lifted when they can be folded
+                Bytecode::Div | Bytecode::DivD | Bytecode::Rem | Bytecode::RemD => {
+                    let width = Self::width(op);
+                    let b = self.pop(width)?;
+                    let a = self.pop(width)?;
+                    self.push(width, Value::Const(width, Self::fold(op, a, b)?));
+                }
+                _ => None?,
+            },
+            _ => None?,
+        }
+
+        Some(())
+    }
+
+    /// Lowers the block back to instructions, or returns None if the values left on the stack
+    /// can't be produced without reordering the parameters.
+    pub fn lower(&self) -> Option<Vec<Instr>> {
+        // Parameters which are left where they are at the bottom of the stack
+        let kept = self
+            .stack
+            .iter()
+            .zip((0..self.params.len()).rev())
+            .take_while(|((_, value), param)| *value == Value::Param(*param))
+            .count();
+        let stack = &self.stack[kept..];
+
+        let mut order = Vec::new();
+        let mut uses = vec![0; self.nodes.len()];
+        for &(_, value) in stack {
+            self.params_of(value, &mut order, &mut uses);
+        }
+        if uses.iter().any(|&n| n > 1) {
+            return None;
+        }
+
+        // The parameters which are read must be the deepest of those which aren't kept, in the
+        // order they are on the stack, and the rest are popped from above them
+        let remaining = self.params.len() - kept;
+        let dropped = remaining.checked_sub(order.len())?;
+        if !order.iter().rev().copied().eq(dropped..remaining) {
+            return None;
+        }
+
+        let mut instrs: Vec<Instr> = self.params[..dropped]
+            .iter()
+            .map(|width| match width {
+                Width::Word => Instr::Op(Bytecode::Pop),
+                Width::DoubleWord => Instr::Op(Bytecode::PopD),
+            })
+            .collect();
+
+        let mut emitted = false;
+        for &(_, value) in stack {
+            self.emit(value, &mut instrs, &mut emitted)?;
+        }
+
+        Some(instrs)
+    }
+
+    fn emit(&self, value: Value, instrs: &mut Vec<Instr>, emitted: &mut bool) -> Option<()> {
+        match value {
+            // The parameter is already on top of the stack as long as nothing has been pushed
+            Value::Param(_) if *emitted => None?,
+            Value::Param(_) => {}
+            Value::Const(Width::Word, n) => {
+                instrs.push(Instr::Push(n as i32));
+                *emitted = true;
+            }
+            Value::Const(Width::DoubleWord, n) => {
+                instrs.push(Instr::PushD(n));
+                *emitted = true;
+            }
+            Value::Node(i) => {
+                let node = &self.nodes[i];
+                for arg in node.args {
+                    self.emit(arg, instrs, emitted)?;
+                }
+                instrs.push(Instr::Op(node.op));
+                *emitted = true;
+            }
+        }
+
+        Some(())
+    }
+
+    /// Collects the parameters read by `value` in the order they are emitted, and counts the
+    /// uses of each node.
+    fn params_of(&self, value: Value, order: &mut Vec<usize>, uses: &mut [usize]) {
+        match value {
+            Value::Param(param) => order.push(param),
+            Value::Const(..) => {}
+            Value::Node(i) => {
+                uses[i] += 1;
+                for arg in self.nodes[i].args {
+                    self.params_of(arg, order, uses);
+                }
+            }
+        }
+    }
+
+    fn push(&mut self, width: Width, value: Value) {
+        self.stack.push((width, value));
+    }
+
+    /// Pops a value of the given width, reading a new parameter if the block's stack is empty.
+    /// Returns None if the value on top has a different width.
+    fn pop(&mut self, width: Width) -> Option<Value> {
+        match self.stack.pop() {
+            Some((have, value)) if have == width => Some(value),
+            Some(_) => None,
+            None => {
+                self.params.push(width);
+                Some(Value::Param(self.params.len() - 1))
+            }
+        }
+    }
+
+    fn node(&mut self, op: Bytecode, args: [Value; 2]) -> Value {
+        self.nodes.push(Node { op, args });
+        Value::Node(self.nodes.len() - 1)
+    }
+
+    fn width(op: Bytecode) -> Width {
+        match op {
+            Bytecode::PopD
+            | Bytecode::DupD
+            | Bytecode::SwapD
+            | Bytecode::OverD
+            | Bytecode::AddD
+            | Bytecode::SubD
+            | Bytecode::MulD
+            | Bytecode::AndD
+            | Bytecode::OrD
+            | Bytecode::XorD
+            | Bytecode::DivD
+            | Bytecode::RemD => Width::DoubleWord,
+            _ => Width::Word,
+        }
+    }
+
+    /// Evaluates `op` if both operands are constant and it wouldn't overflow or trap
+    fn fold(op: Bytecode, a: Value, b: Value) -> Option<i64> {
+        let (Value::Const(_, a), Value::Const(_, b)) = (a, b) else {
+            return None;
+        };
+
+        match Self::width(op) {
+            Width::Word => {
+                let (a, b) = (a as i32, b as i32);
+                let n = match op {
+                    Bytecode::Add => a.checked_add(b),
+                    Bytecode::Sub => a.checked_sub(b),
+                    Bytecode::Mul => a.checked_mul(b),
+                    Bytecode::Div => a.checked_div(b),
+                    Bytecode::Rem => a.checked_rem(b),
+                    Bytecode::And => Some(a & b),
+                    Bytecode::Or => Some(a | b),
+                    Bytecode::Xor => Some(a ^ b),
+                    _ => None,
+                };
+                n.map(i64::from)
+            }
+            Width::DoubleWord => match op {
+                Bytecode::AddD => a.checked_add(b),
+                Bytecode::SubD => a.checked_sub(b),
+                Bytecode::MulD => a.checked_mul(b),
+                Bytecode::DivD => a.checked_div(b),
+                Bytecode::RemD => a.checked_rem(b),
+                Bytecode::AndD => Some(a & b),
+                Bytecode::OrD => Some(a | b),
+                Bytecode::XorD => Some(a ^ b),
+                _ => None,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Block, Value, Width};
+    use crate::program::{Bytecode, Instr};
+
+    fn lower(instrs: &[Instr]) -> Option<Vec<Instr>> {
+        let (block, n) = Block::lift(instrs);
+        assert_eq!(n, instrs.len());
+        block.lower()
+    }
+
+    #[test]
+    fn test_constant_propagation() {
+        let instrs = [
+            Instr::Push(2),
+            Instr::Push(3),
+            Instr::Op(Bytecode::Mul),
+            Instr::Push(4),
+            Instr::Op(Bytecode::Add),
+            Instr::PushD(9),
+            Instr::PushD(4),
+            Instr::Op(Bytecode::RemD),
+        ];
+        assert_eq!(lower(&instrs), Some(vec![Instr::Push(10), Instr::PushD(1)]));
+    }
+
+    #[test]
+    fn test_dead_code() {
+        // The sum of the parameters is dropped, so only the pops of the parameters remain
+        let instrs = [
+            Instr::Op(Bytecode::Add),
+            Instr::Push(1),
+            Instr::Op(Bytecode::Swap),
+            Instr::Op(Bytecode::Pop),
+        ];
+        assert_eq!(
+            lower(&instrs),
+            Some(vec![
+                Instr::Op(Bytecode::Pop),
+                Instr::Op(Bytecode::Pop),
+                Instr::Push(1)
+            ])
+        );
+    }
+
+    #[test]
+    fn test_params() {
+        let instrs = [
+            Instr::Push(2),
+            Instr::Push(3),
+            Instr::Op(Bytecode::Add),
+            Instr::Op(Bytecode::Sub),
+        ];
+        let (block, _) = Block::lift(&instrs);
+        assert_eq!(block.params, vec![Width::Word]);
+        assert_eq!(block.stack, vec![(Width::Word, Value::Node(0))]);
+        assert_eq!(
+            block.lower(),
+            Some(vec![Instr::Push(5), Instr::Op(Bytecode::Sub)])
+        );
+
+        // The parameter is needed after a constant has been pushed
+        let instrs = [
+            Instr::Push(2),
+            Instr::Op(Bytecode::Swap),
+            Instr::Op(Bytecode::Sub),
+        ];
+        assert_eq!(lower(&instrs), None);
+    }
+
+    #[test]
+    fn test_lift_stops() {
+        let instrs = [
+            Instr::Push(1),
+            Instr::Push(0),
+            Instr::Op(Bytecode::Div),
+            Instr::Op(Bytecode::Pop),
+        ];
+        assert_eq!(Block::lift(&instrs).1, 2);
+
+        let instrs = [Instr::PushD(1), Instr::Op(Bytecode::Pop)];
+        assert_eq!(Block::lift(&instrs).1, 1);
+
+        let instrs = [Instr::Push(1), Instr::Local(Bytecode::Store, 0)];
+        assert_eq!(Block::lift(&instrs).1, 1);
+    }
+}
@@ -1,8 +1,12 @@
+use std::mem;
 use std::ops::{Deref, DerefMut};
 
-use crate::Number;
+use crate::limits::MAX_STACK_SIZE;
+use crate::program::Fields;
+use crate::{Float, Number, Result};
 
 #[repr(align(8))]
+#[derive(Clone)]
 struct Stack<const T: usize>([u8; T]);
 
 impl<const T: usize> Deref for Stack<T> {
@@ -19,29 +23,46 @@ impl<const T: usize> DerefMut for Stack<T> {
     }
 }
 
-const STACK_SIZE: usize = 512;
+const STACK_SIZE: usize = MAX_STACK_SIZE;
 const SLOT_SIZE: usize = std::mem::size_of::<i32>();
+/// Read in place of the bytes of a stack which hasn't had anything pushed
+static EMPTY: Stack<STACK_SIZE> = Stack([0; STACK_SIZE]);
+
+/// The operand stack of a frame. By default every value takes a whole number of word sized
+/// slots, so bytes are widened to a word. A packed stack instead lays values out one after
+/// another at their own width. The bytes are only allocated once something is pushed.
+#[derive(Clone)]
 pub struct OperandStack {
-    stack: Box<Stack<STACK_SIZE>>,
-    idx: usize,
+    stack: Option<Box<Stack<STACK_SIZE>>>,
+    /// The number of bytes in use
+    len: usize,
+    packed: bool,
+    /// Set when a push didn't fit, so the frame can fail rather than write past the end
+    overflowed: bool,
+    /// Set when a pop found too few bytes, so the frame can fail rather than read below the bottom
+    underflowed: bool,
+    /// Set when integer arithmetic wrapped, until it's taken by [`OperandStack::take_wrapped`]
+    wrapped: bool,
 }
 
 impl Default for OperandStack {
     fn default() -> Self {
-        let stack = Box::new(Stack([0; STACK_SIZE]));
-        let idx = 0;
-        Self { stack, idx }
+        Self::new(false)
     }
 }
 
 impl std::fmt::Display for OperandStack {
+    /// Shows the top 8 slots, or every slot if there are fewer, with a cursor after the top
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let from = self.idx.saturating_sub(8) * SLOT_SIZE;
-        let until = (from + 8) * SLOT_SIZE;
+        const WINDOW: usize = 8;
+
+        let idx = self.depth();
+        let from = idx.saturating_sub(WINDOW) * SLOT_SIZE;
+        let until = from + WINDOW * SLOT_SIZE;
 
         let width = 8;
         let mut sep = "";
-        let mut slice = &self.stack[from..until];
+        let mut slice = &self.bytes()[from..until];
         write!(f, "[")?;
         while !slice.is_empty() {
             let n = i32::from_le_bytes(slice[..i32::SIZE].try_into().unwrap());
@@ -52,47 +73,225 @@ impl std::fmt::Display for OperandStack {
         }
         writeln!(f, "]")?;
 
-        let idx = self.idx;
-        let min_idx = self.idx.min(8);
+        let min_idx = idx.min(WINDOW);
         let cursor = min_idx + min_idx * width;
         write!(f, "{:cursor$}^{idx}", "")
     }
 }
 
+/// A value on the operand stack with the width it's read as
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum StackValue {
+    Byte(i8),
+    Word(i32),
+    DoubleWord(i64),
+}
+
+impl std::fmt::Display for StackValue {
+    /// Writes the value after the letter for its width, such as `d 5`
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StackValue::Byte(value) => write!(f, "b {value}"),
+            StackValue::Word(value) => write!(f, "w {value}"),
+            StackValue::DoubleWord(value) => write!(f, "d {value}"),
+        }
+    }
+}
+
+impl std::str::FromStr for StackValue {
+    type Err = Box<dyn std::error::Error>;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let Some((width, value)) = s.trim().split_once(char::is_whitespace) else {
+            Err(format!("expected a width and a value: {s}"))?
+        };
+
+        let value = value.trim();
+        Ok(match width {
+            "b" => StackValue::Byte(value.parse()?),
+            "w" => StackValue::Word(value.parse()?),
+            "d" => StackValue::DoubleWord(value.parse()?),
+            _ => Err(format!("expected a width of b, w or d: {s}"))?,
+        })
+    }
+}
+
+impl StackValue {
+    /// Renders the values as a JSON array of objects holding the width letter and the value
+    pub fn json(values: &[StackValue]) -> String {
+        let values: Vec<String> = values
+            .iter()
+            .map(|value| {
+                let (width, value) = match *value {
+                    StackValue::Byte(value) => ('b', i64::from(value)),
+                    StackValue::Word(value) => ('w', i64::from(value)),
+                    StackValue::DoubleWord(value) => ('d', value),
+                };
+                format!("{{\"width\":\"{width}\",\"value\":{value}}}")
+            })
+            .collect();
+
+        format!("[{}]", values.join(","))
+    }
+}
+
 impl OperandStack {
+    pub fn new(packed: bool) -> Self {
+        let stack = None;
+        let len = 0;
+        let overflowed = false;
+        let underflowed = false;
+        let wrapped = false;
+        Self {
+            stack,
+            len,
+            packed,
+            overflowed,
+            underflowed,
+            wrapped,
+        }
+    }
+
+    fn bytes(&self) -> &[u8; STACK_SIZE] {
+        self.stack.as_deref().unwrap_or(&EMPTY)
+    }
+
+    fn bytes_mut(&mut self) -> &mut [u8; STACK_SIZE] {
+        self.stack
+            .get_or_insert_with(|| Box::new(Stack([0; STACK_SIZE])))
+    }
+
+    /// The number of bytes a value of type `T` takes on the stack
+    fn width<T: Number>(&self) -> usize {
+        match self.packed {
+            true => T::SIZE,
+            false => T::SIZE.max(SLOT_SIZE),
+        }
+    }
+
     pub fn as_slice(&self) -> &[u8] {
-        &self.stack[..self.idx * SLOT_SIZE]
+        &self.bytes()[..self.len]
+    }
+
+    /// The number of word sized slots in use. A packed stack counts a partly used slot.
+    pub fn depth(&self) -> usize {
+        self.len.div_ceil(SLOT_SIZE)
+    }
+
+    /// Whether the stack has grown past `size` bytes, or a push hasn't fit at all
+    pub fn exceeds(&self, size: usize) -> bool {
+        self.overflowed || self.len > size
+    }
+
+    /// Whether a pop has found too few bytes on the stack, in which case it gave zero
+    pub fn underflowed(&self) -> bool {
+        self.underflowed
+    }
+
+    /// Whether arithmetic has wrapped since this was last called
+    pub fn take_wrapped(&mut self) -> bool {
+        mem::take(&mut self.wrapped)
     }
 
     pub fn clear(&mut self) {
-        self.idx = 0;
+        self.len = 0;
+        self.overflowed = false;
+        self.underflowed = false;
+        self.wrapped = false;
     }
 
     pub fn peek<T: Number>(&self) -> Option<T> {
-        if self.idx < T::SIZE / 4 {
-            return None;
+        let offset = self.len.checked_sub(self.width::<T>())?;
+        Some(T::from_le_bytes(&self.bytes()[offset..offset + T::SIZE]))
+    }
+
+    /// Reads the stack from the bottom, taking the values in `widths` first and then words. A
+    /// packed stack with fewer bytes left than a word ends with bytes.
+    pub fn to_values(&self, widths: Fields) -> Vec<StackValue> {
+        let values = self.to_values_at(widths).into_iter();
+        values.map(|(_, value)| value).collect()
+    }
+
+    /// Reads the stack like [`OperandStack::to_values`], along with the offset of each value in
+    /// bytes from the bottom
+    pub fn to_values_at(&self, widths: Fields) -> Vec<(usize, StackValue)> {
+        let mut values = Vec::new();
+        let mut offset = 0;
+        let mut widths = widths.widths();
+        loop {
+            let left = self.len - offset;
+            let width = match widths.next() {
+                Some(width) => width,
+                None if self.packed && left < SLOT_SIZE => i8::SIZE,
+                None => i32::SIZE,
+            };
+            let slot = match self.packed {
+                true => width,
+                false => width.max(SLOT_SIZE),
+            };
+            if slot > left {
+                break;
+            }
+
+            let bytes = &self.bytes()[offset..offset + width];
+            let value = match width {
+                1 => StackValue::Byte(<i8 as Number>::from_le_bytes(bytes)),
+                4 => StackValue::Word(<i32 as Number>::from_le_bytes(bytes)),
+                _ => StackValue::DoubleWord(<i64 as Number>::from_le_bytes(bytes)),
+            };
+            values.push((offset, value));
+            offset += slot;
         }
 
-        let idx = self.idx - T::SIZE.max(4) / 4;
-        let offset = idx * SLOT_SIZE;
-        Some(T::from_le_bytes(&self.stack[offset..offset + T::SIZE]))
+        values
+    }
+
+    /// Removes the top `count` slots, returning their bytes
+    pub fn pop_slots(&mut self, count: usize) -> Vec<u8> {
+        let Some(len) = self.len.checked_sub(count * SLOT_SIZE) else {
+            self.underflowed = true;
+            return vec![0; count * SLOT_SIZE];
+        };
+        self.len = len;
+        self.bytes()[len..len + count * SLOT_SIZE].to_vec()
+    }
+
+    /// Pushes whole slots, such as those from [`OperandStack::pop_slots`]
+    pub fn push_slots(&mut self, slots: &[u8]) {
+        let len = self.len;
+        if len + slots.len() > STACK_SIZE {
+            self.overflowed = true;
+            return;
+        }
+
+        self.bytes_mut()[len..len + slots.len()].copy_from_slice(slots);
+        self.len += slots.len();
     }
 
     pub fn push<T: Number>(&mut self, value: T) {
-        let offset = self.idx * SLOT_SIZE;
-        self.idx += T::SIZE.max(4) / 4;
+        let offset = self.len;
+        let width = self.width::<T>();
+        if offset + width > STACK_SIZE {
+            self.overflowed = true;
+            return;
+        }
+        self.len += width;
 
-        if T::SIZE < 4 {
-            self.stack[offset..offset + SLOT_SIZE].copy_from_slice(&[0u8; 4]);
+        let stack = self.bytes_mut();
+        if T::SIZE < width {
+            stack[offset..offset + width].fill(0);
         }
 
-        self.stack[offset..offset + T::SIZE].copy_from_slice(value.to_le_bytes().as_ref());
+        stack[offset..offset + T::SIZE].copy_from_slice(value.to_le_bytes().as_ref());
     }
 
     pub fn pop<T: Number>(&mut self) -> T {
-        self.idx -= T::SIZE.max(4) / 4;
-        let offset = self.idx * SLOT_SIZE;
-        T::from_le_bytes(&self.stack[offset..offset + T::SIZE])
+        let Some(offset) = self.len.checked_sub(self.width::<T>()) else {
+            self.underflowed = true;
+            return T::default();
+        };
+        self.len = offset;
+        T::from_le_bytes(&self.bytes()[offset..offset + T::SIZE])
     }
 
     pub fn drop<T: Number>(&mut self) {
@@ -101,48 +300,160 @@ impl OperandStack {
 
     pub fn add<T: Number>(&mut self) {
         let (b, a) = (self.pop::<T>(), self.pop::<T>());
-        let value = a + b;
+        let (value, wrapped) = a.overflowing_add(b);
+        self.wrapped |= wrapped;
         self.push(value);
     }
 
     pub fn sub<T: Number>(&mut self) {
         let (b, a) = (self.pop::<T>(), self.pop::<T>());
-        let value = a - b;
+        let (value, wrapped) = a.overflowing_sub(b);
+        self.wrapped |= wrapped;
         self.push(value);
     }
 
     pub fn mul<T: Number>(&mut self) {
         let (b, a) = (self.pop::<T>(), self.pop::<T>());
-        let value = a * b;
+        let (value, wrapped) = a.overflowing_mul(b);
+        self.wrapped |= wrapped;
         self.push(value);
     }
 
     pub fn div<T: Number>(&mut self) {
         let (b, a) = (self.pop::<T>(), self.pop::<T>());
-        let value = a / b;
+        let (value, wrapped) = match b == T::default() {
+            // The frame checks the divisor first, so this is only reached after an underflow
+            true => (T::default(), false),
+            false => a.overflowing_div(b),
+        };
+        self.wrapped |= wrapped;
+        self.push(value);
+    }
+
+    pub fn rem<T: Number>(&mut self) {
+        let (b, a) = (self.pop::<T>(), self.pop::<T>());
+        let (value, wrapped) = match b == T::default() {
+            // The frame checks the divisor first, so this is only reached after an underflow
+            true => (T::default(), false),
+            false => a.overflowing_rem(b),
+        };
+        self.wrapped |= wrapped;
+        self.push(value);
+    }
+
+    pub fn and<T: Number>(&mut self) {
+        let (b, a) = (self.pop::<T>(), self.pop::<T>());
+        self.push(a & b);
+    }
+
+    pub fn or<T: Number>(&mut self) {
+        let (b, a) = (self.pop::<T>(), self.pop::<T>());
+        self.push(a | b);
+    }
+
+    pub fn xor<T: Number>(&mut self) {
+        let (b, a) = (self.pop::<T>(), self.pop::<T>());
+        self.push(a ^ b);
+    }
+
+    pub fn not<T: Number>(&mut self) {
+        let a = self.pop::<T>();
+        self.push(!a);
+    }
+
+    /// Pops a `T` and pushes it as a `U`, extending the sign when widening and keeping the low
+    /// bytes when narrowing
+    pub fn convert<T: Number, U: Number>(&mut self) {
+        let value = self.pop::<T>();
+        self.push(U::truncate(value.to_i64()));
+    }
+
+    /// Subtracts the value on top from zero
+    pub fn neg<T: Number>(&mut self) {
+        let (value, wrapped) = self.pop::<T>().overflowing_neg();
+        self.wrapped |= wrapped;
         self.push(value);
     }
 
+    pub fn min<T: Number>(&mut self) {
+        let (b, a) = (self.pop::<T>(), self.pop::<T>());
+        self.push(a.min(b));
+    }
+
+    pub fn max<T: Number>(&mut self) {
+        let (b, a) = (self.pop::<T>(), self.pop::<T>());
+        self.push(a.max(b));
+    }
+
+    /// Replaces the value with its magnitude. The smallest value has none which fits, so it wraps
+    /// back to itself.
+    pub fn abs<T: Number>(&mut self) {
+        let a = self.pop::<T>();
+        match a < T::default() {
+            true => {
+                let (value, wrapped) = a.overflowing_neg();
+                self.wrapped |= wrapped;
+                self.push(value);
+            }
+            false => self.push(a),
+        }
+    }
+
+    /// Pops a word giving the number of bits, then shifts the value below it with `f`
+    pub fn shift<T: Number>(&mut self, f: impl Fn(T, u32) -> T) {
+        let n = self.pop::<i32>() as u32;
+        let value = self.pop::<T>();
+        self.push(f(value, n));
+    }
+
+    /// Pops two floats and pushes the result of `f`
+    pub fn float<F: Float>(&mut self, f: impl Fn(F, F) -> F) {
+        let (b, a) = (F::from_bits(self.pop()), F::from_bits(self.pop()));
+        self.push(f(a, b).to_bits());
+    }
+
+    /// Pushes 2 rather than an ordering if either float is NaN
+    pub fn cmp_float<F: Float>(&mut self) {
+        let (b, a) = (F::from_bits(self.pop()), F::from_bits(self.pop()));
+        self.push(a.partial_cmp(&b).map_or(2, |ordering| ordering as i32));
+    }
+
     pub fn cmp<T: Number>(&mut self) {
         let (b, a) = (self.pop::<T>(), self.pop::<T>());
         self.push(a.cmp(&b) as i32);
     }
 
     pub fn dup<T: Number>(&mut self) {
-        let idx = self.idx - T::SIZE.max(4) / 4;
-        let offset = idx * SLOT_SIZE;
-        let value = T::from_le_bytes(&self.stack[offset..offset + T::SIZE]);
+        let Some(offset) = self.len.checked_sub(self.width::<T>()) else {
+            self.underflowed = true;
+            return;
+        };
+        let value = T::from_le_bytes(&self.bytes()[offset..offset + T::SIZE]);
         self.push(value);
     }
 
-    // TODO: swap and over are useful
-    // swap (dd, dw, wd, ww) - swap the two top stack items
-    // over (d, w) - copy second item to top
+    /// Swaps the top two values
+    pub fn swap<T: Number>(&mut self) {
+        let (b, a) = (self.pop::<T>(), self.pop::<T>());
+        self.push(b);
+        self.push(a);
+    }
+
+    /// Copies the second value to the top
+    pub fn over<T: Number>(&mut self) {
+        let Some(offset) = self.len.checked_sub(2 * self.width::<T>()) else {
+            self.underflowed = true;
+            return;
+        };
+        let value = T::from_le_bytes(&self.bytes()[offset..offset + T::SIZE]);
+        self.push(value);
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use super::OperandStack;
+    use super::{OperandStack, StackValue};
+    use crate::{Number, Result};
 
     #[test]
     fn test_stack() {
@@ -167,6 +478,27 @@ mod test {
         stack.mul::<i32>();
         assert_eq!(stack.pop::<i32>(), 200);
 
+        stack.push(-7);
+        stack.push(3);
+        stack.rem::<i32>();
+        assert_eq!(stack.pop::<i32>(), -1);
+
+        stack.push(0b1100);
+        stack.push(0b1010);
+        stack.xor::<i32>();
+        stack.not::<i32>();
+        assert_eq!(stack.pop::<i32>(), !0b0110);
+
+        stack.push(-16);
+        stack.push(2);
+        stack.shift::<i32>(Number::shr);
+        assert_eq!(stack.pop::<i32>(), -4);
+
+        stack.push(-16);
+        stack.push(28);
+        stack.shift::<i32>(Number::shr_logical);
+        assert_eq!(stack.pop::<i32>(), 15);
+
         stack.push(10);
         stack.push(20);
         stack.cmp::<i32>();
@@ -177,5 +509,204 @@ mod test {
         assert_eq!(stack.pop::<i64>(), 0x4000000040000000);
 
         assert_eq!(stack.peek::<i32>(), None);
+
+        stack.push::<i32>(1);
+        stack.push::<i32>(2);
+        stack.swap::<i32>();
+        stack.over::<i32>();
+        assert_eq!(stack.pop::<i32>(), 2);
+        assert_eq!(stack.pop::<i32>(), 1);
+        assert_eq!(stack.pop::<i32>(), 2);
+
+        stack.push::<i64>(3);
+        stack.push::<i64>(4);
+        stack.over::<i64>();
+        stack.swap::<i64>();
+        assert_eq!(stack.pop::<i64>(), 4);
+        assert_eq!(stack.pop::<i64>(), 3);
+        assert_eq!(stack.pop::<i64>(), 3);
+    }
+
+    #[test]
+    fn test_packed_stack() {
+        let mut stack = OperandStack::new(true);
+        stack.push(1i8);
+        stack.push(2i8);
+        stack.push(0x0102030405060708i64);
+        assert_eq!(stack.as_slice().len(), 10);
+        assert_eq!(stack.depth(), 3);
+
+        stack.dup::<i64>();
+        stack.add::<i64>();
+        assert_eq!(stack.pop::<i64>(), 0x020406080a0c0e10);
+        stack.add::<i8>();
+        assert_eq!(stack.peek::<i8>(), Some(3));
+        assert_eq!(stack.peek::<i32>(), None);
+    }
+
+    #[test]
+    fn test_to_values() -> Result<()> {
+        for packed in [false, true] {
+            let mut stack = OperandStack::new(packed);
+            stack.push(-1i8);
+            stack.push(5i64);
+            stack.push(7i32);
+            stack.push(8i32);
+
+            let values = stack.to_values("bd".parse()?);
+            assert_eq!(
+                values,
+                [
+                    StackValue::Byte(-1),
+                    StackValue::DoubleWord(5),
+                    StackValue::Word(7),
+                    StackValue::Word(8)
+                ]
+            );
+            assert_eq!(
+                StackValue::json(&values[..2]),
+                r#"[{"width":"b","value":-1},{"width":"d","value":5}]"#
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_display_window() {
+        let mut stack = OperandStack::default();
+        for i in 1..=10 {
+            stack.push::<i32>(i);
+        }
+
+        let display = stack.to_string();
+        let (slots, cursor) = display.split_once('\n').unwrap();
+        let slots: Vec<i32> = slots
+            .trim_matches(['[', ']'])
+            .split(',')
+            .map(|slot| slot.trim().parse().unwrap())
+            .collect();
+        assert_eq!(slots, [3, 4, 5, 6, 7, 8, 9, 10]);
+        assert!(cursor.ends_with("^10"));
+    }
+
+    /// Runs the same random sequence of operations against a slotted and a packed stack, only
+    /// popping values with the width they were pushed with, and checks they agree with each other
+    /// and with a model of the values
+    #[test]
+    fn test_packed_matches_slotted() {
+        #[derive(Clone, Copy, PartialEq)]
+        enum Width {
+            B,
+            W,
+            D,
+        }
+
+        impl Width {
+            fn fits(self, value: i128) -> bool {
+                match self {
+                    Width::B => i8::try_from(value).is_ok(),
+                    Width::W => i32::try_from(value).is_ok(),
+                    Width::D => i64::try_from(value).is_ok(),
+                }
+            }
+        }
+
+        let mut slotted = OperandStack::default();
+        let mut packed = OperandStack::new(true);
+        let mut model: Vec<(Width, i64)> = Vec::new();
+
+        // xorshift, so the sequence is the same every run
+        let mut state = 0x2545f4914f6cdd1du64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+
+        // Calls the method on both stacks, checking they return the same
+        macro_rules! both {
+            ($method:ident::<$t:ty>($($arg:expr)?)) => {{
+                let a = slotted.$method::<$t>($($arg)?);
+                let b = packed.$method::<$t>($($arg)?);
+                assert_eq!(a, b);
+                a
+            }};
+        }
+
+        // Calls the method on both stacks with the type for `width`
+        macro_rules! each {
+            ($width:expr, $method:ident) => {
+                match $width {
+                    Width::B => both!($method::<i8>()),
+                    Width::W => both!($method::<i32>()),
+                    Width::D => both!($method::<i64>()),
+                }
+            };
+        }
+
+        for _ in 0..10_000 {
+            let r = next();
+            let top = model.last().copied();
+            // Keep well within the 512 bytes of the slotted stack
+            let room = model.len() < 48;
+            match (r % 4, top) {
+                (0, _) if room => {
+                    let (width, value) = match (r >> 8) % 3 {
+                        0 => (Width::B, i64::from(r as i8)),
+                        1 => (Width::W, i64::from(r as i32)),
+                        _ => (Width::D, r as i64),
+                    };
+                    match width {
+                        Width::B => both!(push::<i8>(value as i8)),
+                        Width::W => both!(push::<i32>(value as i32)),
+                        Width::D => both!(push::<i64>(value)),
+                    }
+                    model.push((width, value));
+                }
+                (1, Some((width, b))) if model.len() >= 2 && model[model.len() - 2].0 == width => {
+                    let (_, a) = model[model.len() - 2];
+                    let (a, b) = (i128::from(a), i128::from(b));
+                    model.truncate(model.len() - 2);
+                    if width.fits(a + b) {
+                        each!(width, add);
+                        model.push((width, (a + b) as i64));
+                    } else if width.fits(a - b) {
+                        each!(width, sub);
+                        model.push((width, (a - b) as i64));
+                    } else {
+                        each!(width, cmp);
+                        model.push((Width::W, a.cmp(&b) as i64));
+                    }
+                }
+                (2, Some(top)) if room => {
+                    each!(top.0, dup);
+                    model.push(top);
+                }
+                (_, Some((width, value))) => {
+                    let popped = match width {
+                        Width::B => i64::from(both!(pop::<i8>())),
+                        Width::W => i64::from(both!(pop::<i32>())),
+                        Width::D => both!(pop::<i64>()),
+                    };
+                    assert_eq!(popped, value);
+                    model.pop();
+                }
+                _ => {}
+            }
+
+            match model.last() {
+                Some(&(width, value)) => {
+                    let peeked = match width {
+                        Width::B => both!(peek::<i8>()).map(i64::from),
+                        Width::W => both!(peek::<i32>()).map(i64::from),
+                        Width::D => both!(peek::<i64>()),
+                    };
+                    assert_eq!(peeked, Some(value));
+                }
+                None => assert!(slotted.as_slice().is_empty() && packed.as_slice().is_empty()),
+            }
+        }
     }
 }
@@ -1,6 +1,7 @@
-use std::iter::Peekable;
+use std::rc::Rc;
 use std::str::Chars;
 
+use crate::diagnostic::{Diagnostic, Source, Span};
 use crate::Result;
 
 #[derive(Debug, PartialEq, Clone)]
@@ -28,11 +29,19 @@ pub enum Token {
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum Keyword {
     Byte,
+    CString,
     Data,
     Define,
     Dword,
     Entry,
+    Equ,
+    IncBin,
     Include,
+    Meta,
+    Namespace,
+    OnEnter,
+    OnExit,
+    PString,
     SizeOf,
     String,
     Text,
@@ -54,8 +63,16 @@ impl<'a> TryFrom<&'a str> for Keyword {
             "byte" => Ok(Byte),
             "sizeof" => Ok(SizeOf),
             "string" => Ok(String),
+            "cstring" => Ok(CString),
+            "pstring" => Ok(PString),
             "include" => Ok(Include),
+            "incbin" => Ok(IncBin),
             "define" => Ok(Define),
+            "meta" => Ok(Meta),
+            "equ" => Ok(Equ),
+            "namespace" => Ok(Namespace),
+            "onenter" => Ok(OnEnter),
+            "onexit" => Ok(OnExit),
             _ => Err("not a keyword")?,
         }
     }
@@ -66,8 +83,9 @@ impl Keyword {
         use Keyword::*;
 
         match self {
-            Word | Dword | Byte | String => true,
-            Entry | Data | Text | Include | Define | SizeOf => false,
+            Word | Dword | Byte | String | CString | PString | IncBin => true,
+            Entry | Data | Text | Include | Define | Meta | Equ | Namespace | OnEnter | OnExit
+            | SizeOf => false,
         }
     }
 }
@@ -105,13 +123,44 @@ impl<'a> IntoIterator for Tokeniser<'a> {
 }
 
 pub struct Tokeniser<'s> {
-    src: Peekable<Chars<'s>>,
+    src: Chars<'s>,
+    len: usize,
 }
 
 impl<'s> Tokeniser<'s> {
     pub fn new(src: &'s str) -> Self {
-        let src = src.chars().peekable();
-        Self { src }
+        let len = src.len();
+        let src = src.chars();
+        Self { src, len }
+    }
+
+    /// Tokenises the whole source, returning the span of each token alongside it
+    pub fn spanned(mut self) -> (Vec<Token>, Vec<Span>) {
+        let mut tokens = Vec::new();
+        let mut spans = Vec::new();
+
+        loop {
+            self.skip_whitespace();
+            let start = self.offset();
+            let token = self.next_token();
+            let end = self.offset();
+
+            let eof = token == Token::Eof;
+            tokens.push(token);
+            spans.push(Span { start, end });
+            if eof {
+                break (tokens, spans);
+            }
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.src.clone().next()
+    }
+
+    /// The byte offset of the next character in the source
+    fn offset(&self) -> usize {
+        self.len - self.src.as_str().len()
     }
 
     fn take_while(&mut self, f: impl Fn(char) -> bool) -> String {
@@ -121,8 +170,8 @@ impl<'s> Tokeniser<'s> {
     }
 
     fn extend_while(&mut self, s: &mut String, f: impl Fn(char) -> bool) {
-        while let Some(c) = self.src.peek() {
-            if f(*c) {
+        while let Some(c) = self.peek() {
+            if f(c) {
                 s.push(self.src.next().unwrap());
                 continue;
             }
@@ -131,9 +180,18 @@ impl<'s> Tokeniser<'s> {
         }
     }
 
+    /// Takes the fraction of a number such as `1.5`, which is a dot followed by a digit
+    fn extend_fraction(&mut self, s: &mut String) {
+        let mut rest = self.src.clone();
+        if rest.next() == Some('.') && rest.next().is_some_and(|c| c.is_ascii_digit()) {
+            s.push(self.src.next().unwrap());
+            self.extend_while(s, |c| c.is_ascii_alphanumeric());
+        }
+    }
+
     fn skip_line(&mut self) {
         loop {
-            match self.src.peek() {
+            match self.peek() {
                 Some('\n') => {
                     self.src.next();
                     break;
@@ -148,7 +206,7 @@ impl<'s> Tokeniser<'s> {
 
     fn skip_whitespace(&mut self) -> bool {
         loop {
-            match self.src.peek() {
+            match self.peek() {
                 Some(c) if c.is_whitespace() => {
                     self.src.next();
                     continue;
@@ -168,7 +226,7 @@ impl<'s> Tokeniser<'s> {
             return Token::Eof;
         }
 
-        match self.src.peek() {
+        match self.peek() {
             Some(c) => match c {
                 '.' => {
                     self.src.next();
@@ -198,16 +256,12 @@ impl<'s> Tokeniser<'s> {
                     self.src.next();
                     Token::RBrace
                 }
-                '0'..='9' => {
-                    let value = self.take_while(|c| c.is_numeric());
-                    Token::Value(Value::Number(value))
-                }
-                '-' => {
-                    let mut value = self.src.next().unwrap().to_string();
-                    self.extend_while(&mut value, |c| c.is_numeric());
-                    if value == "-" {
-                        panic!("unexpected char: -")
-                    }
+                '0'..='9' | '-' => {
+                    // Signs are taken as part of the number, so a malformed one such as `--5` or
+                    // `0x+5` is rejected when it's parsed rather than here
+                    let mut value =
+                        self.take_while(|c| c.is_ascii_alphanumeric() || ['+', '-'].contains(&c));
+                    self.extend_fraction(&mut value);
                     Token::Value(Value::Number(value))
                 }
                 '\'' => {
@@ -242,12 +296,12 @@ impl<'s> Tokeniser<'s> {
                     self.src.next();
 
                     let mut value = String::new();
-                    while let Some(c) = self.src.peek() {
-                        if *c == '"' {
+                    while let Some(c) = self.peek() {
+                        if c == '"' {
                             break;
                         }
 
-                        let mut c = *c;
+                        let mut c = c;
                         self.src.next();
 
                         if c == '\\' {
@@ -275,7 +329,21 @@ impl<'s> Tokeniser<'s> {
                     Token::Value(Value::String(value))
                 }
                 c if c.is_alphabetic() => {
-                    let word = self.take_while(|c| c.is_alphanumeric() || ['.', '_'].contains(&c));
+                    let is_word = |c: char| c.is_alphanumeric() || ['.', '_'].contains(&c);
+                    let mut word = self.take_while(is_word);
+
+                    // Qualified names such as `math::add` are a single word
+                    while self
+                        .src
+                        .as_str()
+                        .strip_prefix("::")
+                        .is_some_and(|rest| rest.starts_with(char::is_alphabetic))
+                    {
+                        word.push_str("::");
+                        self.src.nth(1);
+                        self.extend_while(&mut word, is_word);
+                    }
+
                     if let Ok(keyword) = word.as_str().try_into() {
                         Token::Keyword(keyword)
                     } else {
@@ -291,13 +359,76 @@ impl<'s> Tokeniser<'s> {
 
 pub struct TokenState {
     tokens: Vec<Token>,
+    /// The span of each token within [`TokenState::source`], if they came straight from a source
+    spans: Vec<Span>,
+    source: Option<Rc<Source>>,
+    /// Whether the tokens are from a source other than the one diagnostics are rendered against
+    nested: bool,
     position: usize,
 }
 
 impl TokenState {
     pub fn new(tokens: Vec<Token>) -> Self {
+        let spans = Vec::new();
+        let source = None;
+        let nested = false;
+        let position = 0;
+        Self {
+            tokens,
+            spans,
+            source,
+            nested,
+            position,
+        }
+    }
+
+    pub fn with_spans((tokens, spans): (Vec<Token>, Vec<Span>), source: Rc<Source>) -> Self {
+        let source = Some(source);
+        let nested = false;
         let position = 0;
-        Self { tokens, position }
+        Self {
+            tokens,
+            spans,
+            source,
+            nested,
+            position,
+        }
+    }
+
+    /// Only use the spans for [`TokenState::location`], since they point into another source
+    pub fn nested(mut self) -> Self {
+        self.nested = true;
+        self
+    }
+
+    /// Where the last token taken is, as `name:line:column`
+    pub fn location(&self) -> Option<String> {
+        let source = self.source.as_ref()?;
+        let span = self.last_span()?;
+        Some(source.location(span))
+    }
+
+    /// The span of the last token taken
+    pub fn span(&self) -> Option<Span> {
+        match self.nested {
+            true => None,
+            false => self.last_span(),
+        }
+    }
+
+    fn last_span(&self) -> Option<Span> {
+        let position = self.position.saturating_sub(1);
+        self.spans.get(position).or(self.spans.last()).copied()
+    }
+
+    /// Points `err` at the last token taken, unless it already points somewhere
+    pub fn diagnose(&self, err: Box<dyn std::error::Error>) -> Box<dyn std::error::Error> {
+        match self.span() {
+            Some(span) if !err.is::<Diagnostic>() => {
+                Box::new(Diagnostic::new(err.to_string(), span))
+            }
+            _ => err,
+        }
     }
 
     pub fn check(&mut self, tokens: &[Token]) -> bool {
@@ -0,0 +1,238 @@
+//! Checking a program's text decodes before it runs, so a corrupt or hand-written program is
+//! rejected up front rather than failing partway through. Every opcode must be known, every
+//! operand must fit before the end of the text, and the entry, hooks and each jump and call must
+//! land on the start of an instruction.
+
+use std::collections::HashSet;
+
+use crate::extension::Extension;
+use crate::output::Output;
+use crate::program::{Bytecode, Program};
+
+/// A problem found by [`verify`]. Positions are from the start of the program.
+#[derive(Debug, Clone, PartialEq)]
+pub enum VerifyError {
+    /// A byte which isn't an opcode, where an instruction should start
+    InvalidOpcode { position: u64, op: u8 },
+    /// An instruction whose operand runs past the end of the text
+    TruncatedOperand { position: u64, op: Bytecode },
+    /// A jump or call to a position which isn't the start of an instruction
+    InvalidTarget { position: u64, target: u64 },
+    /// The entry or a hook isn't the start of an instruction
+    InvalidEntry { entry: u64 },
+    /// An extension this build doesn't enable, with the position of the first instruction which
+    /// uses it unless it's only recorded in the output
+    DisabledExtension {
+        extension: Extension,
+        position: Option<u64>,
+    },
+}
+
+impl std::fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VerifyError::InvalidOpcode { position, op } => {
+                write!(f, "unexpected opcode: {op} at {position}")?;
+                if let Some(extension) = Extension::owning(*op) {
+                    write!(f, ", which is reserved for the {extension} extension")?;
+                }
+                Ok(())
+            }
+            VerifyError::TruncatedOperand { position, op } => {
+                write!(
+                    f,
+                    "operand of {op} at {position} runs past the end of the text"
+                )
+            }
+            VerifyError::InvalidTarget { position, target } => write!(
+                f,
+                "jump from {position} to {target}, which is not the start of an instruction"
+            ),
+            VerifyError::InvalidEntry { entry } => {
+                write!(f, "entry {entry} is not the start of an instruction")
+            }
+            VerifyError::DisabledExtension {
+                extension,
+                position: Some(position),
+            } => write!(
+                f,
+                "program uses the {extension} extension at {position}, which this build doesn't enable"
+            ),
+            VerifyError::DisabledExtension {
+                extension,
+                position: None,
+            } => write!(
+                f,
+                "program requires the {extension} extension, which this build doesn't enable"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+/// Checks the text of `output`, returning every problem found. Decoding stops at the first
+/// unknown opcode or truncated operand, since the instructions after it can't be found.
+pub fn verify(output: &Output) -> Vec<VerifyError> {
+    let text_offset = (size_of::<u64>() + output.data().len()) as u64;
+    let text = output.text();
+
+    let mut errors = Vec::new();
+    let mut positions = HashSet::new();
+    let mut targets = Vec::new();
+    let mut disabled = Vec::new();
+    let mut pc = Program::new(text);
+    while (pc.position() as usize) < text.len() {
+        let start = pc.position() as usize;
+        let position = text_offset + start as u64;
+
+        let Some(op) = Bytecode::decode(text[start]) else {
+            errors.push(VerifyError::InvalidOpcode {
+                position,
+                op: text[start],
+            });
+            break;
+        };
+        if start + 1 + op.operand_size() > text.len() {
+            errors.push(VerifyError::TruncatedOperand { position, op });
+            break;
+        }
+
+        if let Some(extension) = Extension::of(op).filter(|extension| !extension.is_enabled()) {
+            if !disabled.contains(&extension) {
+                disabled.push(extension);
+                errors.push(VerifyError::DisabledExtension {
+                    extension,
+                    position: Some(position),
+                });
+            }
+        }
+
+        let instr = pc.next_instr().expect("instruction was checked to decode");
+        positions.insert(position);
+        if let Some(target) = instr.target() {
+            targets.push((position, target));
+        }
+    }
+
+    for (position, target) in targets {
+        if !positions.contains(&target) {
+            errors.push(VerifyError::InvalidTarget { position, target });
+        }
+    }
+
+    for &extension in output.extensions() {
+        if !extension.is_enabled() && !disabled.contains(&extension) {
+            errors.push(VerifyError::DisabledExtension {
+                extension,
+                position: None,
+            });
+        }
+    }
+
+    let hooks = output.hooks();
+    let entries = [Some(output.entry()), hooks.enter, hooks.exit];
+    for entry in entries.into_iter().flatten() {
+        if !positions.contains(&entry) {
+            errors.push(VerifyError::InvalidEntry { entry });
+        }
+    }
+
+    errors
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use crate::assembler::Assembler;
+    use crate::extension::Extension;
+    use crate::output::Output;
+    use crate::program::Bytecode;
+    use crate::Result;
+
+    use super::{verify, VerifyError};
+
+    #[test]
+    fn test_verify() -> Result<()> {
+        let src = "
+.entry main
+
+main:
+    push 1
+    jmp.eq end
+    call main
+end:
+    ret
+";
+        assert_eq!(verify(&Assembler::new().assemble(src)?), []);
+
+        // A jump into the operand of the push, and a call past the end
+        let mut text = vec![Bytecode::Push as u8];
+        text.extend(1i32.to_le_bytes());
+        text.push(Bytecode::Jmp as u8);
+        text.extend(9u64.to_le_bytes());
+        text.push(Bytecode::Call as u8);
+        text.extend(100u64.to_le_bytes());
+        let output = Output::new(8, Vec::new(), text.clone(), HashMap::new());
+        assert_eq!(
+            verify(&output),
+            [
+                VerifyError::InvalidTarget {
+                    position: 13,
+                    target: 9
+                },
+                VerifyError::InvalidTarget {
+                    position: 22,
+                    target: 100
+                },
+            ]
+        );
+
+        // An entry inside an instruction, and an operand cut short by the end of the text
+        let output = Output::new(9, Vec::new(), text[..8].to_vec(), HashMap::new());
+        assert_eq!(
+            verify(&output),
+            [
+                VerifyError::TruncatedOperand {
+                    position: 13,
+                    op: Bytecode::Jmp
+                },
+                VerifyError::InvalidEntry { entry: 9 },
+            ]
+        );
+
+        let output = Output::new(8, Vec::new(), vec![0xff], HashMap::new());
+        assert_eq!(
+            verify(&output)[0].to_string(),
+            "unexpected opcode: 255 at 8"
+        );
+
+        // Opcodes kept for an extension's later instructions say which extension has them
+        let output = Output::new(8, Vec::new(), vec![0xd0], HashMap::new());
+        assert_eq!(
+            verify(&output)[0].to_string(),
+            "unexpected opcode: 208 at 8, which is reserved for the arrays extension"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_extensions() -> Result<()> {
+        let src = ".entry main\nmain:\n    push 1\n    push.f 1.5\n    ret\n";
+        let output = Assembler::new().assemble(src)?;
+        assert_eq!(output.extensions(), [Extension::Floats]);
+
+        let want = match Extension::Floats.is_enabled() {
+            true => Vec::new(),
+            false => vec![VerifyError::DisabledExtension {
+                extension: Extension::Floats,
+                position: Some(13),
+            }],
+        };
+        assert_eq!(verify(&output), want);
+
+        Ok(())
+    }
+}
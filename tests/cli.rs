@@ -0,0 +1,58 @@
+//! Runs the `stack` binary on assembled programs, checking the status it exits with
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use stack::assembler::Assembler;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+/// Pipes `src` to `stack -` with `args`, returning the exit status and what was written to stderr
+fn run(src: &str, args: &[&str]) -> Result<(Option<i32>, String)> {
+    let output = Assembler::new().assemble(src)?;
+    let mut child = Command::new(env!("CARGO_BIN_EXE_stack"))
+        .arg("-")
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    child.stdin.take().unwrap().write_all(&output.serialise())?;
+
+    let result = child.wait_with_output()?;
+    Ok((result.status.code(), String::from_utf8(result.stderr)?))
+}
+
+#[test]
+fn exit_status() -> Result<()> {
+    for (src, want) in [
+        (".entry main\nmain:\n    ret", 0),
+        (".entry main\nmain:\n    push 3\n    ret.w", 3),
+        (".entry main\nmain:\n    push 4\n    halt", 4),
+        // A trap exits with a status of its own, rather than 0 or the status of a clean exit
+        (".entry main\nmain:\n    panic", 101),
+        (".entry main\nmain:\n    ret.w", 101),
+    ] {
+        let (status, stderr) = run(src, &[])?;
+        assert_eq!(status, Some(want), "{src}\n{stderr}");
+    }
+
+    Ok(())
+}
+
+#[test]
+fn out_of_gas() -> Result<()> {
+    let src = ".entry main\nmain:\n    jmp main";
+    let (status, stderr) = run(src, &["--gas", "100"])?;
+    assert_eq!(status, Some(102), "{stderr}");
+    assert!(stderr.starts_with("out of gas"), "{stderr}");
+
+    // A program which finishes within its gas exits as it would without a limit
+    let (status, stderr) = run(
+        ".entry main\nmain:\n    push 3\n    ret.w",
+        &["--gas", "100"],
+    )?;
+    assert_eq!(status, Some(3), "{stderr}");
+
+    Ok(())
+}
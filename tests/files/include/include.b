@@ -8,6 +8,12 @@
 #define OPEN 5
 #define CLOSE 6
 #define FSYNC 95
+#define CONCAT 1000
+#define SLICE 1001
+#define COMPARE 1002
+#define META 1003
+#define EXEC 1004
+#define ASM_COMPILE 1005
 
 print:
     push @STDOUT
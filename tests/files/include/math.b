@@ -0,0 +1,10 @@
+; Labels for the namespace test, which don't collide with those of the file including them
+.namespace math
+
+add:
+    load 0
+    load 1
+    add
+    ; math doesn't define twice, so this calls the global label
+    call twice
+    ret.w
@@ -0,0 +1,182 @@
+//! Runs randomly generated programs which pass the verifier, checking that the interpreter always
+//! ends them with an outcome rather than panicking, and that it ends them the same way again
+//! after a reset. `STACK_FUZZ_RUNS` sets how many programs are run and `STACK_FUZZ_SEED` where
+//! the generator starts, to soak for longer or to reproduce a failure.
+
+use std::collections::HashMap;
+use std::env;
+use std::panic::{self, AssertUnwindSafe};
+
+use stack::assembler::Assembler;
+use stack::extension::Extension;
+use stack::gas::GasTable;
+use stack::interpreter::{Interpreter, InterpreterConfig, RunOutcome};
+use stack::limits::Limits;
+use stack::output::Output;
+use stack::verify::verify;
+use stack::Bytecode;
+
+const RUNS: u64 = 2_000;
+const MAX_INSTRUCTIONS: u64 = 64;
+const FUEL: u64 = 10_000;
+
+/// Programs the generator has found to panic the interpreter, run every time
+const REGRESSIONS: &[&str] = &[
+    // An allocation size which overflows the heap's limit check
+    "push.d 8\n    alloc\n    push.d -5\n    alloc",
+];
+
+/// xorshift, so a seed always gives the same programs
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+}
+
+/// Encodes an operand for `op`, favouring small values so locals, fields and counts are mostly in
+/// range. Jump and call targets are filled in once every instruction has a position.
+fn operand(rng: &mut Rng, op: Bytecode, data: usize) -> Vec<u8> {
+    if op == Bytecode::IncLocal {
+        let mut operand = rng.below(16).to_le_bytes().to_vec();
+        operand.extend((rng.next() as i8 as i32).to_le_bytes());
+        return operand;
+    }
+
+    let value = match op {
+        Bytecode::Load
+        | Bytecode::LoadB
+        | Bytecode::LoadD
+        | Bytecode::Store
+        | Bytecode::StoreB
+        | Bytecode::StoreD
+        | Bytecode::SLoad
+        | Bytecode::SStore => rng.below(16),
+        Bytecode::RetN => rng.below(4),
+        Bytecode::Pack | Bytecode::Unpack => rng.below(64),
+        Bytecode::DataPtr | Bytecode::PanicMsg => rng.below(data as u64 + 8),
+        _ => match rng.below(4) {
+            0 => rng.next(),
+            _ => rng.below(32).wrapping_sub(8),
+        },
+    };
+    value.to_le_bytes()[..op.operand_size()].to_vec()
+}
+
+/// A program of random instructions, with every jump and call landing on one of them
+fn generate(rng: &mut Rng) -> Output {
+    // System calls can reach the host's files, so they're left out, as are the instructions of
+    // extensions this build doesn't enable
+    let ops: Vec<Bytecode> = Bytecode::all()
+        .filter(|&op| op != Bytecode::System)
+        .filter(|&op| Extension::of(op).is_none_or(|extension| extension.is_enabled()))
+        .collect();
+
+    let data: Vec<u8> = (0..rng.below(32)).map(|_| rng.next() as u8).collect();
+    let text_offset = (size_of::<u64>() + data.len()) as u64;
+
+    let mut instructions = Vec::new();
+    let mut positions = Vec::new();
+    let mut position = text_offset;
+    for _ in 0..=rng.below(MAX_INSTRUCTIONS) {
+        // Plenty of pushes, so the other instructions have something to work on
+        let op = match rng.below(2) {
+            0 => [Bytecode::Push, Bytecode::PushB, Bytecode::PushD][rng.below(3) as usize],
+            _ => ops[rng.below(ops.len() as u64) as usize],
+        };
+        let operand = operand(rng, op, data.len());
+        instructions.push((op, operand));
+        positions.push(position);
+        position += 1 + op.operand_size() as u64;
+    }
+
+    let mut text = Vec::new();
+    for (op, operand) in instructions {
+        let operand = match op {
+            Bytecode::Call
+            | Bytecode::Jmp
+            | Bytecode::JmpEq
+            | Bytecode::JmpGe
+            | Bytecode::JmpGt
+            | Bytecode::JmpLe
+            | Bytecode::JmpLt
+            | Bytecode::JmpNe => {
+                let target: u64 = positions[rng.below(positions.len() as u64) as usize];
+                target.to_le_bytes().to_vec()
+            }
+            _ => operand,
+        };
+        text.push(op as u8);
+        text.extend(operand);
+    }
+
+    Output::new(text_offset, data, text, HashMap::new())
+}
+
+fn run(output: &Output) -> (RunOutcome, RunOutcome) {
+    let gas = GasTable {
+        limit: Some(FUEL),
+        ..Default::default()
+    };
+    let limits = Limits {
+        call_depth: Some(64),
+        heap_size: Some(1 << 16),
+        ..Default::default()
+    };
+    // Pointers are only checked when they aren't host addresses
+    let config = InterpreterConfig {
+        gas: Some(gas),
+        limits,
+        ..InterpreterConfig::deterministic()
+    };
+
+    let mut interpreter = Interpreter::new_checked(output, None, None)
+        .expect("generated program should pass the verifier")
+        .with_config(config);
+    let first = interpreter.run();
+    interpreter.reset();
+    let second = interpreter.run();
+
+    (first, second)
+}
+
+#[test]
+fn fuzz_interpreter() {
+    let runs = env::var("STACK_FUZZ_RUNS").map_or(RUNS, |runs| runs.parse().unwrap());
+    let seed = env::var("STACK_FUZZ_SEED").map_or(0x2545f4914f6cdd1d, |seed| seed.parse().unwrap());
+    let mut rng = Rng(seed);
+
+    for run in 0..runs {
+        let output = generate(&mut rng);
+        assert_eq!(verify(&output), []);
+
+        match panic::catch_unwind(AssertUnwindSafe(|| self::run(&output))) {
+            Ok((first, second)) => assert_eq!(
+                first, second,
+                "run {run} from seed {seed} ended differently after a reset:\n{output}"
+            ),
+            Err(_) => panic!("run {run} from seed {seed} panicked:\n{output}"),
+        }
+    }
+}
+
+#[test]
+fn fuzz_regressions() {
+    for src in REGRESSIONS {
+        let src = format!(".entry main\nmain:\n    {src}\n    ret");
+        let output = Assembler::new().assemble(&src).unwrap();
+
+        match panic::catch_unwind(AssertUnwindSafe(|| run(&output))) {
+            Ok((first, second)) => assert_eq!(first, second, "{src}"),
+            Err(_) => panic!("panicked:\n{src}"),
+        }
+    }
+}
@@ -1,13 +1,13 @@
 use std::{
-    fs::File,
-    io::Read,
+    fs::{self, File},
+    io::{self, Read},
     iter::Peekable,
     path::{Path, PathBuf},
     str::{Chars, Lines},
     sync::{Arc, Mutex},
 };
 
-use stack::{assembler::Assembler, interpreter::Interpreter, SharedWriter};
+use stack::{assembler::Assembler, interpreter::Interpreter, Fields, StackValue};
 
 const SEPARATOR: &str = "----";
 
@@ -39,7 +39,7 @@ pub struct TestRunner {
 impl TestRunner {
     pub fn new(file: String, include_paths: Vec<PathBuf>) -> Self {
         Self {
-            file: file.into(),
+            file,
             include_paths,
             errors: Vec::new(),
         }
@@ -58,19 +58,39 @@ impl TestRunner {
             .with_include_paths(self.include_paths.clone())
             .assemble(&testcase.src)?;
 
-        let stdout = Arc::new(Mutex::new(Vec::new()));
-        let stderr = None;
+        // Programs using an extension this build leaves out can't run, such as with
+        // `--no-default-features`
+        if output
+            .extensions()
+            .iter()
+            .any(|extension| !extension.is_enabled())
+        {
+            return Ok(());
+        }
+
         // TODO: this could panic, which we should interpret as an error (or new panic status?)
-        let mut interpreter =
-            Interpreter::new(&output, Some(Arc::clone(&stdout) as SharedWriter), stderr)?;
+        let stdin = io::Cursor::new(testcase.stdin.clone().unwrap_or_default());
+        let mut interpreter = Interpreter::new(&output, None, None)?
+            .with_stdin(Arc::new(Mutex::new(stdin)))
+            .with_captured_output();
 
-        let status = if interpreter.run().is_ok() {
+        let status = if interpreter.run().into_result().is_ok() {
             Status::Ok
         } else {
             Status::Error
         };
 
-        let stack = interpreter.frames().last().unwrap().opstack.as_slice();
+        let stack = interpreter.current_frame().stack();
+
+        if let Some(want) = testcase.exit {
+            let have = interpreter.exit_code().unwrap_or(0);
+            if want != have {
+                self.add_error(
+                    &testcase,
+                    format!("exit code mismatch: want {want}, have {have}"),
+                );
+            }
+        }
 
         if testcase.status != status {
             self.add_error(
@@ -99,10 +119,38 @@ impl TestRunner {
             }
         }
 
+        if let Some(want) = &testcase.values {
+            let have = interpreter.current_frame().values(widths(want)?);
+
+            if *want != have {
+                self.add_error(
+                    &testcase,
+                    format!("values mismatch: want {want:?}, have {have:?}"),
+                );
+            }
+        }
+
+        let stats = interpreter.stats();
+        for (name, max, have) in [
+            (
+                "instructions",
+                testcase.max_instructions,
+                stats.instructions,
+            ),
+            ("heap", testcase.max_heap, stats.peak_heap as u64),
+        ] {
+            if let Some(max) = max.filter(|&max| have > max) {
+                self.add_error(
+                    &testcase,
+                    format!("{name} over budget: want at most {max}, have {have}"),
+                );
+            }
+        }
+
         if let Some(want) = testcase.stdout.clone() {
             // TODO: fail testcase if stdout is not valid utf8
-            let stdout = stdout.lock().unwrap();
-            let have = std::str::from_utf8(&stdout)?.to_string();
+            let stdout = interpreter.captured_stdout().unwrap_or_default();
+            let have = String::from_utf8(stdout)?;
 
             if want != have {
                 self.add_error(
@@ -152,6 +200,16 @@ pub struct TestCase {
     /// The length of the vector will be used to check the position of the stack pointer, so we
     /// need to be able to distinguish between stack not provided and empty stack
     stack: Option<Vec<i32>>,
+    /// The whole operand stack read as typed values, from the bottom
+    values: Option<Vec<StackValue>>,
+    /// The exit code, where a program which didn't set one exited with 0
+    exit: Option<i32>,
+    /// The most instructions the program may run
+    max_instructions: Option<u64>,
+    /// The most bytes the program may have allocated at once
+    max_heap: Option<u64>,
+    /// The input to the program, otherwise it reads nothing
+    stdin: Option<String>,
     stdout: Option<String>,
 }
 
@@ -166,15 +224,22 @@ pub fn parse_test_file(file: impl AsRef<Path>) -> Result<Vec<TestCase>> {
     while {
         skip_empty_lines(&mut lines);
 
-        let mut testcase = TestCase::default();
+        let mut testcase = TestCase {
+            name: expect_name(&mut lines)?,
+            ..Default::default()
+        };
 
-        testcase.name = expect_name(&mut lines)?;
         expect_separator(&mut lines)?;
-        testcase.src = read_until_separator(&mut lines);
+        testcase.src = read_source(&mut lines)?;
         expect_separator(&mut lines)?;
         testcase.status = expect_status(&mut lines)?;
+        testcase.exit = check_exit(&mut lines)?;
         testcase.stack = check_stack(&mut lines)?;
-        testcase.stdout = check_stdout(&mut lines)?;
+        testcase.values = check_values(&mut lines)?;
+        testcase.max_instructions = check_budget(&mut lines, "max_instructions")?;
+        testcase.max_heap = check_budget(&mut lines, "max_heap")?;
+        testcase.stdin = check_block(&mut lines, "stdin")?;
+        testcase.stdout = check_block(&mut lines, "stdout")?;
 
         testcases.push(testcase);
 
@@ -192,7 +257,7 @@ fn expect_name(lines: &mut Peekable<Lines<'_>>) -> Result<String> {
 
 fn expect_separator(lines: &mut Peekable<Lines<'_>>) -> Result<()> {
     if expect_line(lines)? != SEPARATOR {
-        Err(format!("expected separator"))?
+        Err("expected separator")?
     }
 
     Ok(())
@@ -215,7 +280,7 @@ fn read_until_separator(lines: &mut Peekable<Lines<'_>>) -> String {
             break;
         }
 
-        s.extend(line.chars());
+        s.push_str(line);
         s.push('\n'); // lines() strips the \n which could mess up the program
         lines.next();
     }
@@ -223,6 +288,30 @@ fn read_until_separator(lines: &mut Peekable<Lines<'_>>) -> String {
     s
 }
 
+/// Reads the program source, or the file it names as `< path` relative to the crate root, such as
+/// one of the examples
+fn read_source(lines: &mut Peekable<Lines<'_>>) -> Result<String> {
+    let src = read_until_separator(lines);
+    match src.trim().strip_prefix("< ") {
+        Some(path) => Ok(fs::read_to_string(path.trim())?),
+        None => Ok(src),
+    }
+}
+
+fn check_exit(lines: &mut Peekable<Lines<'_>>) -> Result<Option<i32>> {
+    if !check_line(lines)
+        .map(|s| s.starts_with("exit"))
+        .unwrap_or_default()
+    {
+        return Ok(None);
+    }
+
+    let line = expect_line(lines)?;
+    let (_, code) = line.split_at("exit".len());
+
+    Ok(Some(code.trim().parse::<i32>()?))
+}
+
 fn check_stack(lines: &mut Peekable<Lines<'_>>) -> Result<Option<Vec<i32>>> {
     if !check_line(lines)
         .map(|s| s.starts_with("stack"))
@@ -257,26 +346,87 @@ fn check_stack(lines: &mut Peekable<Lines<'_>>) -> Result<Option<Vec<i32>>> {
     Ok(Some(values))
 }
 
-fn check_stdout(lines: &mut Peekable<Lines<'_>>) -> Result<Option<String>> {
+fn check_values(lines: &mut Peekable<Lines<'_>>) -> Result<Option<Vec<StackValue>>> {
+    if !check_line(lines)
+        .map(|s| s.starts_with("values"))
+        .unwrap_or_default()
+    {
+        return Ok(None);
+    }
+
+    let line = expect_line(lines)?;
+    let (_, values) = line.split_at("values".len());
+
+    let values = values
+        .trim()
+        .strip_prefix('[')
+        .and_then(|values| values.strip_suffix(']'))
+        .ok_or("expected values in brackets")?;
+
+    let values = values
+        .split(',')
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+        .map(str::parse)
+        .collect::<Result<Vec<StackValue>>>()?;
+
+    Ok(Some(values))
+}
+
+/// Reads a line such as `max_heap 64`, giving the most of something the program may use
+fn check_budget(lines: &mut Peekable<Lines<'_>>, name: &str) -> Result<Option<u64>> {
+    if !check_line(lines)
+        .map(|s| s.starts_with(name))
+        .unwrap_or_default()
+    {
+        return Ok(None);
+    }
+
+    let line = expect_line(lines)?;
+    let (_, max) = line.split_at(name.len());
+
+    Ok(Some(max.trim().parse::<u64>()?))
+}
+
+/// The widths of `values`, to read the operand stack with
+fn widths(values: &[StackValue]) -> Result<Fields> {
+    if values.is_empty() {
+        return Ok(Fields(0));
+    }
+
+    let widths: String = values
+        .iter()
+        .map(|value| match value {
+            StackValue::Byte(_) => 'b',
+            StackValue::Word(_) => 'w',
+            StackValue::DoubleWord(_) => 'd',
+        })
+        .collect();
+
+    widths.parse()
+}
+
+/// Reads the lines after a `stdin` or `stdout` line up to a separator
+fn check_block(lines: &mut Peekable<Lines<'_>>, name: &str) -> Result<Option<String>> {
     if !check_line(lines)
-        .map(|s| s.starts_with("stdout"))
+        .map(|s| s.starts_with(name))
         .unwrap_or_default()
     {
         return Ok(None);
     }
     expect_line(lines)?;
 
-    let stdout = read_until_separator(lines);
+    let block = read_until_separator(lines);
     expect_separator(lines)?;
 
-    Ok(Some(stdout))
+    Ok(Some(block))
 }
 
 fn expect_line<'a>(lines: &mut Peekable<Lines<'a>>) -> Result<&'a str> {
     lines
         .next()
         .map(str::trim)
-        .ok_or(format!("unexpected eof").into())
+        .ok_or_else(|| "unexpected eof".into())
 }
 
 fn check_line<'a>(lines: &mut Peekable<Lines<'a>>) -> Option<&'a str> {
@@ -286,7 +436,7 @@ fn check_line<'a>(lines: &mut Peekable<Lines<'a>>) -> Option<&'a str> {
 fn expect_char(chars: &mut Peekable<Chars<'_>>, want: char) -> Result<()> {
     skip_whitespace(chars);
 
-    let have = chars.next().ok_or(format!("unexpected eof"))?;
+    let have = chars.next().ok_or("unexpected eof")?;
     if want != have {
         Err(format!("want {want}, have {have}"))?
     }